@@ -5,6 +5,9 @@ pub struct KernelConfig {
     pub machine_name: String,
     pub acpi: bool,
     pub gdbstub: bool,
+    /// Compile the names from pci.ids into the kernel, so that pci functions are printed with vendor, device, and class names
+    #[serde(default)]
+    pub pci_ids: bool,
 }
 
 impl KernelConfig {
@@ -6,10 +6,10 @@
 /// This macro re-exports definitions required to fill out enum variants
 #[macro_export]
 macro_rules! enum_reexport {
-    ( $m:ident) => {
+    ( $($m:ident),+ ) => {
         /// A module for re-exporting things for enumeration fillout
         pub mod doors_enum_variants {
-            pub use super::$m::doors_enum_variants::*;
+            $(pub use super::$m::doors_enum_variants::*;)+
         }
     };
 }
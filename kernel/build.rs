@@ -71,6 +71,120 @@ fn write_font_source(name: String, table: Vec<FontData>) {
     std::fs::write(dest_path, contents).unwrap();
 }
 
+/// The names from the pci id database
+#[derive(Default)]
+struct PciIds {
+    vendors: Vec<(u16, String)>,
+    devices: Vec<(u16, u16, String)>,
+    classes: Vec<(u8, String)>,
+    subclasses: Vec<(u8, u8, String)>,
+    prog_ifs: Vec<(u8, u8, u8, String)>,
+}
+
+/// Parse the contents of a pci.ids file. Subsystem entries are skipped.
+fn parse_pci_ids(contents: &str) -> PciIds {
+    let mut ids = PciIds::default();
+    let mut vendor = None;
+    let mut class = None;
+    let mut subclass = None;
+    for line in contents.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let tabs = line.chars().take_while(|c| *c == '\t').count();
+        let Some((id, name)) = line[tabs..].split_once("  ") else {
+            continue;
+        };
+        let name = name.trim().to_string();
+        match tabs {
+            0 => {
+                if let Some(c) = id.strip_prefix("C ") {
+                    vendor = None;
+                    class = u8::from_str_radix(c.trim(), 16).ok();
+                    if let Some(c) = class {
+                        ids.classes.push((c, name));
+                    }
+                } else {
+                    class = None;
+                    vendor = u16::from_str_radix(id.trim(), 16).ok();
+                    if let Some(v) = vendor {
+                        ids.vendors.push((v, name));
+                    }
+                }
+            }
+            1 => {
+                if let Some(v) = vendor {
+                    if let Ok(d) = u16::from_str_radix(id.trim(), 16) {
+                        ids.devices.push((v, d, name));
+                    }
+                } else if let Some(c) = class {
+                    subclass = u8::from_str_radix(id.trim(), 16).ok();
+                    if let Some(s) = subclass {
+                        ids.subclasses.push((c, s, name));
+                    }
+                }
+            }
+            2 => {
+                if let (Some(c), Some(s)) = (class, subclass) {
+                    if let Ok(p) = u8::from_str_radix(id.trim(), 16) {
+                        ids.prog_ifs.push((c, s, p, name));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    ids.vendors.sort_by_key(|a| a.0);
+    ids.devices.sort_by_key(|a| (a.0, a.1));
+    ids.classes.sort_by_key(|a| a.0);
+    ids.subclasses.sort_by_key(|a| (a.0, a.1));
+    ids.prog_ifs.sort_by_key(|a| (a.0, a.1, a.2));
+    ids
+}
+
+/// Write the lookup tables for the pci names, the tables are empty when the names are not enabled
+fn write_pci_ids_source(name: String, ids: PciIds) {
+    let out_dir = std::env::var_os("OUT_DIR").unwrap();
+    let dest_path = std::path::Path::new(&out_dir).join(&name);
+    let mut contents = String::new();
+
+    contents.push_str("/// The vendor names, sorted by vendor id\n");
+    contents.push_str("pub static VENDORS: &[(u16, &str)] = &[\n");
+    for (v, n) in &ids.vendors {
+        contents.push_str(&format!("(0x{:04x}, {:?}),\n", v, n));
+    }
+    contents.push_str("];\n");
+    contents.push_str("/// The device names, sorted by vendor id and device id\n");
+    contents.push_str("pub static DEVICES: &[(u16, u16, &str)] = &[\n");
+    for (v, d, n) in &ids.devices {
+        contents.push_str(&format!("(0x{:04x}, 0x{:04x}, {:?}),\n", v, d, n));
+    }
+    contents.push_str("];\n");
+    contents.push_str("/// The class names, sorted by class\n");
+    contents.push_str("pub static CLASSES: &[(u8, &str)] = &[\n");
+    for (c, n) in &ids.classes {
+        contents.push_str(&format!("(0x{:02x}, {:?}),\n", c, n));
+    }
+    contents.push_str("];\n");
+    contents.push_str("/// The subclass names, sorted by class and subclass\n");
+    contents.push_str("pub static SUBCLASSES: &[(u8, u8, &str)] = &[\n");
+    for (c, s, n) in &ids.subclasses {
+        contents.push_str(&format!("(0x{:02x}, 0x{:02x}, {:?}),\n", c, s, n));
+    }
+    contents.push_str("];\n");
+    contents.push_str("/// The programming interface names, sorted by class, subclass, and programming interface\n");
+    contents.push_str("pub static PROG_IFS: &[(u8, u8, u8, &str)] = &[\n");
+    for (c, s, p, n) in &ids.prog_ifs {
+        contents.push_str(&format!(
+            "(0x{:02x}, 0x{:02x}, 0x{:02x}, {:?}),\n",
+            c, s, p, n
+        ));
+    }
+    contents.push_str("];\n");
+
+    std::fs::write(dest_path, contents).unwrap();
+}
+
 fn main() {
     println!("cargo:rerun-if-changed=./src/cmunbtl.ttf");
     let font = include_bytes!("./src/cmunbtl.ttf");
@@ -87,6 +201,15 @@ fn main() {
         String::from_utf8(config_contents).expect("Invalid contents in kernel configuration");
     let config = toml::from_str::<KernelConfig>(&config).expect("Invalid kernel configuration");
 
+    println!("cargo:rerun-if-changed=./src/pci.ids");
+    let pci_ids = if config.pci_ids {
+        let ids = std::fs::read_to_string("./src/pci.ids").expect("Failed to read pci.ids");
+        parse_pci_ids(&ids)
+    } else {
+        PciIds::default()
+    };
+    write_pci_ids_source("pci_ids.rs".to_string(), pci_ids);
+
     println!("cargo::rustc-check-cfg=cfg(kernel_machine, values(\"pc64\", \"stm32f769i-disco\"))");
     println!("cargo:rustc-cfg=kernel_machine=\"{}\"", config.machine_name);
 
@@ -159,11 +159,11 @@ impl IsrTable {
             debug: default_handler,
             reserved2: 0,
             pending: default_handler,
-            systick: default_handler,
+            systick: crate::modules::time::stm32f769::systick_handler,
             watchdog: default_handler,
             pvd: default_handler,
             tamp_stamp: default_handler,
-            rtc_wakeup: default_handler,
+            rtc_wakeup: crate::modules::rtc::stm32f769::rtc_wakeup_handler,
             flash: default_handler,
             rcc: default_handler,
             exti0: default_handler,
@@ -185,7 +185,7 @@ impl IsrTable {
             can1_sce: default_handler,
             exti5_9: default_handler,
             tim1_9_brk: default_handler,
-            tim1_10_update: default_handler,
+            tim1_10_update: crate::modules::timer::stm32f769::tim1_update_handler,
             tim1_11_trigger_commutation: default_handler,
             tim1_cc: default_handler,
             tim2: default_handler,
@@ -201,10 +201,10 @@ impl IsrTable {
             usart2: default_handler,
             usart3: default_handler,
             exti10_15: default_handler,
-            rtc_alarm: default_handler,
+            rtc_alarm: crate::modules::rtc::stm32f769::rtc_alarm_handler,
             usb_otg_fs_wakeup: default_handler,
             tim8_12_break: default_handler,
-            tim8_13_update: default_handler,
+            tim8_13_update: crate::modules::timer::stm32f769::tim8_update_handler,
             tim8_14_trigger_commutation: default_handler,
             tim8_cc: default_handler,
             dma1_7: default_handler,
@@ -394,6 +394,46 @@ pub extern "C" fn _start() -> ! {
     c.main_mux_select(2); //use the pll as the sysclk
     drop(c);
 
+    // The sysclk is now 216 mhz, from the pll configured above
+    let systick =
+        unsafe { crate::modules::time::stm32f769::SysTickClock::new(0xe000_e010, 216_000_000) };
+    crate::modules::time::set_monotonic_clock(crate::modules::time::MonotonicClock::Stm32SysTick(
+        systick,
+    ));
+
+    // The real time clock runs from the 32.768 khz crystal, or the internal oscillator when the crystal does not start
+    crate::modules::clock::ClockProviderTrait::enable_clock(&ctree, 4 * 32 + 28);
+    let mut power = unsafe { crate::modules::power::stm32f769::Power::new(0x4000_7000) };
+    power.set_backup_domain_access(true);
+    let mut r = rcc_mod.sync_lock();
+    if !r.rtc_enabled() {
+        r.set_lse(true);
+        let mut tries = 0;
+        while !r.lse_ready() && tries < 10_000_000 {
+            tries += 1;
+        }
+        if r.lse_ready() {
+            r.enable_rtc(1);
+        } else {
+            r.set_lsi(true);
+            r.enable_rtc(2);
+        }
+    } else if r.get_rtc_mux() == 2 {
+        r.set_lsi(true);
+    }
+    let rtc_clock = if r.get_rtc_mux() == 2 {
+        while !r.lsi_ready() {}
+        32_000
+    } else {
+        32_768
+    };
+    drop(r);
+    let rtc = unsafe { crate::modules::rtc::stm32f769::Rtc::new(0x4000_2800, rtc_clock) };
+    crate::kernel::RTCS
+        .sync_lock()
+        .register_rtc(crate::modules::rtc::Rtc::Stm32f769(LockedArc::new(rtc)));
+    let _ = crate::modules::rtc::sync_wall_clock();
+
     let ga = LockedArc::new(unsafe {
         crate::modules::gpio::stm32f769::Gpio::new(&ctree_provider, 32 + 0, 0x4002_0000)
     });
@@ -8,6 +8,8 @@ pub struct PciMemory {
     phys: usize,
     /// The size in bytes
     size: usize,
+    /// True when the physical address space was allocated by the kernel, false when it was assigned to the device elsewhere
+    allocated: bool,
 }
 
 impl PciMemory {
@@ -16,7 +18,30 @@ impl PciMemory {
     /// virt should be mapped to phys over a length of size
     /// this mapping should not be changed over the life of this object
     pub(super) unsafe fn build_with(virt: usize, phys: usize, size: usize) -> Self {
-        Self { virt, phys, size }
+        Self {
+            virt,
+            phys,
+            size,
+            allocated: true,
+        }
+    }
+
+    /// Construct a new instance for physical address space that was not allocated by the kernel. Should only be used in the memory management code!
+    /// # Safety
+    /// virt should be mapped to phys over a length of size
+    /// this mapping should not be changed over the life of this object
+    pub(super) unsafe fn build_mapped(virt: usize, phys: usize, size: usize) -> Self {
+        Self {
+            virt,
+            phys,
+            size,
+            allocated: false,
+        }
+    }
+
+    /// Returns true if the physical address space was allocated by the kernel
+    pub fn is_allocated(&self) -> bool {
+        self.allocated
     }
 
     /// Read a u32 at the specified index (byte based index)
@@ -37,6 +62,36 @@ impl PciMemory {
         unsafe { core::ptr::write_volatile(c, val) };
     }
 
+    /// Read a u16 at the specified index (byte based index)
+    pub fn read_u16(&self, address: usize) -> u16 {
+        let mem = unsafe { core::slice::from_raw_parts(self.virt as *const u8, self.size) };
+        let a: &u8 = &mem[address];
+        let b: *const u8 = a as *const u8;
+        let c: &u16 = unsafe { &*(b as *const u16) };
+        unsafe { core::ptr::read_volatile(c) }
+    }
+
+    /// Write a u16 at the specified index (byte based index), with the specified value
+    pub fn write_u16(&mut self, address: usize, val: u16) {
+        let mem = unsafe { core::slice::from_raw_parts_mut(self.virt as *mut u8, self.size) };
+        let a: &mut u8 = &mut mem[address];
+        let b: *mut u8 = a as *mut u8;
+        let c: &mut u16 = unsafe { &mut *(b as *mut u16) };
+        unsafe { core::ptr::write_volatile(c, val) };
+    }
+
+    /// Read a u8 at the specified index
+    pub fn read_u8(&self, address: usize) -> u8 {
+        let mem = unsafe { core::slice::from_raw_parts(self.virt as *const u8, self.size) };
+        unsafe { core::ptr::read_volatile(&mem[address]) }
+    }
+
+    /// Write a u8 at the specified index, with the specified value
+    pub fn write_u8(&mut self, address: usize, val: u8) {
+        let mem = unsafe { core::slice::from_raw_parts_mut(self.virt as *mut u8, self.size) };
+        unsafe { core::ptr::write_volatile(&mut mem[address], val) };
+    }
+
     /// Get the size of the memory area in bytes
     pub fn size(&self) -> usize {
         self.size
@@ -172,3 +227,86 @@ impl<T> core::ops::DerefMut for DmaMemorySlice<T> {
         &mut self.data
     }
 }
+
+/// A region of physical address space (normally memory mapped hardware registers) that has been mapped into virtual memory
+pub struct MappedMemory {
+    /// The starting address for virtual memory address space
+    virt: usize,
+    /// The starting address for physical memory address space
+    phys: usize,
+    /// The size in bytes
+    size: usize,
+}
+
+impl MappedMemory {
+    /// Construct a new instance. Should only be used in the memory management code!
+    /// # Safety
+    /// virt should be mapped to phys over a length of size
+    /// this mapping should not be changed over the life of this object
+    pub(super) unsafe fn build_with(virt: usize, phys: usize, size: usize) -> Self {
+        Self { virt, phys, size }
+    }
+
+    /// Read a u8 at the specified index (byte based index)
+    pub fn read_u8(&self, address: usize) -> u8 {
+        assert!(address < self.size);
+        unsafe { core::ptr::read_volatile((self.virt + address) as *const u8) }
+    }
+
+    /// Read a u16 at the specified index (byte based index)
+    pub fn read_u16(&self, address: usize) -> u16 {
+        assert!(address + 2 <= self.size);
+        unsafe { core::ptr::read_volatile((self.virt + address) as *const u16) }
+    }
+
+    /// Read a u32 at the specified index (byte based index)
+    pub fn read_u32(&self, address: usize) -> u32 {
+        assert!(address + 4 <= self.size);
+        unsafe { core::ptr::read_volatile((self.virt + address) as *const u32) }
+    }
+
+    /// Read a u64 at the specified index (byte based index)
+    pub fn read_u64(&self, address: usize) -> u64 {
+        assert!(address + 8 <= self.size);
+        unsafe { core::ptr::read_volatile((self.virt + address) as *const u64) }
+    }
+
+    /// Write a u8 at the specified index (byte based index), with the specified value
+    pub fn write_u8(&self, address: usize, val: u8) {
+        assert!(address < self.size);
+        unsafe { core::ptr::write_volatile((self.virt + address) as *mut u8, val) };
+    }
+
+    /// Write a u16 at the specified index (byte based index), with the specified value
+    pub fn write_u16(&self, address: usize, val: u16) {
+        assert!(address + 2 <= self.size);
+        unsafe { core::ptr::write_volatile((self.virt + address) as *mut u16, val) };
+    }
+
+    /// Write a u32 at the specified index (byte based index), with the specified value
+    pub fn write_u32(&self, address: usize, val: u32) {
+        assert!(address + 4 <= self.size);
+        unsafe { core::ptr::write_volatile((self.virt + address) as *mut u32, val) };
+    }
+
+    /// Write a u64 at the specified index (byte based index), with the specified value
+    pub fn write_u64(&self, address: usize, val: u64) {
+        assert!(address + 8 <= self.size);
+        unsafe { core::ptr::write_volatile((self.virt + address) as *mut u64, val) };
+    }
+
+    /// Get the size of the memory area in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Get the starting physical address for the region
+    pub fn phys(&self) -> usize {
+        self.phys
+    }
+
+    /// Get the starting virtual address for the region
+    pub fn virt(&self) -> usize {
+        self.virt
+    }
+}
@@ -456,7 +456,7 @@ unsafe impl core::alloc::Allocator for Locked<SimpleMemoryManager<'_>> {
 }
 
 impl memory::PciMemory {
-    /// Allocate some pci memory with the given size. TODO implement a 32-bit restricted version of this function.
+    /// Allocate some pci memory with the given size, mapped uncached. TODO implement a 32-bit restricted version of this function.
     pub fn new(size: usize) -> Result<Self, core::alloc::AllocError> {
         let mut t = super::PAGE_ALLOCATOR.sync_lock();
         let phys = t.extra_mem.allocate_nonram_memory(size, size)?;
@@ -466,21 +466,36 @@ impl memory::PciMemory {
         let mut mm = super::PAGING_MANAGER.sync_lock();
         let va = unsafe { virt.as_ref() }.as_ptr() as usize;
         let pa = unsafe { phys.as_ref() }.as_ptr() as usize;
-        match mm.map_addresses_read_write(va, pa, layout.size()) {
+        match mm.map_addresses_uncached(va, pa, layout.size()) {
             Ok(()) => Ok(unsafe { Self::build_with(va, pa, size) }),
             Err(()) => Err(core::alloc::AllocError),
         }
     }
+
+    /// Map pci memory that has already been assigned to a device, such as a bar assigned by the pci resource allocator or the firmware.
+    /// The memory is mapped uncached, because it holds the registers of the device.
+    pub fn map(phys: usize, size: usize) -> Result<Self, core::alloc::AllocError> {
+        let va = map_registers(phys, size)?;
+        Ok(unsafe { Self::build_mapped(va, phys, size) })
+    }
 }
 
 impl Drop for memory::PciMemory {
     fn drop(&mut self) {
+        if !self.is_allocated() {
+            unmap_registers(self.virt(), self.phys(), self.size());
+            return;
+        }
+        let mut mm = super::PAGING_MANAGER.sync_lock();
+        mm.unmap_mapped_pages(self.virt(), self.size());
+        drop(mm);
         let mut t = super::PAGE_ALLOCATOR.sync_lock();
         let layout = core::alloc::Layout::from_size_align(self.size(), self.size()).unwrap();
         t.extra_mem.deallocate_nonram_memory(
             unsafe { core::ptr::NonNull::new_unchecked(self.phys() as *mut u8) },
             layout,
         );
+        drop(t);
         let layout =
             core::alloc::Layout::from_size_align(self.size(), core::mem::size_of::<Page>())
                 .unwrap();
@@ -490,8 +505,59 @@ impl Drop for memory::PciMemory {
                 layout,
             )
         };
-        let mut mm = super::PAGING_MANAGER.sync_lock();
-        mm.unmap_mapped_pages(self.virt(), self.size());
+    }
+}
+
+/// Map a range of physical addresses holding hardware registers into newly allocated virtual addresses, with caching disabled.
+/// Returns the virtual address that corresponds to phys.
+fn map_registers(phys: usize, size: usize) -> Result<usize, core::alloc::AllocError> {
+    let pagesize = core::mem::size_of::<Page>();
+    let offset = phys % pagesize;
+    let total = (offset + size).div_ceil(pagesize) * pagesize;
+    let layout = core::alloc::Layout::from_size_align(total, pagesize).unwrap();
+    let virt = super::VIRTUAL_MEMORY_ALLOCATOR.allocate(layout)?;
+    let mut mm = super::PAGING_MANAGER.sync_lock();
+    let va = unsafe { virt.as_ref() }.as_ptr() as usize;
+    match mm.map_addresses_uncached(va, phys - offset, total) {
+        Ok(()) => Ok(va + offset),
+        Err(()) => {
+            drop(mm);
+            unsafe {
+                super::VIRTUAL_MEMORY_ALLOCATOR
+                    .deallocate(core::ptr::NonNull::new_unchecked(va as *mut u8), layout)
+            };
+            Err(core::alloc::AllocError)
+        }
+    }
+}
+
+/// Unmap registers that were mapped with [map_registers], then release the virtual addresses
+fn unmap_registers(virt: usize, phys: usize, size: usize) {
+    let pagesize = core::mem::size_of::<Page>();
+    let offset = phys % pagesize;
+    let total = (offset + size).div_ceil(pagesize) * pagesize;
+    let va = virt - offset;
+    let mut mm = super::PAGING_MANAGER.sync_lock();
+    mm.unmap_mapped_pages(va, total);
+    drop(mm);
+    let layout = core::alloc::Layout::from_size_align(total, pagesize).unwrap();
+    unsafe {
+        super::VIRTUAL_MEMORY_ALLOCATOR
+            .deallocate(core::ptr::NonNull::new_unchecked(va as *mut u8), layout)
+    };
+}
+
+impl memory::MappedMemory {
+    /// Map the specified range of physical addresses (normally hardware registers) into virtual memory, with caching disabled.
+    pub fn new(phys: usize, size: usize) -> Result<Self, core::alloc::AllocError> {
+        let va = map_registers(phys, size)?;
+        Ok(unsafe { Self::build_with(va, phys, size) })
+    }
+}
+
+impl Drop for memory::MappedMemory {
+    fn drop(&mut self) {
+        unmap_registers(self.virt(), self.phys(), self.size());
     }
 }
 
@@ -764,6 +830,29 @@ impl<'a> PagingTableManager<'a> {
         virtual_address: usize,
         physical_address: usize,
         size: usize,
+    ) -> Result<(), ()> {
+        self.map_addresses(virtual_address, physical_address, size, 0x3)
+    }
+
+    /// Map the specified range of physical addresses to the specified virtual addresses as read/write with caching disabled,
+    /// as required for hardware registers. size is in bytes.
+    pub fn map_addresses_uncached(
+        &mut self,
+        virtual_address: usize,
+        physical_address: usize,
+        size: usize,
+    ) -> Result<(), ()> {
+        // Present, writable, write through and cache disable
+        self.map_addresses(virtual_address, physical_address, size, 0x1b)
+    }
+
+    /// Map the specified range of physical addresses to the specified virtual addresses with the given page table entry flags. size is in bytes.
+    fn map_addresses(
+        &mut self,
+        virtual_address: usize,
+        physical_address: usize,
+        size: usize,
+        flags: u64,
     ) -> Result<(), ()> {
         let (cr3, _) = x86_64::registers::control::Cr3::read();
         let cr3 = cr3.start_address().as_u64() as usize;
@@ -776,7 +865,7 @@ impl<'a> PagingTableManager<'a> {
 
             if (unsafe { &*self.pt1.as_ptr() }.table.entries[pt1_index] & 1) == 0 {
                 let table = unsafe { &mut *self.pt1.as_mut_ptr() };
-                table.table.entries[pt1_index] = (paddr as u64 | 0x3) & self.physical_mask as u64;
+                table.table.entries[pt1_index] = (paddr as u64 | flags) & self.physical_mask as u64;
                 x86_64::instructions::tlb::flush(x86_64::addr::VirtAddr::new(vaddr as u64));
             } else {
                 return Err(());
@@ -6,7 +6,6 @@ use crate::IoReadWrite;
 use crate::Locked;
 use crate::LockedArc;
 use acpi::fadt::Fadt;
-use acpi::hpet::HpetTable;
 use acpi::madt::Madt;
 use acpi::sdt::SdtHeader;
 use acpi::PlatformInfo;
@@ -102,7 +101,21 @@ pub extern "C" fn divide_by_zero() {
 }
 
 doors_macros::todo_item!("Make a macro to build interrupt handlers on x86");
-/// The irq4 handler
+/// The irq0 handler
+pub extern "x86-interrupt" fn irq0(_isf: InterruptStackFrame) {
+    if let Ok(h) = IRQ_HANDLERS[0].try_get() {
+        let mut h = h.sync_lock();
+        if let Some(h2) = h.as_mut() {
+            h2();
+        }
+    }
+    let p = INTERRUPT_CONTROLLER.read();
+    if let Some(p) = p.as_ref() {
+        p.end_of_interrupt(0)
+    }
+}
+
+/// The irq3 handler
 pub extern "x86-interrupt" fn irq3(_isf: InterruptStackFrame) {
     if let Ok(h) = IRQ_HANDLERS[3].try_get() {
         let mut h = h.sync_lock();
@@ -144,6 +157,20 @@ pub extern "x86-interrupt" fn irq7(_isf: InterruptStackFrame) {
     }
 }
 
+/// The irq8 handler
+pub extern "x86-interrupt" fn irq8(_isf: InterruptStackFrame) {
+    if let Ok(h) = IRQ_HANDLERS[8].try_get() {
+        let mut h = h.sync_lock();
+        if let Some(h2) = h.as_mut() {
+            h2();
+        }
+    }
+    let p = INTERRUPT_CONTROLLER.read();
+    if let Some(p) = p.as_ref() {
+        p.end_of_interrupt(8)
+    }
+}
+
 /// The irq9 handler
 pub extern "x86-interrupt" fn irq9(_isf: InterruptStackFrame) {
     if let Ok(h) = IRQ_HANDLERS[9].try_get() {
@@ -186,6 +213,22 @@ pub extern "x86-interrupt" fn irq11(_isf: InterruptStackFrame) {
     }
 }
 
+/// The local apic timer handler
+pub extern "x86-interrupt" fn lapic_timer(_isf: InterruptStackFrame) {
+    if let Ok(h) = IRQ_HANDLERS[LAPIC_TIMER_IRQ as usize].try_get() {
+        let mut h = h.sync_lock();
+        if let Some(h2) = h.as_mut() {
+            h2();
+        }
+    }
+    if let Ok(apic) = LOCAL_APIC.try_get() {
+        apic.end_of_interrupt();
+    }
+}
+
+/// The spurious interrupt handler for the local apic, these do not get an end of interrupt
+pub extern "x86-interrupt" fn spurious_interrupt(_isf: InterruptStackFrame) {}
+
 ///The handler for segment not present
 #[interrupt_arg_64]
 pub extern "C" fn segment_not_present(arg: u32) {
@@ -529,15 +572,167 @@ impl Pic {
     }
 }
 
-/// The registers for a local apic
-#[repr(align(16))]
-struct LocalApicRegister {
-    /// The apic registers
-    regs: [u32; 256],
+/// The irq number used by the local apic timer. It is delivered on interrupt vector 0x20 + this number, like the pic irqs.
+pub const LAPIC_TIMER_IRQ: u8 = 0x20;
+
+/// The local apic of the processor
+pub struct LocalApic {
+    /// The memory mapped registers of the local apic
+    regs: crate::MappedMemory,
+}
+
+impl LocalApic {
+    /// Read a local apic register, offset is in bytes
+    pub fn read(&self, offset: usize) -> u32 {
+        self.regs.read_u32(offset)
+    }
+
+    /// Write a local apic register, offset is in bytes
+    pub fn write(&self, offset: usize, val: u32) {
+        self.regs.write_u32(offset, val)
+    }
+
+    /// Signal end of interrupt to the local apic
+    pub fn end_of_interrupt(&self) {
+        self.write(0xb0, 0);
+    }
+
+    /// Software enable the local apic, with a spurious interrupt vector of 0xff
+    fn enable(&self) {
+        let v = self.read(0xf0);
+        self.write(0xf0, v | 0x1ff);
+    }
+
+    /// Mask or unmask the local apic timer interrupt
+    fn mask_timer(&self, mask: bool) {
+        let v = self.read(0x320);
+        if mask {
+            self.write(0x320, v | (1 << 16));
+        } else {
+            self.write(0x320, v & !(1 << 16));
+        }
+    }
+}
+
+/// The local apic for the boot processor
+pub static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+
+/// The acpi machine language context, used to evaluate acpi objects after the acpi tables have been parsed
+pub static AML_CONTEXT: Locked<Option<aml::AmlContext>> = Locked::new(None);
+
+/// Aml processing struct, giving acpi methods access to memory, io ports, and pci configuration space
+struct AmlHandler {
+    /// The physical memory mapped for acpi methods, indexed by the physical address of the first page
+    mappings: Locked<alloc::collections::BTreeMap<usize, crate::MappedMemory>>,
+    /// Access to pci configuration space
+    pci: Locked<crate::modules::pci::x86::PciRegisters>,
 }
 
-/// Aml processing struct
-struct AmlHandler {}
+impl AmlHandler {
+    /// Construct a new self
+    fn new() -> Self {
+        Self {
+            mappings: Locked::new(alloc::collections::BTreeMap::new()),
+            pci: Locked::new(unsafe { crate::modules::pci::x86::PciRegisters::new_unchecked() }),
+        }
+    }
+
+    /// Run a closure with the ecam access for a bus of a pci segment, if any mcfg region covers it.
+    /// The ecam access is shared with the pci enumeration.
+    fn with_ecam<T>(
+        &self,
+        segment: u16,
+        bus: u8,
+        f: impl FnOnce(&mut crate::modules::pci::ecam::Ecam) -> T,
+    ) -> Option<T> {
+        let ecam = crate::modules::pci::ecam::ECAM
+            .sync_lock()
+            .iter()
+            .find(|e| e.contains(segment, bus))
+            .cloned();
+        ecam.map(|mut e| f(&mut e))
+    }
+
+    /// Run a closure with the mapped memory containing a physical address and the offset of the address in that memory.
+    /// Returns None when the memory cannot be mapped, so that a bad address in the firmware does not stop the kernel.
+    fn with_memory<T>(
+        &self,
+        address: usize,
+        f: impl FnOnce(&crate::MappedMemory, usize) -> T,
+    ) -> Option<T> {
+        let page = address & !0xfff;
+        let mut mappings = self.mappings.sync_lock();
+        let m = match mappings.entry(page) {
+            alloc::collections::btree_map::Entry::Occupied(e) => e.into_mut(),
+            alloc::collections::btree_map::Entry::Vacant(e) => {
+                // Two pages are mapped so that an access crossing into the next page is covered
+                match crate::MappedMemory::new(page, 0x2000) {
+                    Ok(m) => e.insert(m),
+                    Err(err) => {
+                        crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+                            "Failed to map memory at {:x} for acpi {:?}\r\n",
+                            address,
+                            err
+                        ));
+                        return None;
+                    }
+                }
+            }
+        };
+        Some(f(m, address - page))
+    }
+
+    /// Read the dword of pci configuration space containing the given offset.
+    /// Ecam is used when an mcfg region covers the bus, otherwise only the first 256 bytes of segment 0 are accessible and everything else reads as all ones.
+    fn read_pci(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        use crate::modules::pci::PciConfigurationSpaceTrait;
+        if let Some(v) = self.with_ecam(segment, bus, |e| {
+            e.read_extended_u32(bus, device, function, offset & 0xffc)
+        }) {
+            return v.unwrap_or(0xffff_ffff);
+        }
+        if segment != 0 || offset > 0xff {
+            return 0xffff_ffff;
+        }
+        self.pci
+            .sync_lock()
+            .read_u32(bus, device, function, (offset & 0xfc) as u8)
+    }
+
+    /// Write size bytes of pci configuration space at the given offset, using a read-modify-write of the containing dword.
+    /// Ecam is used when an mcfg region covers the bus, otherwise only the first 256 bytes of segment 0 are accessible.
+    fn write_pci(&self, address: (u16, u8, u8, u8), offset: u16, value: u32, size: u16) {
+        use crate::modules::pci::PciConfigurationSpaceTrait;
+        let (segment, bus, device, function) = address;
+        let merge = |old: u32| {
+            if size == 4 {
+                value
+            } else {
+                let shift = (offset & 3) * 8;
+                let mask = ((1u32 << (size * 8)) - 1) << shift;
+                (old & !mask) | ((value << shift) & mask)
+            }
+        };
+        let reg = offset & 0xffc;
+        if self
+            .with_ecam(segment, bus, |e| {
+                if let Some(old) = e.read_extended_u32(bus, device, function, reg) {
+                    e.write_extended_u32(bus, device, function, reg, merge(old));
+                }
+            })
+            .is_some()
+        {
+            return;
+        }
+        if segment != 0 || offset > 0xff {
+            return;
+        }
+        let reg = reg as u8;
+        let mut pci = self.pci.sync_lock();
+        let v = merge(pci.read_u32(bus, device, function, reg));
+        pci.write_u32(bus, device, function, reg, v);
+    }
+}
 
 /// The system boot structure
 #[doors_macros::config_check_struct]
@@ -681,20 +876,70 @@ impl LockedArc<Pin<Box<X86System<'_>>>> {
                 acpi::sdt::Signature::WAET => {
                     crate::VGA.print_str("TODO Parse the Waet table\r\n");
                 }
-                acpi::sdt::Signature::HPET => match acpi.find_table::<HpetTable>() {
-                    Ok(_hpet) => crate::VGA.print_str("TODO Parse the Hpet table\r\n"),
+                acpi::sdt::Signature::HPET => match acpi::HpetInfo::new(&acpi) {
+                    Ok(hpet) => {
+                        if let Some(h) =
+                            crate::modules::timer::x86::hpet::Hpet::new(hpet.base_address)
+                        {
+                            crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+                                "HPET at {:x}, {} hz\r\n",
+                                hpet.base_address,
+                                h.frequency()
+                            ));
+                            crate::kernel::TIMERS.sync_lock().register_timer(
+                                crate::modules::timer::Timer::X86Hpet(LockedArc::new(h)),
+                            );
+                        }
+                    }
                     Err(e) => crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
                         "HPET ERROR {:?}\r\n",
                         e
                     )),
                 },
                 acpi::sdt::Signature::FADT => match acpi.find_table::<Fadt>() {
-                    Ok(_fadt) => crate::VGA.print_str("TODO Parse the Fadt\r\n"),
+                    Ok(fadt) => {
+                        *crate::modules::power::x86::ACPI_POWER.sync_lock() =
+                            Some(crate::modules::power::x86::AcpiPower::new(&fadt));
+                        crate::modules::acpi::sci::init(&fadt);
+                        let century = { fadt.century };
+                        if let Some(rtc) = crate::modules::rtc::x86::CmosRtc::new(
+                            (century != 0).then_some(century),
+                        ) {
+                            crate::kernel::RTCS.sync_lock().register_rtc(
+                                crate::modules::rtc::Rtc::X86Cmos(LockedArc::new(rtc)),
+                            );
+                        }
+                    }
                     Err(e) => crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
                         "FADT ERROR {:?}\r\n",
                         e
                     )),
                 },
+                acpi::sdt::Signature::MCFG => match acpi.find_table::<acpi::mcfg::Mcfg>() {
+                    Ok(mcfg) => {
+                        let mut ecam = crate::modules::pci::ecam::ECAM.sync_lock();
+                        for e in mcfg.entries() {
+                            let region = crate::modules::pci::ecam::EcamRegion {
+                                segment: { e.pci_segment_group },
+                                base: { e.base_address },
+                                bus_start: e.bus_number_start,
+                                bus_end: e.bus_number_end,
+                            };
+                            crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+                                "mcfg segment {} busses {}-{} at {:x}\r\n",
+                                region.segment,
+                                region.bus_start,
+                                region.bus_end,
+                                region.base
+                            ));
+                            ecam.push(crate::modules::pci::ecam::Ecam::new(region));
+                        }
+                    }
+                    Err(e) => crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+                        "MCFG ERROR {:?}\r\n",
+                        e
+                    )),
+                },
                 acpi::sdt::Signature::MADT => match acpi.find_table::<Madt>() {
                     Err(e) => crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
                         "MADT ERROR {:?}\r\n",
@@ -756,6 +1001,95 @@ impl LockedArc<Pin<Box<X86System<'_>>>> {
     }
 }
 
+impl LockedArc<Pin<Box<X86System<'_>>>> {
+    /// Start the timer that wakes sleeping tasks with the local apic timer, leaving irq 0 to the tick counter
+    fn setup_sleep_timer() {
+        use crate::modules::timer::{Timer, TimerTrait};
+        let lapic = {
+            let mut timers = crate::kernel::TIMERS.sync_lock();
+            let mut i = 0;
+            let mut lapic = None;
+            while timers.exists(i) {
+                if let Timer::X86LocalApic(l) = &*timers.module(i).sync_lock() {
+                    lapic = Some(l.clone());
+                }
+                i += 1;
+            }
+            lapic
+        };
+        let Some(timer) = lapic.and_then(|mut l| l.get_timer(0).ok()) else {
+            crate::VGA.print_str("No timer to wake sleeping tasks\r\n");
+            return;
+        };
+        if let Err(e) = crate::modules::time::start_sleep_timer(timer, 1000) {
+            crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+                "Failed to start the sleep timer {:?}\r\n",
+                e
+            ));
+        }
+    }
+
+    /// Select and start the monotonic clock for the kernel, preferring the invariant time stamp counter
+    fn setup_monotonic_clock(&self) {
+        use crate::modules::time::MonotonicClock;
+        use crate::modules::timer::{Timer, TimerTrait};
+        let mut hpet = None;
+        let mut pit = None;
+        {
+            let mut timers = crate::kernel::TIMERS.sync_lock();
+            let mut i = 0;
+            while timers.exists(i) {
+                let t = timers.module(i);
+                match &*t.sync_lock() {
+                    Timer::X86Hpet(h) if hpet.is_none() => hpet = Some(h.clone()),
+                    Timer::X86Pit(p) if pit.is_none() => pit = Some(p.clone()),
+                    _ => {}
+                }
+                i += 1;
+            }
+        }
+
+        let tsc = {
+            let this = self.sync_lock();
+            crate::modules::time::x86::TscClock::new(&this.cpuid, |us| {
+                if let Some(h) = &hpet {
+                    h.sync_lock().calibration_delay_us(us)
+                } else if let Some(p) = &pit {
+                    p.sync_lock().calibration_delay_us(us)
+                } else {
+                    Err(crate::modules::timer::TimerError::TimerDoesNotExist)
+                }
+            })
+        };
+        if let Some(tsc) = tsc {
+            crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+                "Monotonic clock is the time stamp counter at {} hz\r\n",
+                tsc.frequency()
+            ));
+            crate::modules::time::set_monotonic_clock(MonotonicClock::X86Tsc(tsc));
+            return;
+        }
+
+        let timer = pit
+            .and_then(|mut p| p.get_timer(0).ok())
+            .or_else(|| hpet.and_then(|mut h| h.get_timer(0).ok()));
+        if let Some(timer) = timer {
+            match crate::modules::time::TickCounter::new(timer, 1000) {
+                Ok(tc) => {
+                    crate::VGA.print_str("Monotonic clock is a 1 ms tick counter\r\n");
+                    crate::modules::time::set_monotonic_clock(MonotonicClock::TickCounter(tc));
+                }
+                Err(e) => crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+                    "Failed to start the tick counter {:?}\r\n",
+                    e
+                )),
+            }
+        } else {
+            crate::VGA.print_str("No timer available for the monotonic clock\r\n");
+        }
+    }
+}
+
 impl crate::kernel::SystemTrait for LockedArc<Pin<Box<X86System<'_>>>> {
     fn enable_interrupts(&self) {
         x86_64::instructions::interrupts::enable();
@@ -767,9 +1101,15 @@ impl crate::kernel::SystemTrait for LockedArc<Pin<Box<X86System<'_>>>> {
 
     fn enable_irq(&self, irq: u8) {
         self.disable_interrupts_for(|| {
-            let p = INTERRUPT_CONTROLLER.read();
-            if let Some(p) = p.as_ref() {
-                p.enable_irq(irq)
+            if irq == LAPIC_TIMER_IRQ {
+                if let Ok(apic) = LOCAL_APIC.try_get() {
+                    apic.mask_timer(false);
+                }
+            } else if irq < 16 {
+                let p = INTERRUPT_CONTROLLER.read();
+                if let Some(p) = p.as_ref() {
+                    p.enable_irq(irq)
+                }
             }
         });
     }
@@ -785,9 +1125,15 @@ impl crate::kernel::SystemTrait for LockedArc<Pin<Box<X86System<'_>>>> {
 
     fn disable_irq(&self, irq: u8) {
         self.disable_interrupts_for(|| {
-            let p = INTERRUPT_CONTROLLER.read();
-            if let Some(p) = p.as_ref() {
-                p.disable_irq(irq)
+            if irq == LAPIC_TIMER_IRQ {
+                if let Ok(apic) = LOCAL_APIC.try_get() {
+                    apic.mask_timer(true);
+                }
+            } else if irq < 16 {
+                let p = INTERRUPT_CONTROLLER.read();
+                if let Some(p) = p.as_ref() {
+                    p.disable_irq(irq)
+                }
             }
         });
     }
@@ -809,8 +1155,15 @@ impl crate::kernel::SystemTrait for LockedArc<Pin<Box<X86System<'_>>>> {
         crate::VGA.print_str_async("ACPI INFORMATION\r\n").await;
     }
 
+    fn shutdown(&self) -> ! {
+        crate::modules::power::x86::shutdown()
+    }
+
+    fn reboot(&self) -> ! {
+        crate::modules::power::x86::reboot()
+    }
+
     fn init(&self) {
-        super::setup_serial();
         {
             let this = self.sync_lock();
             let cap = this.cpuid.get_processor_capacity_feature_info().unwrap();
@@ -820,10 +1173,25 @@ impl crate::kernel::SystemTrait for LockedArc<Pin<Box<X86System<'_>>>> {
             }
         }
 
-        super::serial_interrupts();
-        let aml_handler = Box::new(AmlHandler {});
+        if let Some(pit) = crate::modules::timer::x86::pit::Pit::new() {
+            let lapic = crate::modules::timer::x86::lapic::LocalApicTimer::new(|us| {
+                pit.calibration_delay_us(us)
+            });
+            let mut timers = crate::kernel::TIMERS.sync_lock();
+            timers.register_timer(crate::modules::timer::Timer::X86Pit(LockedArc::new(pit)));
+            if let Some(lapic) = lapic {
+                crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+                    "Local apic timer at {} hz\r\n",
+                    lapic.frequency()
+                ));
+                timers.register_timer(crate::modules::timer::Timer::X86LocalApic(LockedArc::new(
+                    lapic,
+                )));
+            }
+        }
+
+        let aml_handler = Box::new(AmlHandler::new());
         let mut aml = aml::AmlContext::new(aml_handler, aml::DebugVerbosity::All);
-        aml.initialize_objects().unwrap();
 
         {
             let this = self.sync_lock();
@@ -850,147 +1218,160 @@ impl crate::kernel::SystemTrait for LockedArc<Pin<Box<X86System<'_>>>> {
 
         doors_macros::config_check_bool!(acpi, {
             self.handle_acpi(&mut aml);
+            if let Err(e) = aml.initialize_objects() {
+                crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+                    "Failed to initialize acpi objects {:?}\r\n",
+                    e
+                ));
+            }
+            crate::modules::acpi::register_devices(&mut aml);
+            // Interrupts are delivered by the 8259 interrupt controllers, the io apic is not programmed yet
+            crate::modules::acpi::prt::build_routing(&mut aml, false);
+            crate::modules::acpi::sci::register_listener(|e| {
+                if let crate::modules::acpi::sci::AcpiEvent::PowerButton = e {
+                    use crate::kernel::SystemTrait;
+                    crate::VGA.print_str("Power button pressed\r\n");
+                    crate::SYSTEM.read().shutdown();
+                }
+            });
+            crate::modules::acpi::sci::start(&mut aml);
         });
+        AML_CONTEXT.sync_lock().replace(aml);
+
+        super::setup_serial();
+        super::serial_interrupts();
+
+        self.setup_monotonic_clock();
+        Self::setup_sleep_timer();
+
+        {
+            let mut rtcs = crate::kernel::RTCS.sync_lock();
+            if !rtcs.exists(0) {
+                if let Some(rtc) = crate::modules::rtc::x86::CmosRtc::new(None) {
+                    rtcs.register_rtc(crate::modules::rtc::Rtc::X86Cmos(LockedArc::new(rtc)));
+                }
+            }
+        }
+        match crate::modules::rtc::sync_wall_clock() {
+            Ok(t) => crate::VGA
+                .print_fixed_str(doors_macros2::fixed_string_format!("The time is {}\r\n", t)),
+            Err(e) => crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+                "Failed to read the real time clock {:?}\r\n",
+                e
+            )),
+        }
     }
 }
 
 impl aml::Handler for AmlHandler {
-    fn read_u8(&self, _address: usize) -> u8 {
-        crate::VGA.print_str("r1\r\n");
-        todo!()
+    fn read_u8(&self, address: usize) -> u8 {
+        self.with_memory(address, |m, o| m.read_u8(o)).unwrap_or(0)
     }
 
-    fn read_u16(&self, _address: usize) -> u16 {
-        crate::VGA.print_str("r2\r\n");
-        todo!()
+    fn read_u16(&self, address: usize) -> u16 {
+        self.with_memory(address, |m, o| m.read_u16(o)).unwrap_or(0)
     }
 
-    fn read_u32(&self, _address: usize) -> u32 {
-        crate::VGA.print_str("r3\r\n");
-        todo!()
+    fn read_u32(&self, address: usize) -> u32 {
+        self.with_memory(address, |m, o| m.read_u32(o)).unwrap_or(0)
     }
 
-    fn read_u64(&self, _address: usize) -> u64 {
-        crate::VGA.print_str("r4\r\n");
-        todo!()
+    fn read_u64(&self, address: usize) -> u64 {
+        self.with_memory(address, |m, o| m.read_u64(o)).unwrap_or(0)
     }
 
-    fn write_u8(&mut self, _address: usize, _value: u8) {
-        crate::VGA.print_str("w1\r\n");
-        todo!()
+    fn write_u8(&mut self, address: usize, value: u8) {
+        let _ = self.with_memory(address, |m, o| m.write_u8(o, value));
     }
 
-    fn write_u16(&mut self, _address: usize, _value: u16) {
-        crate::VGA.print_str("w2\r\n");
-        todo!()
+    fn write_u16(&mut self, address: usize, value: u16) {
+        let _ = self.with_memory(address, |m, o| m.write_u16(o, value));
     }
 
-    fn write_u32(&mut self, _address: usize, _value: u32) {
-        crate::VGA.print_str("w3\r\n");
-        todo!()
+    fn write_u32(&mut self, address: usize, value: u32) {
+        let _ = self.with_memory(address, |m, o| m.write_u32(o, value));
     }
 
-    fn write_u64(&mut self, _address: usize, _value: u64) {
-        crate::VGA.print_str("w4\r\n");
-        todo!()
+    fn write_u64(&mut self, address: usize, value: u64) {
+        let _ = self.with_memory(address, |m, o| m.write_u64(o, value));
     }
 
-    fn read_io_u8(&self, _port: u16) -> u8 {
-        crate::VGA.print_str("i1\r\n");
-        todo!()
+    fn read_io_u8(&self, port: u16) -> u8 {
+        unsafe { super::IOPORTS.get_port_unchecked::<u8>(port) }.port_read()
     }
 
-    fn read_io_u16(&self, _port: u16) -> u16 {
-        crate::VGA.print_str("i2\r\n");
-        todo!()
+    fn read_io_u16(&self, port: u16) -> u16 {
+        unsafe { super::IOPORTS.get_port_unchecked::<u16>(port) }.port_read()
     }
 
-    fn read_io_u32(&self, _port: u16) -> u32 {
-        crate::VGA.print_str("i3\r\n");
-        todo!()
+    fn read_io_u32(&self, port: u16) -> u32 {
+        unsafe { super::IOPORTS.get_port_unchecked::<u32>(port) }.port_read()
     }
 
-    fn write_io_u8(&self, _port: u16, _value: u8) {
-        crate::VGA.print_str("o1\r\n");
-        todo!()
+    fn write_io_u8(&self, port: u16, value: u8) {
+        unsafe { super::IOPORTS.get_port_unchecked::<u8>(port) }.port_write(value)
     }
 
-    fn write_io_u16(&self, _port: u16, _value: u16) {
-        crate::VGA.print_str("o2\r\n");
-        todo!()
+    fn write_io_u16(&self, port: u16, value: u16) {
+        unsafe { super::IOPORTS.get_port_unchecked::<u16>(port) }.port_write(value)
     }
 
-    fn write_io_u32(&self, _port: u16, _value: u32) {
-        crate::VGA.print_str("o3\r\n");
-        todo!()
+    fn write_io_u32(&self, port: u16, value: u32) {
+        unsafe { super::IOPORTS.get_port_unchecked::<u32>(port) }.port_write(value)
     }
 
-    fn read_pci_u8(&self, _segment: u16, _bus: u8, _device: u8, _function: u8, _offset: u16) -> u8 {
-        crate::VGA.print_str("pr1\r\n");
-        todo!()
+    fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        let v = self.read_pci(segment, bus, device, function, offset);
+        (v >> ((offset & 3) * 8)) as u8
     }
 
-    fn read_pci_u16(
-        &self,
-        _segment: u16,
-        _bus: u8,
-        _device: u8,
-        _function: u8,
-        _offset: u16,
-    ) -> u16 {
-        crate::VGA.print_str("pr2\r\n");
-        todo!()
+    fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        let v = self.read_pci(segment, bus, device, function, offset);
+        (v >> ((offset & 2) * 8)) as u16
     }
 
-    fn read_pci_u32(
-        &self,
-        _segment: u16,
-        _bus: u8,
-        _device: u8,
-        _function: u8,
-        _offset: u16,
-    ) -> u32 {
-        crate::VGA.print_str("pr3\r\n");
-        todo!()
+    fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        self.read_pci(segment, bus, device, function, offset)
     }
 
     fn write_pci_u8(
         &self,
-        _segment: u16,
-        _bus: u8,
-        _device: u8,
-        _function: u8,
-        _offset: u16,
-        _value: u8,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u8,
     ) {
-        crate::VGA.print_str("pw1\r\n");
-        todo!()
+        self.write_pci((segment, bus, device, function), offset, value as u32, 1);
     }
 
     fn write_pci_u16(
         &self,
-        _segment: u16,
-        _bus: u8,
-        _device: u8,
-        _function: u8,
-        _offset: u16,
-        _value: u16,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u16,
     ) {
-        crate::VGA.print_str("pw2\r\n");
-        todo!()
+        self.write_pci((segment, bus, device, function), offset, value as u32, 2);
     }
 
     fn write_pci_u32(
         &self,
-        _segment: u16,
-        _bus: u8,
-        _device: u8,
-        _function: u8,
-        _offset: u16,
-        _value: u32,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u32,
     ) {
-        crate::VGA.print_str("pw3\r\n");
-        todo!()
+        self.write_pci((segment, bus, device, function), offset, value, 4);
+    }
+
+    fn handle_notify(&self, object: &aml::AmlName, value: u64) {
+        crate::modules::acpi::sci::notify(object.as_string(), value);
     }
 }
 
@@ -1072,9 +1453,6 @@ pub extern "C" fn start64() -> ! {
         .sync_lock()
         .stop_allocating(0x3fffff);
 
-    let apic: Box<LocalApicRegister, &Locked<memory::BumpAllocator>> =
-        unsafe { Box::new_uninit_in(&VIRTUAL_MEMORY_ALLOCATOR).assume_init() };
-
     PAGING_MANAGER.sync_lock().init();
 
     if true {
@@ -1099,10 +1477,14 @@ pub extern "C" fn start64() -> ! {
     let apic_msr_value = unsafe { apic_msr.read() };
     let apic_address = apic_msr_value & 0xFFFFF000;
 
-    PAGING_MANAGER
-        .sync_lock()
-        .map_addresses_read_write(crate::address(apic.as_ref()), apic_address as usize, 0x400)
-        .unwrap();
+    {
+        let apic = LocalApic {
+            regs: crate::MappedMemory::new(apic_address as usize, 0x400).unwrap(),
+        };
+        apic.enable();
+        apic.write(0x320, (1 << 16) | (0x20 + LAPIC_TIMER_IRQ as u32));
+        LOCAL_APIC.try_init_once(|| apic).unwrap();
+    }
 
     for ih in &IRQ_HANDLERS {
         ih.try_init_once(|| LockedArc::new(None)).unwrap();
@@ -1144,12 +1526,16 @@ pub extern "C" fn start64() -> ! {
                 invalid_opcode as *const (),
             ));
             idt.invalid_opcode = entry;
+            idt[0x20].set_handler_fn(irq0);
             idt[0x23].set_handler_fn(irq3);
             idt[0x24].set_handler_fn(irq4);
             idt[0x27].set_handler_fn(irq7);
+            idt[0x28].set_handler_fn(irq8);
             idt[0x29].set_handler_fn(irq9);
             idt[0x2a].set_handler_fn(irq10);
             idt[0x2b].set_handler_fn(irq11);
+            idt[0x20 + LAPIC_TIMER_IRQ].set_handler_fn(lapic_timer);
+            idt[0xff].set_handler_fn(spurious_interrupt);
         }
     }
 
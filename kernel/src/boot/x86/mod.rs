@@ -162,6 +162,16 @@ impl Locked<IoPortManager> {
         }
     }
 
+    /// Get a reference to a port without claiming it. This is for firmware code, such as acpi methods, that must access ports owned by drivers.
+    /// # Safety
+    /// The caller must ensure that the access does not interfere with the driver that owns the port.
+    pub unsafe fn get_port_unchecked<T>(&self, base: u16) -> IoPortRef<T> {
+        IoPortRef {
+            r: base,
+            _marker: PhantomData,
+        }
+    }
+
     /// Try to get some io ports from the system.
     pub fn get_ports(&self, base: u16, quantity: u16) -> Option<IoPortArray> {
         let mut manager = self.sync_lock();
@@ -223,11 +233,50 @@ extern "C" {
     pub static END_OF_KERNEL: u8;
 }
 
-/// Probe and setup all serial ports for x86
-/// This will probably be removed once pci space is further developed
+/// The addresses and irqs of the legacy com ports, probed when there is no acpi to describe the serial ports
+const LEGACY_SERIAL: [(u16, u8); 4] = [(0x3f8, 4), (0x2f8, 3), (0x3e8, 4), (0x2e8, 3)];
+
+/// Setup the serial ports described by acpi, or the ones at the legacy com port addresses when there is no acpi.
+/// This must be done after the acpi devices have been registered.
 fn setup_serial() {
+    match acpi_serial_ports() {
+        Some(ports) => register_serial_ports(&ports),
+        None => register_serial_ports(&LEGACY_SERIAL),
+    }
+}
+
+/// Find the (base, irq) of the serial ports described by acpi. Returns None if there are no acpi devices at all.
+fn acpi_serial_ports() -> Option<alloc::vec::Vec<(u16, u8)>> {
+    let mut ports = alloc::vec::Vec::new();
+    let mut acpi = false;
+    let mut devices = crate::kernel::DEVICES.sync_lock();
+    let mut i = 0;
+    while devices.exists(i) {
+        let d = devices.module(i);
+        if let crate::modules::Device::Platform(p) = &*d.sync_lock() {
+            acpi = true;
+            if p.is_compatible("PNP0501") {
+                if let (Some((base, _)), Some(irq)) = (p.io_ports().next(), p.irqs().next()) {
+                    match u8::try_from(irq) {
+                        Ok(irq) => ports.push((base, irq)),
+                        Err(_) => crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+                            "Serial port {:x} uses irq {} which is not supported\r\n",
+                            base,
+                            irq
+                        )),
+                    }
+                }
+            }
+        }
+        i += 1;
+    }
+    acpi.then_some(ports)
+}
+
+/// Probe the serial ports at the given addresses and register the ones that are present
+fn register_serial_ports(ports: &[(u16, u8)]) {
     let mut serials = crate::kernel::SERIAL.sync_lock();
-    for (base, irq) in [(0x3f8, 4), (0x2f8, 3), (0x3e8, 4), (0x2e8, 3)] {
+    for &(base, irq) in ports {
         if let Some(com) = crate::modules::serial::x86::X86SerialPort::new(base, irq) {
             crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
                 "Registered serial port {:x}\r\n",
@@ -243,7 +292,7 @@ fn setup_serial() {
     }
 }
 
-/// Enable interrupts for the first serial port if it is present
+/// Enable interrupts for every serial port, the first one also becomes the text display
 fn serial_interrupts() {
     let sys = crate::SYSTEM.read().clone();
     if let Some(mut s) = crate::kernel::SERIAL.take_device(0) {
@@ -258,8 +307,10 @@ fn serial_interrupts() {
         );
         crate::common::VGA.sync_replace(Some(t));
     }
-    if let Some(mut s) = crate::kernel::SERIAL.take_device(1) {
+    let mut i = 1;
+    while let Some(mut s) = crate::kernel::SERIAL.take_device(i) {
         s.enable_async(sys.clone()).unwrap();
+        i += 1;
     }
 }
 
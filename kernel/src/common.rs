@@ -50,6 +50,13 @@ pub fn slice_address<T>(v: &[T]) -> usize {
     v as *const [T] as *const T as usize
 }
 
+/// Add a waker to a list of wakers to wake later, unless the list already has a waker that wakes the same task
+pub fn add_waker(wakers: &mut alloc::vec::Vec<core::task::Waker>, waker: &core::task::Waker) {
+    if !wakers.iter().any(|w| w.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+}
+
 /// The trait that allows reading and writing to and from io ports
 pub trait IoReadWrite<T> {
     /// Read data from the io port, with the proper size. It is advised that the address be properly aligned for the size of access being performed.
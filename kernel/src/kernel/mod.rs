@@ -192,6 +192,11 @@ impl TimerHandler {
         self.timerp.push(LockedArc::new(m));
     }
 
+    /// Does the module index exist?
+    pub fn exists(&self, i: usize) -> bool {
+        i < self.timerp.len()
+    }
+
     /// Get a serial module
     pub fn module(&mut self, i: usize) -> LockedArc<crate::modules::timer::Timer> {
         self.timerp[i].clone()
@@ -262,6 +267,64 @@ impl RngHandler {
     }
 }
 
+/// Tracks all real time clocks in the kernel
+pub struct RtcHandler {
+    /// The real time clocks
+    rtcs: Vec<LockedArc<crate::modules::rtc::Rtc>>,
+}
+
+impl RtcHandler {
+    /// Create a new empty set of real time clocks
+    fn new() -> Self {
+        Self { rtcs: Vec::new() }
+    }
+
+    /// Add a real time clock to the system
+    pub fn register_rtc(&mut self, m: crate::modules::rtc::Rtc) {
+        self.rtcs.push(LockedArc::new(m));
+    }
+
+    /// Does the module index exist?
+    pub fn exists(&self, i: usize) -> bool {
+        i < self.rtcs.len()
+    }
+
+    /// Get a real time clock
+    pub fn module(&mut self, i: usize) -> LockedArc<crate::modules::rtc::Rtc> {
+        self.rtcs[i].clone()
+    }
+}
+
+/// Tracks all devices in the kernel that are not tracked by a more specific handler
+pub struct DeviceHandler {
+    /// The devices
+    devices: Vec<LockedArc<crate::modules::Device>>,
+}
+
+impl DeviceHandler {
+    /// Create a new empty set of devices
+    fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+        }
+    }
+
+    /// Add a device to the system
+    pub fn register_device(&mut self, d: crate::modules::Device) {
+        self.devices.push(LockedArc::new(d));
+    }
+
+    /// Does the device index exist?
+    pub fn exists(&self, i: usize) -> bool {
+        i < self.devices.len()
+    }
+
+    /// Get a device
+    pub fn module(&mut self, i: usize) -> LockedArc<crate::modules::Device> {
+        self.devices[i].clone()
+    }
+}
+
 lazy_static! {
     /// The entire list of gpios for the kernel
     pub static ref GPIO: Locked<GpioHandler> =
@@ -278,6 +341,12 @@ lazy_static! {
     /// The list of rng devices for the kernel
     pub static ref RNGS : AsyncLocked<RngHandler> =
         AsyncLocked::new(RngHandler::new());
+    /// The list of real time clocks for the kernel
+    pub static ref RTCS : Locked<RtcHandler> =
+        Locked::new(RtcHandler::new());
+    /// The list of devices for the kernel
+    pub static ref DEVICES : Locked<DeviceHandler> =
+        Locked::new(DeviceHandler::new());
 }
 
 /// This trait defines system specific elements
@@ -308,6 +377,10 @@ pub trait SystemTrait {
     fn idle_if(&self, f: impl FnMut() -> bool);
     /// Print debug stuff for acpi
     async fn acpi_debug(&self);
+    /// Turn the system off
+    fn shutdown(&self) -> !;
+    /// Reset the system
+    fn reboot(&self) -> !;
 }
 
 /// This struct implements the SystemTrait
@@ -353,4 +426,21 @@ impl SystemTrait for NullSystem {
     fn idle(&self) {}
     fn idle_if(&self, _f: impl FnMut() -> bool) {}
     async fn acpi_debug(&self) {}
+    fn shutdown(&self) -> ! {
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+    fn reboot(&self) -> ! {
+        #[cfg(kernel_machine = "stm32f769i-disco")]
+        {
+            /// The application interrupt and reset control register of the cortex-m system control block
+            const AIRCR: usize = 0xe000_ed0c;
+            // Request a system reset, the upper half is the key required for the write to take effect
+            unsafe { core::ptr::write_volatile(AIRCR as *mut u32, 0x05fa_0004) };
+        }
+        loop {
+            core::hint::spin_loop();
+        }
+    }
 }
@@ -71,7 +71,6 @@ use kernel::SystemTrait;
 use modules::network::NetworkAdapterTrait;
 use modules::rng;
 use modules::rng::RngTrait;
-use modules::video::hex_dump_generic_async;
 pub use modules::video::TextDisplay;
 
 /// This creates the multiboot2 signature that allows the kernel to be booted by a multiboot compliant bootloader such as grub.
@@ -83,17 +82,21 @@ static MULTIBOOT_HEADER: boot::multiboot::Multiboot = boot::multiboot::Multiboot
 /// Used to debug some stuff in the kernel
 pub static DEBUG_STUFF: Locked<[u32; 82]> = Locked::new([0; 82]);
 
-/// Run a network test on the net0 network card, if it exists
+/// Run a network test on the first network card that is registered
 async fn net_test() {
+    use futures::StreamExt;
     crate::VGA
-        .print_str_async("Waiting for network card net0\r\n")
+        .print_str_async("Waiting for a network card\r\n")
         .await;
-    while crate::modules::network::get_network_adapter("net0")
-        .await
-        .is_none()
-    {
-        executor::Task::yield_now().await;
-    }
+    let mut events = crate::modules::network::subscribe().await;
+    let name = loop {
+        match events.next().await {
+            Some(crate::modules::network::NetworkEvent::Added(name)) => break name,
+            Some(_) => {}
+            None => return,
+        }
+    };
+    drop(events);
     crate::VGA
         .print_str_async("Waiting for first rng\r\n")
         .await;
@@ -101,30 +104,121 @@ async fn net_test() {
         executor::Task::yield_now().await;
     }
     crate::VGA
-        .print_str_async("About to do some stuff with a network card net0\r\n")
+        .print_str_async(&alloc::format!(
+            "About to do some stuff with a network card {}\r\n",
+            name
+        ))
         .await;
-    if let Some(na) = crate::modules::network::get_network_adapter("net0").await {
+    if let Some(iface) = crate::modules::network::get_interface(&name).await {
         let rng = kernel::RNGS.lock().await.module(0);
-        let mut na = na.lock().await;
-        crate::VGA
-            .print_str_async("About to do some stuff with a network card\r\n")
-            .await;
-        let ma = na.get_mac_address().await;
-        hex_dump_generic_async(&ma, false, false).await;
         crate::VGA
-            .print_str_async("Done doing stuff with network card\r\n")
+            .print_str_async(&alloc::format!(
+                "Network card {} has mac address {}\r\n",
+                name,
+                iface.mac()
+            ))
             .await;
         for i in 0..32 {
             crate::VGA
                 .print_str_async(&alloc::format!("Sending packet {}\r\n", i))
                 .await;
-            let mut packet = [0; 64];
+            let mut packet = [0; 50];
             {
                 let rng = rng.lock().await;
                 rng.generate_iter(packet.iter_mut());
             }
-            na.send_packet(&packet).await.unwrap();
+            // The local experimental ethertype, so the packets are not mistaken for a real protocol
+            iface
+                .send(
+                    modules::network::MacAddress::BROADCAST,
+                    modules::network::ethernet::EtherType::Other(0x88b5),
+                    &packet,
+                )
+                .await
+                .unwrap();
+        }
+        crate::VGA
+            .print_str_async("Waiting for a dhcp lease\r\n")
+            .await;
+        if !modules::network::dhcp::wait_for_lease(&iface, modules::time::Duration::from_secs(60))
+            .await
+        {
+            crate::VGA
+                .print_str_async("No dhcp lease was received\r\n")
+                .await;
+            return;
+        }
+        let gateway = modules::network::ipv4::routes()
+            .into_iter()
+            .find(|r| r.interface == name && r.prefix_length == 0)
+            .and_then(|r| r.gateway);
+        if let Some(gateway) = gateway {
+            for _ in 0..4 {
+                let r = modules::network::icmp::ping(
+                    gateway,
+                    56,
+                    modules::time::Duration::from_secs(2),
+                )
+                .await;
+                let msg = match r {
+                    Ok(t) => alloc::format!("Reply from {} in {:?}\r\n", gateway, t),
+                    Err(()) => alloc::format!("No reply from {}\r\n", gateway),
+                };
+                crate::VGA.print_str_async(&msg).await;
+            }
         }
+        let addresses = modules::network::dns::resolve("example.com").await;
+        crate::VGA
+            .print_str_async(&alloc::format!(
+                "example.com resolves to {:?}\r\n",
+                addresses
+            ))
+            .await;
+        let stats = iface.adapter().lock().await.statistics().await;
+        crate::VGA
+            .print_str_async(&alloc::format!("{} statistics {:?}\r\n", name, stats))
+            .await;
+        futures::future::join(udp_echo(), tcp_echo()).await;
+    }
+}
+
+/// Echo datagrams sent to port 7, for network_test to exchange datagrams with
+async fn udp_echo() {
+    let any = modules::network::SocketAddress::new(modules::network::Ipv4Address::UNSPECIFIED, 7);
+    if let Ok(s) = modules::network::udp::UdpSocket::bind(any).await {
+        while let Ok((d, source)) = s.recv_from().await {
+            crate::VGA
+                .print_str_async(&alloc::format!(
+                    "Echoing {} bytes to {}\r\n",
+                    d.len(),
+                    source
+                ))
+                .await;
+            let _ = s.send_to(&d, source).await;
+        }
+    }
+}
+
+/// Echo the data sent over tcp connections to port 7, one connection at a time
+async fn tcp_echo() {
+    let any = modules::network::SocketAddress::new(modules::network::Ipv4Address::UNSPECIFIED, 7);
+    let Ok(l) = modules::network::tcp::TcpListener::listen(any, 4).await else {
+        return;
+    };
+    while let Ok(s) = l.accept().await {
+        crate::VGA
+            .print_str_async(&alloc::format!(
+                "Accepted a connection from {}\r\n",
+                s.peer_address()
+            ))
+            .await;
+        let mut buf = [0; 512];
+        while let Ok(n) = s.read(&mut buf).await {
+            if n == 0 || s.write(&buf[..n]).await.is_err() {
+                break;
+            }
+        }
+        let _ = s.close().await;
     }
 }
 
@@ -200,6 +294,24 @@ fn main() -> ! {
             })
             .unwrap();
         executor.spawn(executor::Task::new(net_test())).unwrap();
+        executor
+            .spawn_local(executor::LocalTask::new(
+                modules::network::interface::stack_task(),
+            ))
+            .unwrap();
+        executor
+            .spawn(executor::Task::new(modules::network::arp::retry_task()))
+            .unwrap();
+        executor
+            .spawn(executor::Task::new(modules::network::tcp::timer_task()))
+            .unwrap();
+        executor
+            .spawn(executor::Task::new(modules::network::dhcp::client_task()))
+            .unwrap();
+        #[cfg(kernel_machine = "pc64")]
+        executor
+            .spawn(executor::Task::new(modules::acpi::sci::event_task()))
+            .unwrap();
         executor
             .spawn_closure(async || {
                 modules::pci::setup_pci().await;
@@ -0,0 +1,372 @@
+//! Code for evaluating the acpi namespace, to find the platform devices that are not discovered on a bus.
+
+use acpi::address::{AddressSpace, GenericAddress};
+use alloc::string::String;
+use alloc::vec::Vec;
+use aml::{namespace::LevelType, value::Args, AmlContext, AmlName, AmlValue};
+
+use crate::modules::platform::{PlatformDevice, PlatformResource};
+use crate::IoReadWrite;
+
+pub mod prt;
+pub mod sci;
+
+/// The _STA bit indicating that a device is present
+const STA_PRESENT: u64 = 1;
+
+/// A block of fixed hardware registers described by an acpi generic address
+pub enum AcpiRegister {
+    /// Registers in io port space
+    Io(u16),
+    /// Registers in memory space
+    Memory(crate::MappedMemory),
+}
+
+impl AcpiRegister {
+    /// Build a register block of the given size in bytes from a generic address, if the address space is supported
+    pub fn new(ga: &GenericAddress, size: usize) -> Option<Self> {
+        if ga.address == 0 {
+            return None;
+        }
+        match ga.address_space {
+            AddressSpace::SystemIo => Some(Self::Io(ga.address as u16)),
+            AddressSpace::SystemMemory => crate::MappedMemory::new(ga.address as usize, size)
+                .ok()
+                .map(Self::Memory),
+            _ => None,
+        }
+    }
+
+    /// Read an 8 bit register at the given offset into the block
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        match self {
+            Self::Io(p) => {
+                unsafe { crate::boot::x86::IOPORTS.get_port_unchecked::<u8>(*p + offset as u16) }
+                    .port_read()
+            }
+            Self::Memory(m) => m.read_u8(offset),
+        }
+    }
+
+    /// Write an 8 bit register at the given offset into the block
+    pub fn write_u8(&self, offset: usize, v: u8) {
+        match self {
+            Self::Io(p) => {
+                unsafe { crate::boot::x86::IOPORTS.get_port_unchecked::<u8>(*p + offset as u16) }
+                    .port_write(v)
+            }
+            Self::Memory(m) => m.write_u8(offset, v),
+        }
+    }
+
+    /// Read a 16 bit register at the given offset into the block
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        match self {
+            Self::Io(p) => {
+                unsafe { crate::boot::x86::IOPORTS.get_port_unchecked::<u16>(*p + offset as u16) }
+                    .port_read()
+            }
+            Self::Memory(m) => m.read_u16(offset),
+        }
+    }
+
+    /// Write a 16 bit register at the given offset into the block
+    pub fn write_u16(&self, offset: usize, v: u16) {
+        match self {
+            Self::Io(p) => {
+                unsafe { crate::boot::x86::IOPORTS.get_port_unchecked::<u16>(*p + offset as u16) }
+                    .port_write(v)
+            }
+            Self::Memory(m) => m.write_u16(offset, v),
+        }
+    }
+}
+
+/// Convert a compressed eisa id into its string form, such as PNP0501
+pub fn eisa_id_to_string(id: u64) -> String {
+    let v = (id as u32).swap_bytes();
+    let c = |shift: u32| (((v >> shift) & 0x1f) as u8 + 0x40) as char;
+    alloc::format!("{}{}{}{:04X}", c(26), c(21), c(16), v & 0xffff)
+}
+
+/// Convert an id object (from _HID or _CID) into a string
+pub fn id_string(aml: &AmlContext, v: &AmlValue) -> Option<String> {
+    match v {
+        AmlValue::String(s) => Some(s.clone()),
+        v => v.as_integer(aml).ok().map(eisa_id_to_string),
+    }
+}
+
+/// Invoke a method of a device with the given arguments. Returns None if the method does not exist or fails.
+pub fn invoke(
+    aml: &mut AmlContext,
+    device: &AmlName,
+    method: &str,
+    args: Vec<AmlValue>,
+) -> Option<AmlValue> {
+    let path = AmlName::from_str(method).ok()?.resolve(device).ok()?;
+    let args = Args::from_list(args).ok()?;
+    aml.invoke_method(&path, args).ok()
+}
+
+/// Evaluate an object of a device, which may be a method or a plain value. Returns None if the object does not exist or fails to evaluate.
+pub fn evaluate(aml: &mut AmlContext, device: &AmlName, object: &str) -> Option<AmlValue> {
+    invoke(aml, device, object, Vec::new())
+}
+
+/// Evaluate an object of a device that should be an integer
+pub fn evaluate_integer(aml: &mut AmlContext, device: &AmlName, object: &str) -> Option<u64> {
+    let v = evaluate(aml, device, object)?;
+    v.as_integer(aml).ok()
+}
+
+/// Evaluate an object of a device that should be a buffer, returning the contents of the buffer
+pub fn evaluate_buffer(aml: &mut AmlContext, device: &AmlName, object: &str) -> Option<Vec<u8>> {
+    match evaluate(aml, device, object)? {
+        AmlValue::Buffer(b) => Some(b.lock().clone()),
+        _ => None,
+    }
+}
+
+/// Evaluate the _STA object of a device. A device without a _STA object is present and functioning.
+pub fn device_status(aml: &mut AmlContext, device: &AmlName) -> u64 {
+    match evaluate(aml, device, "_STA") {
+        Some(v) => v.as_integer(aml).unwrap_or(0),
+        None => 0xf,
+    }
+}
+
+/// Read a little endian value of up to 8 bytes from a slice
+fn read_le(b: &[u8]) -> u64 {
+    b.iter().rev().fold(0u64, |acc, v| (acc << 8) | (*v as u64))
+}
+
+/// Add a resource for each bit set in a mask, such as the mask of an irq or dma descriptor
+fn mask_resources(mask: u16, r: &mut Vec<PlatformResource>, f: impl Fn(u8) -> PlatformResource) {
+    for i in 0..16 {
+        if (mask & (1 << i)) != 0 {
+            r.push(f(i));
+        }
+    }
+}
+
+/// Parse a resource template (the buffer returned by _CRS or _PRS) into a list of resources.
+/// Returns None if the buffer is malformed.
+pub fn parse_resources(buf: &[u8]) -> Option<Vec<PlatformResource>> {
+    let mut r = Vec::new();
+    let mut i = 0;
+    while i < buf.len() {
+        let tag = buf[i];
+        if (tag & 0x80) == 0 {
+            let len = (tag & 7) as usize;
+            let d = buf.get(i + 1..i + 1 + len)?;
+            match (tag >> 3) & 0xf {
+                // Irq descriptor
+                4 => {
+                    let mask = read_le(d.get(0..2)?) as u16;
+                    let (level, active_low) = match d.get(2) {
+                        Some(f) => ((f & 1) == 0, (f & 8) != 0),
+                        None => (false, false),
+                    };
+                    mask_resources(mask, &mut r, |irq| PlatformResource::Irq {
+                        irq: irq as u32,
+                        level,
+                        active_low,
+                    });
+                }
+                // Dma descriptor
+                5 => mask_resources(*d.first()? as u16, &mut r, PlatformResource::Dma),
+                // Io port descriptor
+                8 => {
+                    let length = *d.get(6)? as u16;
+                    if length != 0 {
+                        r.push(PlatformResource::Io {
+                            base: read_le(d.get(1..3)?) as u16,
+                            length,
+                        });
+                    }
+                }
+                // Fixed location io port descriptor
+                9 => {
+                    let length = *d.get(2)? as u16;
+                    if length != 0 {
+                        r.push(PlatformResource::Io {
+                            base: read_le(d.get(0..2)?) as u16 & 0x3ff,
+                            length,
+                        });
+                    }
+                }
+                // Fixed dma descriptor
+                0xa => r.push(PlatformResource::Dma(read_le(d.get(2..4)?) as u8)),
+                // End tag
+                0xf => break,
+                _ => {}
+            }
+            i += 1 + len;
+        } else {
+            let len = read_le(buf.get(i + 1..i + 3)?) as usize;
+            let d = buf.get(i + 3..i + 3 + len)?;
+            match tag & 0x7f {
+                // 24 bit memory range descriptor
+                1 => {
+                    let length = read_le(d.get(7..9)?) << 8;
+                    if length != 0 {
+                        r.push(PlatformResource::Memory {
+                            base: read_le(d.get(1..3)?) << 8,
+                            length,
+                        });
+                    }
+                }
+                // 32 bit memory range descriptor
+                5 => {
+                    let length = read_le(d.get(13..17)?);
+                    if length != 0 {
+                        r.push(PlatformResource::Memory {
+                            base: read_le(d.get(1..5)?),
+                            length,
+                        });
+                    }
+                }
+                // 32 bit fixed memory range descriptor
+                6 => {
+                    let length = read_le(d.get(5..9)?);
+                    if length != 0 {
+                        r.push(PlatformResource::Memory {
+                            base: read_le(d.get(1..5)?),
+                            length,
+                        });
+                    }
+                }
+                // Dword, word, and qword address space descriptors
+                7 | 8 | 0xa => {
+                    let size = match tag & 0x7f {
+                        7 => 4,
+                        8 => 2,
+                        _ => 8,
+                    };
+                    let field = |n: usize| d.get(3 + n * size..3 + (n + 1) * size).map(read_le);
+                    let base = field(1)?;
+                    let length = field(4)?;
+                    if length != 0 {
+                        match d.first()? {
+                            0 => r.push(PlatformResource::Memory { base, length }),
+                            1 => r.push(PlatformResource::Io {
+                                base: base as u16,
+                                length: length as u16,
+                            }),
+                            2 => r.push(PlatformResource::BusNumbers {
+                                base: base as u16,
+                                length: length as u16,
+                            }),
+                            _ => {}
+                        }
+                    }
+                }
+                // Extended interrupt descriptor
+                9 => {
+                    let flags = *d.first()?;
+                    let count = *d.get(1)? as usize;
+                    for n in 0..count {
+                        r.push(PlatformResource::Irq {
+                            irq: read_le(d.get(2 + n * 4..6 + n * 4)?) as u32,
+                            level: (flags & 2) == 0,
+                            active_low: (flags & 4) != 0,
+                        });
+                    }
+                }
+                _ => {}
+            }
+            i += 3 + len;
+        }
+    }
+    Some(r)
+}
+
+/// Test the parsing of resource templates
+#[doors_macros::doors_test]
+fn resource_parse_test() -> Result<(), ()> {
+    assert_eq!(eisa_id_to_string(0x0105_d041), "PNP0501");
+    // IO (Decode16, 0x3F8, 0x3F8, 0x01, 0x08), IRQNoFlags () {4}, Memory32Fixed (ReadWrite, 0xFED00000, 0x400)
+    let buf = [
+        0x47, 0x01, 0xf8, 0x03, 0xf8, 0x03, 0x01, 0x08, 0x22, 0x10, 0x00, 0x86, 0x09, 0x00, 0x01,
+        0x00, 0x00, 0xd0, 0xfe, 0x00, 0x04, 0x00, 0x00, 0x79, 0x00,
+    ];
+    let r = parse_resources(&buf).ok_or(())?;
+    assert_eq!(
+        r,
+        [
+            PlatformResource::Io {
+                base: 0x3f8,
+                length: 8
+            },
+            PlatformResource::Irq {
+                irq: 4,
+                level: false,
+                active_low: false
+            },
+            PlatformResource::Memory {
+                base: 0xfed0_0000,
+                length: 0x400
+            },
+        ]
+    );
+    Ok(())
+}
+
+/// Get the names of all devices in the acpi namespace
+fn device_names(aml: &mut AmlContext) -> Vec<AmlName> {
+    let mut names = Vec::new();
+    let _ = aml.namespace.traverse(|name, level| {
+        if let LevelType::Device = level.typ {
+            names.push(name.clone());
+        }
+        Ok(true)
+    });
+    names
+}
+
+/// Walk the acpi namespace, evaluating each device that is present into a platform device
+pub fn enumerate_devices(aml: &mut AmlContext) -> Vec<PlatformDevice> {
+    let mut devices = Vec::new();
+    for name in device_names(aml) {
+        if (device_status(aml, &name) & STA_PRESENT) == 0 {
+            continue;
+        }
+        let hid = evaluate(aml, &name, "_HID").and_then(|v| id_string(aml, &v));
+        let cids = match evaluate(aml, &name, "_CID") {
+            Some(AmlValue::Package(p)) => p.iter().filter_map(|v| id_string(aml, v)).collect(),
+            Some(v) => id_string(aml, &v).into_iter().collect(),
+            None => Vec::new(),
+        };
+        let uid = evaluate(aml, &name, "_UID").and_then(|v| match v {
+            AmlValue::String(s) => s.parse().ok(),
+            v => v.as_integer(aml).ok(),
+        });
+        let resources = evaluate_buffer(aml, &name, "_CRS")
+            .and_then(|b| parse_resources(&b))
+            .unwrap_or_default();
+        devices.push(PlatformDevice {
+            name: name.as_string(),
+            hid,
+            cids,
+            uid,
+            resources,
+        });
+    }
+    devices
+}
+
+/// Enumerate the devices in the acpi namespace and register them with the kernel
+pub fn register_devices(aml: &mut AmlContext) {
+    let devices = enumerate_devices(aml);
+    let mut d = crate::kernel::DEVICES.sync_lock();
+    for dev in devices {
+        crate::VGA.print_str(&alloc::format!(
+            "Acpi device {} {:?} {:?}\r\n",
+            dev.name,
+            dev.hid,
+            dev.resources
+        ));
+        d.register_device(crate::modules::Device::Platform(dev));
+    }
+}
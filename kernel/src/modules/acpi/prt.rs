@@ -0,0 +1,269 @@
+//! Pci interrupt routing, using the _PRT objects of pci root bridges and pci to pci bridges
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use aml::{AmlContext, AmlName, AmlValue};
+
+use crate::modules::pci::PciConfigurationSpaceTrait;
+use crate::modules::platform::PlatformResource;
+use crate::Locked;
+
+/// The pci interrupt routing for the system, filled in by [build_routing]
+static PCI_ROUTING: Locked<PciRouting> = Locked::new(PciRouting::new());
+
+/// The interrupt that a pci interrupt pin is routed to
+#[derive(Clone, Copy, Debug)]
+pub struct PciIrq {
+    /// The global system interrupt
+    pub gsi: u32,
+    /// True when the interrupt is level triggered
+    pub level: bool,
+    /// True when the interrupt is active low
+    pub active_low: bool,
+}
+
+/// A single entry of a _PRT object, with any link device already resolved
+struct PrtEntry {
+    /// The device number on the bus
+    device: u8,
+    /// The interrupt pin, 0 for INTA through 3 for INTD
+    pin: u8,
+    /// The interrupt the pin is routed to
+    irq: PciIrq,
+}
+
+/// The interrupt routing for all pci busses
+struct PciRouting {
+    /// The _PRT entries for each bus that has a _PRT object
+    routes: BTreeMap<u8, Vec<PrtEntry>>,
+    /// The parent bus and device number of the bridge for each secondary bus
+    parents: BTreeMap<u8, (u8, u8)>,
+}
+
+impl PciRouting {
+    /// Construct an empty routing table
+    const fn new() -> Self {
+        Self {
+            routes: BTreeMap::new(),
+            parents: BTreeMap::new(),
+        }
+    }
+
+    /// Find the interrupt for an interrupt pin (1 for INTA through 4 for INTD) of a device.
+    /// Busses without a _PRT object use the standard swizzle of pci to pci bridges to find the pin on the parent bus.
+    fn route(&self, mut bus: u8, mut device: u8, pin: u8) -> Option<PciIrq> {
+        if !(1..=4).contains(&pin) {
+            return None;
+        }
+        let mut pin = pin - 1;
+        loop {
+            if let Some(entries) = self.routes.get(&bus) {
+                return entries
+                    .iter()
+                    .find(|e| e.device == device && e.pin == pin)
+                    .map(|e| e.irq);
+            }
+            let (pbus, pdev) = *self.parents.get(&bus)?;
+            pin = (pin + device) % 4;
+            bus = pbus;
+            device = pdev;
+        }
+    }
+}
+
+/// Get the interrupt for an interrupt pin (1 for INTA through 4 for INTD) of a pci device, if acpi describes the routing
+pub fn pci_irq(bus: u8, device: u8, pin: u8) -> Option<PciIrq> {
+    PCI_ROUTING.sync_lock().route(bus, device, pin)
+}
+
+/// Build a resource template that selects a single interrupt, for the _SRS method of a link device
+fn irq_template(irq: PciIrq, extended: bool) -> Vec<u8> {
+    let mut t = if extended {
+        let flags = 1 | ((!irq.level as u8) << 1) | ((irq.active_low as u8) << 2) | (1 << 3);
+        let mut t = alloc::vec![0x89, 6, 0, flags, 1];
+        t.extend_from_slice(&irq.gsi.to_le_bytes());
+        t
+    } else {
+        let mask = 1u16 << irq.gsi;
+        let flags = (!irq.level as u8) | ((irq.active_low as u8) << 3) | (1 << 4);
+        let m = mask.to_le_bytes();
+        alloc::vec![0x23, m[0], m[1], flags]
+    };
+    t.extend_from_slice(&[0x79, 0]);
+    t
+}
+
+/// Find the interrupt of a link device. A disabled link is programmed with the first interrupt it can use.
+fn link_irq(aml: &mut AmlContext, link: &AmlName, index: usize) -> Option<PciIrq> {
+    let current = super::evaluate_buffer(aml, link, "_CRS")
+        .and_then(|b| super::parse_resources(&b))
+        .unwrap_or_default();
+    let irq = |r: &PlatformResource| match r {
+        PlatformResource::Irq {
+            irq,
+            level,
+            active_low,
+        } => Some(PciIrq {
+            gsi: *irq,
+            level: *level,
+            active_low: *active_low,
+        }),
+        _ => None,
+    };
+    if let Some(i) = current.iter().filter_map(irq).nth(index) {
+        if i.gsi != 0 {
+            return Some(i);
+        }
+    }
+
+    let possible = super::evaluate_buffer(aml, link, "_PRS")?;
+    let choice = super::parse_resources(&possible)?
+        .iter()
+        .filter_map(irq)
+        .find(|i| i.gsi != 0)?;
+    let template = irq_template(choice, possible.first() == Some(&0x89));
+    super::invoke(
+        aml,
+        link,
+        "_SRS",
+        alloc::vec![AmlValue::Buffer(Arc::new(template.into()))],
+    )?;
+    crate::VGA.print_str(&alloc::format!(
+        "Routed link {} to irq {}\r\n",
+        link.as_string(),
+        choice.gsi
+    ));
+    Some(choice)
+}
+
+/// Parse the _PRT object of a bridge, resolving the link devices it refers to
+fn parse_prt(aml: &mut AmlContext, bridge: &AmlName) -> Option<Vec<PrtEntry>> {
+    let AmlValue::Package(prt) = super::evaluate(aml, bridge, "_PRT")? else {
+        return None;
+    };
+    let mut entries = Vec::new();
+    for e in prt {
+        let AmlValue::Package(p) = e else {
+            continue;
+        };
+        let (Some(address), Some(pin), Some(source), Some(index)) =
+            (p.first(), p.get(1), p.get(2), p.get(3))
+        else {
+            continue;
+        };
+        let (Ok(address), Ok(pin), Ok(index)) = (
+            address.as_integer(aml),
+            pin.as_integer(aml),
+            index.as_integer(aml),
+        ) else {
+            continue;
+        };
+        let irq = match source {
+            AmlValue::String(link) => AmlName::from_str(link)
+                .and_then(|l| aml.namespace.search_for_level(&l, bridge))
+                .ok()
+                .and_then(|l| link_irq(aml, &l, index as usize)),
+            _ => Some(PciIrq {
+                gsi: index as u32,
+                level: true,
+                active_low: true,
+            }),
+        };
+        if let Some(irq) = irq {
+            entries.push(PrtEntry {
+                device: (address >> 16) as u8,
+                pin: pin as u8,
+                irq,
+            });
+        }
+    }
+    Some(entries)
+}
+
+/// Scan a pci bus for pci to pci bridges, recording the parent of each secondary bus
+fn scan_bridges(
+    pci: &mut crate::modules::pci::x86::PciRegisters,
+    bus: u8,
+    parents: &mut BTreeMap<u8, (u8, u8)>,
+) {
+    for device in 0..32 {
+        for function in 0..8 {
+            if pci.read_u16(bus, device, function, 0) == 0xffff {
+                if function == 0 {
+                    break;
+                }
+                continue;
+            }
+            let header = (pci.read_u32(bus, device, function, 0xc) >> 16) as u8;
+            if (header & 0x7f) == 1 {
+                let secondary = (pci.read_u32(bus, device, function, 0x18) >> 8) as u8;
+                if secondary > bus && !parents.contains_key(&secondary) {
+                    parents.insert(secondary, (bus, device));
+                    scan_bridges(pci, secondary, parents);
+                }
+            }
+            if function == 0 && (header & 0x80) == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Build the pci interrupt routing from the _PRT objects of the pci root bridges and the bridges below them.
+/// The _PIC method is evaluated first with the interrupt model that delivers interrupts, so that _PRT returns
+/// io apic global system interrupts when apic is true, and 8259 irqs otherwise.
+pub fn build_routing(aml: &mut AmlContext, apic: bool) {
+    super::invoke(
+        aml,
+        &AmlName::root(),
+        "\\_PIC",
+        alloc::vec![AmlValue::Integer(apic as u64)],
+    );
+    let names = super::device_names(aml);
+    let mut routing = PciRouting::new();
+    let mut pci = unsafe { crate::modules::pci::x86::PciRegisters::new_unchecked() };
+    let mut bridges = Vec::new();
+    for name in &names {
+        let hid = super::evaluate(aml, name, "_HID").and_then(|v| super::id_string(aml, &v));
+        let cid = super::evaluate(aml, name, "_CID").and_then(|v| super::id_string(aml, &v));
+        let root = [hid, cid]
+            .iter()
+            .flatten()
+            .any(|id| id == "PNP0A03" || id == "PNP0A08");
+        if root && (super::device_status(aml, name) & super::STA_PRESENT) != 0 {
+            let bus = super::evaluate_integer(aml, name, "_BBN").unwrap_or(0) as u8;
+            scan_bridges(&mut pci, bus, &mut routing.parents);
+            bridges.push((name.clone(), bus));
+        }
+    }
+
+    while let Some((bridge, bus)) = bridges.pop() {
+        if let Some(entries) = parse_prt(aml, &bridge) {
+            routing.routes.insert(bus, entries);
+        }
+        for child in names
+            .iter()
+            .filter(|n| n.parent().ok().as_ref() == Some(&bridge))
+        {
+            let Some(adr) = super::evaluate_integer(aml, child, "_ADR") else {
+                continue;
+            };
+            let (device, function) = ((adr >> 16) as u8, (adr & 0xffff) as u8);
+            if device >= 32 || function >= 8 || pci.read_u16(bus, device, function, 0) == 0xffff {
+                continue;
+            }
+            let header = (pci.read_u32(bus, device, function, 0xc) >> 16) as u8;
+            if (header & 0x7f) == 1 {
+                let secondary = (pci.read_u32(bus, device, function, 0x18) >> 8) as u8;
+                bridges.push((child.clone(), secondary));
+            }
+        }
+    }
+
+    crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+        "Pci interrupt routing for {} busses\r\n",
+        routing.routes.len()
+    ));
+    *PCI_ROUTING.sync_lock() = routing;
+}
@@ -0,0 +1,443 @@
+//! Handling of the acpi system control interrupt, for fixed events (such as the power button) and general purpose events
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use conquer_once::noblock::OnceCell;
+use core::sync::atomic::{AtomicU16, AtomicU8, Ordering};
+use core::task::{Poll, Waker};
+
+use acpi::fadt::Fadt;
+use aml::{AmlContext, AmlName};
+
+use super::AcpiRegister;
+use crate::kernel::SystemTrait;
+use crate::{IoReadWrite, Locked};
+
+/// The power button status and enable bit of the pm1 event registers
+const PWRBTN: u16 = 1 << 8;
+/// The sleep button status and enable bit of the pm1 event registers
+const SLPBTN: u16 = 1 << 9;
+/// The rtc alarm status and enable bit of the pm1 event registers
+const RTC: u16 = 1 << 10;
+/// The fixed events handled by the kernel
+const FIXED_EVENTS: u16 = PWRBTN | SLPBTN | RTC;
+/// The bit of the pm1 control registers that indicates the system is in acpi mode
+const SCI_EN: u16 = 1;
+
+/// The system control interrupt state, filled in when the fadt is parsed
+static SCI: OnceCell<Sci> = OnceCell::uninit();
+
+/// The functions called for each acpi event
+static LISTENERS: Locked<Vec<Box<dyn Fn(&AcpiEvent) + Send + Sync>>> = Locked::new(Vec::new());
+
+/// Notifications waiting to be delivered to the listeners
+static NOTIFICATIONS: Locked<Vec<(String, u64)>> = Locked::new(Vec::new());
+
+/// The functions called for notifications of a single acpi object, with the full name of the object
+static NOTIFY_HANDLERS: Locked<Vec<(String, Box<dyn Fn(u64) + Send + Sync>)>> =
+    Locked::new(Vec::new());
+
+/// An event delivered to the acpi event listeners
+#[derive(Debug)]
+pub enum AcpiEvent {
+    /// The fixed power button was pressed
+    PowerButton,
+    /// The fixed sleep button was pressed
+    SleepButton,
+    /// The rtc alarm fired
+    RtcAlarm,
+    /// A general purpose event occurred, after its _Lxx or _Exx method was evaluated
+    Gpe(u16),
+    /// An acpi object was notified
+    Notify {
+        /// The full name of the object
+        device: String,
+        /// The notification value
+        value: u64,
+    },
+}
+
+/// A block of general purpose event registers
+struct GpeBlock {
+    /// The registers, with the status registers in the first half and the enable registers in the second half
+    regs: AcpiRegister,
+    /// The number of status registers
+    len: usize,
+    /// The number of the first event of the block
+    base: u16,
+    /// The events that occurred and have not been processed yet, one bit per event
+    pending: Vec<AtomicU8>,
+}
+
+impl GpeBlock {
+    /// Construct a block from the generic address and length of the block in bytes
+    fn new(regs: AcpiRegister, length: u8, base: u16) -> Self {
+        let len = length as usize / 2;
+        Self {
+            regs,
+            len,
+            base,
+            pending: (0..len).map(|_| AtomicU8::new(0)).collect(),
+        }
+    }
+
+    /// Set or clear the enable bit of a single event of the block
+    fn set_enable(&self, index: usize, bit: u8, en: bool) {
+        let v = self.regs.read_u8(self.len + index);
+        let v = if en { v | (1 << bit) } else { v & !(1 << bit) };
+        self.regs.write_u8(self.len + index, v);
+    }
+}
+
+/// The system control interrupt and the registers for the events it signals
+pub struct Sci {
+    /// The interrupt number
+    irq: u8,
+    /// The pm1a event registers, with the status register first and the enable register second
+    pm1a_event: Option<AcpiRegister>,
+    /// The pm1b event registers
+    pm1b_event: Option<AcpiRegister>,
+    /// The pm1a control register
+    pm1a_control: Option<AcpiRegister>,
+    /// The offset of the enable registers in the pm1 event blocks
+    pm1_enable: usize,
+    /// The smi command port and the value to write to it to enable acpi mode
+    acpi_enable: Option<(u16, u8)>,
+    /// The general purpose event blocks
+    gpe: Vec<GpeBlock>,
+    /// The fixed events that occurred and have not been processed yet
+    pending_fixed: AtomicU16,
+    /// The wakers for the event task
+    wakers: Locked<Vec<Waker>>,
+}
+
+impl Sci {
+    /// Gather the registers for the system control interrupt from the fadt
+    fn new(fadt: &Fadt) -> Self {
+        let pm1_len = fadt.pm1_event_length as usize;
+        let mut gpe = Vec::new();
+        if let Some(r) = fadt
+            .gpe0_block()
+            .ok()
+            .flatten()
+            .and_then(|ga| AcpiRegister::new(&ga, fadt.gpe0_block_length as usize))
+        {
+            gpe.push(GpeBlock::new(r, fadt.gpe0_block_length, 0));
+        }
+        if let Some(r) = fadt
+            .gpe1_block()
+            .ok()
+            .flatten()
+            .and_then(|ga| AcpiRegister::new(&ga, fadt.gpe1_block_length as usize))
+        {
+            gpe.push(GpeBlock::new(
+                r,
+                fadt.gpe1_block_length,
+                fadt.gpe1_base as u16,
+            ));
+        }
+        let smi_cmd = { fadt.smi_cmd_port };
+        let enable = { fadt.acpi_enable };
+        Self {
+            irq: { fadt.sci_interrupt } as u8,
+            pm1a_event: fadt
+                .pm1a_event_block()
+                .ok()
+                .and_then(|ga| AcpiRegister::new(&ga, pm1_len)),
+            pm1b_event: fadt
+                .pm1b_event_block()
+                .ok()
+                .flatten()
+                .and_then(|ga| AcpiRegister::new(&ga, pm1_len)),
+            pm1a_control: fadt
+                .pm1a_control_block()
+                .ok()
+                .and_then(|ga| AcpiRegister::new(&ga, 2)),
+            pm1_enable: pm1_len / 2,
+            acpi_enable: (smi_cmd != 0 && enable != 0).then_some((smi_cmd as u16, enable)),
+            gpe,
+            pending_fixed: AtomicU16::new(0),
+            wakers: Locked::new(Vec::new()),
+        }
+    }
+
+    /// Iterate over the pm1 event register blocks that exist
+    fn pm1_events(&self) -> impl Iterator<Item = &AcpiRegister> {
+        self.pm1a_event.iter().chain(self.pm1b_event.iter())
+    }
+
+    /// Switch the system into acpi mode, if the firmware has not already done so
+    fn enable_acpi_mode(&self) {
+        let Some(control) = &self.pm1a_control else {
+            return;
+        };
+        if (control.read_u16(0) & SCI_EN) != 0 {
+            return;
+        }
+        if let Some((port, value)) = self.acpi_enable {
+            unsafe { crate::boot::x86::IOPORTS.get_port_unchecked::<u8>(port) }.port_write(value);
+            for _ in 0..1_000_000 {
+                if (control.read_u16(0) & SCI_EN) != 0 {
+                    return;
+                }
+                core::hint::spin_loop();
+            }
+            crate::VGA.print_str("Failed to enable acpi mode\r\n");
+        }
+    }
+
+    /// Handle the interrupt, recording the events that occurred and disabling the general purpose events until they are processed
+    fn handle_interrupt(&self) {
+        let mut fixed = 0;
+        for r in self.pm1_events() {
+            let status = r.read_u16(0) & r.read_u16(self.pm1_enable) & FIXED_EVENTS;
+            if status != 0 {
+                r.write_u16(0, status);
+                fixed |= status;
+            }
+        }
+        self.pending_fixed.fetch_or(fixed, Ordering::Relaxed);
+        let mut any = fixed != 0;
+        for b in &self.gpe {
+            for (i, p) in b.pending.iter().enumerate() {
+                let enable = b.regs.read_u8(b.len + i);
+                let status = b.regs.read_u8(i) & enable;
+                if status != 0 {
+                    b.regs.write_u8(b.len + i, enable & !status);
+                    p.fetch_or(status, Ordering::Relaxed);
+                    any = true;
+                }
+            }
+        }
+        if any {
+            // Drained in place, so that the interrupt handler does not free memory
+            for w in self.wakers.sync_lock().drain(..) {
+                w.wake();
+            }
+        }
+    }
+
+    /// Returns true if there are events waiting to be processed
+    fn has_pending(&self) -> bool {
+        self.pending_fixed.load(Ordering::Relaxed) != 0
+            || self
+                .gpe
+                .iter()
+                .any(|b| b.pending.iter().any(|p| p.load(Ordering::Relaxed) != 0))
+            || !NOTIFICATIONS.sync_lock().is_empty()
+    }
+
+    /// Wait for events to be pending
+    fn poll_pending(&self, cx: &mut core::task::Context<'_>) -> Poll<()> {
+        if self.has_pending() {
+            return Poll::Ready(());
+        }
+        // The interrupt handler locks the wakers too
+        crate::SYSTEM
+            .read()
+            .disable_interrupts_for(|| crate::add_waker(&mut self.wakers.sync_lock(), cx.waker()));
+        if self.has_pending() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Run the method for a general purpose event, then clear its status and enable it again
+    fn process_gpe(&self, aml: &mut AmlContext, b: &GpeBlock, index: usize, bit: u8) {
+        let num = b.base + (index * 8) as u16 + bit as u16;
+        let method = |kind: char| {
+            AmlName::from_str(&alloc::format!("\\_GPE._{}{:02X}", kind, num))
+                .ok()
+                .filter(|n| aml.namespace.get_by_path(n).is_ok())
+        };
+        let (edge, level) = (method('E'), method('L'));
+        let run = |aml: &mut AmlContext, name: &AmlName| {
+            let _ =
+                aml::value::Args::from_list(Vec::new()).and_then(|a| aml.invoke_method(name, a));
+        };
+        if let Some(edge) = edge {
+            b.regs.write_u8(index, 1 << bit);
+            run(aml, &edge);
+        } else if let Some(level) = level {
+            run(aml, &level);
+            b.regs.write_u8(index, 1 << bit);
+        } else {
+            // There is no method for the event, so leave it disabled
+            b.regs.write_u8(index, 1 << bit);
+            return;
+        }
+        crate::SYSTEM
+            .read()
+            .disable_interrupts_for(|| b.set_enable(index, bit, true));
+    }
+
+    /// Process the pending events, returning the events to deliver to the listeners
+    fn process(&self) -> Vec<AcpiEvent> {
+        let mut events = Vec::new();
+        let fixed = self.pending_fixed.swap(0, Ordering::Relaxed);
+        if (fixed & PWRBTN) != 0 {
+            events.push(AcpiEvent::PowerButton);
+        }
+        if (fixed & SLPBTN) != 0 {
+            events.push(AcpiEvent::SleepButton);
+        }
+        if (fixed & RTC) != 0 {
+            events.push(AcpiEvent::RtcAlarm);
+        }
+        {
+            let mut aml = crate::boot::x86::boot64::AML_CONTEXT.sync_lock();
+            for b in &self.gpe {
+                for (i, p) in b.pending.iter().enumerate() {
+                    let status = p.swap(0, Ordering::Relaxed);
+                    for bit in (0..8).filter(|bit| (status & (1 << bit)) != 0) {
+                        if let Some(aml) = aml.as_mut() {
+                            self.process_gpe(aml, b, i, bit);
+                        }
+                        events.push(AcpiEvent::Gpe(b.base + (i * 8) as u16 + bit as u16));
+                    }
+                }
+            }
+        }
+        events.extend(deliver_notifications());
+        events
+    }
+}
+
+/// Gather the system control interrupt information from the fadt. The interrupt is not enabled until [start] is called.
+pub fn init(fadt: &Fadt) {
+    let _ = SCI.try_init_once(|| Sci::new(fadt));
+}
+
+/// Switch to acpi mode and enable the system control interrupt, the fixed events, and the general purpose events that have a method to handle them.
+/// This should be called after the acpi tables have been loaded into the aml context.
+pub fn start(aml: &mut AmlContext) {
+    let Ok(sci) = SCI.try_get() else {
+        return;
+    };
+    sci.enable_acpi_mode();
+    for r in sci.pm1_events() {
+        r.write_u16(0, FIXED_EVENTS);
+        r.write_u16(sci.pm1_enable, r.read_u16(sci.pm1_enable) | FIXED_EVENTS);
+    }
+    let mut count = 0;
+    for b in &sci.gpe {
+        for i in 0..b.len {
+            b.regs.write_u8(b.len + i, 0);
+            b.regs.write_u8(i, 0xff);
+            for bit in 0..8 {
+                let num = b.base + (i * 8) as u16 + bit as u16;
+                let exists = ['L', 'E'].iter().any(|kind| {
+                    AmlName::from_str(&alloc::format!("\\_GPE._{}{:02X}", kind, num))
+                        .is_ok_and(|n| aml.namespace.get_by_path(&n).is_ok())
+                });
+                if exists {
+                    b.set_enable(i, bit, true);
+                    count += 1;
+                }
+            }
+        }
+    }
+    let sys = crate::SYSTEM.read();
+    sys.register_irq_handler(sci.irq, || {
+        if let Ok(sci) = SCI.try_get() {
+            sci.handle_interrupt();
+        }
+    });
+    sys.enable_irq(sci.irq);
+    crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+        "Acpi sci on irq {} with {} general purpose events\r\n",
+        sci.irq,
+        count
+    ));
+}
+
+/// Register a function to be called for every acpi event. The function is called from the acpi event task, not from the interrupt handler.
+pub fn register_listener(f: impl Fn(&AcpiEvent) + Send + Sync + 'static) {
+    LISTENERS.sync_lock().push(Box::new(f));
+}
+
+/// Register a function to be called for every notification of the acpi object with the given full name, such as `\_SB_.PCI0`.
+/// The function is called from the acpi event task, before the listeners see the notification.
+pub fn register_notify_handler(device: &str, f: impl Fn(u64) + Send + Sync + 'static) {
+    NOTIFY_HANDLERS
+        .sync_lock()
+        .push((String::from(device), Box::new(f)));
+}
+
+/// Remove the functions registered for notifications of the acpi object with the given full name
+pub fn unregister_notify_handlers(device: &str) {
+    NOTIFY_HANDLERS.sync_lock().retain(|(d, _)| d != device);
+}
+
+/// Call the handlers registered for the pending notifications, returning the notifications as events for the listeners
+fn deliver_notifications() -> Vec<AcpiEvent> {
+    let pending = core::mem::take(&mut *NOTIFICATIONS.sync_lock());
+    let handlers = NOTIFY_HANDLERS.sync_lock();
+    pending
+        .into_iter()
+        .map(|(device, value)| {
+            for (_, h) in handlers.iter().filter(|(d, _)| *d == device) {
+                h(value);
+            }
+            AcpiEvent::Notify { device, value }
+        })
+        .collect()
+}
+
+/// Deliver a notification for an acpi object to the handlers registered for it and to the listeners.
+/// This is called by the aml interpreter when aml notifies an object.
+pub fn notify(device: String, value: u64) {
+    NOTIFICATIONS.sync_lock().push((device, value));
+    if let Ok(sci) = SCI.try_get() {
+        let wakers = crate::SYSTEM
+            .read()
+            .disable_interrupts_for(|| core::mem::take(&mut *sci.wakers.sync_lock()));
+        for w in wakers {
+            w.wake();
+        }
+    }
+}
+
+/// The task that processes acpi events and delivers them to the listeners
+pub async fn event_task() {
+    let Ok(sci) = SCI.try_get() else {
+        return;
+    };
+    loop {
+        core::future::poll_fn(|cx| sci.poll_pending(cx)).await;
+        for e in sci.process() {
+            for l in LISTENERS.sync_lock().iter() {
+                l(&e);
+            }
+        }
+    }
+}
+
+/// Test that a notification reaches the handler registered for the object and no other
+#[doors_macros::doors_test]
+fn notify_dispatch_test() -> Result<(), ()> {
+    use core::sync::atomic::AtomicU64;
+    let seen = alloc::sync::Arc::new(AtomicU64::new(0));
+    let other = alloc::sync::Arc::new(AtomicU64::new(0));
+    let s2 = seen.clone();
+    register_notify_handler("\\_SB_.TST0", move |v| s2.store(v, Ordering::Relaxed));
+    let o2 = other.clone();
+    register_notify_handler("\\_SB_.TST1", move |v| o2.store(v, Ordering::Relaxed));
+    notify(String::from("\\_SB_.TST0"), 0x80);
+    let events = deliver_notifications();
+    assert_eq!(seen.load(Ordering::Relaxed), 0x80);
+    assert_eq!(other.load(Ordering::Relaxed), 0);
+    assert!(matches!(
+        events.as_slice(),
+        [AcpiEvent::Notify { device, value: 0x80 }] if device == "\\_SB_.TST0"
+    ));
+    unregister_notify_handlers("\\_SB_.TST0");
+    unregister_notify_handlers("\\_SB_.TST1");
+    assert!(!NOTIFY_HANDLERS
+        .sync_lock()
+        .iter()
+        .any(|(d, _)| d.starts_with("\\_SB_.TST")));
+    Ok(())
+}
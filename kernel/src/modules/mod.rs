@@ -1,14 +1,19 @@
 //! Kernel modules belong in this module. A lot of the enums will have a dummy provider so that the code will compile.
 
+#[cfg(kernel_machine = "pc64")]
+pub mod acpi;
 pub mod clock;
 pub mod gpio;
 pub mod memory;
 pub mod network;
 pub mod pci;
+pub mod platform;
 pub mod power;
 pub mod reset;
 pub mod rng;
+pub mod rtc;
 pub mod serial;
+pub mod time;
 pub mod timer;
 pub mod video;
 
@@ -21,6 +26,8 @@ pub trait DeviceTrait {}
 pub enum Device {
     /// A single function of a pci device
     PciFunction(pci::PciFunction),
+    /// A device described by the platform firmware
+    Platform(platform::PlatformDevice),
 }
 
 #[enum_dispatch::enum_dispatch]
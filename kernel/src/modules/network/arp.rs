@@ -0,0 +1,410 @@
+//! The address resolution protocol, for finding the mac address that belongs to an ipv4 address on the local network
+
+use alloc::{collections::BTreeMap, format, vec::Vec};
+
+use super::ethernet::EtherType;
+use super::interface::Interface;
+use super::{ChangeNotifier, Ipv4Address, MacAddress};
+use crate::modules::time::{Duration, Instant};
+use crate::Locked;
+
+/// How long a resolved address is trusted before it must be resolved again
+const ENTRY_LIFETIME: Duration = Duration::from_secs(60);
+/// How long to wait for a reply to a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// The number of requests to send before giving up
+const REQUEST_ATTEMPTS: u8 = 3;
+/// The length of an arp packet for ipv4 over ethernet
+const PACKET_LENGTH: usize = 28;
+/// The number of packets that can wait for addresses to be resolved
+const MAX_QUEUED: usize = 32;
+/// How often [retry_task] checks for requests that need to be sent again
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The kind of arp packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArpOperation {
+    /// Asks who has the target address
+    Request,
+    /// Answers a request
+    Reply,
+}
+
+/// An arp packet for ipv4 over ethernet
+#[derive(Clone, Copy, Debug)]
+pub struct ArpPacket {
+    /// The kind of packet
+    pub operation: ArpOperation,
+    /// The mac address of the sender
+    pub sender_mac: MacAddress,
+    /// The ipv4 address of the sender, unspecified when the sender is probing for an address
+    pub sender_ip: Ipv4Address,
+    /// The mac address of the target, ignored in requests
+    pub target_mac: MacAddress,
+    /// The ipv4 address of the target
+    pub target_ip: Ipv4Address,
+}
+
+impl ArpPacket {
+    /// Parse an arp packet, returning None if it is not ipv4 over ethernet
+    pub fn parse(p: &[u8]) -> Option<Self> {
+        if p.len() < PACKET_LENGTH
+            || u16::from_be_bytes([p[0], p[1]]) != 1
+            || u16::from_be_bytes([p[2], p[3]]) != u16::from(EtherType::Ipv4)
+            || p[4] != 6
+            || p[5] != 4
+        {
+            return None;
+        }
+        let operation = match u16::from_be_bytes([p[6], p[7]]) {
+            1 => ArpOperation::Request,
+            2 => ArpOperation::Reply,
+            _ => return None,
+        };
+        let mac = |o: usize| {
+            let mut a = [0; 6];
+            a.copy_from_slice(&p[o..o + 6]);
+            MacAddress::new(a)
+        };
+        let ip = |o: usize| Ipv4Address::new(p[o], p[o + 1], p[o + 2], p[o + 3]);
+        Some(Self {
+            operation,
+            sender_mac: mac(8),
+            sender_ip: ip(14),
+            target_mac: mac(18),
+            target_ip: ip(24),
+        })
+    }
+
+    /// The bytes of the packet
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut p = Vec::with_capacity(PACKET_LENGTH);
+        p.extend_from_slice(&1u16.to_be_bytes());
+        p.extend_from_slice(&u16::from(EtherType::Ipv4).to_be_bytes());
+        p.push(6);
+        p.push(4);
+        let op: u16 = match self.operation {
+            ArpOperation::Request => 1,
+            ArpOperation::Reply => 2,
+        };
+        p.extend_from_slice(&op.to_be_bytes());
+        p.extend_from_slice(&self.sender_mac.octets());
+        p.extend_from_slice(&self.sender_ip.octets());
+        p.extend_from_slice(&self.target_mac.octets());
+        p.extend_from_slice(&self.target_ip.octets());
+        p
+    }
+}
+
+/// A resolved address in the arp cache
+#[derive(Clone, Copy, Debug)]
+struct ArpEntry {
+    /// The mac address
+    mac: MacAddress,
+    /// When the entry should no longer be trusted
+    expires: Instant,
+}
+
+/// A packet waiting for the address of its next hop to be resolved
+struct QueuedPacket {
+    /// The next hop
+    ip: Ipv4Address,
+    /// The protocol of the packet
+    ethertype: EtherType,
+    /// The packet
+    payload: Vec<u8>,
+    /// When to give up on the packet
+    expires: Instant,
+}
+
+/// The arp cache of an interface
+pub struct ArpCache {
+    /// The resolved addresses
+    entries: Locked<BTreeMap<Ipv4Address, ArpEntry>>,
+    /// The addresses that requests have been sent for, with the time of the latest request
+    pending: Locked<BTreeMap<Ipv4Address, Instant>>,
+    /// The packets waiting for an address to be resolved
+    queued: Locked<Vec<QueuedPacket>>,
+    /// Notified every time an address is added or refreshed
+    updated: ChangeNotifier,
+}
+
+impl Default for ArpCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ArpCache {
+    /// Construct an empty cache
+    pub fn new() -> Self {
+        Self {
+            entries: Locked::new(BTreeMap::new()),
+            pending: Locked::new(BTreeMap::new()),
+            queued: Locked::new(Vec::new()),
+            updated: ChangeNotifier::new(),
+        }
+    }
+
+    /// Look up an address, forgetting it if it has expired
+    pub fn lookup(&self, ip: Ipv4Address) -> Option<MacAddress> {
+        let mut entries = self.entries.sync_lock();
+        match entries.get(&ip) {
+            Some(e) if Instant::now() < e.expires => Some(e.mac),
+            Some(_) => {
+                entries.remove(&ip);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Add or refresh an address
+    pub fn insert(&self, ip: Ipv4Address, mac: MacAddress) {
+        self.pending.sync_lock().remove(&ip);
+        self.entries.sync_lock().insert(
+            ip,
+            ArpEntry {
+                mac,
+                expires: Instant::now() + ENTRY_LIFETIME,
+            },
+        );
+        self.updated.notify();
+    }
+
+    /// Refresh an address only if it is already known or being resolved, returning true if it was
+    fn update(&self, ip: Ipv4Address, mac: MacAddress) -> bool {
+        let known = self.entries.sync_lock().contains_key(&ip)
+            || self.pending.sync_lock().contains_key(&ip);
+        if known {
+            self.insert(ip, mac);
+        }
+        known
+    }
+
+    /// Forget an address
+    pub fn remove(&self, ip: Ipv4Address) {
+        self.entries.sync_lock().remove(&ip);
+    }
+
+    /// Take the packets that were waiting for an address, dropping the ones that waited too long
+    fn take_queued(&self, ip: Ipv4Address) -> Vec<QueuedPacket> {
+        let now = Instant::now();
+        let mut q = self.queued.sync_lock();
+        q.retain(|p| now < p.expires);
+        let (ready, waiting): (Vec<_>, Vec<_>) = core::mem::take(&mut *q)
+            .into_iter()
+            .partition(|p| p.ip == ip);
+        *q = waiting;
+        ready
+    }
+
+    /// The addresses that have not expired
+    pub fn entries(&self) -> Vec<(Ipv4Address, MacAddress)> {
+        let now = Instant::now();
+        self.entries
+            .sync_lock()
+            .iter()
+            .filter(|(_, e)| now < e.expires)
+            .map(|(ip, e)| (*ip, e.mac))
+            .collect()
+    }
+}
+
+/// Process a received arp packet, answering requests for the address of the interface
+pub async fn handle(iface: &Interface, payload: &[u8]) {
+    let Some(p) = ArpPacket::parse(payload) else {
+        return;
+    };
+    if p.sender_mac == iface.mac() {
+        return;
+    }
+    let ours = iface.ipv4_address();
+    if !p.sender_ip.is_unspecified() && Some(p.sender_ip) == ours {
+        crate::VGA
+            .print_str_async(&format!(
+                "{}: address {} is also used by {}\r\n",
+                iface.name(),
+                p.sender_ip,
+                p.sender_mac
+            ))
+            .await;
+        return;
+    }
+    // Gratuitous arp packets refresh the entries that already exist
+    let merged = !p.sender_ip.is_unspecified() && iface.arp().update(p.sender_ip, p.sender_mac);
+    let learned = if ours.is_some() && Some(p.target_ip) == ours {
+        if !merged && !p.sender_ip.is_unspecified() {
+            iface.arp().insert(p.sender_ip, p.sender_mac);
+        }
+        if p.operation == ArpOperation::Request {
+            let reply = ArpPacket {
+                operation: ArpOperation::Reply,
+                sender_mac: iface.mac(),
+                sender_ip: p.target_ip,
+                target_mac: p.sender_mac,
+                target_ip: p.sender_ip,
+            };
+            let _ = iface
+                .send(p.sender_mac, EtherType::Arp, &reply.to_bytes())
+                .await;
+        }
+        !p.sender_ip.is_unspecified()
+    } else {
+        merged
+    };
+    if learned {
+        for q in iface.arp().take_queued(p.sender_ip) {
+            let _ = iface.send(p.sender_mac, q.ethertype, &q.payload).await;
+        }
+    }
+}
+
+/// Map a multicast ipv4 address to the multicast mac address it is sent to
+pub fn multicast_mac(ip: Ipv4Address) -> MacAddress {
+    let o = ip.octets();
+    MacAddress::new([0x01, 0x00, 0x5e, o[1] & 0x7f, o[2], o[3]])
+}
+
+/// Send a request for the mac address of an ipv4 address
+async fn request(iface: &Interface, ip: Ipv4Address) -> Result<(), ()> {
+    iface.arp().pending.sync_lock().insert(ip, Instant::now());
+    let p = ArpPacket {
+        operation: ArpOperation::Request,
+        sender_mac: iface.mac(),
+        sender_ip: iface.ipv4_address().unwrap_or(Ipv4Address::UNSPECIFIED),
+        target_mac: MacAddress::default(),
+        target_ip: ip,
+    };
+    iface
+        .send(MacAddress::BROADCAST, EtherType::Arp, &p.to_bytes())
+        .await
+}
+
+/// Announce the address of the interface with a gratuitous arp request, so other hosts update their caches
+pub async fn announce(iface: &Interface) -> Result<(), ()> {
+    let Some(ip) = iface.ipv4_address() else {
+        return Err(());
+    };
+    let p = ArpPacket {
+        operation: ArpOperation::Request,
+        sender_mac: iface.mac(),
+        sender_ip: ip,
+        target_mac: MacAddress::default(),
+        target_ip: ip,
+    };
+    iface
+        .send(MacAddress::BROADCAST, EtherType::Arp, &p.to_bytes())
+        .await
+}
+
+/// The mac address for an address that does not need to be resolved, such as broadcast and multicast addresses
+fn fixed_mac(iface: &Interface, ip: Ipv4Address) -> Option<MacAddress> {
+    if ip.is_broadcast() || iface.ipv4_config().is_some_and(|c| c.broadcast() == ip) {
+        Some(MacAddress::BROADCAST)
+    } else if ip.is_multicast() {
+        Some(multicast_mac(ip))
+    } else {
+        None
+    }
+}
+
+/// Send a packet to a host on the local network of the interface. When the mac address of the host is not known, the packet is queued and sent once a reply to the request arrives.
+/// Unlike [resolve], this can be used by the task that receives packets.
+pub async fn send_or_queue(
+    iface: &Interface,
+    ip: Ipv4Address,
+    ethertype: EtherType,
+    payload: Vec<u8>,
+) -> Result<(), ()> {
+    if let Some(mac) = fixed_mac(iface, ip).or_else(|| iface.arp().lookup(ip)) {
+        return iface.send(mac, ethertype, &payload).await;
+    }
+    let requested = iface
+        .arp()
+        .pending
+        .sync_lock()
+        .get(&ip)
+        .is_some_and(|t| t.elapsed() < REQUEST_TIMEOUT);
+    {
+        let mut q = iface.arp().queued.sync_lock();
+        if q.len() >= MAX_QUEUED {
+            q.remove(0);
+        }
+        q.push(QueuedPacket {
+            ip,
+            ethertype,
+            payload,
+            expires: Instant::now() + REQUEST_TIMEOUT * REQUEST_ATTEMPTS as u32,
+        });
+    }
+    if requested {
+        Ok(())
+    } else {
+        request(iface, ip).await
+    }
+}
+
+/// Send the requests again for the addresses that queued packets are still waiting for, dropping the packets that waited too long
+async fn retry(iface: &Interface) {
+    let now = Instant::now();
+    let mut waiting: Vec<Ipv4Address> = {
+        let mut q = iface.arp().queued.sync_lock();
+        q.retain(|p| now < p.expires);
+        q.iter().map(|p| p.ip).collect()
+    };
+    waiting.sort();
+    waiting.dedup();
+    for ip in waiting {
+        let due = iface
+            .arp()
+            .pending
+            .sync_lock()
+            .get(&ip)
+            .is_none_or(|t| t.elapsed() >= REQUEST_TIMEOUT);
+        if due {
+            let _ = request(iface, ip).await;
+        }
+    }
+}
+
+/// The task that sends the requests again for the packets queued by [send_or_queue] when no reply arrives
+pub async fn retry_task() {
+    loop {
+        crate::modules::time::sleep(RETRY_INTERVAL).await;
+        for iface in super::interfaces().await {
+            retry(&iface).await;
+        }
+    }
+}
+
+/// Find the mac address of an ipv4 address on the local network of the interface, sending requests as needed.
+/// This must not be awaited by the task that receives packets, because the reply would never be processed.
+pub async fn resolve(iface: &Interface, ip: Ipv4Address) -> Option<MacAddress> {
+    if let Some(mac) = fixed_mac(iface, ip) {
+        return Some(mac);
+    }
+    for _ in 0..REQUEST_ATTEMPTS {
+        if let Some(mac) = iface.arp().lookup(ip) {
+            return Some(mac);
+        }
+        if request(iface, ip).await.is_err() {
+            break;
+        }
+        let mut timeout = core::pin::pin!(crate::modules::time::sleep(REQUEST_TIMEOUT));
+        loop {
+            let generation = iface.arp().updated.generation();
+            if let Some(mac) = iface.arp().lookup(ip) {
+                return Some(mac);
+            }
+            let changed = core::pin::pin!(iface.arp().updated.changed(generation));
+            if let futures::future::Either::Right(_) =
+                futures::future::select(changed, timeout.as_mut()).await
+            {
+                break;
+            }
+        }
+    }
+    iface.arp().pending.sync_lock().remove(&ip);
+    iface.arp().lookup(ip)
+}
@@ -0,0 +1,705 @@
+//! A dhcp client that configures the ipv4 address, gateway, and dns servers of every network adapter as it is registered
+
+use alloc::{collections::BTreeMap, format, vec::Vec};
+
+use futures::{FutureExt, StreamExt};
+
+use super::interface::Interface;
+use super::ipv4::{self, Ipv4Config};
+use super::udp::UdpSocket;
+use super::{ChangeNotifier, Ipv4Address, MacAddress, NetworkEvent, SocketAddress};
+use crate::modules::time::{Duration, Instant};
+use crate::Arc;
+
+/// The port dhcp servers listen on
+const SERVER_PORT: u16 = 67;
+/// The port dhcp clients listen on
+const CLIENT_PORT: u16 = 68;
+/// Identifies the options field of a dhcp message
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// The offset of the options in a message
+const OPTIONS_OFFSET: usize = 240;
+/// The first retransmission timeout when selecting and requesting
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(4);
+/// The longest retransmission timeout when selecting and requesting
+const MAX_TIMEOUT: Duration = Duration::from_secs(64);
+/// The shortest time between requests when renewing or rebinding
+const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+/// The number of requests sent for an offer before starting over
+const REQUEST_ATTEMPTS: u8 = 4;
+/// The lease time assumed when the server does not send one
+const DEFAULT_LEASE: Duration = Duration::from_secs(3600);
+
+/// The subnet mask option
+const OPTION_SUBNET_MASK: u8 = 1;
+/// The router option
+const OPTION_ROUTER: u8 = 3;
+/// The dns server option
+const OPTION_DNS: u8 = 6;
+/// The host name option
+const OPTION_HOST_NAME: u8 = 12;
+/// The requested address option
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+/// The lease time option
+const OPTION_LEASE_TIME: u8 = 51;
+/// The message type option
+const OPTION_MESSAGE_TYPE: u8 = 53;
+/// The server identifier option
+const OPTION_SERVER_ID: u8 = 54;
+/// The parameter request list option
+const OPTION_PARAMETERS: u8 = 55;
+/// The renewal time option
+const OPTION_RENEWAL_TIME: u8 = 58;
+/// The rebinding time option
+const OPTION_REBINDING_TIME: u8 = 59;
+/// Padding between options
+const OPTION_PAD: u8 = 0;
+/// The end of the options
+const OPTION_END: u8 = 255;
+
+/// Notified every time a lease is applied to or removed from an interface
+static LEASES: ChangeNotifier = ChangeNotifier::new();
+
+/// The kind of dhcp message, from the message type option
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MessageType {
+    /// A client looking for servers
+    Discover = 1,
+    /// A server offering an address
+    Offer = 2,
+    /// A client requesting an offered address, or extending a lease
+    Request = 3,
+    /// A client declining an address that is already in use
+    Decline = 4,
+    /// A server confirming a lease
+    Ack = 5,
+    /// A server refusing a request
+    Nak = 6,
+    /// A client giving up a lease
+    Release = 7,
+}
+
+impl MessageType {
+    /// Convert the value of the message type option
+    fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            1 => Self::Discover,
+            2 => Self::Offer,
+            3 => Self::Request,
+            4 => Self::Decline,
+            5 => Self::Ack,
+            6 => Self::Nak,
+            7 => Self::Release,
+            _ => return None,
+        })
+    }
+}
+
+/// A reply from a dhcp server
+#[derive(Clone, Debug)]
+struct Reply {
+    /// The kind of reply
+    kind: MessageType,
+    /// The transaction the reply belongs to
+    xid: u32,
+    /// The address offered or leased to the client
+    yiaddr: Ipv4Address,
+    /// The hardware address of the client
+    chaddr: MacAddress,
+    /// The options, by code
+    options: BTreeMap<u8, Vec<u8>>,
+}
+
+impl Reply {
+    /// Parse a reply, returning None if it is not a valid reply from a server
+    fn parse(m: &[u8]) -> Option<Self> {
+        if m.len() < OPTIONS_OFFSET || m[0] != 2 || m[1] != 1 || m[2] != 6 {
+            return None;
+        }
+        if m[236..240] != MAGIC_COOKIE {
+            return None;
+        }
+        let mut options = BTreeMap::new();
+        let mut o = &m[OPTIONS_OFFSET..];
+        while let Some(&code) = o.first() {
+            match code {
+                OPTION_PAD => o = &o[1..],
+                OPTION_END => break,
+                _ => {
+                    let length = *o.get(1)? as usize;
+                    let value = o.get(2..2 + length)?;
+                    options
+                        .entry(code)
+                        .or_insert_with(Vec::new)
+                        .extend_from_slice(value);
+                    o = &o[2 + length..];
+                }
+            }
+        }
+        let kind = MessageType::from_u8(*options.get(&OPTION_MESSAGE_TYPE)?.first()?)?;
+        let mut chaddr = [0; 6];
+        chaddr.copy_from_slice(&m[28..34]);
+        Some(Self {
+            kind,
+            xid: u32::from_be_bytes([m[4], m[5], m[6], m[7]]),
+            yiaddr: Ipv4Address::new(m[16], m[17], m[18], m[19]),
+            chaddr: MacAddress::new(chaddr),
+            options,
+        })
+    }
+
+    /// An option holding a single address
+    fn address(&self, code: u8) -> Option<Ipv4Address> {
+        self.addresses(code).first().copied()
+    }
+
+    /// An option holding a list of addresses
+    fn addresses(&self, code: u8) -> Vec<Ipv4Address> {
+        self.options
+            .get(&code)
+            .map(|v| {
+                v.chunks_exact(4)
+                    .map(|a| Ipv4Address::new(a[0], a[1], a[2], a[3]))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// An option holding a number of seconds
+    fn seconds(&self, code: u8) -> Option<Duration> {
+        let v = self.options.get(&code)?;
+        let v: [u8; 4] = v.get(0..4)?.try_into().ok()?;
+        Some(Duration::from_secs(u32::from_be_bytes(v) as u64))
+    }
+}
+
+/// Build a message from a client with a hardware address, for the transaction and the number of seconds since it started
+fn build_message(
+    xid: u32,
+    mac: MacAddress,
+    kind: MessageType,
+    ciaddr: Ipv4Address,
+    secs: u16,
+    extra: &[(u8, Vec<u8>)],
+) -> Vec<u8> {
+    let mut m = Vec::with_capacity(300);
+    m.extend_from_slice(&[1, 1, 6, 0]);
+    m.extend_from_slice(&xid.to_be_bytes());
+    m.extend_from_slice(&secs.to_be_bytes());
+    // Servers are asked to broadcast replies, since the interface cannot receive unicast before it has an address
+    let flags: u16 = if ciaddr.is_unspecified() { 0x8000 } else { 0 };
+    m.extend_from_slice(&flags.to_be_bytes());
+    m.extend_from_slice(&ciaddr.octets());
+    m.extend_from_slice(&[0; 12]);
+    m.extend_from_slice(&mac.octets());
+    m.resize(OPTIONS_OFFSET - 4, 0);
+    m.extend_from_slice(&MAGIC_COOKIE);
+    m.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, kind as u8]);
+    for (code, value) in extra {
+        m.push(*code);
+        m.push(value.len() as u8);
+        m.extend_from_slice(value);
+    }
+    m.extend_from_slice(&[OPTION_HOST_NAME, 5]);
+    m.extend_from_slice(b"doors");
+    m.extend_from_slice(&[
+        OPTION_PARAMETERS,
+        4,
+        OPTION_SUBNET_MASK,
+        OPTION_ROUTER,
+        OPTION_DNS,
+        OPTION_LEASE_TIME,
+    ]);
+    m.push(OPTION_END);
+    // Some servers ignore messages shorter than a bootp message
+    if m.len() < 300 {
+        m.resize(300, 0);
+    }
+    m
+}
+
+/// An address leased from a server
+#[derive(Clone, Debug)]
+struct Lease {
+    /// The address and the size of the network
+    config: Ipv4Config,
+    /// The default gateway
+    gateway: Option<Ipv4Address>,
+    /// The dns servers
+    dns_servers: Vec<Ipv4Address>,
+    /// The server that granted the lease
+    server: Ipv4Address,
+    /// When the lease was granted
+    start: Instant,
+    /// When to start renewing the lease with the server that granted it
+    renew: Instant,
+    /// When to start asking any server to extend the lease
+    rebind: Instant,
+    /// When the lease runs out
+    expires: Instant,
+}
+
+impl Lease {
+    /// Build a lease from an acknowledgment, with the time the request was sent
+    fn from_ack(r: &Reply, server: Ipv4Address, start: Instant) -> Self {
+        let time = r.seconds(OPTION_LEASE_TIME).unwrap_or(DEFAULT_LEASE);
+        let renew = r.seconds(OPTION_RENEWAL_TIME).unwrap_or(time / 2);
+        let rebind = r.seconds(OPTION_REBINDING_TIME).unwrap_or(time * 7 / 8);
+        let prefix_length = r
+            .address(OPTION_SUBNET_MASK)
+            .map(|m| u32::from(m).leading_ones() as u8)
+            .unwrap_or(24);
+        Self {
+            config: Ipv4Config {
+                address: r.yiaddr,
+                prefix_length,
+            },
+            gateway: r.address(OPTION_ROUTER),
+            dns_servers: r.addresses(OPTION_DNS),
+            server,
+            start,
+            renew: start + renew,
+            rebind: start + rebind,
+            expires: start + time,
+        }
+    }
+}
+
+/// The states of the client, from rfc 2131
+#[derive(Clone, Debug)]
+enum State {
+    /// Looking for servers
+    Selecting,
+    /// Requesting the address offered by a server
+    Requesting {
+        /// The offered address
+        address: Ipv4Address,
+        /// The server that offered it
+        server: Ipv4Address,
+        /// The number of requests sent
+        attempts: u8,
+    },
+    /// The interface has an address
+    Bound,
+    /// Asking the server that granted the lease to extend it
+    Renewing,
+    /// Asking any server to extend the lease
+    Rebinding,
+}
+
+/// The dhcp client for a single interface
+struct Client {
+    /// The interface being configured
+    iface: Arc<Interface>,
+    /// The state of the client
+    state: State,
+    /// The transaction id of the current exchange
+    xid: u32,
+    /// When the current exchange started
+    exchange_start: Instant,
+    /// The current lease
+    lease: Option<Lease>,
+    /// When to send the next message
+    next_send: Instant,
+    /// The retransmission timeout when selecting and requesting
+    timeout: Duration,
+}
+
+/// Where a message is sent
+enum Destination {
+    /// Broadcast on the local network of the interface
+    Broadcast,
+    /// Sent to a server through the routing table
+    Server(Ipv4Address),
+}
+
+impl Client {
+    /// Construct a client that starts by looking for servers
+    fn new(iface: Arc<Interface>) -> Self {
+        let now = Instant::now();
+        let mut c = Self {
+            iface,
+            state: State::Selecting,
+            xid: 0,
+            exchange_start: now,
+            lease: None,
+            next_send: now,
+            timeout: INITIAL_TIMEOUT,
+        };
+        c.new_exchange(now);
+        c
+    }
+
+    /// Start a new exchange with a new transaction id
+    fn new_exchange(&mut self, now: Instant) {
+        let clock = crate::modules::time::uptime().as_micros() as u32;
+        let mac = self.iface.mac().octets();
+        self.xid = clock ^ u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]]);
+        self.exchange_start = now;
+        self.next_send = now;
+        self.timeout = INITIAL_TIMEOUT;
+    }
+
+    /// Start looking for servers again
+    fn restart(&mut self, now: Instant) {
+        self.state = State::Selecting;
+        self.new_exchange(now);
+    }
+
+    /// Build a message from the client
+    fn message(
+        &self,
+        kind: MessageType,
+        ciaddr: Ipv4Address,
+        extra: &[(u8, Vec<u8>)],
+        now: Instant,
+    ) -> Vec<u8> {
+        let secs = now
+            .duration_since(self.exchange_start)
+            .as_secs()
+            .min(0xffff) as u16;
+        build_message(self.xid, self.iface.mac(), kind, ciaddr, secs, extra)
+    }
+
+    /// Produce the message to send if it is time to send one, handling the expiry of the lease
+    fn poll(&mut self, now: Instant) -> Option<(Vec<u8>, Destination)> {
+        if let Some((renew, rebind, expires)) =
+            self.lease.as_ref().map(|l| (l.renew, l.rebind, l.expires))
+        {
+            if now >= expires {
+                self.lease = None;
+                self.restart(now);
+                return None;
+            }
+            match self.state {
+                State::Bound | State::Renewing if now >= rebind => {
+                    self.enter(State::Rebinding, now)
+                }
+                State::Bound if now >= renew => self.enter(State::Renewing, now),
+                _ => {}
+            }
+        }
+        if now < self.next_send {
+            return None;
+        }
+        match self.state.clone() {
+            State::Selecting => {
+                let m = self.message(MessageType::Discover, Ipv4Address::UNSPECIFIED, &[], now);
+                self.backoff(now);
+                Some((m, Destination::Broadcast))
+            }
+            State::Requesting {
+                address,
+                server,
+                attempts,
+            } => {
+                if attempts >= REQUEST_ATTEMPTS {
+                    self.restart(now);
+                    return None;
+                }
+                self.state = State::Requesting {
+                    address,
+                    server,
+                    attempts: attempts + 1,
+                };
+                let m = self.message(
+                    MessageType::Request,
+                    Ipv4Address::UNSPECIFIED,
+                    &[
+                        (OPTION_REQUESTED_ADDRESS, address.octets().to_vec()),
+                        (OPTION_SERVER_ID, server.octets().to_vec()),
+                    ],
+                    now,
+                );
+                self.backoff(now);
+                Some((m, Destination::Broadcast))
+            }
+            State::Bound => None,
+            State::Renewing | State::Rebinding => {
+                let l = self.lease.as_ref()?;
+                let (until, destination) = match self.state {
+                    State::Renewing => (l.rebind, Destination::Server(l.server)),
+                    _ => (l.expires, Destination::Broadcast),
+                };
+                let m = self.message(MessageType::Request, l.config.address, &[], now);
+                // Retransmit at half the time remaining, as rfc 2131 suggests
+                self.next_send = now + (until.duration_since(now) / 2).max(MIN_RENEW_INTERVAL);
+                Some((m, destination))
+            }
+        }
+    }
+
+    /// The next instant that [Self::poll] has something to do
+    fn next_deadline(&self, now: Instant) -> Option<Instant> {
+        let mut times = alloc::vec![self.next_send];
+        if let Some(l) = &self.lease {
+            times.extend([l.renew, l.rebind, l.expires]);
+        }
+        times.into_iter().filter(|t| *t > now).min()
+    }
+
+    /// Move to a state that starts a new exchange
+    fn enter(&mut self, state: State, now: Instant) {
+        self.state = state;
+        self.new_exchange(now);
+    }
+
+    /// Schedule the retransmission of a message, doubling the timeout each time
+    fn backoff(&mut self, now: Instant) {
+        self.next_send = now + self.timeout;
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+    }
+
+    /// Process a reply from a server, returning the lease to apply, or Some(None) when the address must be removed
+    fn reply(&mut self, r: &Reply, now: Instant) -> Option<Option<Lease>> {
+        if r.xid != self.xid || r.chaddr != self.iface.mac() {
+            return None;
+        }
+        match (&self.state, r.kind) {
+            (State::Selecting, MessageType::Offer) => {
+                let server = r.address(OPTION_SERVER_ID)?;
+                self.state = State::Requesting {
+                    address: r.yiaddr,
+                    server,
+                    attempts: 0,
+                };
+                self.next_send = now;
+                self.timeout = INITIAL_TIMEOUT;
+                None
+            }
+            (State::Requesting { server, .. }, MessageType::Ack) => {
+                let l = Lease::from_ack(r, *server, self.exchange_start);
+                self.bind(l)
+            }
+            (State::Renewing | State::Rebinding, MessageType::Ack) => {
+                let server = r
+                    .address(OPTION_SERVER_ID)
+                    .or(self.lease.as_ref().map(|l| l.server))?;
+                let l = Lease::from_ack(r, server, self.exchange_start);
+                self.bind(l)
+            }
+            (State::Requesting { .. } | State::Renewing | State::Rebinding, MessageType::Nak) => {
+                let had_lease = self.lease.take().is_some();
+                self.restart(now);
+                had_lease.then_some(None)
+            }
+            _ => None,
+        }
+    }
+
+    /// Enter the bound state with a lease
+    fn bind(&mut self, l: Lease) -> Option<Option<Lease>> {
+        self.state = State::Bound;
+        self.next_send = l.renew;
+        self.lease = Some(l.clone());
+        Some(Some(l))
+    }
+}
+
+/// Apply a lease to an interface, or remove the configuration from it
+async fn apply(iface: &Interface, lease: Option<Lease>) {
+    let changed = iface.ipv4_config() != lease.as_ref().map(|l| l.config);
+    match lease {
+        Some(l) => {
+            if changed {
+                iface.set_ipv4_config(Some(l.config)).await;
+                crate::VGA
+                    .print_str_async(&format!(
+                        "{}: leased {}/{} from {} for {:?}\r\n",
+                        iface.name(),
+                        l.config.address,
+                        l.config.prefix_length,
+                        l.server,
+                        l.expires.duration_since(l.start)
+                    ))
+                    .await;
+            }
+            ipv4::set_default_gateway(iface.name(), l.gateway);
+            iface.set_dns_servers(l.dns_servers);
+        }
+        None => {
+            iface.set_ipv4_config(None).await;
+            ipv4::set_default_gateway(iface.name(), None);
+            iface.set_dns_servers(Vec::new());
+            crate::VGA
+                .print_str_async(&format!("{}: lost its dhcp lease\r\n", iface.name()))
+                .await;
+        }
+    }
+    LEASES.notify();
+}
+
+/// Wait for an interface to have an address, giving up after the timeout. Returns true if the interface has an address.
+pub async fn wait_for_lease(iface: &Interface, timeout: Duration) -> bool {
+    let mut expired = core::pin::pin!(crate::modules::time::sleep(timeout));
+    loop {
+        let generation = LEASES.generation();
+        if iface.ipv4_config().is_some() {
+            return true;
+        }
+        let leased = core::pin::pin!(LEASES.changed(generation));
+        if let futures::future::Either::Right(_) =
+            futures::future::select(leased, expired.as_mut()).await
+        {
+            return iface.ipv4_config().is_some();
+        }
+    }
+}
+
+/// What the client task has to do next
+enum Work {
+    /// Something happened to the set of adapters
+    Event(Option<NetworkEvent>),
+    /// A datagram arrived on the client port
+    Datagram(Vec<u8>, SocketAddress),
+    /// A client may have a message to send or a lease that ran out
+    Timer,
+}
+
+/// The task that runs a dhcp client for every network adapter as it is registered.
+/// Clients are dropped when their adapter is unregistered.
+pub async fn client_task() {
+    let mut events = super::subscribe().await;
+    let any = SocketAddress::new(Ipv4Address::UNSPECIFIED, CLIENT_PORT);
+    let Ok(socket) = UdpSocket::bind(any).await else {
+        crate::VGA
+            .print_str_async("The dhcp client port is already in use\r\n")
+            .await;
+        return;
+    };
+    let mut clients: BTreeMap<alloc::string::String, Client> = BTreeMap::new();
+    let mut work = Work::Timer;
+    loop {
+        let now = Instant::now();
+        match work {
+            Work::Event(Some(NetworkEvent::Added(name))) => {
+                if let Some(iface) = super::get_interface(&name).await {
+                    clients.insert(name, Client::new(iface));
+                }
+            }
+            Work::Event(Some(NetworkEvent::Removed(name))) => {
+                clients.remove(&name);
+            }
+            Work::Event(None) => return,
+            Work::Datagram(d, source) => {
+                if let Some(r) = Reply::parse(&d).filter(|_| source.port == SERVER_PORT) {
+                    for c in clients.values_mut() {
+                        if let Some(l) = c.reply(&r, now) {
+                            apply(&c.iface, l).await;
+                        }
+                    }
+                }
+            }
+            Work::Timer => {}
+        }
+        for c in clients.values_mut() {
+            let expired = c.lease.as_ref().is_some_and(|l| now >= l.expires);
+            if let Some((m, destination)) = c.poll(now) {
+                let _ = match destination {
+                    Destination::Broadcast => {
+                        let to = SocketAddress::new(Ipv4Address::BROADCAST, SERVER_PORT);
+                        socket.send_on(&c.iface, &m, to).await
+                    }
+                    Destination::Server(ip) => {
+                        socket
+                            .send_to(&m, SocketAddress::new(ip, SERVER_PORT))
+                            .await
+                    }
+                };
+            }
+            if expired {
+                apply(&c.iface, None).await;
+            }
+        }
+        let deadline = clients.values().filter_map(|c| c.next_deadline(now)).min();
+        let event = events.next().map(Work::Event).boxed_local();
+        let datagram = socket
+            .recv_from()
+            .map(|r| match r {
+                Ok((d, source)) => Work::Datagram(d, source),
+                Err(_) => Work::Timer,
+            })
+            .boxed_local();
+        let timer = match deadline {
+            Some(d) => crate::modules::time::sleep_until(d)
+                .map(|_| Work::Timer)
+                .boxed_local(),
+            None => futures::future::pending::<Work>().boxed_local(),
+        };
+        work = futures::future::select_all([event, datagram, timer])
+            .await
+            .0;
+    }
+}
+
+/// Test building a client message and parsing a reply built from it
+#[doors_macros::doors_test]
+fn dhcp_message_test() -> Result<(), ()> {
+    let mac = MacAddress::new([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+    let requested = Ipv4Address::new(10, 0, 2, 15);
+    let m = build_message(
+        0x1234_5678,
+        mac,
+        MessageType::Request,
+        Ipv4Address::UNSPECIFIED,
+        3,
+        &[(OPTION_REQUESTED_ADDRESS, requested.octets().to_vec())],
+    );
+    assert_eq!(m.len(), 300);
+    assert_eq!(m[0..4], [1, 1, 6, 0]);
+    assert_eq!(m[4..8], 0x1234_5678u32.to_be_bytes());
+    assert_eq!(m[8..10], [0, 3]);
+    assert_eq!(m[10..12], [0x80, 0]);
+    assert_eq!(m[28..34], mac.octets());
+    assert_eq!(m[236..240], MAGIC_COOKIE);
+    assert_eq!(
+        m[240..243],
+        [OPTION_MESSAGE_TYPE, 1, MessageType::Request as u8]
+    );
+    assert_eq!(m[243..249], [OPTION_REQUESTED_ADDRESS, 4, 10, 0, 2, 15]);
+    // Messages from clients are not replies
+    assert!(Reply::parse(&m).is_none());
+
+    let mut r = m.clone();
+    r[0] = 2;
+    r[16..20].copy_from_slice(&requested.octets());
+    r.truncate(OPTIONS_OFFSET);
+    r.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, MessageType::Ack as u8]);
+    r.extend_from_slice(&[OPTION_SERVER_ID, 4, 10, 0, 2, 2]);
+    r.extend_from_slice(&[OPTION_SUBNET_MASK, 4, 255, 255, 255, 0]);
+    r.extend_from_slice(&[OPTION_PAD, OPTION_ROUTER, 4, 10, 0, 2, 2]);
+    // The dns servers are split over two options, which are joined
+    r.extend_from_slice(&[OPTION_DNS, 4, 8, 8, 8, 8, OPTION_DNS, 4, 1, 1, 1, 1]);
+    r.extend_from_slice(&[OPTION_LEASE_TIME, 4, 0, 0, 0x0e, 0x10, OPTION_END]);
+    let reply = Reply::parse(&r).ok_or(())?;
+    assert_eq!(reply.kind, MessageType::Ack);
+    assert_eq!(reply.xid, 0x1234_5678);
+    assert_eq!(reply.yiaddr, requested);
+    assert_eq!(reply.chaddr, mac);
+    assert_eq!(
+        reply.address(OPTION_SERVER_ID),
+        Some(Ipv4Address::new(10, 0, 2, 2))
+    );
+    assert_eq!(
+        reply.addresses(OPTION_DNS),
+        [Ipv4Address::new(8, 8, 8, 8), Ipv4Address::new(1, 1, 1, 1)]
+    );
+    assert_eq!(
+        reply.seconds(OPTION_LEASE_TIME),
+        Some(Duration::from_secs(3600))
+    );
+
+    let start = Instant::now();
+    let l = Lease::from_ack(&reply, Ipv4Address::new(10, 0, 2, 2), start);
+    assert_eq!(l.config.address, requested);
+    assert_eq!(l.config.prefix_length, 24);
+    assert_eq!(l.gateway, Some(Ipv4Address::new(10, 0, 2, 2)));
+    assert_eq!(l.renew, start + Duration::from_secs(1800));
+    assert_eq!(l.rebind, start + Duration::from_secs(3150));
+    assert_eq!(l.expires, start + Duration::from_secs(3600));
+
+    // An option that runs past the end of the message is rejected
+    r.truncate(r.len() - 3);
+    assert!(Reply::parse(&r).is_none());
+    Ok(())
+}
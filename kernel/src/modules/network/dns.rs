@@ -0,0 +1,540 @@
+//! A stub resolver that asks the dns servers configured on the interfaces for the addresses of a host name, and caches the answers
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::tcp::TcpStream;
+use super::udp::UdpSocket;
+use super::{Ipv4Address, SocketAddress};
+use crate::modules::time::{Duration, Instant};
+use crate::Locked;
+
+/// The port dns servers listen on
+const PORT: u16 = 53;
+/// How long to wait for a reply from a server
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// The number of times each server is asked
+const ATTEMPTS: u8 = 3;
+/// The longest chain of aliases that is followed
+const MAX_CNAME: u8 = 8;
+/// The number of names the cache holds
+const CACHE_SIZE: usize = 128;
+/// The longest time an answer is cached for
+const MAX_TTL: u32 = 86400;
+/// How long a name that does not exist is remembered when the server does not say
+const NEGATIVE_TTL: u32 = 60;
+/// The length of the header of a message
+const HEADER_LENGTH: usize = 12;
+/// The class of internet records
+const CLASS_IN: u16 = 1;
+/// The type of a record holding the canonical name of an alias
+const TYPE_CNAME: u16 = 5;
+/// The type of a record describing a zone, used for negative caching
+const TYPE_SOA: u16 = 6;
+
+/// Used to pick the id of the next query
+static QUERY_ID: AtomicU16 = AtomicU16::new(0);
+
+/// The cached answers, by name and record type
+static CACHE: Locked<BTreeMap<(String, RecordType), CacheEntry>> = Locked::new(BTreeMap::new());
+
+/// The errors that can occur when resolving a name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DnsError {
+    /// No interface has a dns server
+    NoServers,
+    /// The name is not a valid host name
+    InvalidName,
+    /// The name does not exist
+    NotFound,
+    /// The servers could not answer the query
+    ServerFailure,
+    /// No server replied
+    TimedOut,
+}
+
+/// The types of address records
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RecordType {
+    /// An ipv4 address
+    A,
+    /// An ipv6 address
+    Aaaa,
+}
+
+impl RecordType {
+    /// The value of the type in a message
+    fn value(&self) -> u16 {
+        match self {
+            Self::A => 1,
+            Self::Aaaa => 28,
+        }
+    }
+}
+
+/// A cached answer
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    /// The addresses, or an empty list when the name has no records of the type
+    addresses: Vec<IpAddr>,
+    /// The name does not exist
+    not_found: bool,
+    /// When the answer must be asked for again
+    expires: Instant,
+}
+
+/// The useful parts of a reply
+#[derive(Debug, Default)]
+struct Answer {
+    /// The addresses of the name that was asked for, after following aliases
+    addresses: Vec<IpAddr>,
+    /// The name the aliases led to, when the reply has no addresses for it
+    alias: Option<String>,
+    /// The shortest time to live of the records used
+    ttl: u32,
+    /// The name does not exist
+    not_found: bool,
+}
+
+/// Convert a name to the form used to compare and cache names
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Build a query for a name, checking that the name is valid
+fn build_query(id: u16, name: &str, t: RecordType) -> Result<Vec<u8>, DnsError> {
+    if name.is_empty() || name.len() > 253 {
+        return Err(DnsError::InvalidName);
+    }
+    let mut q = Vec::with_capacity(HEADER_LENGTH + name.len() + 6);
+    q.extend_from_slice(&id.to_be_bytes());
+    // A standard query with recursion desired
+    q.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::InvalidName);
+        }
+        q.push(label.len() as u8);
+        q.extend_from_slice(label.as_bytes());
+    }
+    q.push(0);
+    q.extend_from_slice(&t.value().to_be_bytes());
+    q.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(q)
+}
+
+/// Read a possibly compressed name from a message, returning it and the position after it
+fn read_name(m: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let length = *m.get(pos)? as usize;
+        if length & 0xc0 == 0xc0 {
+            end.get_or_insert(pos + 2);
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            pos = ((length & 0x3f) << 8) | *m.get(pos + 1)? as usize;
+        } else if length & 0xc0 != 0 {
+            return None;
+        } else if length == 0 {
+            return Some((name, end.unwrap_or(pos + 1)));
+        } else {
+            let label = m.get(pos + 1..pos + 1 + length)?;
+            if !name.is_empty() {
+                name.push('.');
+            }
+            name.extend(label.iter().map(|c| c.to_ascii_lowercase() as char));
+            pos += 1 + length;
+        }
+    }
+}
+
+/// A resource record from a reply
+struct Record {
+    /// The name the record belongs to
+    name: String,
+    /// The type of the record
+    kind: u16,
+    /// The time to live
+    ttl: u32,
+    /// The position of the data in the message
+    data: usize,
+    /// The length of the data
+    length: usize,
+}
+
+/// Read the records of a section of a message, returning them and the position after them
+fn read_records(m: &[u8], mut pos: usize, count: u16) -> Option<(Vec<Record>, usize)> {
+    let mut records = Vec::new();
+    for _ in 0..count {
+        let (name, p) = read_name(m, pos)?;
+        let f = m.get(p..p + 10)?;
+        let kind = u16::from_be_bytes([f[0], f[1]]);
+        let class = u16::from_be_bytes([f[2], f[3]]);
+        let ttl = u32::from_be_bytes([f[4], f[5], f[6], f[7]]);
+        let length = u16::from_be_bytes([f[8], f[9]]) as usize;
+        let data = p + 10;
+        m.get(data..data + length)?;
+        if class == CLASS_IN {
+            records.push(Record {
+                name,
+                kind,
+                ttl: ttl.min(MAX_TTL),
+                data,
+                length,
+            });
+        }
+        pos = data + length;
+    }
+    Some((records, pos))
+}
+
+/// Parse a reply to a query for a name. Returns None if the reply is malformed or does not match the query.
+fn parse_reply(m: &[u8], id: u16, name: &str, t: RecordType) -> Option<Result<Answer, DnsError>> {
+    if m.len() < HEADER_LENGTH || u16::from_be_bytes([m[0], m[1]]) != id || m[2] & 0x80 == 0 {
+        return None;
+    }
+    let rcode = m[3] & 0x0f;
+    let count = |i: usize| u16::from_be_bytes([m[i], m[i + 1]]);
+    let mut pos = HEADER_LENGTH;
+    for _ in 0..count(4) {
+        let (_, p) = read_name(m, pos)?;
+        pos = p + 4;
+    }
+    let (answers, pos) = read_records(m, pos, count(6))?;
+    let (authority, _) = read_records(m, pos, count(8))?;
+    // Negative answers are cached for the minimum time of the zone
+    let negative_ttl = authority
+        .iter()
+        .find(|r| r.kind == TYPE_SOA && r.length >= 4)
+        .map(|r| {
+            let e = r.data + r.length;
+            u32::from_be_bytes([m[e - 4], m[e - 3], m[e - 2], m[e - 1]]).min(r.ttl)
+        })
+        .unwrap_or(NEGATIVE_TTL);
+    match rcode {
+        0 => {}
+        3 => {
+            return Some(Ok(Answer {
+                ttl: negative_ttl,
+                not_found: true,
+                ..Default::default()
+            }))
+        }
+        _ => return Some(Err(DnsError::ServerFailure)),
+    }
+    let mut answer = Answer {
+        ttl: MAX_TTL,
+        ..Default::default()
+    };
+    let mut current = String::from(name);
+    for _ in 0..=MAX_CNAME {
+        for r in answers
+            .iter()
+            .filter(|r| r.name == current && r.kind == t.value())
+        {
+            let d = &m[r.data..r.data + r.length];
+            let a = match (t, r.length) {
+                (RecordType::A, 4) => IpAddr::V4(Ipv4Addr::new(d[0], d[1], d[2], d[3])),
+                (RecordType::Aaaa, 16) => {
+                    let mut o = [0; 16];
+                    o.copy_from_slice(d);
+                    IpAddr::V6(Ipv6Addr::from(o))
+                }
+                _ => continue,
+            };
+            answer.addresses.push(a);
+            answer.ttl = answer.ttl.min(r.ttl);
+        }
+        if !answer.addresses.is_empty() {
+            return Some(Ok(answer));
+        }
+        let Some(c) = answers
+            .iter()
+            .find(|r| r.name == current && r.kind == TYPE_CNAME)
+        else {
+            break;
+        };
+        answer.ttl = answer.ttl.min(c.ttl);
+        current = read_name(m, c.data)?.0;
+        answer.alias = Some(current.clone());
+    }
+    if answer.alias.is_none() {
+        // The name exists but has no records of this type
+        answer.ttl = negative_ttl;
+    }
+    Some(Ok(answer))
+}
+
+/// The dns servers of all interfaces
+async fn servers() -> Vec<Ipv4Address> {
+    let mut s = Vec::new();
+    for iface in super::interfaces().await {
+        for d in iface.dns_servers() {
+            if !s.contains(&d) {
+                s.push(d);
+            }
+        }
+    }
+    s
+}
+
+/// Read exactly enough data to fill the buffer from a stream
+async fn read_exact(s: &TcpStream, buf: &mut [u8]) -> Option<()> {
+    let mut done = 0;
+    while done < buf.len() {
+        match s.read(&mut buf[done..]).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => done += n,
+        }
+    }
+    Some(())
+}
+
+/// Send a query over tcp, used when the reply over udp was truncated
+async fn query_tcp(server: Ipv4Address, query: &[u8]) -> Option<Vec<u8>> {
+    let s = TcpStream::connect(SocketAddress::new(server, PORT))
+        .await
+        .ok()?;
+    let mut m = (query.len() as u16).to_be_bytes().to_vec();
+    m.extend_from_slice(query);
+    s.write(&m).await.ok()?;
+    let mut length = [0; 2];
+    read_exact(&s, &mut length).await?;
+    let mut reply = alloc::vec![0; u16::from_be_bytes(length) as usize];
+    read_exact(&s, &mut reply).await?;
+    let _ = s.close().await;
+    Some(reply)
+}
+
+/// Ask the servers for the records of a name, retrying each server in turn
+async fn query(name: &str, t: RecordType) -> Result<Answer, DnsError> {
+    let servers = servers().await;
+    if servers.is_empty() {
+        return Err(DnsError::NoServers);
+    }
+    let id = QUERY_ID.fetch_add(1, Ordering::Relaxed)
+        ^ (crate::modules::time::uptime().as_micros() as u16);
+    let q = build_query(id, name, t)?;
+    let socket = UdpSocket::bind(SocketAddress::new(Ipv4Address::UNSPECIFIED, 0))
+        .await
+        .map_err(|_| DnsError::ServerFailure)?;
+    let mut failure = DnsError::TimedOut;
+    for _ in 0..ATTEMPTS {
+        for server in &servers {
+            let from = SocketAddress::new(*server, PORT);
+            if socket.send_to(&q, from).await.is_err() {
+                continue;
+            }
+            let mut expired = core::pin::pin!(crate::modules::time::sleep(TIMEOUT));
+            loop {
+                let received = core::pin::pin!(socket.recv_from());
+                let (mut reply, source) =
+                    match futures::future::select(received, expired.as_mut()).await {
+                        futures::future::Either::Left((Ok(d), _)) => d,
+                        _ => break,
+                    };
+                if source != from {
+                    continue;
+                }
+                // A truncated reply is asked for again over tcp, limited by the same timeout as a udp reply
+                if reply.len() > 2 && reply[2] & 0x02 != 0 {
+                    let tcp = core::pin::pin!(query_tcp(*server, &q));
+                    let expired = core::pin::pin!(crate::modules::time::sleep(TIMEOUT));
+                    match futures::future::select(tcp, expired).await {
+                        futures::future::Either::Left((Some(r), _)) => reply = r,
+                        _ => break,
+                    }
+                }
+                match parse_reply(&reply, id, name, t) {
+                    Some(Ok(a)) => return Ok(a),
+                    Some(Err(e)) => {
+                        failure = e;
+                        break;
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+    Err(failure)
+}
+
+/// Find the addresses of one type for a name, using the cache and following aliases
+pub async fn lookup(name: &str, t: RecordType) -> Result<Vec<IpAddr>, DnsError> {
+    let original = normalize(name);
+    let mut current = original.clone();
+    // The time to live of the aliases followed so far
+    let mut alias_ttl = MAX_TTL;
+    for _ in 0..MAX_CNAME {
+        let now = Instant::now();
+        let cached = CACHE.sync_lock().get(&(current.clone(), t)).cloned();
+        if let Some(c) = cached.filter(|c| now < c.expires) {
+            return if c.not_found {
+                Err(DnsError::NotFound)
+            } else {
+                Ok(c.addresses)
+            };
+        }
+        let a = query(&current, t).await?;
+        if let Some(alias) = a.alias.clone().filter(|_| a.addresses.is_empty()) {
+            alias_ttl = alias_ttl.min(a.ttl);
+            current = alias;
+            continue;
+        }
+        {
+            let mut cache = CACHE.sync_lock();
+            cache.retain(|_, e| now < e.expires);
+            if cache.len() >= CACHE_SIZE {
+                if let Some(k) = cache
+                    .iter()
+                    .min_by_key(|(_, e)| e.expires)
+                    .map(|(k, _)| k.clone())
+                {
+                    cache.remove(&k);
+                }
+            }
+            let entry = CacheEntry {
+                addresses: a.addresses.clone(),
+                not_found: a.not_found,
+                expires: now + Duration::from_secs(a.ttl as u64),
+            };
+            // The original name is remembered for as long as the whole chain of aliases is valid
+            if current != original {
+                let mut e = entry.clone();
+                e.expires = now + Duration::from_secs(a.ttl.min(alias_ttl) as u64);
+                cache.insert((original, t), e);
+            }
+            cache.insert((current, t), entry);
+        }
+        return if a.not_found {
+            Err(DnsError::NotFound)
+        } else {
+            Ok(a.addresses)
+        };
+    }
+    Err(DnsError::ServerFailure)
+}
+
+/// Find the ipv4 and ipv6 addresses of a host name. Addresses written as dotted decimal are returned as is.
+/// An empty list is returned when the name cannot be resolved.
+pub async fn resolve(name: &str) -> Vec<IpAddr> {
+    if let Ok(a) = name.parse::<IpAddr>() {
+        return alloc::vec![a];
+    }
+    let mut addresses = lookup(name, RecordType::A).await.unwrap_or_default();
+    addresses.extend(lookup(name, RecordType::Aaaa).await.unwrap_or_default());
+    addresses
+}
+
+/// Test building queries and reading compressed names
+#[doors_macros::doors_test]
+fn dns_query_test() -> Result<(), ()> {
+    let q = build_query(0x1234, "www.example.com", RecordType::A).map_err(|_| ())?;
+    assert_eq!(q[0..6], [0x12, 0x34, 0x01, 0x00, 0, 1]);
+    assert_eq!(q[12..29], *b"\x03www\x07example\x03com\x00");
+    assert_eq!(q[29..33], [0, 1, 0, 1]);
+    assert_eq!(
+        build_query(1, "a..b", RecordType::A),
+        Err(DnsError::InvalidName)
+    );
+    assert_eq!(
+        build_query(1, "", RecordType::A),
+        Err(DnsError::InvalidName)
+    );
+    let long = alloc::format!("{}.com", "a".repeat(64));
+    assert_eq!(
+        build_query(1, &long, RecordType::Aaaa),
+        Err(DnsError::InvalidName)
+    );
+
+    // A pointer to the middle of the question, followed by the end of the name
+    let mut m = q.clone();
+    let at = m.len();
+    m.extend_from_slice(&[3, b'f', b'o', b'o', 0xc0, 16]);
+    assert_eq!(
+        read_name(&m, at),
+        Some((String::from("foo.example.com"), at + 6))
+    );
+    assert_eq!(
+        read_name(&m, 12),
+        Some((String::from("www.example.com"), 29))
+    );
+    // A pointer to itself is cut off by the jump limit
+    let at = m.len();
+    m.extend_from_slice(&[0xc0, at as u8]);
+    assert_eq!(read_name(&m, at), None);
+    Ok(())
+}
+
+/// Test parsing replies that follow aliases, and negative replies that use the zone minimum
+#[doors_macros::doors_test]
+fn dns_reply_test() -> Result<(), ()> {
+    let record = |m: &mut Vec<u8>, name: [u8; 2], kind: u16, ttl: u32, data: &[u8]| {
+        m.extend_from_slice(&name);
+        m.extend_from_slice(&kind.to_be_bytes());
+        m.extend_from_slice(&CLASS_IN.to_be_bytes());
+        m.extend_from_slice(&ttl.to_be_bytes());
+        m.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        m.extend_from_slice(data);
+    };
+    let name = "www.example.com";
+    let q = build_query(0x1234, name, RecordType::A).map_err(|_| ())?;
+    // The question holds www.example.com at offset 12, and example.com at offset 16
+    let www = [0xc0, 12];
+    let example = [0xc0, 16];
+
+    let mut m = q.clone();
+    m[2] |= 0x80;
+    m[7] = 2;
+    record(&mut m, www, TYPE_CNAME, 600, &example);
+    record(&mut m, example, 1, 300, &[93, 184, 216, 34]);
+    assert!(parse_reply(&m, 0x4321, name, RecordType::A).is_none());
+    let a = parse_reply(&m, 0x1234, name, RecordType::A)
+        .ok_or(())?
+        .map_err(|_| ())?;
+    assert_eq!(a.addresses, [IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))]);
+    assert_eq!(a.alias.as_deref(), Some("example.com"));
+    assert_eq!(a.ttl, 300);
+    assert!(!a.not_found);
+
+    // An alias without the address of the name it leads to
+    let mut m = q.clone();
+    m[2] |= 0x80;
+    m[7] = 1;
+    record(&mut m, www, TYPE_CNAME, 600, &example);
+    let a = parse_reply(&m, 0x1234, name, RecordType::A)
+        .ok_or(())?
+        .map_err(|_| ())?;
+    assert!(a.addresses.is_empty());
+    assert_eq!(a.alias.as_deref(), Some("example.com"));
+    assert_eq!(a.ttl, 600);
+
+    // The name does not exist, and the minimum of the zone is shorter than the time to live of the soa record
+    let mut soa = alloc::vec![0xc0, 16, 0xc0, 16];
+    for v in [1u32, 7200, 3600, 1209600, 300] {
+        soa.extend_from_slice(&v.to_be_bytes());
+    }
+    let mut m = q.clone();
+    m[2] |= 0x80;
+    m[3] = 0x83;
+    m[9] = 1;
+    record(&mut m, example, TYPE_SOA, 900, &soa);
+    let a = parse_reply(&m, 0x1234, name, RecordType::A)
+        .ok_or(())?
+        .map_err(|_| ())?;
+    assert!(a.not_found);
+    assert_eq!(a.ttl, 300);
+
+    // A server failure
+    m[3] = 0x82;
+    assert_eq!(
+        parse_reply(&m, 0x1234, name, RecordType::A).map(|r| r.err()),
+        Some(Some(DnsError::ServerFailure))
+    );
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! Ethernet framing for network adapters
+
+use alloc::vec::Vec;
+
+use super::MacAddress;
+
+/// The length of the ethernet header, destination, source, and ethertype
+pub const HEADER_LENGTH: usize = 14;
+/// The shortest frame that can be sent, not counting the crc. Shorter frames are padded with zeros.
+pub const MINIMUM_FRAME_LENGTH: usize = 60;
+/// The largest payload of a frame without jumbo frames
+pub const MTU: usize = 1500;
+
+/// The protocol carried in the payload of an ethernet frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EtherType {
+    /// Internet protocol version 4
+    Ipv4,
+    /// Address resolution protocol
+    Arp,
+    /// An 802.1q vlan tag
+    Vlan,
+    /// Internet protocol version 6
+    Ipv6,
+    /// Any other protocol
+    Other(u16),
+}
+
+impl From<u16> for EtherType {
+    fn from(value: u16) -> Self {
+        match value {
+            0x0800 => Self::Ipv4,
+            0x0806 => Self::Arp,
+            0x8100 => Self::Vlan,
+            0x86dd => Self::Ipv6,
+            o => Self::Other(o),
+        }
+    }
+}
+
+impl From<EtherType> for u16 {
+    fn from(value: EtherType) -> u16 {
+        match value {
+            EtherType::Ipv4 => 0x0800,
+            EtherType::Arp => 0x0806,
+            EtherType::Vlan => 0x8100,
+            EtherType::Ipv6 => 0x86dd,
+            EtherType::Other(o) => o,
+        }
+    }
+}
+
+/// A received ethernet frame, borrowing the payload from the packet
+#[derive(Debug)]
+pub struct EthernetFrame<'a> {
+    /// The destination address
+    pub destination: MacAddress,
+    /// The source address
+    pub source: MacAddress,
+    /// The protocol of the payload
+    pub ethertype: EtherType,
+    /// The payload, which may include padding at the end
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    /// Parse a frame, returning None if it is too short. Values of the ethertype field below 0x600 are 802.3 lengths and are reported as [EtherType::Other].
+    pub fn parse(packet: &'a [u8]) -> Option<Self> {
+        if packet.len() < HEADER_LENGTH {
+            return None;
+        }
+        let mut destination = [0; 6];
+        destination.copy_from_slice(&packet[0..6]);
+        let mut source = [0; 6];
+        source.copy_from_slice(&packet[6..12]);
+        Some(Self {
+            destination: MacAddress::new(destination),
+            source: MacAddress::new(source),
+            ethertype: u16::from_be_bytes([packet[12], packet[13]]).into(),
+            payload: &packet[HEADER_LENGTH..],
+        })
+    }
+
+    /// Build the bytes of a frame, padding it to the minimum length
+    pub fn build(
+        destination: MacAddress,
+        source: MacAddress,
+        ethertype: EtherType,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut f = Vec::with_capacity((HEADER_LENGTH + payload.len()).max(MINIMUM_FRAME_LENGTH));
+        f.extend_from_slice(&destination.octets());
+        f.extend_from_slice(&source.octets());
+        f.extend_from_slice(&u16::from(ethertype).to_be_bytes());
+        f.extend_from_slice(payload);
+        if f.len() < MINIMUM_FRAME_LENGTH {
+            f.resize(MINIMUM_FRAME_LENGTH, 0);
+        }
+        f
+    }
+}
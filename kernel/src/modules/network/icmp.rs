@@ -0,0 +1,117 @@
+//! The internet control message protocol, answering echo requests and sending them with [ping]
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::interface::Interface;
+use super::ipv4::{self, checksum, IpProtocol, Ipv4Header};
+use super::{ChangeNotifier, Ipv4Address};
+use crate::modules::time::{Duration, Instant};
+use crate::Locked;
+
+/// The message type of an echo reply
+const ECHO_REPLY: u8 = 0;
+/// The message type of an echo request
+const ECHO_REQUEST: u8 = 8;
+/// The identifier used for the echo requests sent by the kernel
+const ECHO_IDENTIFIER: u16 = 0x444f;
+
+/// The echo requests waiting for a reply, by sequence number, with the time the reply arrived
+static PINGS: Locked<BTreeMap<u16, Option<Instant>>> = Locked::new(BTreeMap::new());
+
+/// Notified every time a reply to an echo request arrives
+static REPLIES: ChangeNotifier = ChangeNotifier::new();
+
+/// The sequence number of the next echo request
+static SEQUENCE: AtomicU16 = AtomicU16::new(0);
+
+/// Build an icmp message with a correct checksum
+fn build(kind: u8, code: u8, rest: [u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut m = Vec::with_capacity(8 + data.len());
+    m.push(kind);
+    m.push(code);
+    m.extend_from_slice(&[0, 0]);
+    m.extend_from_slice(&rest);
+    m.extend_from_slice(data);
+    let c = checksum(&m);
+    m[2..4].copy_from_slice(&c.to_be_bytes());
+    m
+}
+
+/// Process a received icmp message
+pub async fn handle(iface: &Interface, h: &Ipv4Header, payload: &[u8]) {
+    if payload.len() < 8 || checksum(payload) != 0 {
+        return;
+    }
+    let rest = [payload[4], payload[5], payload[6], payload[7]];
+    match payload[0] {
+        ECHO_REQUEST => {
+            // Requests sent to broadcast and multicast addresses are not answered
+            let to_us = iface.ipv4_address() == Some(h.destination);
+            if to_us {
+                // The reply goes back out the interface the request came in on, from the address it was sent to
+                let reply = build(ECHO_REPLY, 0, rest, &payload[8..]);
+                let next_hop = ipv4::next_hop_on(iface.name(), h.source).unwrap_or(h.source);
+                let _ = ipv4::send_packet(
+                    iface,
+                    h.destination,
+                    h.source,
+                    next_hop,
+                    IpProtocol::Icmp,
+                    &reply,
+                )
+                .await;
+            }
+        }
+        ECHO_REPLY => {
+            let identifier = u16::from_be_bytes([rest[0], rest[1]]);
+            let sequence = u16::from_be_bytes([rest[2], rest[3]]);
+            if identifier == ECHO_IDENTIFIER {
+                if let Some(p) = PINGS.sync_lock().get_mut(&sequence) {
+                    p.get_or_insert(Instant::now());
+                }
+                REPLIES.notify();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Send an echo request with the given amount of data, and wait for the reply. Returns the round trip time.
+pub async fn ping(
+    destination: Ipv4Address,
+    data_length: usize,
+    timeout: Duration,
+) -> Result<Duration, ()> {
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let mut rest = [0; 4];
+    rest[0..2].copy_from_slice(&ECHO_IDENTIFIER.to_be_bytes());
+    rest[2..4].copy_from_slice(&sequence.to_be_bytes());
+    let data: Vec<u8> = (0..data_length).map(|i| i as u8).collect();
+    let request = build(ECHO_REQUEST, 0, rest, &data);
+    PINGS.sync_lock().insert(sequence, None);
+    let start = Instant::now();
+    if ipv4::send(destination, IpProtocol::Icmp, &request)
+        .await
+        .is_err()
+    {
+        PINGS.sync_lock().remove(&sequence);
+        return Err(());
+    }
+    let mut expired = core::pin::pin!(crate::modules::time::sleep_until(start + timeout));
+    let result = loop {
+        let generation = REPLIES.generation();
+        let reply = PINGS.sync_lock().get(&sequence).copied().flatten();
+        if let Some(t) = reply {
+            break Ok(t.duration_since(start));
+        }
+        let replied = core::pin::pin!(REPLIES.changed(generation));
+        if let futures::future::Either::Right(_) =
+            futures::future::select(replied, expired.as_mut()).await
+        {
+            break Err(());
+        }
+    };
+    PINGS.sync_lock().remove(&sequence);
+    result
+}
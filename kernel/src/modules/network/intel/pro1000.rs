@@ -1,29 +1,24 @@
 //! This driver is for the intel pro/1000 networking hardware.
 //! TODO: Implement support notation for ipv6 (82544GC/EI does not support ipv6)
 
-use alloc::collections::btree_map::BTreeMap;
 use alloc::format;
+use alloc::vec::Vec;
 
 use crate::kernel::System;
-use crate::modules::network::{MacAddress, NetworkAdapterTrait};
+use crate::modules::network::{
+    MacAddress, MemoryOrIo, NetworkAdapterTrait, NetworkStatistics, ReceiveNotifier,
+};
 use crate::modules::video::{hex_dump_async, hex_dump_generic_async, hex_dump_generic_slice_async};
 use crate::modules::{
     pci::{
-        BarSpace, ConfigurationSpaceEnum, PciBus, PciConfigurationSpace, PciDevice, PciFunction,
-        PciFunctionDriver, PciFunctionDriverTrait,
+        capability::PowerState, BarSpace, ConfigurationSpaceEnum, PciBus, PciConfigurationSpace,
+        PciDevice, PciFunction, PciFunctionDriver, PciFunctionDriverTrait, PciMatch,
+        PciProbeResult,
     },
     video::hex_dump_generic,
 };
-use crate::{Arc, IoReadWrite, IrqGuarded, IrqGuardedInner};
-use core::sync::atomic::{AtomicBool, Ordering};
-
-/// Holds either memory or io space
-enum MemoryOrIo {
-    /// Regular memory
-    Memory(crate::PciMemory),
-    /// Io space
-    Io(crate::IoPortArray<'static>),
-}
+use crate::{Arc, IrqGuarded, IrqGuardedInner};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 /// The model variants for the pro1000
 #[derive(Debug)]
@@ -112,46 +107,6 @@ impl TryFrom<u16> for Model {
     }
 }
 
-impl MemoryOrIo {
-    /// Dump the contents of the data as hex
-    async fn hex_dump(&self) {
-        match self {
-            MemoryOrIo::Memory(_m) => {
-                let mut buffer = [0u32; 32];
-                for (i, b) in buffer.iter_mut().enumerate() {
-                    *b = self.read(i as u16);
-                }
-                hex_dump_generic_async(&buffer, true, false).await;
-            }
-            MemoryOrIo::Io(_io_port_array) => todo!(),
-        }
-    }
-
-    /// Read a u32 from the specified address
-    fn read(&self, address: u16) -> u32 {
-        match self {
-            MemoryOrIo::Memory(mem) => mem.read_u32(address as usize),
-            MemoryOrIo::Io(io) => {
-                let mut iop: crate::IoPortRef<u32> = io.port(address);
-                iop.port_read()
-            }
-        }
-    }
-
-    /// Write the specified address with the specified u32
-    fn write(&mut self, address: u16, val: u32) {
-        match self {
-            MemoryOrIo::Memory(mem) => {
-                mem.write_u32(address as usize, val);
-            }
-            MemoryOrIo::Io(io) => {
-                let mut iop: crate::IoPortRef<u32> = io.port(address);
-                iop.port_write(val);
-            }
-        }
-    }
-}
-
 /// Defines the addresses of various registers for the pro1000 device
 #[derive(Clone, Copy, PartialEq, Debug)]
 #[allow(non_camel_case_types)]
@@ -195,6 +150,10 @@ enum IntelPro1000Registers {
     TxDescHead = 0x3810,
     /// Transmit descriptor tail
     TxDescTail = 0x3818,
+    /// CRC error count, cleared on read
+    CRCERRS = 0x4000,
+    /// Missed packets count, cleared on read
+    MPC = 0x4010,
     /// Multicast table array base register
     MTA_BASE = 0x5200,
     /// Receive address low
@@ -490,6 +449,10 @@ struct IntelPro1000DeviceInternal {
     bar0: crate::IrqGuarded<MemoryOrIo>,
     /// The link is up
     up: AtomicBool,
+    /// Signalled when the receive interrupts fire
+    rx_notify: Arc<ReceiveNotifier>,
+    /// The number of receiver overrun interrupts
+    rx_overruns: AtomicU32,
 }
 
 impl Arc<IntelPro1000DeviceInternal> {
@@ -498,7 +461,7 @@ impl Arc<IntelPro1000DeviceInternal> {
         let status = self
             .bar0
             .interrupt_access()
-            .read(IntelPro1000Registers::STATUS as u16);
+            .read_u32(IntelPro1000Registers::STATUS as u16);
         let linkstat = (status & 2) != 0;
         self.up.store(linkstat, Ordering::Relaxed);
     }
@@ -508,7 +471,7 @@ impl Arc<IntelPro1000DeviceInternal> {
             .bar0
             .access()
             .await
-            .read(IntelPro1000Registers::STATUS as u16);
+            .read_u32(IntelPro1000Registers::STATUS as u16);
         let linkstat = (status & 2) != 0;
         self.up.store(linkstat, Ordering::Relaxed);
     }
@@ -517,7 +480,12 @@ impl Arc<IntelPro1000DeviceInternal> {
 impl IntelPro1000DeviceInternal {
     /// Create a new Self
     fn new(bar0: crate::IrqGuarded<MemoryOrIo>, up: AtomicBool) -> Self {
-        Self { bar0, up }
+        Self {
+            bar0,
+            up,
+            rx_notify: Arc::new(ReceiveNotifier::new()),
+            rx_overruns: AtomicU32::new(0),
+        }
     }
 }
 
@@ -536,6 +504,10 @@ pub struct IntelPro1000Device {
     rxbufs: Option<RxBuffers>,
     /// The current rx buffer
     rxbufindex: Option<u8>,
+    /// The start of a packet that spans multiple rx buffers
+    rxpartial: Vec<u8>,
+    /// The receive counters
+    stats: NetworkStatistics,
     /// The tx buffers
     txbufs: Option<TxBuffers>,
     /// The current tx buffer
@@ -725,6 +697,9 @@ bitfield::bitfield! {
 
 impl super::super::NetworkAdapterTrait for IntelPro1000Device {
     async fn get_mac_address(&mut self) -> MacAddress {
+        if self.rxbufs.is_some() {
+            return self.mac_address;
+        }
         if self.detect_eeprom().await {
             let v = self.read_from_eeprom(0).await;
             let v2 = self.read_from_eeprom(1).await;
@@ -757,9 +732,6 @@ impl super::super::NetworkAdapterTrait for IntelPro1000Device {
                 let txb = self.txbufs.as_mut().unwrap();
                 let dest = &mut txb.dmas[txindex];
                 dest[..len].clone_from_slice(packet);
-                let ma: u64 = self.mac_address.into();
-                let ma: [u8; 8] = ma.to_le_bytes();
-                dest[6..12].clone_from_slice(&ma[0..6]);
                 {
                     let descriptor = &mut txb.bufs[txindex];
                     descriptor.length = len as u16;
@@ -776,7 +748,7 @@ impl super::super::NetworkAdapterTrait for IntelPro1000Device {
                 .bar0
                 .access()
                 .await
-                .write(IntelPro1000Registers::TxDescTail as u16, newindex as u32);
+                .write_u32(IntelPro1000Registers::TxDescTail as u16, newindex as u32);
             self.txbufindex = Some(newindex as u8);
             let mut tries = 0;
             let a = loop {
@@ -808,30 +780,121 @@ impl super::super::NetworkAdapterTrait for IntelPro1000Device {
                     }
                 }
             }
-            self.check_for_received_packets().await;
             Ok(())
         } else {
             doors_macros::todo_item_panic!("Packets larger than 8192 bytes not yet handled");
         }
     }
+
+    async fn receive_packet(&mut self) -> Result<Vec<u8>, ()> {
+        if self.rxbufs.is_none() {
+            return Err(());
+        }
+        let notifier = self.internal.rx_notify.clone();
+        loop {
+            if let Some(p) = self.check_for_received_packets().await {
+                return Ok(p);
+            }
+            notifier.wait().await;
+        }
+    }
+
+    async fn try_receive_packet(&mut self) -> Option<Vec<u8>> {
+        self.check_for_received_packets().await
+    }
+
+    fn receive_notifier(&self) -> Option<Arc<ReceiveNotifier>> {
+        self.rxbufs
+            .as_ref()
+            .map(|_| self.internal.rx_notify.clone())
+    }
+
+    async fn statistics(&mut self) -> NetworkStatistics {
+        {
+            let bar0 = self.internal.bar0.access().await;
+            self.stats.rx_dropped += bar0.read_u32(IntelPro1000Registers::MPC as u16) as u64;
+            // The descriptors already count these, reading clears the register
+            let _ = bar0.read_u32(IntelPro1000Registers::CRCERRS as u16);
+        }
+        self.stats.rx_overruns += self.internal.rx_overruns.swap(0, Ordering::Relaxed) as u64;
+        self.stats
+    }
+
+    async fn set_multicast(&mut self, addresses: &[MacAddress]) -> Result<(), ()> {
+        let mut table = [0u32; 128];
+        for a in addresses {
+            // The hash is bits 47:36 of the address, because the multicast offset in RCTL is 0
+            let o = a.octets();
+            let hash = ((o[4] as u16 >> 4) | ((o[5] as u16) << 4)) & 0xfff;
+            table[(hash >> 5) as usize] |= 1 << (hash & 0x1f);
+        }
+        let mut bar0 = self.internal.bar0.access().await;
+        for (i, v) in table.iter().enumerate() {
+            bar0.write_u32(IntelPro1000Registers::MTA_BASE as u16 + 4 * i as u16, *v);
+        }
+        Ok(())
+    }
 }
 
 impl IntelPro1000Device {
-    /// Check for received packets
-    async fn check_for_received_packets(&mut self) {
-        crate::VGA
-            .print_str_async(&format!("Checking for received packets\r\n"))
-            .await;
+    /// Process the rx descriptor ring, returning the next complete packet if there is one.
+    /// Each processed descriptor is handed back to the device by moving the tail to it.
+    async fn check_for_received_packets(&mut self) -> Option<Vec<u8>> {
+        let rxb = self.rxbufs.as_mut()?;
+        let mut index = self.rxbufindex? as usize;
+        let mut packet = None;
+        while packet.is_none() {
+            let descriptor = &mut rxb.bufs[index];
+            let status = RxBufferStatus(unsafe {
+                core::ptr::read_volatile(core::ptr::addr_of!(descriptor.status.0))
+            });
+            if !status.dd() {
+                break;
+            }
+            let errors = RxError(unsafe {
+                core::ptr::read_volatile(core::ptr::addr_of!(descriptor.errors.0))
+            });
+            let length = unsafe { core::ptr::read_volatile(core::ptr::addr_of!(descriptor.length)) }
+                as usize;
+            if errors.ce() || errors.se() || errors.seq() || errors.cxe() || errors.rxe() {
+                self.stats.rx_errors += 1;
+                if errors.ce() {
+                    self.stats.rx_crc_errors += 1;
+                }
+                self.rxpartial.clear();
+            } else {
+                let data = &rxb.dmas[index];
+                let length = length.min(data.len());
+                self.rxpartial.extend_from_slice(&data[..length]);
+                if status.eop() {
+                    self.stats.rx_packets += 1;
+                    self.stats.rx_bytes += self.rxpartial.len() as u64;
+                    packet = Some(core::mem::take(&mut self.rxpartial));
+                }
+            }
+            unsafe {
+                core::ptr::write_volatile(core::ptr::addr_of_mut!(descriptor.status.0), 0);
+                core::ptr::write_volatile(core::ptr::addr_of_mut!(descriptor.errors.0), 0);
+            }
+            self.internal
+                .bar0
+                .access()
+                .await
+                .write_u32(IntelPro1000Registers::RxDescTail as u16, index as u32);
+            index = (index + 1) % rxb.bufs.len();
+        }
+        self.rxbufindex = Some(index as u8);
+        packet
     }
 
     /// Detect the presence of an eeprom and store the result
     async fn detect_eeprom(&mut self) -> bool {
         if self.eeprom_present.is_none() {
             let mut bar0 = self.internal.bar0.access().await;
-            bar0.write(IntelPro1000Registers::Eeprom as u16, 1);
+            bar0.write_u32(IntelPro1000Registers::Eeprom as u16, 1);
             self.eeprom_present = Some(false);
             for _i in 0..10000 {
-                let val = bar0.read(IntelPro1000Registers::Eeprom as u16);
+                let val = bar0.read_u32(IntelPro1000Registers::Eeprom as u16);
                 let val2 = val & 0x10;
                 if (val2) != 0 {
                     self.eeprom_present = Some(true);
@@ -866,7 +929,7 @@ impl IntelPro1000Device {
                 .print_str_async(&format!(
                     "{:?} register is {:x}\r\n",
                     r,
-                    bar0.read(*r as u16)
+                    bar0.read_u32(*r as u16)
                 ))
                 .await;
         }
@@ -915,24 +978,24 @@ impl IntelPro1000Device {
     async fn set_receive_mac_address(&mut self, index: u8, ra: &ReceiveAddress) {
         let (low, high) = Self::receive_mac_address_registers(index);
         let mut bar0 = self.internal.bar0.access().await;
-        bar0.write(low as u16, ra.low());
-        bar0.write(high as u16, ra.high());
+        bar0.write_u32(low as u16, ra.low());
+        bar0.write_u32(high as u16, ra.high());
     }
 
     /// Clear the receive address at the specified index
     async fn clear_receive_mac_address(&mut self, index: u8) {
         let (low, high) = Self::receive_mac_address_registers(index);
         let mut bar0 = self.internal.bar0.access().await;
-        bar0.write(high as u16, 0u32);
-        bar0.write(low as u16, 0u32);
+        bar0.write_u32(high as u16, 0u32);
+        bar0.write_u32(low as u16, 0u32);
     }
 
     /// Retrieve the existing receive address at the specified index from the device
     async fn get_receive_mac_address(&mut self, index: u8) -> ReceiveAddress {
         let (low, high) = Self::receive_mac_address_registers(index);
         let bar0 = self.internal.bar0.access().await;
-        let ral = bar0.read(low as u16);
-        let rah = bar0.read(high as u16);
+        let ral = bar0.read_u32(low as u16);
+        let rah = bar0.read_u32(high as u16);
         let combined: u64 = ((rah as u64) << 32) | (ral as u64);
         ReceiveAddress(combined)
     }
@@ -943,26 +1006,26 @@ impl IntelPro1000Device {
         let end = base + 0x200;
         let mut bar0 = self.internal.bar0.access().await;
         for r in (base..end).step_by(4) {
-            bar0.write(r, 0u32);
+            bar0.write_u32(r, 0u32);
         }
     }
 
     /// Read a u16 from the specified phy
     async fn read_from_phy(&mut self, phy: u8, index: u8) -> Option<u16> {
         let mut bar0 = self.internal.bar0.access().await;
-        bar0.write(
+        bar0.write_u32(
             IntelPro1000Registers::MDIC as u16,
             MdicRegister::new(0, index, phy, 2, false).0,
         );
 
         loop {
-            let v = bar0.read(IntelPro1000Registers::MDIC as u16);
+            let v = bar0.read_u32(IntelPro1000Registers::MDIC as u16);
             let mdic = MdicRegister(v);
             if mdic.ready() {
                 break;
             }
         }
-        let v = bar0.read(IntelPro1000Registers::MDIC as u16);
+        let v = bar0.read_u32(IntelPro1000Registers::MDIC as u16);
         let mdic = MdicRegister(v);
         if mdic.error() {
             None
@@ -987,9 +1050,9 @@ impl IntelPro1000Device {
             todo!("Clear statistics counters");
         }
         let mut bar0 = self.internal.bar0.access().await;
-        let mut ctrl = bar0.read(IntelPro1000Registers::CTRL as u16);
+        let mut ctrl = bar0.read_u32(IntelPro1000Registers::CTRL as u16);
         ctrl = ctrl | 0x40;
-        bar0.write(IntelPro1000Registers::CTRL as u16, ctrl);
+        bar0.write_u32(IntelPro1000Registers::CTRL as u16, ctrl);
     }
 
     /// Initialize the rx buffers for the device
@@ -1006,25 +1069,25 @@ impl IntelPro1000Device {
             crate::VGA
                 .print_str_async(&format!("Writing RX stuff to network card\r\n"))
                 .await;
-            bar0.write(
+            bar0.write_u32(
                 IntelPro1000Registers::RxDescLow as u16,
                 (rxaddr & 0xFFFFFFFF) as u32,
             );
-            bar0.write(
+            bar0.write_u32(
                 IntelPro1000Registers::RxDescHigh as u16,
                 (rxaddr >> 32) as u32,
             );
-            bar0.write(
+            bar0.write_u32(
                 IntelPro1000Registers::RxDescLen as u16,
                 core::mem::size_of::<RxBuffer>() as u32 * rxbuf.bufs.len() as u32,
             );
-            bar0.write(IntelPro1000Registers::RxDescHead as u16, 0);
+            bar0.write_u32(IntelPro1000Registers::RxDescHead as u16, 0);
             // This might be off by 1, as the manual states tail should point to the element after the last valid descriptor
-            bar0.write(
+            bar0.write_u32(
                 IntelPro1000Registers::RxDescTail as u16,
                 rxbuf.bufs.len() as u32 - 1,
             );
-            bar0.write(
+            bar0.write_u32(
                 IntelPro1000Registers::Rctrl as u16,
                 (RctrlFlags::EN
                     | RctrlFlags::BAM
@@ -1047,20 +1110,20 @@ impl IntelPro1000Device {
                 .await;
             let txbuf = TxBuffers::new(8, 8192)?;
             let txaddr = txbuf.bufs.phys();
-            bar0.write(
+            bar0.write_u32(
                 IntelPro1000Registers::TxDescLow as u16,
                 (txaddr & 0xFFFFFFFF) as u32,
             );
-            bar0.write(
+            bar0.write_u32(
                 IntelPro1000Registers::TxDescHigh as u16,
                 (txaddr >> 32) as u32,
             );
             let desclen = core::mem::size_of::<TxBuffer>() as u32 * txbuf.bufs.len() as u32;
-            bar0.write(IntelPro1000Registers::TxDescLen as u16, desclen);
-            bar0.write(IntelPro1000Registers::TxDescHead as u16, 0);
-            bar0.write(IntelPro1000Registers::TxDescTail as u16, 0);
+            bar0.write_u32(IntelPro1000Registers::TxDescLen as u16, desclen);
+            bar0.write_u32(IntelPro1000Registers::TxDescHead as u16, 0);
+            bar0.write_u32(IntelPro1000Registers::TxDescTail as u16, 0);
 
-            bar0.write(
+            bar0.write_u32(
                 IntelPro1000Registers::Tctrl as u16,
                 (TctrlFlags::EN | TctrlFlags::PSP | TctrlFlags::RTLC).bits()
                     | (15 << TctrlFlags::CT_SHIFT.bits())
@@ -1079,11 +1142,17 @@ impl IntelPro1000Device {
         let reason = this
             .bar0
             .interrupt_access()
-            .read(IntelPro1000Registers::ICR as u16);
+            .read_u32(IntelPro1000Registers::ICR as u16);
         let reason = InterruptCauseRegister(reason);
         if reason.LSC() {
             this.update_link_status_interrupt();
         }
+        if reason.RXO() {
+            this.rx_overruns.fetch_add(1, Ordering::Relaxed);
+        }
+        if reason.RXTO() || reason.RXDMT0() || reason.RXO() {
+            this.rx_notify.notify();
+        }
     }
 
     /// Enable interrupts for the network card
@@ -1098,16 +1167,16 @@ impl IntelPro1000Device {
         let val = 0x1f6fc;
         // Marked as interrupt access becuase interrupts are not fully setup yet
         let mut bar0 = self.internal.bar0.interrupt_access();
-        while bar0.read(IntelPro1000Registers::IMS as u16) != val {
-            bar0.write(IntelPro1000Registers::IMS as u16, val);
-            bar0.read(IntelPro1000Registers::STATUS as u16);
+        while bar0.read_u32(IntelPro1000Registers::IMS as u16) != val {
+            bar0.write_u32(IntelPro1000Registers::IMS as u16, val);
+            bar0.read_u32(IntelPro1000Registers::STATUS as u16);
         }
         let val = 0xff & !4;
-        bar0.write(IntelPro1000Registers::IMS as u16, val);
-        bar0.read(IntelPro1000Registers::STATUS as u16);
+        bar0.write_u32(IntelPro1000Registers::IMS as u16, val);
+        bar0.read_u32(IntelPro1000Registers::STATUS as u16);
 
         // Read the interrupt register to clear it
-        let _ = bar0.read(IntelPro1000Registers::ICR as u16);
+        let _ = bar0.read_u32(IntelPro1000Registers::ICR as u16);
         let c = self.internal.clone();
         sys.register_irq_handler(irqnum, move || IntelPro1000Device::handle_interrupt(&c));
         drop(bar0);
@@ -1118,24 +1187,24 @@ impl IntelPro1000Device {
     async fn read_from_eeprom(&mut self, addr: u8) -> u16 {
         if self.detect_eeprom().await {
             let mut bar0 = self.internal.bar0.access().await;
-            bar0.write(
+            bar0.write_u32(
                 IntelPro1000Registers::Eeprom as u16,
                 1 | ((addr as u32) << 8),
             );
             loop {
-                let a = bar0.read(IntelPro1000Registers::Eeprom as u16);
+                let a = bar0.read_u32(IntelPro1000Registers::Eeprom as u16);
                 if (a & (0x10)) != 0 {
                     return (a >> 16) as u16;
                 }
             }
         } else {
             let mut bar0 = self.internal.bar0.access().await;
-            bar0.write(
+            bar0.write_u32(
                 IntelPro1000Registers::Eeprom as u16,
                 1 | ((addr as u32) << 2),
             );
             loop {
-                let a = bar0.read(IntelPro1000Registers::Eeprom as u16);
+                let a = bar0.read_u32(IntelPro1000Registers::Eeprom as u16);
                 if (a & (0x2)) != 0 {
                     return (a >> 16) as u16;
                 }
@@ -1152,21 +1221,49 @@ impl IntelPro1000 {
 }
 
 impl PciFunctionDriverTrait for IntelPro1000 {
-    async fn register(&self, m: &mut BTreeMap<u32, PciFunctionDriver>) {
+    async fn register(&self, m: &mut Vec<PciFunctionDriver>) {
         crate::VGA
             .print_str_async("Register intel pro/1000 pci driver\r\n")
             .await;
-        for dev in [
-            0x100e, 0x100f, 0x1011, 0x1015, 0x1019, 0x101a, 0x1010, 0x1012, 0x1013, 0x1016, 0x1017,
-            0x1018, 0x101d, 0x1026, 0x1027, 0x1028, 0x1076, 0x1077, 0x1078, 0x1079, 0x107a, 0x107b,
-            0x1107, 0x1112,
-        ] {
-            let dev = dev as u16;
-            let vendor_combo = ((dev as u32) << 16) | 0x8086;
-            m.entry(vendor_combo).or_insert_with(|| self.clone().into());
+        if !m
+            .iter()
+            .any(|d| matches!(d, PciFunctionDriver::IntelPro1000(_)))
+        {
+            m.push(self.clone().into());
         }
     }
 
+    fn match_table(&self) -> &'static [PciMatch] {
+        /// The intel vendor id
+        const INTEL: u16 = 0x8086;
+        &[
+            PciMatch::device(INTEL, 0x100e),
+            PciMatch::device(INTEL, 0x100f),
+            PciMatch::device(INTEL, 0x1011),
+            PciMatch::device(INTEL, 0x1015),
+            PciMatch::device(INTEL, 0x1019),
+            PciMatch::device(INTEL, 0x101a),
+            PciMatch::device(INTEL, 0x1010),
+            PciMatch::device(INTEL, 0x1012),
+            PciMatch::device(INTEL, 0x1013),
+            PciMatch::device(INTEL, 0x1016),
+            PciMatch::device(INTEL, 0x1017),
+            PciMatch::device(INTEL, 0x1018),
+            PciMatch::device(INTEL, 0x101d),
+            PciMatch::device(INTEL, 0x1026),
+            PciMatch::device(INTEL, 0x1027),
+            PciMatch::device(INTEL, 0x1028),
+            PciMatch::device(INTEL, 0x1076),
+            PciMatch::device(INTEL, 0x1077),
+            PciMatch::device(INTEL, 0x1078),
+            PciMatch::device(INTEL, 0x1079),
+            PciMatch::device(INTEL, 0x107a),
+            PciMatch::device(INTEL, 0x107b),
+            PciMatch::device(INTEL, 0x1107),
+            PciMatch::device(INTEL, 0x1112),
+        ]
+    }
+
     async fn parse_bars(
         &mut self,
         cs: &mut PciConfigurationSpace,
@@ -1175,7 +1272,7 @@ impl PciFunctionDriverTrait for IntelPro1000 {
         f: &PciFunction,
         config: &ConfigurationSpaceEnum,
         mut bars: [Option<BarSpace>; 6],
-    ) {
+    ) -> PciProbeResult {
         let bar0 = {
             if let Some(bar) = &mut bars[0] {
                 if bar.is_size_valid() {
@@ -1216,7 +1313,7 @@ impl PciFunctionDriverTrait for IntelPro1000 {
                 let model = Model::try_from(configspace.get_device_id()).unwrap();
                 let irqnum = match config {
                     ConfigurationSpaceEnum::Standard(configuration_space_standard) => {
-                        configuration_space_standard.get_interrupt_line()
+                        f.get_irq(bus, dev, configuration_space_standard)
                     }
                     ConfigurationSpaceEnum::Bridge(_configuration_space_bridge) => {
                         doors_macros::todo!()
@@ -1225,6 +1322,9 @@ impl PciFunctionDriverTrait for IntelPro1000 {
                         doors_macros::todo!()
                     }
                 };
+                let Some(irqnum) = irqnum else {
+                    return PciProbeResult::Declined;
+                };
                 let com = IrqGuardedInner::new(irqnum, false, |_| {}, |_| {});
                 let m = IrqGuarded::new(m, &com);
                 let up = AtomicBool::new(false);
@@ -1235,6 +1335,8 @@ impl PciFunctionDriverTrait for IntelPro1000 {
                     eeprom_present: None,
                     rxbufs: None,
                     rxbufindex: None,
+                    rxpartial: Vec::new(),
+                    stats: NetworkStatistics::default(),
                     txbufs: None,
                     txbufindex: None,
                     model,
@@ -1281,10 +1383,17 @@ impl PciFunctionDriverTrait for IntelPro1000 {
                             .await;
                     }
                 }
-                super::super::register_network_adapter(d.into()).await;
+                let location = super::super::NetworkAdapterLocation::Pci {
+                    segment: cs.segment(),
+                    location: f.location(bus, dev),
+                };
+                super::super::register_network_adapter(d.into(), Some(location)).await;
+                let configspace = f.get_all_configuration(cs, bus, dev);
+                configspace.dump("\t*").await;
+                return PciProbeResult::Bound;
             }
         }
-        let configspace = f.get_all_configuration(cs, bus, dev);
-        configspace.dump("\t*").await;
+        f.set_power_state(cs, bus, dev, PowerState::D3Hot).await;
+        PciProbeResult::Declined
     }
 }
@@ -0,0 +1,223 @@
+//! The network stack state of each network adapter, and the task that passes received packets to the protocols
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use futures::{Stream, StreamExt};
+
+use super::arp::{self, ArpCache};
+use super::ethernet::{EtherType, EthernetFrame};
+use super::ipv4::{self, Ipv4Config, Route};
+use super::{Ipv4Address, MacAddress, NetworkAdapter, NetworkAdapterTrait, NetworkEvent};
+use crate::{Arc, AsyncLockedArc, Locked};
+
+/// The network stack state of a network adapter
+pub struct Interface {
+    /// The sequential name of the adapter
+    name: String,
+    /// The adapter
+    adapter: AsyncLockedArc<NetworkAdapter>,
+    /// The mac address of the adapter
+    mac: MacAddress,
+    /// The multicast groups that frames are accepted for
+    multicast: Locked<Vec<MacAddress>>,
+    /// The ipv4 address configuration of the interface
+    ipv4: Locked<Option<Ipv4Config>>,
+    /// The dns servers reached through the interface
+    dns_servers: Locked<Vec<Ipv4Address>>,
+    /// The arp cache
+    arp: ArpCache,
+    /// The adapter has been unregistered
+    removed: AtomicBool,
+}
+
+impl Interface {
+    /// Construct the state for a newly registered adapter
+    pub fn new(name: String, adapter: AsyncLockedArc<NetworkAdapter>, mac: MacAddress) -> Self {
+        Self {
+            name,
+            adapter,
+            mac,
+            multicast: Locked::new(Vec::new()),
+            ipv4: Locked::new(None),
+            dns_servers: Locked::new(Vec::new()),
+            arp: ArpCache::new(),
+            removed: AtomicBool::new(false),
+        }
+    }
+
+    /// The sequential name of the adapter
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The mac address of the adapter
+    pub fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    /// The adapter for the interface
+    pub fn adapter(&self) -> AsyncLockedArc<NetworkAdapter> {
+        self.adapter.clone()
+    }
+
+    /// The arp cache of the interface
+    pub fn arp(&self) -> &ArpCache {
+        &self.arp
+    }
+
+    /// The ipv4 address of the interface, if it has one
+    pub fn ipv4_address(&self) -> Option<Ipv4Address> {
+        self.ipv4_config().map(|c| c.address)
+    }
+
+    /// The ipv4 address configuration of the interface, if it has one
+    pub fn ipv4_config(&self) -> Option<Ipv4Config> {
+        *self.ipv4.sync_lock()
+    }
+
+    /// Set or clear the ipv4 address of the interface. The route to the directly connected network is replaced, and a new address is announced to the network.
+    pub async fn set_ipv4_config(&self, c: Option<Ipv4Config>) {
+        *self.ipv4.sync_lock() = c;
+        ipv4::remove_routes(|r| r.interface == self.name && r.gateway.is_none());
+        if let Some(c) = c {
+            ipv4::add_route(Route {
+                destination: c.network(),
+                prefix_length: c.prefix_length,
+                gateway: None,
+                interface: self.name.clone(),
+            });
+            let _ = arp::announce(self).await;
+        }
+    }
+
+    /// The dns servers reached through the interface
+    pub fn dns_servers(&self) -> Vec<Ipv4Address> {
+        self.dns_servers.sync_lock().clone()
+    }
+
+    /// Set the dns servers reached through the interface
+    pub fn set_dns_servers(&self, servers: Vec<Ipv4Address>) {
+        *self.dns_servers.sync_lock() = servers;
+    }
+
+    /// Mark the interface as no longer having an adapter
+    pub(super) fn remove(&self) {
+        self.removed.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true when the adapter has been unregistered
+    pub fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Relaxed)
+    }
+
+    /// Accept frames sent to a multicast group
+    pub async fn join_multicast(&self, group: MacAddress) -> Result<(), ()> {
+        let groups = {
+            let mut m = self.multicast.sync_lock();
+            if m.contains(&group) {
+                return Ok(());
+            }
+            m.push(group);
+            m.clone()
+        };
+        self.adapter.lock().await.set_multicast(&groups).await
+    }
+
+    /// Stop accepting frames sent to a multicast group
+    pub async fn leave_multicast(&self, group: MacAddress) -> Result<(), ()> {
+        let groups = {
+            let mut m = self.multicast.sync_lock();
+            m.retain(|g| *g != group);
+            m.clone()
+        };
+        self.adapter.lock().await.set_multicast(&groups).await
+    }
+
+    /// Returns true if frames sent to the address are for this interface
+    fn accepts(&self, destination: MacAddress) -> bool {
+        destination == self.mac
+            || destination.is_broadcast()
+            || (destination.is_multicast() && self.multicast.sync_lock().contains(&destination))
+    }
+
+    /// Send a frame from the interface
+    pub async fn send(
+        &self,
+        destination: MacAddress,
+        ethertype: EtherType,
+        payload: &[u8],
+    ) -> Result<(), ()> {
+        let f = EthernetFrame::build(destination, self.mac, ethertype, payload);
+        self.adapter.lock().await.send_packet(&f).await
+    }
+
+    /// Pass a received frame to the protocol it carries
+    async fn handle_frame(&self, packet: &[u8]) {
+        let Some(f) = EthernetFrame::parse(packet) else {
+            return;
+        };
+        if !self.accepts(f.destination) {
+            return;
+        }
+        match f.ethertype {
+            EtherType::Arp => arp::handle(self, f.payload).await,
+            EtherType::Ipv4 => ipv4::handle(self, f.payload).await,
+            _ => {}
+        }
+    }
+}
+
+/// The packets received from all adapters, with the interface each came from
+type PacketStreams =
+    futures::stream::SelectAll<Pin<Box<dyn Stream<Item = (Arc<Interface>, Vec<u8>)>>>>;
+
+/// What the stack task has to do next
+enum Work {
+    /// Something happened to the set of adapters
+    Event(Option<NetworkEvent>),
+    /// A packet was received
+    Packet(Option<(Arc<Interface>, Vec<u8>)>),
+}
+
+/// The task that receives packets from every network adapter and passes them to the protocols.
+/// Adapters are picked up as they are registered, and dropped as soon as they are unregistered.
+pub async fn stack_task() {
+    let mut events = super::subscribe().await;
+    let mut packets: PacketStreams = futures::stream::SelectAll::new();
+    // Stops the packet stream of each adapter, so that it releases the adapter when it is unregistered
+    let mut streams: Vec<(String, futures::stream::AbortHandle)> = Vec::new();
+    loop {
+        let w = if packets.is_empty() {
+            Work::Event(events.next().await)
+        } else {
+            match futures::future::select(events.next(), packets.next()).await {
+                futures::future::Either::Left((e, _)) => Work::Event(e),
+                futures::future::Either::Right((p, _)) => Work::Packet(p),
+            }
+        };
+        match w {
+            Work::Event(Some(NetworkEvent::Added(name))) => {
+                if let Some(iface) = super::get_interface(&name).await {
+                    let s = super::packet_stream(iface.adapter()).map(move |p| (iface.clone(), p));
+                    let (s, handle) = futures::stream::abortable(s);
+                    streams.push((name, handle));
+                    packets.push(Box::pin(s));
+                }
+            }
+            Work::Event(Some(NetworkEvent::Removed(name))) => {
+                // The aborted stream is dropped the next time the packet streams are polled
+                streams.retain(|(n, handle)| {
+                    if *n == name {
+                        handle.abort();
+                    }
+                    *n != name
+                });
+            }
+            Work::Event(None) => break,
+            Work::Packet(Some((iface, p))) => iface.handle_frame(&p).await,
+            Work::Packet(None) => {}
+        }
+    }
+}
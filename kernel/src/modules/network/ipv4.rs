@@ -0,0 +1,512 @@
+//! Internet protocol version 4, with the routing table and the reassembly of fragmented packets
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::arp;
+use super::ethernet::{EtherType, MTU};
+use super::icmp;
+use super::interface::Interface;
+use super::tcp;
+use super::udp;
+use super::Ipv4Address;
+use crate::modules::time::{Duration, Instant};
+use crate::{Arc, Locked};
+
+/// The length of a header without options
+const HEADER_LENGTH: usize = 20;
+/// The time to live of the packets that are sent
+const DEFAULT_TTL: u8 = 64;
+/// How long to wait for the rest of a fragmented packet
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// The number of fragmented packets that can be reassembled at the same time
+const MAX_REASSEMBLIES: usize = 16;
+/// The number of fragments a single packet can be made from
+const MAX_FRAGMENTS: usize = 64;
+/// The largest packet that can be reassembled
+const MAX_PACKET_LENGTH: usize = 65535;
+
+/// The routing table
+static ROUTES: Locked<Vec<Route>> = Locked::new(Vec::new());
+
+/// The packets being reassembled, by source, destination, identification, and protocol
+static REASSEMBLY: Locked<BTreeMap<(Ipv4Address, Ipv4Address, u16, u8), Reassembly>> =
+    Locked::new(BTreeMap::new());
+
+/// The identification of the next packet sent
+static IDENTIFICATION: AtomicU16 = AtomicU16::new(1);
+
+/// Add data to a running ones complement sum, as used by the internet checksum
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for c in &mut chunks {
+        sum += u16::from_be_bytes([c[0], c[1]]) as u32;
+    }
+    if let [b] = chunks.remainder() {
+        sum += (*b as u32) << 8;
+    }
+    sum
+}
+
+/// Fold a running sum into the final internet checksum
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Calculate the internet checksum of some data. Data that includes a correct checksum sums to 0.
+pub fn checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+/// The running sum of the pseudo header that the udp and tcp checksums cover
+pub fn pseudo_header_sum(
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    protocol: IpProtocol,
+    length: usize,
+) -> u32 {
+    let sum = checksum_add(0, &source.octets());
+    let sum = checksum_add(sum, &destination.octets());
+    sum + u8::from(protocol) as u32 + length as u32
+}
+
+/// Test the internet checksum against the example header from rfc 1071
+#[doors_macros::doors_test]
+fn ipv4_checksum_test() -> Result<(), ()> {
+    let mut header = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8, 0x00,
+        0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+    let c = checksum(&header);
+    assert_eq!(c, 0xb861);
+    header[10..12].copy_from_slice(&c.to_be_bytes());
+    assert_eq!(checksum(&header), 0);
+    Ok(())
+}
+
+/// The protocol carried by an ipv4 packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpProtocol {
+    /// Internet control message protocol
+    Icmp,
+    /// Transmission control protocol
+    Tcp,
+    /// User datagram protocol
+    Udp,
+    /// Any other protocol
+    Other(u8),
+}
+
+impl From<u8> for IpProtocol {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Icmp,
+            6 => Self::Tcp,
+            17 => Self::Udp,
+            o => Self::Other(o),
+        }
+    }
+}
+
+impl From<IpProtocol> for u8 {
+    fn from(value: IpProtocol) -> u8 {
+        match value {
+            IpProtocol::Icmp => 1,
+            IpProtocol::Tcp => 6,
+            IpProtocol::Udp => 17,
+            IpProtocol::Other(o) => o,
+        }
+    }
+}
+
+/// The address configuration of an interface
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv4Config {
+    /// The address of the interface
+    pub address: Ipv4Address,
+    /// The number of bits in the network part of the address
+    pub prefix_length: u8,
+}
+
+/// The mask for the network part of an address with the given prefix length
+fn prefix_mask(prefix_length: u8) -> u32 {
+    if prefix_length == 0 {
+        0
+    } else {
+        u32::MAX << (32 - prefix_length.min(32) as u32)
+    }
+}
+
+impl Ipv4Config {
+    /// The netmask of the network
+    pub fn netmask(&self) -> Ipv4Address {
+        prefix_mask(self.prefix_length).into()
+    }
+
+    /// The address of the network
+    pub fn network(&self) -> Ipv4Address {
+        (u32::from(self.address) & prefix_mask(self.prefix_length)).into()
+    }
+
+    /// The broadcast address of the network
+    pub fn broadcast(&self) -> Ipv4Address {
+        (u32::from(self.address) | !prefix_mask(self.prefix_length)).into()
+    }
+
+    /// Returns true if the address is on the network
+    pub fn contains(&self, a: Ipv4Address) -> bool {
+        let m = prefix_mask(self.prefix_length);
+        (u32::from(a) & m) == (u32::from(self.address) & m)
+    }
+}
+
+/// An entry in the routing table
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    /// The network the route is for
+    pub destination: Ipv4Address,
+    /// The number of bits in the network part of the destination, 0 for the default route
+    pub prefix_length: u8,
+    /// The router that packets are sent to, or None when the network is directly connected
+    pub gateway: Option<Ipv4Address>,
+    /// The sequential name of the interface packets are sent on
+    pub interface: String,
+}
+
+impl Route {
+    /// Returns true if the route can be used for the address
+    fn matches(&self, a: Ipv4Address) -> bool {
+        let m = prefix_mask(self.prefix_length);
+        (u32::from(a) & m) == (u32::from(self.destination) & m)
+    }
+}
+
+/// Add a route, replacing a route for the same network on the same interface
+pub fn add_route(r: Route) {
+    let mut routes = ROUTES.sync_lock();
+    routes.retain(|o| {
+        !(o.destination == r.destination
+            && o.prefix_length == r.prefix_length
+            && o.interface == r.interface)
+    });
+    routes.push(r);
+}
+
+/// Remove the routes that match a condition
+pub fn remove_routes(f: impl Fn(&Route) -> bool) {
+    ROUTES.sync_lock().retain(|r| !f(r));
+}
+
+/// Set or clear the default gateway reached through an interface
+pub fn set_default_gateway(interface: &str, gateway: Option<Ipv4Address>) {
+    remove_routes(|r| r.interface == interface && r.prefix_length == 0);
+    if let Some(gateway) = gateway {
+        add_route(Route {
+            destination: Ipv4Address::UNSPECIFIED,
+            prefix_length: 0,
+            gateway: Some(gateway),
+            interface: String::from(interface),
+        });
+    }
+}
+
+/// A copy of the routing table
+pub fn routes() -> Vec<Route> {
+    ROUTES.sync_lock().clone()
+}
+
+/// The result of looking up a destination in the routing table
+pub struct RouteResult {
+    /// The interface to send on
+    pub interface: Arc<Interface>,
+    /// The source address to use
+    pub source: Ipv4Address,
+    /// The host on the local network to send the packet to
+    pub next_hop: Ipv4Address,
+}
+
+/// Find the route for a destination, preferring the most specific route
+pub async fn lookup_route(destination: Ipv4Address) -> Option<RouteResult> {
+    let r = ROUTES
+        .sync_lock()
+        .iter()
+        .filter(|r| r.matches(destination))
+        .max_by_key(|r| r.prefix_length)
+        .cloned()?;
+    let interface = super::get_interface(&r.interface).await?;
+    let source = interface.ipv4_address()?;
+    Some(RouteResult {
+        interface,
+        source,
+        // Broadcast and multicast packets are never sent through a gateway
+        next_hop: if destination.is_broadcast() || destination.is_multicast() {
+            destination
+        } else {
+            r.gateway.unwrap_or(destination)
+        },
+    })
+}
+
+/// Find the host on the local network of an interface to send a packet for a destination to, using only the routes through that interface
+pub fn next_hop_on(interface: &str, destination: Ipv4Address) -> Option<Ipv4Address> {
+    let r = ROUTES
+        .sync_lock()
+        .iter()
+        .filter(|r| r.interface == interface && r.matches(destination))
+        .max_by_key(|r| r.prefix_length)
+        .cloned()?;
+    Some(r.gateway.unwrap_or(destination))
+}
+
+/// The header of an ipv4 packet
+#[derive(Clone, Debug)]
+pub struct Ipv4Header {
+    /// The type of service field
+    pub tos: u8,
+    /// Identifies the fragments of a single packet
+    pub identification: u16,
+    /// The packet must not be fragmented
+    pub dont_fragment: bool,
+    /// More fragments follow this one
+    pub more_fragments: bool,
+    /// The offset of this fragment in bytes
+    pub fragment_offset: usize,
+    /// The time to live
+    pub ttl: u8,
+    /// The protocol of the payload
+    pub protocol: IpProtocol,
+    /// The source address
+    pub source: Ipv4Address,
+    /// The destination address
+    pub destination: Ipv4Address,
+}
+
+impl Ipv4Header {
+    /// Parse a packet, returning the header and the payload. The checksum and lengths are validated.
+    pub fn parse(p: &[u8]) -> Option<(Self, &[u8])> {
+        if p.len() < HEADER_LENGTH || (p[0] >> 4) != 4 {
+            return None;
+        }
+        let header_length = (p[0] & 0xf) as usize * 4;
+        let total_length = u16::from_be_bytes([p[2], p[3]]) as usize;
+        if header_length < HEADER_LENGTH
+            || total_length < header_length
+            || total_length > p.len()
+            || checksum(&p[..header_length]) != 0
+        {
+            return None;
+        }
+        let flags = u16::from_be_bytes([p[6], p[7]]);
+        Some((
+            Self {
+                tos: p[1],
+                identification: u16::from_be_bytes([p[4], p[5]]),
+                dont_fragment: (flags & 0x4000) != 0,
+                more_fragments: (flags & 0x2000) != 0,
+                fragment_offset: (flags & 0x1fff) as usize * 8,
+                ttl: p[8],
+                protocol: p[9].into(),
+                source: Ipv4Address::new(p[12], p[13], p[14], p[15]),
+                destination: Ipv4Address::new(p[16], p[17], p[18], p[19]),
+            },
+            &p[header_length..total_length],
+        ))
+    }
+
+    /// Build the bytes of a packet with this header and payload
+    fn build(&self, payload: &[u8]) -> Vec<u8> {
+        let mut p = Vec::with_capacity(HEADER_LENGTH + payload.len());
+        p.push(0x45);
+        p.push(self.tos);
+        p.extend_from_slice(&((HEADER_LENGTH + payload.len()) as u16).to_be_bytes());
+        p.extend_from_slice(&self.identification.to_be_bytes());
+        let mut flags = (self.fragment_offset / 8) as u16;
+        if self.dont_fragment {
+            flags |= 0x4000;
+        }
+        if self.more_fragments {
+            flags |= 0x2000;
+        }
+        p.extend_from_slice(&flags.to_be_bytes());
+        p.push(self.ttl);
+        p.push(self.protocol.into());
+        p.extend_from_slice(&[0, 0]);
+        p.extend_from_slice(&self.source.octets());
+        p.extend_from_slice(&self.destination.octets());
+        let c = checksum(&p);
+        p[10..12].copy_from_slice(&c.to_be_bytes());
+        p.extend_from_slice(payload);
+        p
+    }
+}
+
+/// A packet that is being reassembled from its fragments
+struct Reassembly {
+    /// The header of the first fragment, once it has arrived
+    header: Option<Ipv4Header>,
+    /// The fragments received, with their offsets
+    fragments: Vec<(usize, Vec<u8>)>,
+    /// The length of the packet, known once the last fragment has arrived
+    length: Option<usize>,
+    /// When to give up on the packet
+    expires: Instant,
+}
+
+impl Reassembly {
+    /// Returns the complete packet if every part of it has arrived
+    fn complete(&mut self) -> Option<(Ipv4Header, Vec<u8>)> {
+        let length = self.length?;
+        self.fragments.sort_by_key(|(o, _)| *o);
+        let mut end = 0;
+        for (o, d) in &self.fragments {
+            if *o > end {
+                return None;
+            }
+            end = end.max(o + d.len());
+        }
+        if end < length {
+            return None;
+        }
+        let mut header = self.header.clone()?;
+        let mut data = alloc::vec![0; length];
+        for (o, d) in &self.fragments {
+            let l = d.len().min(length.saturating_sub(*o));
+            data[*o..*o + l].copy_from_slice(&d[..l]);
+        }
+        header.more_fragments = false;
+        header.fragment_offset = 0;
+        Some((header, data))
+    }
+}
+
+/// Add a packet to the reassembly buffers, returning the whole packet once all of its fragments have arrived
+fn reassemble(h: Ipv4Header, payload: &[u8]) -> Option<(Ipv4Header, Vec<u8>)> {
+    if !h.more_fragments && h.fragment_offset == 0 {
+        return Some((h, payload.to_vec()));
+    }
+    let end = h.fragment_offset + payload.len();
+    if end > MAX_PACKET_LENGTH - HEADER_LENGTH || (h.more_fragments && payload.len() % 8 != 0) {
+        return None;
+    }
+    let now = Instant::now();
+    let mut r = REASSEMBLY.sync_lock();
+    r.retain(|_, p| now < p.expires);
+    let key = (
+        h.source,
+        h.destination,
+        h.identification,
+        u8::from(h.protocol),
+    );
+    if !r.contains_key(&key) && r.len() >= MAX_REASSEMBLIES {
+        return None;
+    }
+    let p = r.entry(key).or_insert_with(|| Reassembly {
+        header: None,
+        fragments: Vec::new(),
+        length: None,
+        expires: now + REASSEMBLY_TIMEOUT,
+    });
+    if p.fragments.len() >= MAX_FRAGMENTS {
+        r.remove(&key);
+        return None;
+    }
+    if !h.more_fragments {
+        p.length = Some(end);
+    }
+    let offset = h.fragment_offset;
+    if offset == 0 {
+        p.header = Some(h);
+    }
+    p.fragments.push((offset, payload.to_vec()));
+    let done = p.complete();
+    if done.is_some() {
+        r.remove(&key);
+    }
+    done
+}
+
+/// Process a received ipv4 packet
+pub async fn handle(iface: &Interface, packet: &[u8]) {
+    let Some((h, payload)) = Ipv4Header::parse(packet) else {
+        return;
+    };
+    let for_us = match iface.ipv4_config() {
+        Some(c) => {
+            h.destination == c.address
+                || h.destination == c.broadcast()
+                || h.destination.is_broadcast()
+                || h.destination.is_multicast()
+        }
+        // Without an address, everything that made it through the ethernet filter is accepted, so the address can be configured
+        None => true,
+    };
+    if !for_us {
+        return;
+    }
+    let Some((h, payload)) = reassemble(h, payload) else {
+        return;
+    };
+    match h.protocol {
+        IpProtocol::Icmp => icmp::handle(iface, &h, &payload).await,
+        IpProtocol::Tcp => tcp::handle(&h, &payload).await,
+        IpProtocol::Udp => udp::handle(&h, &payload),
+        _ => {}
+    }
+}
+
+/// Send a packet on an interface to a host on the local network, fragmenting it as required.
+/// The packet is queued if the mac address of the next hop is not known yet.
+pub async fn send_packet(
+    iface: &Interface,
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    next_hop: Ipv4Address,
+    protocol: IpProtocol,
+    payload: &[u8],
+) -> Result<(), ()> {
+    let mut h = Ipv4Header {
+        tos: 0,
+        identification: IDENTIFICATION.fetch_add(1, Ordering::Relaxed),
+        dont_fragment: false,
+        more_fragments: false,
+        fragment_offset: 0,
+        ttl: DEFAULT_TTL,
+        protocol,
+        source,
+        destination,
+    };
+    if payload.len() > MAX_PACKET_LENGTH - HEADER_LENGTH {
+        return Err(());
+    }
+    let max = (MTU - HEADER_LENGTH) & !7;
+    if payload.len() <= MTU - HEADER_LENGTH {
+        return arp::send_or_queue(iface, next_hop, EtherType::Ipv4, h.build(payload)).await;
+    }
+    for (i, chunk) in payload.chunks(max).enumerate() {
+        h.fragment_offset = i * max;
+        h.more_fragments = h.fragment_offset + chunk.len() < payload.len();
+        arp::send_or_queue(iface, next_hop, EtherType::Ipv4, h.build(chunk)).await?;
+    }
+    Ok(())
+}
+
+/// Send a packet to a destination, using the routing table to pick the interface and next hop
+pub async fn send(
+    destination: Ipv4Address,
+    protocol: IpProtocol,
+    payload: &[u8],
+) -> Result<(), ()> {
+    let r = lookup_route(destination).await.ok_or(())?;
+    send_packet(
+        &r.interface,
+        r.source,
+        destination,
+        r.next_hop,
+        protocol,
+        payload,
+    )
+    .await
+}
@@ -1,53 +1,317 @@
 //! Networking code for the kernel
 
-use alloc::{borrow::ToOwned, collections::btree_map::BTreeMap, string::String};
+use alloc::{
+    borrow::ToOwned,
+    collections::{btree_map::BTreeMap, VecDeque},
+    format,
+    string::String,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Poll, Waker};
 
-use crate::{AsyncLocked, AsyncLockedArc, LockedArc};
+use crate::kernel::SystemTrait;
+use crate::modules::pci::capability::PciLocation;
+use crate::{Arc, AsyncLocked, AsyncLockedArc, IoReadWrite, Locked, LockedArc};
 
 doors_macros::declare_enum!(NetworkAdapter);
 
+pub mod arp;
+pub mod dhcp;
+pub mod dns;
+pub mod ethernet;
+pub mod icmp;
 pub mod intel;
+pub mod interface;
+pub mod ipv4;
+pub mod realtek;
+pub mod tcp;
+pub mod udp;
+pub mod virtio;
 
-doors_macros2::enum_reexport!(intel);
+doors_macros2::enum_reexport!(intel, realtek, virtio);
 
 lazy_static::lazy_static! {
-    /// Represents all network adapters for the kernel
-    static ref NETWORK_ADAPTERS: AsyncLocked<BTreeMap<String, AsyncLockedArc<NetworkAdapter>>> =
+    /// Represents all network adapters for the kernel, by their sequential name
+    static ref NETWORK_ADAPTERS: AsyncLocked<BTreeMap<String, NetworkInterface>> =
         AsyncLocked::new(BTreeMap::new());
 }
 
-/// Register a network adapter
-pub async fn register_network_adapter(na: NetworkAdapter) {
+/// The queues of the tasks subscribed to network adapter events
+static SUBSCRIBERS: Locked<Vec<alloc::sync::Weak<NetworkEventQueue>>> = Locked::new(Vec::new());
+
+/// Where a network adapter is attached, used to give it a name that does not depend on the order adapters are found in
+#[derive(Clone, Copy, Debug)]
+pub enum NetworkAdapterLocation {
+    /// A pci function
+    Pci {
+        /// The pci segment
+        segment: u16,
+        /// The bus, device, and function
+        location: PciLocation,
+    },
+}
+
+impl NetworkAdapterLocation {
+    /// The name for an adapter at this location, such as enp0s3f0
+    fn name(&self) -> String {
+        match self {
+            Self::Pci { segment, location } => {
+                let domain = if *segment != 0 {
+                    format!("P{}", segment)
+                } else {
+                    String::new()
+                };
+                format!(
+                    "en{}p{}s{}f{}",
+                    domain, location.bus, location.device, location.function
+                )
+            }
+        }
+    }
+}
+
+/// A network adapter registered with the kernel
+struct NetworkInterface {
+    /// The name derived from the location of the adapter
+    path_name: Option<String>,
+    /// The name derived from the mac address of the adapter
+    mac_name: Option<String>,
+    /// The mac address of the adapter
+    mac: MacAddress,
+    /// The adapter
+    adapter: AsyncLockedArc<NetworkAdapter>,
+    /// The network stack state of the adapter
+    interface: Arc<interface::Interface>,
+}
+
+impl NetworkInterface {
+    /// Iterate over the alternate names of the adapter
+    fn aliases(&self) -> impl Iterator<Item = &String> {
+        self.path_name.iter().chain(self.mac_name.iter())
+    }
+}
+
+/// A description of a registered network adapter
+#[derive(Clone, Debug)]
+pub struct NetworkInterfaceInfo {
+    /// The sequential name of the adapter, netN
+    pub name: String,
+    /// The other names the adapter can be found with
+    pub aliases: Vec<String>,
+    /// The mac address of the adapter
+    pub mac: MacAddress,
+}
+
+/// Something that happened to the set of network adapters
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NetworkEvent {
+    /// An adapter with the given name was registered
+    Added(String),
+    /// The adapter with the given name was unregistered
+    Removed(String),
+}
+
+/// The events waiting to be delivered to a single subscriber
+struct NetworkEventQueue {
+    /// The events not yet delivered
+    events: Locked<VecDeque<NetworkEvent>>,
+    /// The wakers for the subscriber
+    wakers: Locked<Vec<Waker>>,
+}
+
+/// A stream of [NetworkEvent] for a subscriber, see [subscribe]
+pub struct NetworkEvents {
+    /// The queue shared with the registration functions
+    queue: alloc::sync::Arc<NetworkEventQueue>,
+}
+
+impl futures::Stream for NetworkEvents {
+    type Item = NetworkEvent;
+    fn poll_next(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        if let Some(e) = self.queue.events.sync_lock().pop_front() {
+            return Poll::Ready(Some(e));
+        }
+        crate::add_waker(&mut self.queue.wakers.sync_lock(), cx.waker());
+        match self.queue.events.sync_lock().pop_front() {
+            Some(e) => Poll::Ready(Some(e)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Deliver an event to all subscribers, forgetting the subscribers that no longer exist
+fn publish(e: NetworkEvent) {
+    SUBSCRIBERS.sync_lock().retain(|s| {
+        if let Some(s) = s.upgrade() {
+            s.events.sync_lock().push_back(e.clone());
+            let wakers = core::mem::take(&mut *s.wakers.sync_lock());
+            for w in wakers {
+                w.wake();
+            }
+            true
+        } else {
+            false
+        }
+    });
+}
+
+/// Subscribe to network adapter events. An [NetworkEvent::Added] is delivered first for every adapter that already exists.
+pub async fn subscribe() -> NetworkEvents {
+    let nal = NETWORK_ADAPTERS.lock().await;
+    let queue = alloc::sync::Arc::new(NetworkEventQueue {
+        events: Locked::new(nal.keys().cloned().map(NetworkEvent::Added).collect()),
+        wakers: Locked::new(Vec::new()),
+    });
+    SUBSCRIBERS
+        .sync_lock()
+        .push(alloc::sync::Arc::downgrade(&queue));
+    NetworkEvents { queue }
+}
+
+/// Register a network adapter, returning the sequential name given to it.
+/// The adapter can also be found by a name derived from its location, and one derived from its mac address.
+pub async fn register_network_adapter(
+    mut na: NetworkAdapter,
+    location: Option<NetworkAdapterLocation>,
+) -> String {
+    let mac = na.get_mac_address().await;
     let mut nal = NETWORK_ADAPTERS.lock().await;
-    //TODO implement an automatic naming scheme
-    use alloc::string::ToString;
-    let name = "net0".to_string();
+    let name = (0..)
+        .map(|i| format!("net{}", i))
+        .find(|n| !nal.contains_key(n))
+        .unwrap();
+    let unused = |n: &String| !nal.values().any(|i| i.aliases().any(|a| a == n));
+    let path_name = location.map(|l| l.name()).filter(unused);
+    let mac_name = Some(format!("enx{}", mac.hex())).filter(unused);
+    let adapter = AsyncLockedArc::new(na);
+    let ni = NetworkInterface {
+        path_name,
+        mac_name,
+        mac,
+        adapter: adapter.clone(),
+        interface: Arc::new(interface::Interface::new(name.clone(), adapter, mac)),
+    };
+    let aliases: Vec<&str> = ni.aliases().map(|a| a.as_str()).collect();
     crate::VGA
-        .print_str_async(&alloc::format!(
-            "Registering a network adapter for {}\r\n",
-            name
+        .print_str_async(&format!(
+            "Registering a network adapter for {} ({})\r\n",
+            name,
+            aliases.join(", ")
         ))
         .await;
-    nal.insert(name, AsyncLockedArc::new(na));
+    nal.insert(name.clone(), ni);
+    publish(NetworkEvent::Added(name.clone()));
+    name
+}
+
+/// Unregister a network adapter by any of its names, returning the adapter
+pub async fn unregister_network_adapter(s: &str) -> Option<AsyncLockedArc<NetworkAdapter>> {
+    let mut nal = NETWORK_ADAPTERS.lock().await;
+    let name = find_network_adapter(&nal, s)?.to_owned();
+    let ni = nal.remove(&name)?;
+    ni.interface.remove();
+    ipv4::remove_routes(|r| r.interface == name);
+    crate::VGA
+        .print_str_async(&format!("Unregistering network adapter {}\r\n", name))
+        .await;
+    publish(NetworkEvent::Removed(name));
+    Some(ni.adapter)
+}
+
+/// Find the sequential name of an adapter from any of its names
+fn find_network_adapter<'a>(
+    nal: &'a BTreeMap<String, NetworkInterface>,
+    s: &str,
+) -> Option<&'a String> {
+    nal.iter()
+        .find(|(name, ni)| *name == s || ni.aliases().any(|a| a == s))
+        .map(|(name, _)| name)
 }
 
-/// Grab a network adapter by name
+/// Grab a network adapter by any of its names
 pub async fn get_network_adapter(s: &str) -> Option<AsyncLockedArc<NetworkAdapter>> {
     let nal = NETWORK_ADAPTERS.lock().await;
-    if nal.contains_key(s) {
-        Some(nal.get(s).unwrap().to_owned())
-    } else {
-        None
-    }
+    let name = find_network_adapter(&nal, s)?;
+    nal.get(name).map(|ni| ni.adapter.to_owned())
+}
+
+/// Grab the network stack state of an adapter by any of its names
+pub async fn get_interface(s: &str) -> Option<Arc<interface::Interface>> {
+    let nal = NETWORK_ADAPTERS.lock().await;
+    let name = find_network_adapter(&nal, s)?;
+    nal.get(name).map(|ni| ni.interface.clone())
+}
+
+/// The network stack state of every registered adapter
+pub async fn interfaces() -> Vec<Arc<interface::Interface>> {
+    let nal = NETWORK_ADAPTERS.lock().await;
+    nal.values().map(|ni| ni.interface.clone()).collect()
+}
+
+/// List the network adapters that are registered
+pub async fn list_network_adapters() -> Vec<NetworkInterfaceInfo> {
+    let nal = NETWORK_ADAPTERS.lock().await;
+    nal.iter()
+        .map(|(name, ni)| NetworkInterfaceInfo {
+            name: name.clone(),
+            aliases: ni.aliases().cloned().collect(),
+            mac: ni.mac,
+        })
+        .collect()
 }
 
 /// A mac address for a network adapter
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct MacAddress {
     /// The bytes of the mac address
     address: [u8; 6],
 }
 
+impl MacAddress {
+    /// The address that frames are sent to so every host receives them
+    pub const BROADCAST: Self = Self { address: [0xff; 6] };
+
+    /// Construct a mac address from its bytes, in the order they are sent
+    pub const fn new(address: [u8; 6]) -> Self {
+        Self { address }
+    }
+
+    /// The bytes of the address, in the order they are sent
+    pub fn octets(&self) -> [u8; 6] {
+        self.address
+    }
+
+    /// Returns true for the broadcast address
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::BROADCAST
+    }
+
+    /// Returns true for group addresses, which include the broadcast address
+    pub fn is_multicast(&self) -> bool {
+        (self.address[0] & 1) != 0
+    }
+
+    /// The address as twelve lowercase hex digits with no separators
+    pub fn hex(&self) -> String {
+        self.address.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl core::fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let a = &self.address;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a[0], a[1], a[2], a[3], a[4], a[5]
+        )
+    }
+}
+
 impl Default for MacAddress {
     fn default() -> Self {
         Self { address: [0; 6] }
@@ -92,6 +356,341 @@ fn mac_address_conversion_test() -> Result<(), ()> {
     Ok(())
 }
 
+/// An ipv4 address
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Address([u8; 4]);
+
+impl Ipv4Address {
+    /// The address used when a host does not have an address yet
+    pub const UNSPECIFIED: Self = Self([0; 4]);
+    /// The limited broadcast address
+    pub const BROADCAST: Self = Self([255; 4]);
+
+    /// Construct an address from its four parts
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Self([a, b, c, d])
+    }
+
+    /// The bytes of the address, in network order
+    pub fn octets(&self) -> [u8; 4] {
+        self.0
+    }
+
+    /// Returns true for 0.0.0.0
+    pub fn is_unspecified(&self) -> bool {
+        *self == Self::UNSPECIFIED
+    }
+
+    /// Returns true for the limited broadcast address
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::BROADCAST
+    }
+
+    /// Returns true for addresses in 224.0.0.0/4
+    pub fn is_multicast(&self) -> bool {
+        (self.0[0] & 0xf0) == 0xe0
+    }
+}
+
+impl From<u32> for Ipv4Address {
+    fn from(value: u32) -> Self {
+        Self(value.to_be_bytes())
+    }
+}
+
+impl From<Ipv4Address> for u32 {
+    fn from(value: Ipv4Address) -> u32 {
+        u32::from_be_bytes(value.0)
+    }
+}
+
+impl From<[u8; 4]> for Ipv4Address {
+    fn from(value: [u8; 4]) -> Self {
+        Self(value)
+    }
+}
+
+impl From<Ipv4Address> for core::net::Ipv4Addr {
+    fn from(value: Ipv4Address) -> Self {
+        Self::from(value.0)
+    }
+}
+
+impl From<core::net::Ipv4Addr> for Ipv4Address {
+    fn from(value: core::net::Ipv4Addr) -> Self {
+        Self(value.octets())
+    }
+}
+
+impl core::fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.0[0], self.0[1], self.0[2], self.0[3])
+    }
+}
+
+/// An ipv4 address and a port, the endpoint of a udp or tcp connection
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SocketAddress {
+    /// The address
+    pub ip: Ipv4Address,
+    /// The port
+    pub port: u16,
+}
+
+impl SocketAddress {
+    /// Construct a socket address
+    pub const fn new(ip: Ipv4Address, port: u16) -> Self {
+        Self { ip, port }
+    }
+}
+
+impl core::fmt::Display for SocketAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}:{}", self.ip, self.port)
+    }
+}
+
+/// Counters for the packets received by a network adapter
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NetworkStatistics {
+    /// The number of good packets received
+    pub rx_packets: u64,
+    /// The number of bytes in the good packets received
+    pub rx_bytes: u64,
+    /// The number of packets received with any kind of error
+    pub rx_errors: u64,
+    /// The number of packets received with a crc or alignment error
+    pub rx_crc_errors: u64,
+    /// The number of packets dropped because there was nowhere to put them
+    pub rx_dropped: u64,
+    /// The number of times the receiver ran out of buffers
+    pub rx_overruns: u64,
+    /// The number of packets that could not be transmitted
+    pub tx_errors: u64,
+}
+
+/// Holds either memory or io space, the registers of a network card
+enum MemoryOrIo {
+    /// Regular memory
+    Memory(crate::PciMemory),
+    /// Io space
+    Io(crate::IoPortArray<'static>),
+}
+
+impl MemoryOrIo {
+    /// Dump the contents of the data as hex
+    async fn hex_dump(&self) {
+        match self {
+            MemoryOrIo::Memory(_m) => {
+                let mut buffer = [0u32; 32];
+                for (i, b) in buffer.iter_mut().enumerate() {
+                    *b = self.read_u32(i as u16);
+                }
+                crate::modules::video::hex_dump_generic_async(&buffer, true, false).await;
+            }
+            MemoryOrIo::Io(_io_port_array) => todo!(),
+        }
+    }
+
+    /// Read a u8 from the specified address
+    fn read_u8(&self, address: u16) -> u8 {
+        match self {
+            MemoryOrIo::Memory(mem) => mem.read_u8(address as usize),
+            MemoryOrIo::Io(io) => io.port::<u8>(address).port_read(),
+        }
+    }
+
+    /// Read a u16 from the specified address
+    fn read_u16(&self, address: u16) -> u16 {
+        match self {
+            MemoryOrIo::Memory(mem) => mem.read_u16(address as usize),
+            MemoryOrIo::Io(io) => io.port::<u16>(address).port_read(),
+        }
+    }
+
+    /// Read a u32 from the specified address
+    fn read_u32(&self, address: u16) -> u32 {
+        match self {
+            MemoryOrIo::Memory(mem) => mem.read_u32(address as usize),
+            MemoryOrIo::Io(io) => io.port::<u32>(address).port_read(),
+        }
+    }
+
+    /// Write the specified address with the specified u8
+    fn write_u8(&mut self, address: u16, val: u8) {
+        match self {
+            MemoryOrIo::Memory(mem) => mem.write_u8(address as usize, val),
+            MemoryOrIo::Io(io) => io.port::<u8>(address).port_write(val),
+        }
+    }
+
+    /// Write the specified address with the specified u16
+    fn write_u16(&mut self, address: u16, val: u16) {
+        match self {
+            MemoryOrIo::Memory(mem) => mem.write_u16(address as usize, val),
+            MemoryOrIo::Io(io) => io.port::<u16>(address).port_write(val),
+        }
+    }
+
+    /// Write the specified address with the specified u32
+    fn write_u32(&mut self, address: u16, val: u32) {
+        match self {
+            MemoryOrIo::Memory(mem) => mem.write_u32(address as usize, val),
+            MemoryOrIo::Io(io) => io.port::<u32>(address).port_write(val),
+        }
+    }
+}
+
+/// Wakes the tasks waiting for received packets, usually signalled from an interrupt handler
+pub struct ReceiveNotifier {
+    /// Packets may be waiting to be processed
+    pending: AtomicBool,
+    /// The tasks waiting for packets
+    wakers: Locked<Vec<Waker>>,
+}
+
+impl Default for ReceiveNotifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReceiveNotifier {
+    /// Construct a new Self
+    pub fn new() -> Self {
+        Self {
+            pending: AtomicBool::new(false),
+            wakers: Locked::new(Vec::new()),
+        }
+    }
+
+    /// Signal that packets may have arrived, safe to call from an interrupt context
+    pub fn notify(&self) {
+        self.pending.store(true, Ordering::Release);
+        // Drained in place, so that an interrupt handler does not free memory
+        for w in self.wakers.sync_lock().drain(..) {
+            w.wake();
+        }
+    }
+
+    /// Wait until [Self::notify] has been called since the last wait completed
+    pub async fn wait(&self) {
+        core::future::poll_fn(|cx| {
+            if self.pending.swap(false, Ordering::AcqRel) {
+                return Poll::Ready(());
+            }
+            // Interrupt handlers lock the wakers too
+            crate::SYSTEM.read().disable_interrupts_for(|| {
+                crate::add_waker(&mut self.wakers.sync_lock(), cx.waker())
+            });
+            if self.pending.swap(false, Ordering::AcqRel) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// Wakes every task waiting for a change to some state, such as an update of a cache.
+/// Unlike [ReceiveNotifier], where one waiting task consumes each notification, every waiting task sees the same change.
+pub struct ChangeNotifier {
+    /// The number of changes so far
+    generation: AtomicU64,
+    /// The tasks waiting for a change
+    wakers: Locked<Vec<Waker>>,
+}
+
+impl Default for ChangeNotifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChangeNotifier {
+    /// Construct a new Self
+    pub const fn new() -> Self {
+        Self {
+            generation: AtomicU64::new(0),
+            wakers: Locked::new(Vec::new()),
+        }
+    }
+
+    /// The number of changes so far, to check before looking at the state and pass to [Self::changed]
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Signal that the state has changed, waking every waiting task
+    pub fn notify(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        let wakers = core::mem::take(&mut *self.wakers.sync_lock());
+        for w in wakers {
+            w.wake();
+        }
+    }
+
+    /// Wait until the state has changed since the generation was read
+    pub async fn changed(&self, generation: u64) {
+        core::future::poll_fn(|cx| {
+            if self.generation() != generation {
+                return Poll::Ready(());
+            }
+            crate::add_waker(&mut self.wakers.sync_lock(), cx.waker());
+            if self.generation() != generation {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// Build a stream of the packets received by a network adapter. The adapter is only locked while checking for packets, so other tasks can send packets while the stream waits.
+pub fn packet_stream(na: AsyncLockedArc<NetworkAdapter>) -> impl futures::Stream<Item = Vec<u8>> {
+    futures::stream::unfold(na, |na| async move {
+        loop {
+            let received = {
+                let mut a = na.lock().await;
+                a.try_receive_packet()
+                    .await
+                    .ok_or_else(|| a.receive_notifier())
+            };
+            match received {
+                Ok(p) => return Some((p, na)),
+                Err(notifier) => notifier?.wait().await,
+            }
+        }
+    })
+}
+
+/// Test the names built from the location and mac address of an adapter
+#[doors_macros::doors_test]
+fn network_adapter_name_test() -> Result<(), ()> {
+    let location = PciLocation {
+        bus: 0,
+        device: 3,
+        function: 0,
+    };
+    let l = NetworkAdapterLocation::Pci {
+        segment: 0,
+        location,
+    };
+    assert_eq!(l.name(), "enp0s3f0");
+    let l = NetworkAdapterLocation::Pci {
+        segment: 1,
+        location,
+    };
+    assert_eq!(l.name(), "enP1p0s3f0");
+    let mac = MacAddress {
+        address: [0x52, 0x54, 0, 0x12, 0x34, 0x56],
+    };
+    assert_eq!(mac.hex(), "525400123456");
+    Ok(())
+}
+
 /// The trait that defines common functionality for network adapters
 #[enum_dispatch::enum_dispatch]
 pub trait NetworkAdapterTrait {
@@ -99,6 +698,16 @@ pub trait NetworkAdapterTrait {
     async fn get_mac_address(&mut self) -> MacAddress;
     /// Send a packet over the network interface
     async fn send_packet(&mut self, packet: &[u8]) -> Result<(), ()>;
+    /// Wait for the next packet received by the network interface. Errors when the receiver is not running.
+    async fn receive_packet(&mut self) -> Result<Vec<u8>, ()>;
+    /// Retrieve a received packet if one is available, without waiting
+    async fn try_receive_packet(&mut self) -> Option<Vec<u8>>;
+    /// The notifier signalled when packets arrive, if the receiver is running
+    fn receive_notifier(&self) -> Option<Arc<ReceiveNotifier>>;
+    /// Retrieve the packet counters for the network interface
+    async fn statistics(&mut self) -> NetworkStatistics;
+    /// Receive frames sent to the given multicast addresses, in addition to the adapter address and broadcasts
+    async fn set_multicast(&mut self, addresses: &[MacAddress]) -> Result<(), ()>;
 }
 
 /// A network adapter
@@ -0,0 +1,8 @@
+//! Realtek drivers for networking hardware
+
+mod rtl8139;
+
+doors_macros2::enum_reexport!(rtl8139);
+
+pub use rtl8139::Rtl8139;
+pub use rtl8139::Rtl8139Device;
@@ -0,0 +1,741 @@
+//! This driver is for the realtek rtl8139 networking hardware, and the compatible cards made by other vendors.
+
+use alloc::format;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::kernel::System;
+use crate::modules::network::{
+    MacAddress, MemoryOrIo, NetworkAdapterTrait, NetworkStatistics, ReceiveNotifier,
+};
+use crate::modules::pci::{
+    capability::PowerState, BarSpace, ConfigurationSpaceEnum, PciBus, PciConfigurationSpace,
+    PciDevice, PciFunction, PciFunctionDriver, PciFunctionDriverTrait, PciMatch, PciProbeResult,
+};
+use crate::modules::time::{sleep, Duration, Instant};
+use crate::{Arc, IrqGuarded, IrqGuardedInner};
+
+/// The size of the receive ring, selected by [RcrFlags::RBLEN_32K]
+const RX_RING_SIZE: usize = 32768;
+/// The space after the ring that the card writes to when a frame does not fit at the end, because [RcrFlags::WRAP] is set.
+/// The card also needs 16 bytes past the end of the ring.
+const RX_RING_PAD: usize = 16 + 1536;
+/// The number of transmit descriptors the card has
+const TX_DESCRIPTORS: usize = 4;
+/// The largest frame the card can transmit
+const TX_BUFFER_SIZE: usize = 1792;
+/// The shortest frame, without the crc, frames that are shorter are padded to this length
+const MIN_FRAME_LENGTH: usize = 60;
+/// The longest frame that can be received, including a vlan tag and the crc
+const MAX_FRAME_LENGTH: usize = 1522;
+/// The length of the crc at the end of each received frame
+const CRC_LENGTH: usize = 4;
+/// The early transmit threshold, in units of 32 bytes
+const TX_THRESHOLD: u32 = 256 / 32;
+/// How long to wait for the card to finish with a transmit descriptor
+const TX_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to wait for the card to finish a software reset
+const RESET_TIMEOUT: Duration = Duration::from_millis(100);
+/// How often to check whether the card has finished a software reset
+const RESET_POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// The value of the first word of the eeprom
+const EEPROM_ID: u16 = 0x8129;
+/// The eeprom word holding the first part of the mac address
+const EEPROM_MAC: u8 = 7;
+
+/// The errors that can occur setting up the card
+#[derive(Debug)]
+enum Rtl8139Error {
+    /// Memory for the buffers could not be allocated below 4GiB
+    Allocation,
+    /// The card did not finish its software reset
+    ResetTimeout,
+}
+
+impl From<core::alloc::AllocError> for Rtl8139Error {
+    fn from(_: core::alloc::AllocError) -> Self {
+        Self::Allocation
+    }
+}
+
+/// Defines the addresses of various registers for the rtl8139 device
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[allow(non_camel_case_types)]
+#[repr(u16)]
+enum Rtl8139Registers {
+    /// The first byte of the mac address, loaded from the eeprom
+    IDR0 = 0,
+    /// The low half of the multicast hash table
+    MAR0 = 8,
+    /// The high half of the multicast hash table
+    MAR4 = 0xc,
+    /// The status of the first transmit descriptor, the others follow every 4 bytes
+    TSD0 = 0x10,
+    /// The buffer address of the first transmit descriptor, the others follow every 4 bytes
+    TSAD0 = 0x20,
+    /// The physical address of the receive ring
+    RBSTART = 0x30,
+    /// The command register
+    CR = 0x37,
+    /// The offset in the receive ring of the current packet read, minus 16
+    CAPR = 0x38,
+    /// The interrupt mask register
+    IMR = 0x3c,
+    /// The interrupt status register, bits are cleared by writing 1 to them
+    ISR = 0x3e,
+    /// The transmit configuration register
+    TCR = 0x40,
+    /// The receive configuration register
+    RCR = 0x44,
+    /// The missed packet counter, cleared by writing to it
+    MPC = 0x4c,
+    /// The eeprom command register
+    CFG9346 = 0x50,
+    /// Configuration register 1
+    CONFIG1 = 0x52,
+    /// The media status register
+    MSR = 0x58,
+}
+
+bitflags::bitflags! {
+    /// The command register
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    struct CrFlags: u8 {
+        /// The receive ring is empty
+        const BUFE = 1<<0;
+        /// Transmitter enable
+        const TE = 1<<2;
+        /// Receiver enable
+        const RE = 1<<3;
+        /// Software reset, cleared by the card when the reset is done
+        const RST = 1<<4;
+    }
+}
+
+bitflags::bitflags! {
+    /// The interrupt mask and interrupt status registers
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    struct InterruptFlags: u16 {
+        /// A packet was received
+        const ROK = 1<<0;
+        /// A packet was received with an error
+        const RER = 1<<1;
+        /// A packet was transmitted
+        const TOK = 1<<2;
+        /// A packet was aborted from transmission
+        const TER = 1<<3;
+        /// The receive ring overflowed
+        const RXOVW = 1<<4;
+        /// The link status changed
+        const LINKCHG = 1<<5;
+        /// The receive fifo overflowed
+        const FOVW = 1<<6;
+        /// The length of the transmit fifo changed
+        const LENCHG = 1<<13;
+        /// The timer expired
+        const TIMEOUT = 1<<14;
+        /// A system error occurred on the pci bus
+        const SERR = 1<<15;
+    }
+}
+
+bitflags::bitflags! {
+    /// The receive configuration register
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    struct RcrFlags: u32 {
+        /// Accept all packets
+        const AAP = 1<<0;
+        /// Accept packets sent to the mac address
+        const APM = 1<<1;
+        /// Accept multicast packets that pass the hash table
+        const AM = 1<<2;
+        /// Accept broadcast packets
+        const AB = 1<<3;
+        /// Write packets past the end of the ring instead of wrapping them to the start
+        const WRAP = 1<<7;
+        /// No limit on the size of receive dma bursts
+        const MXDMA_UNLIMITED = 7<<8;
+        /// A receive ring of 32K + 16 bytes
+        const RBLEN_32K = 2<<11;
+        /// Transfer packets to memory only once they are completely received
+        const RXFTH_NONE = 7<<13;
+    }
+}
+
+bitflags::bitflags! {
+    /// The transmit configuration register
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    struct TcrFlags: u32 {
+        /// Transmit dma bursts of up to 2048 bytes
+        const MXDMA_2048 = 7<<8;
+        /// The standard inter frame gap
+        const IFG_NORMAL = 3<<24;
+    }
+}
+
+bitflags::bitflags! {
+    /// A transmit status register
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    struct TsdFlags: u32 {
+        /// The mask of the size of the packet
+        const SIZE = 0x1fff;
+        /// The card has finished copying the packet, the buffer can be reused
+        const OWN = 1<<13;
+        /// The transmit fifo ran out of data during transmission
+        const TUN = 1<<14;
+        /// The packet was transmitted
+        const TOK = 1<<15;
+        /// The transmission was aborted
+        const TABT = 1<<30;
+    }
+}
+
+bitflags::bitflags! {
+    /// The status in the header of a received packet
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    struct RxStatus: u16 {
+        /// The packet was received without errors
+        const ROK = 1<<0;
+        /// Frame alignment error
+        const FAE = 1<<1;
+        /// Crc error
+        const CRC = 1<<2;
+        /// The packet is longer than 4K bytes
+        const LONG = 1<<3;
+        /// The packet is shorter than 64 bytes
+        const RUNT = 1<<4;
+        /// Invalid symbol error
+        const ISE = 1<<5;
+    }
+}
+
+/// The eeprom command register value that puts the eeprom pins under program control
+const EEPROM_PROGRAM: u8 = 0x80;
+/// The eeprom chip select pin
+const EEPROM_CS: u8 = 0x08;
+/// The eeprom clock pin
+const EEPROM_SK: u8 = 0x04;
+/// The pin for data into the eeprom
+const EEPROM_DI: u8 = 0x02;
+/// The pin for data out of the eeprom
+const EEPROM_DO: u8 = 0x01;
+/// The start bit and read opcode for the eeprom
+const EEPROM_READ: u32 = 6;
+
+/// The media status bit that is set when the link is down
+const MSR_LINKB: u8 = 1 << 2;
+
+/// Ethernet driver for the realtek rtl8139 ethernet controller on pci
+#[derive(Clone, Default)]
+pub struct Rtl8139 {}
+
+/// The internal data for the network card, used in interrupt and non-interrupt contexts
+struct Rtl8139DeviceInternal {
+    /// The registers of the card
+    regs: IrqGuarded<MemoryOrIo>,
+    /// The link is up
+    up: AtomicBool,
+    /// Signalled when the receive interrupts fire
+    rx_notify: Arc<ReceiveNotifier>,
+    /// Signalled when the transmit interrupts fire, used while waiting for a transmit descriptor
+    tx_notify: Arc<ReceiveNotifier>,
+    /// The number of receiver overrun interrupts
+    rx_overruns: AtomicU32,
+}
+
+impl Rtl8139DeviceInternal {
+    /// Update the link status from an interrupt context
+    fn update_link_status_interrupt(&self) {
+        let msr = self
+            .regs
+            .interrupt_access()
+            .read_u8(Rtl8139Registers::MSR as u16);
+        self.up.store((msr & MSR_LINKB) == 0, Ordering::Relaxed);
+    }
+}
+
+#[doors_macros::enum_variant(NetworkAdapter)]
+/// The actual rtl8139 device
+pub struct Rtl8139Device {
+    /// The internal structure used in interrupt handler and regular code
+    internal: Arc<Rtl8139DeviceInternal>,
+    /// The base address registers
+    _bars: [Option<BarSpace>; 6],
+    /// The receive ring, followed by the space the card writes to when a packet does not fit at the end
+    rx_ring: crate::DmaMemorySlice<u8>,
+    /// The offset in the receive ring of the next packet
+    rx_offset: usize,
+    /// The transmit buffers, one for each transmit descriptor
+    tx_buffers: Vec<crate::DmaMemorySlice<u8>>,
+    /// The next transmit descriptor to use
+    tx_index: usize,
+    /// The transmit descriptors that have been given to the card
+    tx_busy: [bool; TX_DESCRIPTORS],
+    /// The receive and transmit counters
+    stats: NetworkStatistics,
+    /// The mac address
+    mac_address: MacAddress,
+}
+
+impl NetworkAdapterTrait for Rtl8139Device {
+    async fn get_mac_address(&mut self) -> MacAddress {
+        self.mac_address
+    }
+
+    async fn send_packet(&mut self, packet: &[u8]) -> Result<(), ()> {
+        if !self.internal.up.load(Ordering::Relaxed) || packet.len() > TX_BUFFER_SIZE {
+            return Err(());
+        }
+        let index = self.tx_index;
+        let tsd = Rtl8139Registers::TSD0 as u16 + 4 * index as u16;
+        if self.tx_busy[index] {
+            use futures::future::{select, Either};
+            let notifier = self.internal.tx_notify.clone();
+            let mut timeout = core::pin::pin!(sleep(TX_TIMEOUT));
+            loop {
+                // Checked before waiting so that a transmit finished before the wait started is not missed
+                let status =
+                    TsdFlags::from_bits_retain(self.internal.regs.access().await.read_u32(tsd));
+                if status.intersects(TsdFlags::OWN | TsdFlags::TABT) {
+                    if status.intersects(TsdFlags::TABT | TsdFlags::TUN) {
+                        self.stats.tx_errors += 1;
+                    }
+                    break;
+                }
+                match select(core::pin::pin!(notifier.wait()), timeout.as_mut()).await {
+                    Either::Left(_) => {}
+                    Either::Right(_) => return Err(()),
+                }
+            }
+        }
+        let length = packet.len().max(MIN_FRAME_LENGTH);
+        {
+            let buffer = &mut self.tx_buffers[index];
+            buffer[..packet.len()].copy_from_slice(packet);
+            buffer[packet.len()..length].fill(0);
+        }
+        // Clearing the own bit starts the transmission
+        self.internal
+            .regs
+            .access()
+            .await
+            .write_u32(tsd, (TX_THRESHOLD << 16) | length as u32);
+        self.tx_busy[index] = true;
+        self.tx_index = (index + 1) % TX_DESCRIPTORS;
+        Ok(())
+    }
+
+    async fn receive_packet(&mut self) -> Result<Vec<u8>, ()> {
+        let notifier = self.internal.rx_notify.clone();
+        loop {
+            if let Some(p) = self.check_for_received_packets().await {
+                return Ok(p);
+            }
+            notifier.wait().await;
+        }
+    }
+
+    async fn try_receive_packet(&mut self) -> Option<Vec<u8>> {
+        self.check_for_received_packets().await
+    }
+
+    fn receive_notifier(&self) -> Option<Arc<ReceiveNotifier>> {
+        Some(self.internal.rx_notify.clone())
+    }
+
+    async fn statistics(&mut self) -> NetworkStatistics {
+        {
+            let mut regs = self.internal.regs.access().await;
+            self.stats.rx_dropped +=
+                (regs.read_u32(Rtl8139Registers::MPC as u16) & 0xffffff) as u64;
+            regs.write_u32(Rtl8139Registers::MPC as u16, 0);
+        }
+        self.stats.rx_overruns += self.internal.rx_overruns.swap(0, Ordering::Relaxed) as u64;
+        self.stats
+    }
+
+    async fn set_multicast(&mut self, addresses: &[MacAddress]) -> Result<(), ()> {
+        let mut table = [0u32; 2];
+        for a in addresses {
+            // The hash is the top 6 bits of the big endian crc of the address
+            let hash = Self::ether_crc(&a.octets()) >> 26;
+            table[(hash >> 5) as usize] |= 1 << (hash & 0x1f);
+        }
+        let mut regs = self.internal.regs.access().await;
+        regs.write_u32(Rtl8139Registers::MAR0 as u16, table[0]);
+        regs.write_u32(Rtl8139Registers::MAR4 as u16, table[1]);
+        Ok(())
+    }
+}
+
+impl Rtl8139Device {
+    /// The receive configuration
+    fn receive_config() -> RcrFlags {
+        RcrFlags::APM
+            | RcrFlags::AM
+            | RcrFlags::AB
+            | RcrFlags::WRAP
+            | RcrFlags::MXDMA_UNLIMITED
+            | RcrFlags::RBLEN_32K
+            | RcrFlags::RXFTH_NONE
+    }
+
+    /// The big endian crc of ethernet, used for the multicast hash
+    fn ether_crc(data: &[u8]) -> u32 {
+        let mut crc = 0xffff_ffffu32;
+        for b in data {
+            let mut b = *b;
+            for _ in 0..8 {
+                let bit = (crc >> 31) ^ (b as u32 & 1);
+                crc <<= 1;
+                if bit != 0 {
+                    crc ^= 0x04c1_1db7;
+                }
+                b >>= 1;
+            }
+        }
+        crc
+    }
+
+    /// Read a u16 from the receive ring
+    fn ring_u16(&self, offset: usize) -> u16 {
+        unsafe { core::ptr::read_volatile(self.rx_ring.as_ptr().add(offset) as *const u16) }
+    }
+
+    /// Process the receive ring, returning the next packet if there is one.
+    /// The space used by each processed packet is handed back to the card.
+    async fn check_for_received_packets(&mut self) -> Option<Vec<u8>> {
+        let mut regs = self.internal.regs.access().await;
+        if CrFlags::from_bits_retain(regs.read_u8(Rtl8139Registers::CR as u16))
+            .contains(CrFlags::BUFE)
+        {
+            return None;
+        }
+        let offset = self.rx_offset;
+        let status = RxStatus::from_bits_retain(self.ring_u16(offset));
+        let length = self.ring_u16(offset + 2) as usize;
+        // Bad packets are not stored by the card, so anything else means the ring is no longer valid
+        if !status.contains(RxStatus::ROK)
+            || !(MIN_FRAME_LENGTH + CRC_LENGTH..=MAX_FRAME_LENGTH).contains(&length)
+        {
+            self.stats.rx_errors += 1;
+            if status.contains(RxStatus::CRC) {
+                self.stats.rx_crc_errors += 1;
+            }
+            self.reset_receiver(&mut regs);
+            return None;
+        }
+        let packet = self.rx_ring[offset + 4..offset + 4 + length - CRC_LENGTH].to_vec();
+        self.stats.rx_packets += 1;
+        self.stats.rx_bytes += packet.len() as u64;
+        self.rx_offset = ((offset + 4 + length + 3) & !3) % RX_RING_SIZE;
+        regs.write_u16(
+            Rtl8139Registers::CAPR as u16,
+            (self.rx_offset as u16).wrapping_sub(16),
+        );
+        Some(packet)
+    }
+
+    /// Restart the receiver at the start of the receive ring, discarding everything in it
+    fn reset_receiver(&mut self, regs: &mut MemoryOrIo) {
+        regs.write_u8(Rtl8139Registers::CR as u16, CrFlags::TE.bits());
+        regs.write_u32(Rtl8139Registers::RBSTART as u16, self.rx_ring.phys() as u32);
+        regs.write_u8(
+            Rtl8139Registers::CR as u16,
+            (CrFlags::RE | CrFlags::TE).bits(),
+        );
+        regs.write_u32(Rtl8139Registers::RCR as u16, Self::receive_config().bits());
+        self.rx_offset = 0;
+        regs.write_u16(Rtl8139Registers::CAPR as u16, 0u16.wrapping_sub(16));
+    }
+
+    /// Read a word from the eeprom, with the specified number of address bits
+    fn read_from_eeprom(regs: &mut MemoryOrIo, address: u8, address_bits: u32) -> u16 {
+        let command = (address as u32) | (EEPROM_READ << address_bits);
+        let enable = EEPROM_PROGRAM | EEPROM_CS;
+        regs.write_u8(Rtl8139Registers::CFG9346 as u16, EEPROM_PROGRAM);
+        regs.write_u8(Rtl8139Registers::CFG9346 as u16, enable);
+        // Each read of the register is a delay long enough for the eeprom
+        regs.read_u8(Rtl8139Registers::CFG9346 as u16);
+        for i in (0..address_bits + 5).rev() {
+            let data = if (command & (1 << i)) != 0 {
+                EEPROM_DI
+            } else {
+                0
+            };
+            regs.write_u8(Rtl8139Registers::CFG9346 as u16, enable | data);
+            regs.read_u8(Rtl8139Registers::CFG9346 as u16);
+            regs.write_u8(Rtl8139Registers::CFG9346 as u16, enable | data | EEPROM_SK);
+            regs.read_u8(Rtl8139Registers::CFG9346 as u16);
+        }
+        regs.write_u8(Rtl8139Registers::CFG9346 as u16, enable);
+        regs.read_u8(Rtl8139Registers::CFG9346 as u16);
+        let mut value = 0u16;
+        for _ in 0..16 {
+            regs.write_u8(Rtl8139Registers::CFG9346 as u16, enable | EEPROM_SK);
+            regs.read_u8(Rtl8139Registers::CFG9346 as u16);
+            let bit = regs.read_u8(Rtl8139Registers::CFG9346 as u16) & EEPROM_DO;
+            value = (value << 1) | bit as u16;
+            regs.write_u8(Rtl8139Registers::CFG9346 as u16, enable);
+            regs.read_u8(Rtl8139Registers::CFG9346 as u16);
+        }
+        regs.write_u8(Rtl8139Registers::CFG9346 as u16, 0);
+        value
+    }
+
+    /// Read the mac address from the eeprom. The 93c46 and 93c56 eeproms have different address lengths, so both are tried.
+    /// The address loaded into the IDR registers at power on is used when there is no eeprom.
+    fn read_mac_address(regs: &mut MemoryOrIo) -> MacAddress {
+        for address_bits in [6, 8] {
+            if Self::read_from_eeprom(regs, 0, address_bits) == EEPROM_ID {
+                let mut address = [0u8; 6];
+                for i in 0..3 {
+                    let v = Self::read_from_eeprom(regs, EEPROM_MAC + i as u8, address_bits);
+                    address[2 * i..2 * i + 2].copy_from_slice(&v.to_le_bytes());
+                }
+                return MacAddress { address };
+            }
+        }
+        let mut address = [0u8; 6];
+        for (i, a) in address.iter_mut().enumerate() {
+            *a = regs.read_u8(Rtl8139Registers::IDR0 as u16 + i as u16);
+        }
+        MacAddress { address }
+    }
+
+    /// Reset the card and start the receiver and transmitter
+    async fn setup(
+        mut regs: MemoryOrIo,
+        bars: [Option<BarSpace>; 6],
+        irqnum: u8,
+    ) -> Result<Self, Rtl8139Error> {
+        let rx_ring = crate::DmaMemorySlice::new(RX_RING_SIZE + RX_RING_PAD)?;
+        let mut tx_buffers = Vec::with_capacity(TX_DESCRIPTORS);
+        for _ in 0..TX_DESCRIPTORS {
+            tx_buffers.push(crate::DmaMemorySlice::new(TX_BUFFER_SIZE)?);
+        }
+        // The card can only use 32 bit addresses
+        if core::iter::once(&rx_ring)
+            .chain(tx_buffers.iter())
+            .any(|b| b.phys() + b.size() > u32::MAX as usize)
+        {
+            return Err(Rtl8139Error::Allocation);
+        }
+        // Wake the card up
+        regs.write_u8(Rtl8139Registers::CONFIG1 as u16, 0);
+        regs.write_u8(Rtl8139Registers::CR as u16, CrFlags::RST.bits());
+        let deadline = Instant::now() + RESET_TIMEOUT;
+        while CrFlags::from_bits_retain(regs.read_u8(Rtl8139Registers::CR as u16))
+            .contains(CrFlags::RST)
+        {
+            if Instant::now() >= deadline {
+                return Err(Rtl8139Error::ResetTimeout);
+            }
+            sleep(RESET_POLL_INTERVAL).await;
+        }
+        let mac_address = Self::read_mac_address(&mut regs);
+        regs.write_u32(Rtl8139Registers::RBSTART as u16, rx_ring.phys() as u32);
+        for (i, b) in tx_buffers.iter().enumerate() {
+            regs.write_u32(
+                Rtl8139Registers::TSAD0 as u16 + 4 * i as u16,
+                b.phys() as u32,
+            );
+        }
+        regs.write_u32(Rtl8139Registers::MAR0 as u16, 0);
+        regs.write_u32(Rtl8139Registers::MAR4 as u16, 0);
+        // The receiver and transmitter are enabled before they are configured, as the datasheet requires
+        regs.write_u8(
+            Rtl8139Registers::CR as u16,
+            (CrFlags::RE | CrFlags::TE).bits(),
+        );
+        regs.write_u32(Rtl8139Registers::RCR as u16, Self::receive_config().bits());
+        regs.write_u32(
+            Rtl8139Registers::TCR as u16,
+            (TcrFlags::MXDMA_2048 | TcrFlags::IFG_NORMAL).bits(),
+        );
+        regs.write_u16(Rtl8139Registers::CAPR as u16, 0u16.wrapping_sub(16));
+        regs.write_u32(Rtl8139Registers::MPC as u16, 0);
+        let up = (regs.read_u8(Rtl8139Registers::MSR as u16) & MSR_LINKB) == 0;
+        let com = IrqGuardedInner::new(irqnum, false, |_| {}, |_| {});
+        Ok(Self {
+            internal: Arc::new(Rtl8139DeviceInternal {
+                regs: IrqGuarded::new(regs, &com),
+                up: AtomicBool::new(up),
+                rx_notify: Arc::new(ReceiveNotifier::new()),
+                tx_notify: Arc::new(ReceiveNotifier::new()),
+                rx_overruns: AtomicU32::new(0),
+            }),
+            _bars: bars,
+            rx_ring,
+            rx_offset: 0,
+            tx_buffers,
+            tx_index: 0,
+            tx_busy: [false; TX_DESCRIPTORS],
+            stats: NetworkStatistics::default(),
+            mac_address,
+        })
+    }
+
+    /// The interrupt handler for the network card
+    fn handle_interrupt(this: &Arc<Rtl8139DeviceInternal>) {
+        let reason = {
+            let mut regs = this.regs.interrupt_access();
+            let reason = regs.read_u16(Rtl8139Registers::ISR as u16);
+            regs.write_u16(Rtl8139Registers::ISR as u16, reason);
+            InterruptFlags::from_bits_retain(reason)
+        };
+        if reason.contains(InterruptFlags::LINKCHG) {
+            this.update_link_status_interrupt();
+        }
+        if reason.intersects(InterruptFlags::RXOVW | InterruptFlags::FOVW) {
+            this.rx_overruns.fetch_add(1, Ordering::Relaxed);
+        }
+        if reason.intersects(
+            InterruptFlags::ROK
+                | InterruptFlags::RER
+                | InterruptFlags::RXOVW
+                | InterruptFlags::FOVW,
+        ) {
+            this.rx_notify.notify();
+        }
+        if reason.intersects(InterruptFlags::TOK | InterruptFlags::TER) {
+            this.tx_notify.notify();
+        }
+    }
+
+    /// Enable interrupts for the network card
+    async fn enable_interrupts(&self, sys: &System, irqnum: u8) {
+        crate::VGA
+            .print_str_async(&format!("Enabling interrupts on IRQ {}\r\n", irqnum))
+            .await;
+        use crate::kernel::SystemTrait;
+        // Marked as interrupt access because interrupts are not fully setup yet
+        let mut regs = self.internal.regs.interrupt_access();
+        regs.write_u16(
+            Rtl8139Registers::IMR as u16,
+            (InterruptFlags::ROK
+                | InterruptFlags::RER
+                | InterruptFlags::RXOVW
+                | InterruptFlags::TOK
+                | InterruptFlags::TER
+                | InterruptFlags::LINKCHG
+                | InterruptFlags::FOVW)
+                .bits(),
+        );
+        // Clear anything that happened before the handler existed
+        let pending = regs.read_u16(Rtl8139Registers::ISR as u16);
+        regs.write_u16(Rtl8139Registers::ISR as u16, pending);
+        let c = self.internal.clone();
+        sys.register_irq_handler(irqnum, move || Rtl8139Device::handle_interrupt(&c));
+        drop(regs);
+        sys.enable_irq(irqnum);
+    }
+}
+
+impl Rtl8139 {
+    /// Create a new self, in const form
+    pub const fn new() -> Self {
+        Self {}
+    }
+}
+
+impl PciFunctionDriverTrait for Rtl8139 {
+    async fn register(&self, m: &mut Vec<PciFunctionDriver>) {
+        crate::VGA
+            .print_str_async("Register realtek rtl8139 pci driver\r\n")
+            .await;
+        if !m.iter().any(|d| matches!(d, PciFunctionDriver::Rtl8139(_))) {
+            m.push(self.clone().into());
+        }
+    }
+
+    fn match_table(&self) -> &'static [PciMatch] {
+        &[
+            // Realtek
+            PciMatch::device(0x10ec, 0x8139),
+            // Accton
+            PciMatch::device(0x1113, 0x1211),
+            // D-Link
+            PciMatch::device(0x1186, 0x1300),
+        ]
+    }
+
+    async fn parse_bars(
+        &mut self,
+        cs: &mut PciConfigurationSpace,
+        bus: &PciBus,
+        dev: &PciDevice,
+        f: &PciFunction,
+        config: &ConfigurationSpaceEnum,
+        mut bars: [Option<BarSpace>; 6],
+    ) -> PciProbeResult {
+        // The registers are in memory space at bar 1, and in io space at bar 0
+        let regs = bars[1]
+            .as_mut()
+            .and_then(|b| b.get_memory(cs, bus, dev, f, config))
+            .map(MemoryOrIo::Memory)
+            .or_else(|| {
+                bars[0]
+                    .as_mut()
+                    .and_then(|b| b.get_io(cs, bus, dev, f, config))
+                    .map(MemoryOrIo::Io)
+            });
+        let irqnum = match config {
+            ConfigurationSpaceEnum::Standard(configuration_space_standard) => {
+                f.get_irq(bus, dev, configuration_space_standard)
+            }
+            _ => None,
+        };
+        if let (Some(regs), Some(irqnum)) = (regs, irqnum) {
+            f.set_bus_mastering(cs, bus, dev, true);
+            match Rtl8139Device::setup(regs, bars, irqnum).await {
+                Ok(d) => {
+                    {
+                        let sys = crate::SYSTEM.read();
+                        d.enable_interrupts(&sys, irqnum).await;
+                    }
+                    crate::VGA
+                        .print_str_async(&format!(
+                            "rtl8139: Mac address {}, link {}\r\n",
+                            d.mac_address,
+                            if d.internal.up.load(Ordering::Relaxed) {
+                                "up"
+                            } else {
+                                "down"
+                            }
+                        ))
+                        .await;
+                    let location = super::super::NetworkAdapterLocation::Pci {
+                        segment: cs.segment(),
+                        location: f.location(bus, dev),
+                    };
+                    super::super::register_network_adapter(d.into(), Some(location)).await;
+                    return PciProbeResult::Bound;
+                }
+                Err(e) => {
+                    crate::VGA
+                        .print_str_async(&format!("rtl8139: Setup error {:?}\r\n", e))
+                        .await;
+                    f.set_bus_mastering(cs, bus, dev, false);
+                }
+            }
+        }
+        f.set_power_state(cs, bus, dev, PowerState::D3Hot).await;
+        PciProbeResult::Declined
+    }
+}
+
+/// Test the crc used for the multicast hash against known addresses
+#[doors_macros::doors_test]
+fn rtl8139_multicast_hash_test() -> Result<(), ()> {
+    // The all hosts group 224.0.0.1
+    let crc = Rtl8139Device::ether_crc(&[0x01, 0x00, 0x5e, 0x00, 0x00, 0x01]);
+    assert_eq!(crc, 0x7fa3_2d9b);
+    assert_eq!(crc >> 26, 31);
+    assert_eq!(
+        Rtl8139Device::ether_crc(&[0x33, 0x33, 0, 0, 0, 1]) >> 26,
+        62
+    );
+    assert_eq!(Rtl8139Device::ether_crc(&[0xff; 6]) >> 26, 63);
+    Ok(())
+}
@@ -0,0 +1,1514 @@
+//! The transmission control protocol, with listeners that accept connections and streams that carry data reliably.
+//! Received segments are processed by the stack task, and retransmissions and delayed acknowledgments are driven by [timer_task].
+
+use alloc::{collections::BTreeMap, collections::VecDeque, vec::Vec};
+use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use core::task::{Poll, Waker};
+
+use super::ethernet::MTU;
+use super::ipv4::{self, checksum_add, checksum_finish, pseudo_header_sum, IpProtocol, Ipv4Header};
+use super::{ChangeNotifier, Ipv4Address, SocketAddress};
+use crate::modules::time::{Duration, Instant};
+use crate::{Arc, Locked};
+
+/// The length of a header without options
+const HEADER_LENGTH: usize = 20;
+/// The end of the sequence space of a connection
+const FIN: u8 = 0x01;
+/// Synchronize sequence numbers
+const SYN: u8 = 0x02;
+/// Reset the connection
+const RST: u8 = 0x04;
+/// Push the data to the application
+const PSH: u8 = 0x08;
+/// The acknowledgment field is valid
+const ACK: u8 = 0x10;
+/// The maximum segment size option
+const OPTION_MSS: u8 = 2;
+/// The maximum segment size assumed when the other end does not send one
+const DEFAULT_MSS: usize = 536;
+/// The largest segment that fits in an ethernet frame
+const LOCAL_MSS: usize = MTU - 20 - HEADER_LENGTH;
+/// The size of the receive buffer, which is the largest window that can be advertised without window scaling
+const RECEIVE_BUFFER: usize = 65535;
+/// The size of the send buffer
+const SEND_BUFFER: usize = 65536;
+/// The number of segments received out of order that are kept
+const MAX_OUT_OF_ORDER: usize = 32;
+/// The retransmission timeout before the round trip time has been measured
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+/// The shortest retransmission timeout
+const MIN_RTO: Duration = Duration::from_millis(200);
+/// The longest retransmission timeout
+const MAX_RTO: Duration = Duration::from_secs(60);
+/// The number of retransmissions of a segment before the connection is given up on
+const MAX_RETRIES: u32 = 8;
+/// How long an acknowledgment can be delayed, waiting for data to send it with
+const DELAYED_ACK: Duration = Duration::from_millis(200);
+/// How long a connection remembers its sequence numbers after it has been closed
+const TIME_WAIT: Duration = Duration::from_secs(60);
+/// The first port handed out to connections
+const EPHEMERAL_START: u16 = 49152;
+/// The number of ports handed out to connections
+const EPHEMERAL_COUNT: u16 = 16384;
+
+/// The connections, by local and remote address
+static CONNECTIONS: Locked<BTreeMap<(SocketAddress, SocketAddress), Arc<Connection>>> =
+    Locked::new(BTreeMap::new());
+
+/// The listening sockets, by local port
+static LISTENERS: Locked<BTreeMap<u16, Arc<ListenerState>>> = Locked::new(BTreeMap::new());
+
+/// Used to pick the next ephemeral port
+static NEXT_EPHEMERAL: AtomicU16 = AtomicU16::new(0);
+
+/// Mixed into initial sequence numbers so connections opened at the same time differ
+static ISS_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// Notified when a connection may have new work or an earlier deadline for [timer_task]
+static TIMER_WORK: ChangeNotifier = ChangeNotifier::new();
+
+/// The errors that can occur with a tcp connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TcpError {
+    /// Another socket is already listening on the port
+    AddressInUse,
+    /// All of the ephemeral ports are in use
+    NoFreePort,
+    /// There is no route to the destination
+    NoRoute,
+    /// The other end refused the connection
+    ConnectionRefused,
+    /// The other end reset the connection
+    ConnectionReset,
+    /// The other end stopped acknowledging data
+    TimedOut,
+    /// The connection is not open for this operation
+    NotConnected,
+}
+
+/// The states of a connection, from rfc 793
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TcpState {
+    /// A synchronize has been sent, waiting for one in reply
+    SynSent,
+    /// A synchronize has been received and answered, waiting for it to be acknowledged
+    SynReceived,
+    /// Data can flow both ways
+    Established,
+    /// This end has closed, and the finish has not been acknowledged
+    FinWait1,
+    /// This end has closed, waiting for the other end to close
+    FinWait2,
+    /// The other end has closed, waiting for this end to close
+    CloseWait,
+    /// Both ends closed at the same time, waiting for the finish to be acknowledged
+    Closing,
+    /// The other end closed first, waiting for the finish of this end to be acknowledged
+    LastAck,
+    /// Both ends have closed, waiting for stray segments to expire
+    TimeWait,
+    /// There is no connection
+    Closed,
+}
+
+/// Returns true if sequence number a comes before b
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Returns true if sequence number a comes after b
+fn after(a: u32, b: u32) -> bool {
+    before(b, a)
+}
+
+/// The fields of a segment header
+#[derive(Clone, Copy, Debug)]
+struct TcpHeader {
+    /// The port of the sender
+    source_port: u16,
+    /// The port of the receiver
+    destination_port: u16,
+    /// The sequence number of the first byte of the segment
+    sequence: u32,
+    /// The next sequence number the sender expects
+    acknowledgment: u32,
+    /// The control flags
+    flags: u8,
+    /// The receive window of the sender
+    window: u16,
+    /// The maximum segment size option
+    mss: Option<u16>,
+}
+
+impl TcpHeader {
+    /// Parse a segment, checking the checksum, and returning the header and data
+    fn parse<'a>(h: &Ipv4Header, p: &'a [u8]) -> Option<(Self, &'a [u8])> {
+        if p.len() < HEADER_LENGTH {
+            return None;
+        }
+        let offset = (p[12] >> 4) as usize * 4;
+        if offset < HEADER_LENGTH || offset > p.len() {
+            return None;
+        }
+        let sum = pseudo_header_sum(h.source, h.destination, IpProtocol::Tcp, p.len());
+        if checksum_finish(checksum_add(sum, p)) != 0 {
+            return None;
+        }
+        let mut mss = None;
+        let mut options = &p[HEADER_LENGTH..offset];
+        while let Some(&kind) = options.first() {
+            match kind {
+                0 => break,
+                1 => options = &options[1..],
+                _ => {
+                    let length = *options.get(1)? as usize;
+                    if length < 2 || length > options.len() {
+                        return None;
+                    }
+                    if kind == OPTION_MSS && length == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+                    options = &options[length..];
+                }
+            }
+        }
+        Some((
+            Self {
+                source_port: u16::from_be_bytes([p[0], p[1]]),
+                destination_port: u16::from_be_bytes([p[2], p[3]]),
+                sequence: u32::from_be_bytes([p[4], p[5], p[6], p[7]]),
+                acknowledgment: u32::from_be_bytes([p[8], p[9], p[10], p[11]]),
+                flags: p[13],
+                window: u16::from_be_bytes([p[14], p[15]]),
+                mss,
+            },
+            &p[offset..],
+        ))
+    }
+
+    /// The amount of sequence space the segment uses
+    fn length(&self, data: &[u8]) -> u32 {
+        data.len() as u32 + (self.flags & SYN != 0) as u32 + (self.flags & FIN != 0) as u32
+    }
+
+    /// Build a segment with a correct checksum
+    fn build(&self, source: Ipv4Address, destination: Ipv4Address, data: &[u8]) -> Vec<u8> {
+        let options = if self.mss.is_some() { 4 } else { 0 };
+        let length = HEADER_LENGTH + options + data.len();
+        let mut s = Vec::with_capacity(length);
+        s.extend_from_slice(&self.source_port.to_be_bytes());
+        s.extend_from_slice(&self.destination_port.to_be_bytes());
+        s.extend_from_slice(&self.sequence.to_be_bytes());
+        s.extend_from_slice(&self.acknowledgment.to_be_bytes());
+        s.push((((HEADER_LENGTH + options) / 4) as u8) << 4);
+        s.push(self.flags);
+        s.extend_from_slice(&self.window.to_be_bytes());
+        s.extend_from_slice(&[0, 0, 0, 0]);
+        if let Some(mss) = self.mss {
+            s.push(OPTION_MSS);
+            s.push(4);
+            s.extend_from_slice(&mss.to_be_bytes());
+        }
+        s.extend_from_slice(data);
+        let sum = pseudo_header_sum(source, destination, IpProtocol::Tcp, length);
+        let c = checksum_finish(checksum_add(sum, &s));
+        s[16..18].copy_from_slice(&c.to_be_bytes());
+        s
+    }
+}
+
+/// Build a reset from a local address to a remote address
+fn reset(
+    local: SocketAddress,
+    remote: SocketAddress,
+    sequence: u32,
+    acknowledgment: Option<u32>,
+) -> Vec<u8> {
+    TcpHeader {
+        source_port: local.port,
+        destination_port: remote.port,
+        sequence,
+        acknowledgment: acknowledgment.unwrap_or(0),
+        flags: if acknowledgment.is_some() {
+            RST | ACK
+        } else {
+            RST
+        },
+        window: 0,
+        mss: None,
+    }
+    .build(local.ip, remote.ip, &[])
+}
+
+/// Send segments from a local address to a remote address
+async fn transmit(local: SocketAddress, remote: SocketAddress, segments: Vec<Vec<u8>>) {
+    if segments.is_empty() {
+        return;
+    }
+    let Some(r) = ipv4::lookup_route(remote.ip).await else {
+        return;
+    };
+    for s in segments {
+        let _ = ipv4::send_packet(
+            &r.interface,
+            local.ip,
+            remote.ip,
+            r.next_hop,
+            IpProtocol::Tcp,
+            &s,
+        )
+        .await;
+    }
+}
+
+/// Pick an initial sequence number from a clock that advances every 4 microseconds
+fn initial_sequence(local: SocketAddress, remote: SocketAddress) -> u32 {
+    let clock = (crate::modules::time::uptime().as_micros() / 4) as u32;
+    let mix = u32::from(remote.ip)
+        .rotate_left(7)
+        .wrapping_add(((local.port as u32) << 16) | remote.port as u32)
+        .wrapping_mul(0x9e37_79b9);
+    clock
+        .wrapping_add(mix)
+        .wrapping_add(ISS_COUNTER.fetch_add(0x10000, Ordering::Relaxed))
+}
+
+/// The state of a connection, the transmission control block of rfc 793
+struct Tcb {
+    /// The state of the connection
+    state: TcpState,
+    /// The local address
+    local: SocketAddress,
+    /// The remote address
+    remote: SocketAddress,
+    /// The listener that will accept the connection once it is established
+    listener: Option<Arc<ListenerState>>,
+    /// The reason the connection was closed, if it was not closed normally
+    error: Option<TcpError>,
+    /// The initial send sequence number
+    iss: u32,
+    /// The oldest unacknowledged sequence number
+    snd_una: u32,
+    /// The next sequence number to send
+    snd_nxt: u32,
+    /// The highest sequence number sent, which differs from snd_nxt after a retransmission
+    snd_max: u32,
+    /// The send window of the other end
+    snd_wnd: u32,
+    /// The sequence number of the segment that last updated the send window
+    snd_wl1: u32,
+    /// The acknowledgment number of the segment that last updated the send window
+    snd_wl2: u32,
+    /// The sequence number of the first byte in the send buffer
+    data_start: u32,
+    /// The data that has not been acknowledged, starting at data_start
+    send_buffer: VecDeque<u8>,
+    /// The largest segment the other end accepts
+    mss: usize,
+    /// This end has closed, so a finish follows the data in the send buffer
+    fin_queued: bool,
+    /// A reset is to be sent, aborting the connection
+    rst_queued: bool,
+    /// A reset is to be sent in reply to an unacceptable acknowledgment, with the sequence number it needs
+    rst_for_ack: Option<u32>,
+    /// Small segments are held back while data is unacknowledged
+    nagle: bool,
+    /// The initial receive sequence number
+    irs: u32,
+    /// The next sequence number expected
+    rcv_nxt: u32,
+    /// The window most recently advertised
+    rcv_advertised: u32,
+    /// The received data that has not been read
+    receive_buffer: VecDeque<u8>,
+    /// Segments received ahead of rcv_nxt
+    out_of_order: Vec<(u32, Vec<u8>)>,
+    /// The sequence number of the finish from the other end
+    remote_fin: Option<u32>,
+    /// The finish from the other end has been received in order
+    fin_received: bool,
+    /// The smoothed round trip time
+    srtt: Option<Duration>,
+    /// The variation of the round trip time
+    rttvar: Duration,
+    /// The retransmission timeout
+    rto: Duration,
+    /// The sequence number being timed, with when it was sent
+    rtt_sample: Option<(u32, Instant)>,
+    /// When to retransmit the oldest unacknowledged segment
+    retransmit_deadline: Option<Instant>,
+    /// The number of retransmissions since the last acknowledgment of new data
+    retries: u32,
+    /// The window is closed, so the next output probes it with a single byte
+    probe: bool,
+    /// When to probe the closed window of the other end
+    persist_deadline: Option<Instant>,
+    /// The time between probes of a closed window, doubled after each probe
+    persist_timeout: Duration,
+    /// An acknowledgment must be sent now
+    ack_now: bool,
+    /// The number of segments received and not acknowledged yet
+    ack_pending: u32,
+    /// When a delayed acknowledgment must be sent
+    ack_deadline: Option<Instant>,
+    /// When the connection leaves the time wait state
+    time_wait_deadline: Option<Instant>,
+}
+
+impl Tcb {
+    /// Construct a connection that is about to send or answer a synchronize
+    fn new(state: TcpState, local: SocketAddress, remote: SocketAddress) -> Self {
+        let iss = initial_sequence(local, remote);
+        Self {
+            state,
+            local,
+            remote,
+            listener: None,
+            error: None,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_max: iss,
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            data_start: iss.wrapping_add(1),
+            send_buffer: VecDeque::new(),
+            mss: DEFAULT_MSS,
+            fin_queued: false,
+            rst_queued: false,
+            rst_for_ack: None,
+            nagle: true,
+            irs: 0,
+            rcv_nxt: 0,
+            rcv_advertised: 0,
+            receive_buffer: VecDeque::new(),
+            out_of_order: Vec::new(),
+            remote_fin: None,
+            fin_received: false,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+            rtt_sample: None,
+            retransmit_deadline: None,
+            retries: 0,
+            probe: false,
+            persist_deadline: None,
+            persist_timeout: Duration::ZERO,
+            ack_now: false,
+            ack_pending: 0,
+            ack_deadline: None,
+            time_wait_deadline: None,
+        }
+    }
+
+    /// The amount of data that can be received
+    fn receive_window(&self) -> u32 {
+        (RECEIVE_BUFFER - self.receive_buffer.len()) as u32
+    }
+
+    /// The sequence number after the last byte of data in the send buffer
+    fn data_end(&self) -> u32 {
+        self.data_start.wrapping_add(self.send_buffer.len() as u32)
+    }
+
+    /// Returns true if the application can add data to the send buffer
+    fn can_write(&self) -> bool {
+        matches!(self.state, TcpState::Established | TcpState::CloseWait) && !self.fin_queued
+    }
+
+    /// Use the maximum segment size option sent by the other end
+    fn set_mss(&mut self, mss: Option<u16>) {
+        self.mss = mss
+            .map(|m| m as usize)
+            .unwrap_or(DEFAULT_MSS)
+            .clamp(64, LOCAL_MSS);
+    }
+
+    /// Close the connection because of an error
+    fn abort(&mut self, e: Option<TcpError>) {
+        self.state = TcpState::Closed;
+        self.error = self.error.or(e);
+        self.retransmit_deadline = None;
+        self.persist_deadline = None;
+        self.ack_deadline = None;
+    }
+
+    /// Enter the time wait state
+    fn enter_time_wait(&mut self, now: Instant) {
+        self.state = TcpState::TimeWait;
+        self.retransmit_deadline = None;
+        self.time_wait_deadline = Some(now + TIME_WAIT);
+    }
+
+    /// Update the round trip time estimate with a new measurement, as described in rfc 6298
+    fn measure_rtt(&mut self, r: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(r);
+                self.rttvar = r / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(r)) / 4;
+                self.srtt = Some((srtt * 7 + r) / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or(r);
+        self.rto = (srtt + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Process the acknowledgment field of a segment, returning false if the segment must be dropped
+    fn process_ack(&mut self, seg: &TcpHeader, now: Instant) -> bool {
+        let ack = seg.acknowledgment;
+        if after(ack, self.snd_max) {
+            self.ack_now = true;
+            return false;
+        }
+        if after(ack, self.snd_una) {
+            if after(ack, self.data_start) {
+                let n = (ack.wrapping_sub(self.data_start) as usize).min(self.send_buffer.len());
+                self.send_buffer.drain(..n);
+                self.data_start = self.data_start.wrapping_add(n as u32);
+            }
+            self.snd_una = ack;
+            if after(ack, self.snd_nxt) {
+                self.snd_nxt = ack;
+            }
+            if let Some((s, t)) = self.rtt_sample {
+                if after(ack, s) {
+                    self.measure_rtt(now.duration_since(t));
+                    self.rtt_sample = None;
+                }
+            }
+            self.retries = 0;
+            self.retransmit_deadline = if self.snd_una == self.snd_max {
+                None
+            } else {
+                Some(now + self.rto)
+            };
+        }
+        if before(self.snd_wl1, seg.sequence)
+            || (self.snd_wl1 == seg.sequence && !before(ack, self.snd_wl2))
+        {
+            self.snd_wnd = seg.window as u32;
+            self.snd_wl1 = seg.sequence;
+            self.snd_wl2 = ack;
+            if self.snd_wnd != 0 {
+                self.probe = false;
+                self.persist_deadline = None;
+                self.persist_timeout = Duration::ZERO;
+            }
+        }
+        true
+    }
+
+    /// Returns true when the finish of this end has been acknowledged
+    fn fin_acked(&self) -> bool {
+        self.fin_queued
+            && self.send_buffer.is_empty()
+            && self.snd_una == self.data_end().wrapping_add(1)
+    }
+
+    /// Add received data to the receive buffer, along with any segments received early that now fit
+    fn receive_data(&mut self, sequence: u32, data: &[u8]) {
+        let window = self.receive_window() as usize;
+        if sequence == self.rcv_nxt {
+            let n = data.len().min(window);
+            self.receive_buffer.extend(&data[..n]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(n as u32);
+            loop {
+                let rcv_nxt = self.rcv_nxt;
+                let Some(i) = self.out_of_order.iter().position(|(s, d)| {
+                    !after(*s, rcv_nxt) && after(s.wrapping_add(d.len() as u32), rcv_nxt)
+                }) else {
+                    break;
+                };
+                let (s, d) = self.out_of_order.swap_remove(i);
+                let skip = rcv_nxt.wrapping_sub(s) as usize;
+                let n = (d.len() - skip).min(self.receive_window() as usize);
+                self.receive_buffer.extend(&d[skip..skip + n]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(n as u32);
+            }
+            let rcv_nxt = self.rcv_nxt;
+            self.out_of_order
+                .retain(|(s, d)| after(s.wrapping_add(d.len() as u32), rcv_nxt));
+            self.ack_pending += 1;
+            if self.ack_pending >= 2 || !self.out_of_order.is_empty() {
+                self.ack_now = true;
+            }
+        } else {
+            if self.out_of_order.len() < MAX_OUT_OF_ORDER {
+                self.out_of_order.push((sequence, data.to_vec()));
+            }
+            // A duplicate acknowledgment tells the other end something is missing
+            self.ack_now = true;
+        }
+    }
+
+    /// Process a segment received in the syn sent state
+    fn segment_syn_sent(&mut self, seg: &TcpHeader, now: Instant) {
+        let ack = seg.flags & ACK != 0;
+        if ack && (!after(seg.acknowledgment, self.iss) || after(seg.acknowledgment, self.snd_max))
+        {
+            if seg.flags & RST == 0 {
+                self.rst_for_ack = Some(seg.acknowledgment);
+            }
+            return;
+        }
+        if seg.flags & RST != 0 {
+            if ack {
+                self.abort(Some(TcpError::ConnectionRefused));
+            }
+            return;
+        }
+        if seg.flags & SYN == 0 {
+            return;
+        }
+        self.irs = seg.sequence;
+        self.rcv_nxt = seg.sequence.wrapping_add(1);
+        self.set_mss(seg.mss);
+        self.snd_wl1 = seg.sequence;
+        self.snd_wl2 = seg.acknowledgment;
+        self.snd_wnd = seg.window as u32;
+        if ack {
+            self.process_ack(seg, now);
+            self.state = TcpState::Established;
+        } else {
+            // Both ends opened at the same time, so the synchronize is sent again with an acknowledgment
+            self.state = TcpState::SynReceived;
+            self.snd_nxt = self.iss;
+        }
+        self.ack_now = true;
+    }
+
+    /// Process a received segment, returning true if the connection has just been established from a listener
+    fn segment(&mut self, seg: &TcpHeader, data: &[u8], now: Instant) -> bool {
+        if self.state == TcpState::SynSent {
+            self.segment_syn_sent(seg, now);
+            return false;
+        }
+        if self.state == TcpState::Closed {
+            return false;
+        }
+        // A retransmitted synchronize means the reply was lost
+        if self.state == TcpState::SynReceived && seg.flags & SYN != 0 && seg.sequence == self.irs {
+            self.snd_nxt = self.iss;
+            return false;
+        }
+        let window = self.receive_window();
+        let length = seg.length(data);
+        let in_window =
+            |s: u32| !before(s, self.rcv_nxt) && before(s, self.rcv_nxt.wrapping_add(window));
+        let acceptable = match (length, window) {
+            (0, 0) => seg.sequence == self.rcv_nxt,
+            (0, _) => in_window(seg.sequence),
+            (_, 0) => false,
+            _ => in_window(seg.sequence) || in_window(seg.sequence.wrapping_add(length - 1)),
+        };
+        if !acceptable {
+            if seg.flags & RST == 0 {
+                self.ack_now = true;
+            }
+            return false;
+        }
+        if seg.flags & RST != 0 {
+            if self.state == TcpState::SynReceived && self.listener.is_some() {
+                self.abort(None);
+            } else {
+                self.abort(Some(TcpError::ConnectionReset));
+            }
+            return false;
+        }
+        if seg.flags & SYN != 0 {
+            // A synchronize inside the window is answered with an acknowledgment, as recommended by rfc 5961
+            self.ack_now = true;
+            return false;
+        }
+        if seg.flags & ACK == 0 {
+            return false;
+        }
+        let mut established = false;
+        if self.state == TcpState::SynReceived {
+            if after(seg.acknowledgment, self.snd_una) && !after(seg.acknowledgment, self.snd_max) {
+                self.state = TcpState::Established;
+                self.snd_wnd = seg.window as u32;
+                self.snd_wl1 = seg.sequence;
+                self.snd_wl2 = seg.acknowledgment;
+                established = self.listener.is_some();
+            } else {
+                self.rst_for_ack = Some(seg.acknowledgment);
+                return false;
+            }
+        }
+        if !self.process_ack(seg, now) {
+            return established;
+        }
+        if self.fin_acked() {
+            match self.state {
+                TcpState::FinWait1 => self.state = TcpState::FinWait2,
+                TcpState::Closing => self.enter_time_wait(now),
+                TcpState::LastAck => self.abort(None),
+                _ => {}
+            }
+        }
+        if seg.flags & FIN != 0 {
+            self.remote_fin = Some(seg.sequence.wrapping_add(data.len() as u32));
+        }
+        let receiving = matches!(
+            self.state,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        );
+        if receiving && !data.is_empty() {
+            // Drop the part that was already received
+            let skip = if before(seg.sequence, self.rcv_nxt) {
+                (self.rcv_nxt.wrapping_sub(seg.sequence) as usize).min(data.len())
+            } else {
+                0
+            };
+            let sequence = seg.sequence.wrapping_add(skip as u32);
+            if skip < data.len() {
+                self.receive_data(sequence, &data[skip..]);
+            } else {
+                self.ack_now = true;
+            }
+        }
+        if !self.fin_received && self.remote_fin == Some(self.rcv_nxt) {
+            self.fin_received = true;
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.ack_now = true;
+            match self.state {
+                TcpState::SynReceived | TcpState::Established => self.state = TcpState::CloseWait,
+                TcpState::FinWait1 => {
+                    if self.fin_acked() {
+                        self.enter_time_wait(now);
+                    } else {
+                        self.state = TcpState::Closing;
+                    }
+                }
+                TcpState::FinWait2 => self.enter_time_wait(now),
+                _ => {}
+            }
+        } else if self.state == TcpState::TimeWait && seg.flags & FIN != 0 {
+            self.enter_time_wait(now);
+            self.ack_now = true;
+        }
+        if !self.ack_now && self.ack_pending > 0 && self.ack_deadline.is_none() {
+            self.ack_deadline = Some(now + DELAYED_ACK);
+        }
+        established
+    }
+
+    /// Build a segment from this connection
+    fn segment_out(&mut self, sequence: u32, flags: u8, mss: Option<u16>, data: &[u8]) -> Vec<u8> {
+        let window = self.receive_window();
+        let flags = if self.state == TcpState::SynSent {
+            flags
+        } else {
+            self.rcv_advertised = window;
+            self.ack_now = false;
+            self.ack_pending = 0;
+            self.ack_deadline = None;
+            flags | ACK
+        };
+        TcpHeader {
+            source_port: self.local.port,
+            destination_port: self.remote.port,
+            sequence,
+            acknowledgment: self.rcv_nxt,
+            flags,
+            window: window.min(u16::MAX as u32) as u16,
+            mss,
+        }
+        .build(self.local.ip, self.remote.ip, data)
+    }
+
+    /// Record that a segment has been sent, starting the retransmission timer and timing the round trip
+    fn sent(&mut self, sequence: u32, length: u32, now: Instant) {
+        let end = sequence.wrapping_add(length);
+        self.snd_nxt = end;
+        if after(end, self.snd_max) {
+            self.snd_max = end;
+        }
+        if self.rtt_sample.is_none() && self.retries == 0 {
+            self.rtt_sample = Some((sequence, now));
+        }
+        if self.retransmit_deadline.is_none() {
+            self.retransmit_deadline = Some(now + self.rto);
+        }
+    }
+
+    /// Produce the segments that can be sent now: synchronize, data allowed by the window and nagle, finish, and acknowledgments
+    fn output(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        if let Some(ack) = self.rst_for_ack.take() {
+            out.push(reset(self.local, self.remote, ack, None));
+        }
+        if self.rst_queued {
+            self.rst_queued = false;
+            let s = self.snd_nxt;
+            out.push(self.segment_out(s, RST, None, &[]));
+            self.abort(None);
+            return out;
+        }
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived => {
+                if self.snd_nxt == self.iss {
+                    let flags = if self.state == TcpState::SynSent {
+                        SYN
+                    } else {
+                        SYN | ACK
+                    };
+                    let s = self.iss;
+                    out.push(self.segment_out(s, flags, Some(LOCAL_MSS as u16), &[]));
+                    self.sent(s, 1, now);
+                }
+            }
+            TcpState::Established
+            | TcpState::CloseWait
+            | TcpState::FinWait1
+            | TcpState::Closing
+            | TcpState::LastAck => {
+                if self.snd_wnd == 0 && self.unacknowledged_data() {
+                    self.persist(now, &mut out);
+                }
+                while self.snd_wnd != 0 {
+                    let offset = self.snd_nxt.wrapping_sub(self.data_start) as usize;
+                    if offset >= self.send_buffer.len() {
+                        break;
+                    }
+                    let unsent = self.send_buffer.len() - offset;
+                    let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+                    let mut window = self.snd_wnd.saturating_sub(in_flight) as usize;
+                    // Only one segment is in flight while recovering from a retransmission timeout
+                    if self.retries > 0 {
+                        window = window.min(self.mss.saturating_sub(in_flight as usize));
+                    }
+                    let length = unsent.min(self.mss).min(window);
+                    if length == 0 {
+                        break;
+                    }
+                    if self.nagle && length < self.mss && in_flight > 0 && !self.fin_queued {
+                        break;
+                    }
+                    let data: Vec<u8> = self
+                        .send_buffer
+                        .range(offset..offset + length)
+                        .copied()
+                        .collect();
+                    let flags = if length == unsent { PSH } else { 0 };
+                    let s = self.snd_nxt;
+                    out.push(self.segment_out(s, flags, None, &data));
+                    self.sent(s, length as u32, now);
+                }
+                if self.fin_queued && self.snd_nxt == self.data_end() {
+                    let s = self.snd_nxt;
+                    out.push(self.segment_out(s, FIN, None, &[]));
+                    self.sent(s, 1, now);
+                }
+            }
+            _ => {}
+        }
+        if out.is_empty() && self.ack_now && self.state != TcpState::Closed {
+            let s = self.snd_nxt;
+            out.push(self.segment_out(s, 0, None, &[]));
+        }
+        out
+    }
+
+    /// Returns true if the send buffer holds data the other end has not acknowledged
+    fn unacknowledged_data(&self) -> bool {
+        (self.snd_una.wrapping_sub(self.data_start) as usize) < self.send_buffer.len()
+    }
+
+    /// Hold back data while the window of the other end is closed, probing the window with a single byte when the persist timer expires.
+    /// Probes are not retransmissions, so a peer that keeps acknowledging them with a closed window is never timed out.
+    fn persist(&mut self, now: Instant, out: &mut Vec<Vec<u8>>) {
+        // Data sent before the window closed is sent again once it opens
+        self.snd_nxt = self.snd_una;
+        self.retransmit_deadline = None;
+        self.retries = 0;
+        self.rtt_sample = None;
+        if self.probe {
+            self.probe = false;
+            let s = self.snd_una;
+            let offset = s.wrapping_sub(self.data_start) as usize;
+            let data = [self.send_buffer[offset]];
+            out.push(self.segment_out(s, 0, None, &data));
+            if after(s.wrapping_add(1), self.snd_max) {
+                self.snd_max = s.wrapping_add(1);
+            }
+            self.persist_timeout = (self.persist_timeout * 2).min(MAX_RTO);
+            self.persist_deadline = Some(now + self.persist_timeout);
+        } else if self.persist_deadline.is_none() {
+            if self.persist_timeout.is_zero() {
+                self.persist_timeout = self.rto;
+            }
+            self.persist_deadline = Some(now + self.persist_timeout);
+        }
+    }
+
+    /// The earliest instant that [Self::timers] has something to do
+    fn next_deadline(&self) -> Option<Instant> {
+        [
+            self.retransmit_deadline,
+            self.persist_deadline,
+            self.ack_deadline,
+            self.time_wait_deadline,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Handle the timers of the connection
+    fn timers(&mut self, now: Instant) {
+        if self.ack_deadline.is_some_and(|d| now >= d) {
+            self.ack_now = true;
+        }
+        if self.time_wait_deadline.is_some_and(|d| now >= d) {
+            self.abort(None);
+            return;
+        }
+        if self.persist_deadline.is_some_and(|d| now >= d) {
+            self.persist_deadline = None;
+            self.probe = true;
+        }
+        if self.retransmit_deadline.is_some_and(|d| now >= d) {
+            self.retransmit_deadline = None;
+            if self.snd_una == self.snd_max {
+                return;
+            }
+            self.retries += 1;
+            if self.retries > MAX_RETRIES {
+                self.rst_queued = true;
+                self.error = Some(TcpError::TimedOut);
+                return;
+            }
+            self.rto = (self.rto * 2).min(MAX_RTO);
+            // Karn's algorithm, retransmitted segments are not timed
+            self.rtt_sample = None;
+            self.snd_nxt = self.snd_una;
+        }
+    }
+
+    /// Send a window update when reading has opened the window by a useful amount
+    fn window_update_needed(&self) -> bool {
+        let window = self.receive_window();
+        matches!(
+            self.state,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        ) && window.saturating_sub(self.rcv_advertised)
+            >= (2 * self.mss as u32).min(RECEIVE_BUFFER as u32 / 2)
+    }
+
+    /// Close this end of the connection, sending a finish after the data already written
+    fn close(&mut self) {
+        match self.state {
+            TcpState::Established => {
+                self.fin_queued = true;
+                self.state = TcpState::FinWait1;
+            }
+            TcpState::CloseWait => {
+                self.fin_queued = true;
+                self.state = TcpState::LastAck;
+            }
+            TcpState::SynSent => self.abort(None),
+            TcpState::SynReceived => {
+                self.fin_queued = true;
+                self.state = TcpState::FinWait1;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A connection shared between the stream, the stack task, and the timer task
+struct Connection {
+    /// The state of the connection
+    tcb: Locked<Tcb>,
+    /// The tasks waiting for something to happen on the connection
+    wakers: Locked<Vec<Waker>>,
+}
+
+impl Connection {
+    /// Construct a connection
+    fn new(tcb: Tcb) -> Self {
+        Self {
+            tcb: Locked::new(tcb),
+            wakers: Locked::new(Vec::new()),
+        }
+    }
+
+    /// Wake the tasks waiting on the connection
+    fn wake(&self) {
+        let wakers = core::mem::take(&mut *self.wakers.sync_lock());
+        for w in wakers {
+            w.wake();
+        }
+    }
+
+    /// Produce the segments that can be sent now and send them
+    async fn send_output(&self) {
+        let (local, remote, segments) = {
+            let mut t = self.tcb.sync_lock();
+            let s = t.output(Instant::now());
+            (t.local, t.remote, s)
+        };
+        transmit(local, remote, segments).await;
+    }
+
+    /// Send the segments that can be sent now, and let [timer_task] pick up the deadlines that were set
+    async fn flush(&self) {
+        self.send_output().await;
+        TIMER_WORK.notify();
+    }
+
+    /// Wait until the condition is met, returning its result
+    async fn wait<T>(&self, mut f: impl FnMut(&mut Tcb) -> Option<T>) -> T {
+        core::future::poll_fn(|cx| {
+            if let Some(r) = f(&mut self.tcb.sync_lock()) {
+                return Poll::Ready(r);
+            }
+            crate::add_waker(&mut self.wakers.sync_lock(), cx.waker());
+            match f(&mut self.tcb.sync_lock()) {
+                Some(r) => Poll::Ready(r),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+/// The state of a listener shared with the stack task
+struct ListenerState {
+    /// The local address, with an unspecified address to accept connections to any address
+    local: SocketAddress,
+    /// The largest number of connections that can wait to be accepted
+    backlog: usize,
+    /// The established connections waiting to be accepted
+    pending: Locked<VecDeque<Arc<Connection>>>,
+    /// The tasks waiting for a connection
+    wakers: Locked<Vec<Waker>>,
+}
+
+/// A socket that accepts incoming connections. Connections that have not been accepted are reset when the listener is dropped.
+pub struct TcpListener {
+    /// The state shared with the stack task
+    state: Arc<ListenerState>,
+}
+
+impl TcpListener {
+    /// Listen for connections on a local address. An unspecified address accepts connections to any address of the system.
+    pub async fn listen(local: SocketAddress, backlog: usize) -> Result<Self, TcpError> {
+        let mut listeners = LISTENERS.sync_lock();
+        if listeners.contains_key(&local.port) {
+            return Err(TcpError::AddressInUse);
+        }
+        let state = Arc::new(ListenerState {
+            local,
+            backlog: backlog.max(1),
+            pending: Locked::new(VecDeque::new()),
+            wakers: Locked::new(Vec::new()),
+        });
+        listeners.insert(local.port, state.clone());
+        Ok(Self { state })
+    }
+
+    /// The local address of the listener
+    pub fn local_address(&self) -> SocketAddress {
+        self.state.local
+    }
+
+    /// Wait for a connection to be established
+    pub async fn accept(&self) -> Result<TcpStream, TcpError> {
+        let conn = core::future::poll_fn(|cx| {
+            if let Some(c) = self.state.pending.sync_lock().pop_front() {
+                return Poll::Ready(c);
+            }
+            crate::add_waker(&mut self.state.wakers.sync_lock(), cx.waker());
+            match self.state.pending.sync_lock().pop_front() {
+                Some(c) => Poll::Ready(c),
+                None => Poll::Pending,
+            }
+        })
+        .await;
+        Ok(TcpStream { conn })
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        LISTENERS.sync_lock().remove(&self.state.local.port);
+        for c in self.state.pending.sync_lock().drain(..) {
+            c.tcb.sync_lock().rst_queued = true;
+        }
+        // Connections that are still being set up are reset too, and forgotten by the timer task
+        let conns: Vec<Arc<Connection>> = CONNECTIONS.sync_lock().values().cloned().collect();
+        for c in conns {
+            let mut t = c.tcb.sync_lock();
+            if t.listener
+                .as_ref()
+                .is_some_and(|l| Arc::ptr_eq(l, &self.state))
+            {
+                t.listener = None;
+                t.rst_queued = true;
+            }
+        }
+        TIMER_WORK.notify();
+    }
+}
+
+/// A tcp connection. The connection is closed when the stream is dropped.
+pub struct TcpStream {
+    /// The connection
+    conn: Arc<Connection>,
+}
+
+impl TcpStream {
+    /// Open a connection to a remote address
+    pub async fn connect(remote: SocketAddress) -> Result<Self, TcpError> {
+        let r = ipv4::lookup_route(remote.ip)
+            .await
+            .ok_or(TcpError::NoRoute)?;
+        let conn = {
+            let mut conns = CONNECTIONS.sync_lock();
+            let listeners = LISTENERS.sync_lock();
+            let local = (0..EPHEMERAL_COUNT)
+                .map(|_| {
+                    let port = EPHEMERAL_START
+                        + NEXT_EPHEMERAL.fetch_add(1, Ordering::Relaxed) % EPHEMERAL_COUNT;
+                    SocketAddress::new(r.source, port)
+                })
+                .find(|l| !listeners.contains_key(&l.port) && !conns.contains_key(&(*l, remote)))
+                .ok_or(TcpError::NoFreePort)?;
+            let conn = Arc::new(Connection::new(Tcb::new(TcpState::SynSent, local, remote)));
+            conns.insert((local, remote), conn.clone());
+            conn
+        };
+        conn.flush().await;
+        let s = Self { conn };
+        s.conn
+            .wait(|t| match t.state {
+                TcpState::SynSent | TcpState::SynReceived => None,
+                TcpState::Closed => Some(Err(t.error.unwrap_or(TcpError::ConnectionRefused))),
+                _ => Some(Ok(())),
+            })
+            .await?;
+        Ok(s)
+    }
+
+    /// The local address of the connection
+    pub fn local_address(&self) -> SocketAddress {
+        self.conn.tcb.sync_lock().local
+    }
+
+    /// The remote address of the connection
+    pub fn peer_address(&self) -> SocketAddress {
+        self.conn.tcb.sync_lock().remote
+    }
+
+    /// The state of the connection
+    pub fn state(&self) -> TcpState {
+        self.conn.tcb.sync_lock().state
+    }
+
+    /// Disable or enable the nagle algorithm, which holds back small segments while data is unacknowledged
+    pub fn set_nodelay(&self, nodelay: bool) {
+        self.conn.tcb.sync_lock().nagle = !nodelay;
+    }
+
+    /// Wait for data and read it into the buffer, returning the amount read. Returns 0 once the other end has closed and all data has been read.
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize, TcpError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let r = self
+            .conn
+            .wait(|t| {
+                if !t.receive_buffer.is_empty() {
+                    let n = buf.len().min(t.receive_buffer.len());
+                    for (d, s) in buf.iter_mut().zip(t.receive_buffer.drain(..n)) {
+                        *d = s;
+                    }
+                    if t.window_update_needed() {
+                        t.ack_now = true;
+                    }
+                    Some(Ok(n))
+                } else if t.fin_received {
+                    Some(Ok(0))
+                } else if let Some(e) = t.error {
+                    Some(Err(e))
+                } else if t.state == TcpState::Closed {
+                    Some(Err(TcpError::NotConnected))
+                } else {
+                    None
+                }
+            })
+            .await;
+        self.conn.flush().await;
+        r
+    }
+
+    /// Write all of the data, waiting for room in the send buffer as needed
+    pub async fn write(&self, data: &[u8]) -> Result<usize, TcpError> {
+        let mut written = 0;
+        while written < data.len() {
+            let n = self
+                .conn
+                .wait(|t| {
+                    if !t.can_write() {
+                        return Some(Err(t.error.unwrap_or(TcpError::NotConnected)));
+                    }
+                    let room = SEND_BUFFER - t.send_buffer.len();
+                    if room == 0 {
+                        return None;
+                    }
+                    let n = room.min(data.len() - written);
+                    t.send_buffer.extend(&data[written..written + n]);
+                    Some(Ok(n))
+                })
+                .await?;
+            written += n;
+            self.conn.flush().await;
+        }
+        Ok(written)
+    }
+
+    /// Close the sending side of the connection. Data already written is still delivered, and data can still be read until the other end closes.
+    pub async fn close(&self) -> Result<(), TcpError> {
+        {
+            let mut t = self.conn.tcb.sync_lock();
+            if let Some(e) = t.error {
+                return Err(e);
+            }
+            t.close();
+        }
+        self.conn.flush().await;
+        Ok(())
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        // The timer task sends the finish
+        self.conn.tcb.sync_lock().close();
+        TIMER_WORK.notify();
+    }
+}
+
+/// Build a reset in reply to a segment that does not belong to any connection
+fn reset_reply(
+    local: SocketAddress,
+    remote: SocketAddress,
+    seg: &TcpHeader,
+    data: &[u8],
+) -> Vec<u8> {
+    if seg.flags & ACK != 0 {
+        reset(local, remote, seg.acknowledgment, None)
+    } else {
+        reset(
+            local,
+            remote,
+            0,
+            Some(seg.sequence.wrapping_add(seg.length(data))),
+        )
+    }
+}
+
+/// Process a received tcp segment
+pub async fn handle(h: &Ipv4Header, payload: &[u8]) {
+    let Some((seg, data)) = TcpHeader::parse(h, payload) else {
+        return;
+    };
+    let local = SocketAddress::new(h.destination, seg.destination_port);
+    let remote = SocketAddress::new(h.source, seg.source_port);
+    let now = Instant::now();
+    let conn = CONNECTIONS.sync_lock().get(&(local, remote)).cloned();
+    if let Some(conn) = conn {
+        let established = conn.tcb.sync_lock().segment(&seg, data, now);
+        if established {
+            let listener = conn.tcb.sync_lock().listener.take();
+            if let Some(l) = listener {
+                l.pending.sync_lock().push_back(conn.clone());
+                let wakers = core::mem::take(&mut *l.wakers.sync_lock());
+                for w in wakers {
+                    w.wake();
+                }
+            }
+        }
+        conn.flush().await;
+        conn.wake();
+        return;
+    }
+    if seg.flags & RST != 0 || h.destination.is_broadcast() || h.destination.is_multicast() {
+        return;
+    }
+    let listener = LISTENERS
+        .sync_lock()
+        .get(&seg.destination_port)
+        .filter(|l| l.local.ip.is_unspecified() || l.local.ip == h.destination)
+        .cloned();
+    let Some(l) = listener.filter(|_| seg.flags & (SYN | ACK) == SYN) else {
+        transmit(
+            local,
+            remote,
+            alloc::vec![reset_reply(local, remote, &seg, data)],
+        )
+        .await;
+        return;
+    };
+    // Connections that are established or still being set up both count against the backlog
+    let queued = l.pending.sync_lock().len()
+        + CONNECTIONS
+            .sync_lock()
+            .values()
+            .filter(|c| {
+                c.tcb
+                    .sync_lock()
+                    .listener
+                    .as_ref()
+                    .is_some_and(|cl| cl.local.port == l.local.port)
+            })
+            .count();
+    if queued >= l.backlog {
+        return;
+    }
+    let mut t = Tcb::new(TcpState::SynReceived, local, remote);
+    t.listener = Some(l);
+    t.irs = seg.sequence;
+    t.rcv_nxt = seg.sequence.wrapping_add(1);
+    t.snd_wnd = seg.window as u32;
+    t.snd_wl1 = seg.sequence;
+    t.set_mss(seg.mss);
+    let conn = Arc::new(Connection::new(t));
+    CONNECTIONS
+        .sync_lock()
+        .insert((local, remote), conn.clone());
+    conn.flush().await;
+}
+
+/// The task that retransmits segments, sends delayed acknowledgments and finishes, and forgets closed connections.
+/// It sleeps until the earliest deadline of any connection, or until a connection has new work.
+pub async fn timer_task() {
+    loop {
+        let generation = TIMER_WORK.generation();
+        let now = Instant::now();
+        let conns: Vec<Arc<Connection>> = CONNECTIONS.sync_lock().values().cloned().collect();
+        let mut deadline: Option<Instant> = None;
+        for c in conns {
+            let state = {
+                let mut t = c.tcb.sync_lock();
+                t.timers(now);
+                t.state
+            };
+            c.send_output().await;
+            let t = c.tcb.sync_lock();
+            if t.state != state {
+                c.wake();
+            }
+            deadline = deadline.into_iter().chain(t.next_deadline()).min();
+        }
+        CONNECTIONS
+            .sync_lock()
+            .retain(|_, c| c.tcb.sync_lock().state != TcpState::Closed);
+        let work = core::pin::pin!(TIMER_WORK.changed(generation));
+        match deadline {
+            Some(d) => {
+                let expired = core::pin::pin!(crate::modules::time::sleep_until(d));
+                futures::future::select(work, expired).await;
+            }
+            None => work.await,
+        }
+    }
+}
+
+/// Test the comparison of sequence numbers across the wrap of the sequence space
+#[doors_macros::doors_test]
+fn tcp_sequence_test() -> Result<(), ()> {
+    assert!(before(1, 2));
+    assert!(!before(2, 1));
+    assert!(!before(5, 5));
+    assert!(before(0xffff_fff0, 0x10));
+    assert!(after(0x10, 0xffff_fff0));
+    assert!(!after(5, 5));
+    assert!(after(0x8000_0000, 1));
+    Ok(())
+}
+
+/// Test building and parsing segment headers, with and without options
+#[doors_macros::doors_test]
+fn tcp_header_test() -> Result<(), ()> {
+    let source = Ipv4Address::new(10, 0, 2, 15);
+    let destination = Ipv4Address::new(10, 0, 2, 2);
+    let ip = Ipv4Header {
+        tos: 0,
+        identification: 0,
+        dont_fragment: false,
+        more_fragments: false,
+        fragment_offset: 0,
+        ttl: 64,
+        protocol: IpProtocol::Tcp,
+        source,
+        destination,
+    };
+    let h = TcpHeader {
+        source_port: 49152,
+        destination_port: 80,
+        sequence: 0x1234_5678,
+        acknowledgment: 0x9abc_def0,
+        flags: SYN | ACK,
+        window: 8192,
+        mss: Some(1460),
+    };
+    let s = h.build(source, destination, b"hi");
+    assert_eq!(s.len(), HEADER_LENGTH + 4 + 2);
+    let (p, data) = TcpHeader::parse(&ip, &s).ok_or(())?;
+    assert_eq!(p.source_port, 49152);
+    assert_eq!(p.destination_port, 80);
+    assert_eq!(p.sequence, 0x1234_5678);
+    assert_eq!(p.acknowledgment, 0x9abc_def0);
+    assert_eq!(p.flags, SYN | ACK);
+    assert_eq!(p.window, 8192);
+    assert_eq!(p.mss, Some(1460));
+    assert_eq!(data, b"hi");
+    assert_eq!(p.length(data), 3);
+
+    let mut bad = s.clone();
+    bad[20 + 4] ^= 1;
+    assert!(TcpHeader::parse(&ip, &bad).is_none());
+
+    // A no operation, a window scale option and the maximum segment size, with the checksum filled in
+    let mut s = h.build(source, destination, &[]);
+    s.truncate(HEADER_LENGTH);
+    s.extend_from_slice(&[1, 3, 3, 7, OPTION_MSS, 4, 0x02, 0x18, 0, 0, 0, 0]);
+    s[12] = (32 / 4) << 4;
+    s[16..18].copy_from_slice(&[0, 0]);
+    let sum = pseudo_header_sum(source, destination, IpProtocol::Tcp, s.len());
+    let c = checksum_finish(checksum_add(sum, &s));
+    s[16..18].copy_from_slice(&c.to_be_bytes());
+    let (p, data) = TcpHeader::parse(&ip, &s).ok_or(())?;
+    assert_eq!(p.mss, Some(0x218));
+    assert!(data.is_empty());
+
+    // An option whose length runs past the header is rejected
+    s[HEADER_LENGTH + 2] = 20;
+    s[16..18].copy_from_slice(&[0, 0]);
+    let c = checksum_finish(checksum_add(sum, &s));
+    s[16..18].copy_from_slice(&c.to_be_bytes());
+    assert!(TcpHeader::parse(&ip, &s).is_none());
+    Ok(())
+}
+
+/// Test the states of a connection that is opened and then closed by this end
+#[doors_macros::doors_test]
+fn tcp_state_test() -> Result<(), ()> {
+    let local = SocketAddress::new(Ipv4Address::new(10, 0, 2, 15), 49152);
+    let remote = SocketAddress::new(Ipv4Address::new(10, 0, 2, 2), 80);
+    let now = Instant::now();
+    let segment = |sequence, acknowledgment, flags| TcpHeader {
+        source_port: remote.port,
+        destination_port: local.port,
+        sequence,
+        acknowledgment,
+        flags,
+        window: 8192,
+        mss: None,
+    };
+
+    let mut t = Tcb::new(TcpState::SynSent, local, remote);
+    let iss = t.iss;
+    assert_eq!(t.output(now).len(), 1);
+    assert_eq!(t.snd_nxt, iss.wrapping_add(1));
+    assert!(t.retransmit_deadline.is_some());
+
+    let mut synack = segment(1000, iss.wrapping_add(1), SYN | ACK);
+    synack.mss = Some(1200);
+    t.segment(&synack, &[], now);
+    assert_eq!(t.state, TcpState::Established);
+    assert_eq!(t.rcv_nxt, 1001);
+    assert_eq!(t.mss, 1200);
+    assert!(t.retransmit_deadline.is_none());
+    assert_eq!(t.output(now).len(), 1);
+    assert!(!t.ack_now);
+
+    t.close();
+    assert_eq!(t.state, TcpState::FinWait1);
+    assert_eq!(t.output(now).len(), 1);
+    assert_eq!(t.snd_nxt, iss.wrapping_add(2));
+
+    t.segment(&segment(1001, iss.wrapping_add(2), ACK), &[], now);
+    assert_eq!(t.state, TcpState::FinWait2);
+    t.segment(&segment(1001, iss.wrapping_add(2), FIN | ACK), &[], now);
+    assert_eq!(t.state, TcpState::TimeWait);
+    assert_eq!(t.rcv_nxt, 1002);
+    assert_eq!(t.next_deadline(), Some(now + TIME_WAIT));
+    t.timers(now + TIME_WAIT);
+    assert_eq!(t.state, TcpState::Closed);
+    assert_eq!(t.error, None);
+
+    // A reset that acknowledges the synchronize refuses the connection
+    let mut t = Tcb::new(TcpState::SynSent, local, remote);
+    let iss = t.iss;
+    t.output(now);
+    t.segment(&segment(0, iss.wrapping_add(1), RST | ACK), &[], now);
+    assert_eq!(t.state, TcpState::Closed);
+    assert_eq!(t.error, Some(TcpError::ConnectionRefused));
+    Ok(())
+}
+
+/// Test that a closed window is probed with a single byte at growing intervals for as long as the other end acknowledges the probes
+#[doors_macros::doors_test]
+fn tcp_persist_test() -> Result<(), ()> {
+    let local = SocketAddress::new(Ipv4Address::new(10, 0, 2, 15), 49152);
+    let remote = SocketAddress::new(Ipv4Address::new(10, 0, 2, 2), 80);
+    let mut now = Instant::now();
+    let segment = |acknowledgment, window| TcpHeader {
+        source_port: remote.port,
+        destination_port: local.port,
+        sequence: 1001,
+        acknowledgment,
+        flags: ACK,
+        window,
+        mss: None,
+    };
+
+    let mut t = Tcb::new(TcpState::SynSent, local, remote);
+    let iss = t.iss;
+    t.output(now);
+    let mut synack = segment(iss.wrapping_add(1), 0);
+    synack.sequence = 1000;
+    synack.flags = SYN | ACK;
+    t.segment(&synack, &[], now);
+    assert_eq!(t.state, TcpState::Established);
+    t.output(now);
+
+    t.send_buffer.extend([1u8, 2, 3]);
+    assert!(t.output(now).is_empty());
+    assert!(t.retransmit_deadline.is_none());
+    let mut interval = t.rto;
+    assert_eq!(t.next_deadline(), Some(now + interval));
+    for _ in 0..MAX_RETRIES + 2 {
+        now = t.next_deadline().ok_or(())?;
+        t.timers(now);
+        let out = t.output(now);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].len(), HEADER_LENGTH + 1);
+        assert_eq!(out[0][4..8], iss.wrapping_add(1).to_be_bytes());
+        assert_eq!(out[0][HEADER_LENGTH..], [1]);
+        interval = (interval * 2).min(MAX_RTO);
+        assert_eq!(t.next_deadline(), Some(now + interval));
+        t.segment(&segment(iss.wrapping_add(1), 0), &[], now);
+        assert_eq!(t.state, TcpState::Established);
+        assert_eq!(t.error, None);
+    }
+
+    // The window opens, so the data is sent and the persist timer stops
+    t.segment(&segment(iss.wrapping_add(1), 8192), &[], now);
+    let out = t.output(now);
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0][HEADER_LENGTH..], [1, 2, 3]);
+    assert!(t.persist_deadline.is_none());
+    assert!(t.retransmit_deadline.is_some());
+    Ok(())
+}
@@ -0,0 +1,370 @@
+//! The user datagram protocol, with sockets that receive the datagrams sent to a local port
+
+use alloc::{collections::BTreeMap, collections::VecDeque, vec::Vec};
+use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use core::task::{Poll, Waker};
+
+use super::interface::Interface;
+use super::ipv4::{self, checksum_add, checksum_finish, pseudo_header_sum, IpProtocol, Ipv4Header};
+use super::{Ipv4Address, SocketAddress};
+use crate::{Arc, Locked};
+
+/// The length of the udp header
+const HEADER_LENGTH: usize = 8;
+/// The first port handed out to sockets that do not ask for a specific port
+const EPHEMERAL_START: u16 = 49152;
+/// The number of ports handed out to sockets that do not ask for a specific port
+const EPHEMERAL_COUNT: u16 = 16384;
+/// The largest amount of data a datagram can carry
+const MAX_DATA_LENGTH: usize = 65535 - 20 - HEADER_LENGTH;
+/// The number of received datagrams a socket holds before dropping new ones
+const MAX_QUEUED: usize = 64;
+
+/// The open sockets, by local port
+static SOCKETS: Locked<BTreeMap<u16, Arc<SocketState>>> = Locked::new(BTreeMap::new());
+
+/// Used to pick the next ephemeral port
+static NEXT_EPHEMERAL: AtomicU16 = AtomicU16::new(0);
+
+/// The errors that can occur with a udp socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UdpError {
+    /// Another socket is already bound to the port
+    AddressInUse,
+    /// All of the ephemeral ports are in use
+    NoFreePort,
+    /// The socket has no remote address to send to
+    NotConnected,
+    /// There is no route to the destination
+    NoRoute,
+    /// The data does not fit in a single datagram
+    TooLarge,
+    /// The datagram could not be sent
+    SendFailed,
+}
+
+/// The state of a socket shared with the code that delivers received datagrams
+struct SocketState {
+    /// The local address, with an unspecified address to receive on all addresses
+    local: SocketAddress,
+    /// The only address datagrams are accepted from, once connected
+    remote: Locked<Option<SocketAddress>>,
+    /// The received datagrams, with the address each came from
+    received: Locked<VecDeque<(SocketAddress, Vec<u8>)>>,
+    /// The tasks waiting for a datagram
+    wakers: Locked<Vec<Waker>>,
+    /// The number of datagrams dropped because the queue was full
+    dropped: AtomicU32,
+}
+
+impl SocketState {
+    /// Returns true if a datagram from the source to the destination belongs to this socket
+    fn accepts(&self, source: SocketAddress, destination: Ipv4Address) -> bool {
+        (self.local.ip.is_unspecified() || self.local.ip == destination)
+            && self.remote.sync_lock().is_none_or(|r| r == source)
+    }
+
+    /// Add a received datagram to the queue and wake the waiting tasks
+    fn deliver(&self, source: SocketAddress, data: Vec<u8>) {
+        {
+            let mut q = self.received.sync_lock();
+            if q.len() >= MAX_QUEUED {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            q.push_back((source, data));
+        }
+        let wakers = core::mem::take(&mut *self.wakers.sync_lock());
+        for w in wakers {
+            w.wake();
+        }
+    }
+}
+
+/// A udp socket. The port is released when the socket is dropped.
+pub struct UdpSocket {
+    /// The state shared with the receive path
+    state: Arc<SocketState>,
+}
+
+impl UdpSocket {
+    /// Bind a socket to a local address. A port of 0 picks an unused ephemeral port, and an unspecified address receives datagrams sent to any address of the system.
+    pub async fn bind(local: SocketAddress) -> Result<Self, UdpError> {
+        let mut sockets = SOCKETS.sync_lock();
+        let port = if local.port == 0 {
+            (0..EPHEMERAL_COUNT)
+                .map(|_| {
+                    EPHEMERAL_START
+                        + NEXT_EPHEMERAL.fetch_add(1, Ordering::Relaxed) % EPHEMERAL_COUNT
+                })
+                .find(|p| !sockets.contains_key(p))
+                .ok_or(UdpError::NoFreePort)?
+        } else if sockets.contains_key(&local.port) {
+            return Err(UdpError::AddressInUse);
+        } else {
+            local.port
+        };
+        let state = Arc::new(SocketState {
+            local: SocketAddress::new(local.ip, port),
+            remote: Locked::new(None),
+            received: Locked::new(VecDeque::new()),
+            wakers: Locked::new(Vec::new()),
+            dropped: AtomicU32::new(0),
+        });
+        sockets.insert(port, state.clone());
+        Ok(Self { state })
+    }
+
+    /// The local address the socket is bound to
+    pub fn local_address(&self) -> SocketAddress {
+        self.state.local
+    }
+
+    /// The remote address the socket is connected to, if any
+    pub fn peer_address(&self) -> Option<SocketAddress> {
+        *self.state.remote.sync_lock()
+    }
+
+    /// The number of received datagrams dropped because they were not read fast enough
+    pub fn dropped(&self) -> u32 {
+        self.state.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Set the default destination of the socket, and only accept datagrams from it from now on
+    pub async fn connect(&self, remote: SocketAddress) -> Result<(), UdpError> {
+        if ipv4::lookup_route(remote.ip).await.is_none() {
+            return Err(UdpError::NoRoute);
+        }
+        *self.state.remote.sync_lock() = Some(remote);
+        Ok(())
+    }
+
+    /// Send a datagram to a destination, returning the amount of data sent
+    pub async fn send_to(
+        &self,
+        data: &[u8],
+        destination: SocketAddress,
+    ) -> Result<usize, UdpError> {
+        if data.len() > MAX_DATA_LENGTH {
+            return Err(UdpError::TooLarge);
+        }
+        let r = ipv4::lookup_route(destination.ip)
+            .await
+            .ok_or(UdpError::NoRoute)?;
+        let source = if self.state.local.ip.is_unspecified() {
+            r.source
+        } else {
+            self.state.local.ip
+        };
+        let d = build(
+            SocketAddress::new(source, self.state.local.port),
+            destination,
+            data,
+        );
+        ipv4::send_packet(
+            &r.interface,
+            source,
+            destination.ip,
+            r.next_hop,
+            IpProtocol::Udp,
+            &d,
+        )
+        .await
+        .map_err(|_| UdpError::SendFailed)?;
+        Ok(data.len())
+    }
+
+    /// Send a datagram out of a specific interface to a host on its local network, without using the routing table.
+    /// This works before the interface has an address, in which case the datagram is sent from the unspecified address.
+    pub async fn send_on(
+        &self,
+        iface: &Interface,
+        data: &[u8],
+        destination: SocketAddress,
+    ) -> Result<usize, UdpError> {
+        if data.len() > MAX_DATA_LENGTH {
+            return Err(UdpError::TooLarge);
+        }
+        let source = if self.state.local.ip.is_unspecified() {
+            iface.ipv4_address().unwrap_or(Ipv4Address::UNSPECIFIED)
+        } else {
+            self.state.local.ip
+        };
+        let d = build(
+            SocketAddress::new(source, self.state.local.port),
+            destination,
+            data,
+        );
+        ipv4::send_packet(
+            iface,
+            source,
+            destination.ip,
+            destination.ip,
+            IpProtocol::Udp,
+            &d,
+        )
+        .await
+        .map_err(|_| UdpError::SendFailed)?;
+        Ok(data.len())
+    }
+
+    /// Send a datagram to the address the socket is connected to
+    pub async fn send(&self, data: &[u8]) -> Result<usize, UdpError> {
+        let remote = self.peer_address().ok_or(UdpError::NotConnected)?;
+        self.send_to(data, remote).await
+    }
+
+    /// Take a received datagram without waiting, if there is one
+    pub fn try_recv_from(&self) -> Option<(Vec<u8>, SocketAddress)> {
+        self.state
+            .received
+            .sync_lock()
+            .pop_front()
+            .map(|(source, data)| (data, source))
+    }
+
+    /// Wait for a datagram, returning it and the address it came from
+    pub async fn recv_from(&self) -> Result<(Vec<u8>, SocketAddress), UdpError> {
+        core::future::poll_fn(|cx| {
+            if let Some(d) = self.try_recv_from() {
+                return Poll::Ready(Ok(d));
+            }
+            crate::add_waker(&mut self.state.wakers.sync_lock(), cx.waker());
+            match self.try_recv_from() {
+                Some(d) => Poll::Ready(Ok(d)),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// Wait for a datagram from the address the socket is connected to
+    pub async fn recv(&self) -> Result<Vec<u8>, UdpError> {
+        if self.peer_address().is_none() {
+            return Err(UdpError::NotConnected);
+        }
+        self.recv_from().await.map(|(d, _)| d)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.sync_lock().remove(&self.state.local.port);
+    }
+}
+
+/// Build a datagram with a correct checksum
+fn build(source: SocketAddress, destination: SocketAddress, data: &[u8]) -> Vec<u8> {
+    let length = HEADER_LENGTH + data.len();
+    let mut d = Vec::with_capacity(length);
+    d.extend_from_slice(&source.port.to_be_bytes());
+    d.extend_from_slice(&destination.port.to_be_bytes());
+    d.extend_from_slice(&(length as u16).to_be_bytes());
+    d.extend_from_slice(&[0, 0]);
+    d.extend_from_slice(data);
+    let sum = pseudo_header_sum(source.ip, destination.ip, IpProtocol::Udp, length);
+    // A checksum of 0 means no checksum, so it is sent as all ones instead
+    let c = match checksum_finish(checksum_add(sum, &d)) {
+        0 => 0xffff,
+        c => c,
+    };
+    d[6..8].copy_from_slice(&c.to_be_bytes());
+    d
+}
+
+/// Process a received udp datagram, passing it to the socket bound to the destination port
+pub fn handle(h: &Ipv4Header, payload: &[u8]) {
+    if payload.len() < HEADER_LENGTH {
+        return;
+    }
+    let length = u16::from_be_bytes([payload[4], payload[5]]) as usize;
+    if length < HEADER_LENGTH || length > payload.len() {
+        return;
+    }
+    let d = &payload[..length];
+    let stored = u16::from_be_bytes([d[6], d[7]]);
+    if stored != 0 {
+        let sum = pseudo_header_sum(h.source, h.destination, IpProtocol::Udp, length);
+        if checksum_finish(checksum_add(sum, d)) != 0 {
+            return;
+        }
+    }
+    let source = SocketAddress::new(h.source, u16::from_be_bytes([d[0], d[1]]));
+    let port = u16::from_be_bytes([d[2], d[3]]);
+    let s = SOCKETS.sync_lock().get(&port).cloned();
+    if let Some(s) = s {
+        if s.accepts(source, h.destination) {
+            s.deliver(source, d[HEADER_LENGTH..].to_vec());
+        }
+    }
+}
+
+/// Test that built datagrams are accepted with their checksum and length checked, and delivered to the socket bound to the destination port
+#[doors_macros::doors_test]
+fn udp_datagram_test() -> Result<(), ()> {
+    let local = SocketAddress::new(Ipv4Address::new(10, 0, 2, 15), 40007);
+    let remote = SocketAddress::new(Ipv4Address::new(10, 0, 2, 2), 7);
+    let ip = Ipv4Header {
+        tos: 0,
+        identification: 0,
+        dont_fragment: false,
+        more_fragments: false,
+        fragment_offset: 0,
+        ttl: 64,
+        protocol: IpProtocol::Udp,
+        source: remote.ip,
+        destination: local.ip,
+    };
+    let state = Arc::new(SocketState {
+        local,
+        remote: Locked::new(None),
+        received: Locked::new(VecDeque::new()),
+        wakers: Locked::new(Vec::new()),
+        dropped: AtomicU32::new(0),
+    });
+    {
+        let mut sockets = SOCKETS.sync_lock();
+        if sockets.contains_key(&local.port) {
+            return Err(());
+        }
+        sockets.insert(local.port, state.clone());
+    }
+    let take = || state.received.sync_lock().pop_front();
+
+    let d = build(remote, local, b"hello");
+    assert_eq!(d.len(), HEADER_LENGTH + 5);
+    assert_eq!(u16::from_be_bytes([d[4], d[5]]), 13);
+    let sum = pseudo_header_sum(remote.ip, local.ip, IpProtocol::Udp, d.len());
+    assert_eq!(checksum_finish(checksum_add(sum, &d)), 0);
+    handle(&ip, &d);
+    assert_eq!(take(), Some((remote, b"hello".to_vec())));
+
+    // Padding after the datagram is not part of the data
+    let mut padded = d.clone();
+    padded.extend_from_slice(&[0; 4]);
+    handle(&ip, &padded);
+    assert_eq!(take(), Some((remote, b"hello".to_vec())));
+
+    // A bad checksum, and a length longer than the packet
+    let mut bad = d.clone();
+    bad[HEADER_LENGTH] ^= 1;
+    handle(&ip, &bad);
+    let mut long = d.clone();
+    long[5] += 1;
+    handle(&ip, &long);
+    assert_eq!(take(), None);
+
+    // A checksum of 0 is not checked
+    let mut unchecked = bad.clone();
+    unchecked[6..8].copy_from_slice(&[0, 0]);
+    handle(&ip, &unchecked);
+    assert_eq!(take(), Some((remote, b"iello".to_vec())));
+
+    // A datagram for another port
+    let other = build(remote, SocketAddress::new(local.ip, local.port + 1), b"x");
+    handle(&ip, &other);
+    assert_eq!(take(), None);
+
+    SOCKETS.sync_lock().remove(&local.port);
+    Ok(())
+}
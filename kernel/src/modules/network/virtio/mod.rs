@@ -0,0 +1,10 @@
+//! Virtio drivers for paravirtualized networking hardware
+
+mod net;
+mod queue;
+mod transport;
+
+doors_macros2::enum_reexport!(net);
+
+pub use net::VirtioNet;
+pub use net::VirtioNetDevice;
@@ -0,0 +1,529 @@
+//! This driver is for the virtio network device, the paravirtualized network card of hypervisors like qemu.
+//! Both the legacy and modern pci interfaces are supported.
+
+use alloc::format;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::queue::{Virtqueue, DESC_WRITE};
+use super::transport::{
+    Transport, ISR_CONFIG, ISR_QUEUE, STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK,
+    STATUS_FAILED, STATUS_FEATURES_OK,
+};
+use crate::kernel::System;
+use crate::modules::network::ipv4::{checksum_add, checksum_finish};
+use crate::modules::network::{
+    MacAddress, NetworkAdapterTrait, NetworkStatistics, ReceiveNotifier,
+};
+use crate::modules::pci::{
+    capability::PowerState, BarSpace, ConfigurationSpaceEnum, PciBus, PciConfigurationSpace,
+    PciDevice, PciFunction, PciFunctionDriver, PciFunctionDriverTrait, PciMatch, PciProbeResult,
+};
+use crate::modules::time::{sleep, Duration};
+use crate::{Arc, IrqGuarded, IrqGuardedInner};
+
+/// The queue that receives packets
+const RECEIVE_QUEUE: u16 = 0;
+/// The queue that transmits packets
+const TRANSMIT_QUEUE: u16 = 1;
+/// The largest queue used, modern devices that offer larger queues are told to use this size instead
+const MAX_QUEUE_SIZE: u16 = 256;
+/// The largest number of receive buffers given to the device
+const RECEIVE_BUFFERS: u16 = 128;
+/// The largest number of transmit buffers
+const TRANSMIT_BUFFERS: u16 = 32;
+/// The size of each buffer, enough for the header and a full ethernet frame
+const BUFFER_SIZE: usize = 2048;
+/// How long to wait for the device to finish with a transmit buffer when all of them are in use
+const TRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The driver accepts received packets with partial checksums
+const F_GUEST_CSUM: u64 = 1 << 1;
+/// The device has a mac address in its configuration
+const F_MAC: u64 = 1 << 5;
+/// The device reports the link status in its configuration
+const F_STATUS: u64 = 1 << 16;
+/// The device follows version 1 of the specification, required for the modern interface
+const F_VERSION_1: u64 = 1 << 32;
+
+/// The offset of the mac address in the device configuration
+const CONFIG_MAC: u16 = 0;
+/// The offset of the status in the device configuration
+const CONFIG_STATUS: u16 = 6;
+/// The status bit that indicates the link is up
+const STATUS_LINK_UP: u16 = 1;
+
+/// The header flag for a packet that has a partial checksum to be completed
+const HEADER_NEEDS_CSUM: u8 = 1;
+/// The length of the header preceding each packet with the legacy interface
+const LEGACY_HEADER_LENGTH: usize = 10;
+/// The length of the header preceding each packet for version 1 devices, which always includes the buffer count
+const HEADER_LENGTH: usize = 12;
+
+/// The errors that can occur setting up the device
+#[derive(Debug)]
+enum VirtioNetError {
+    /// Memory for the queues or buffers could not be allocated
+    Allocation,
+    /// The device did not accept the features
+    FeaturesRejected,
+    /// A required queue does not exist
+    MissingQueue,
+}
+
+impl From<core::alloc::AllocError> for VirtioNetError {
+    fn from(_: core::alloc::AllocError) -> Self {
+        Self::Allocation
+    }
+}
+
+/// Ethernet driver for the virtio network device on pci
+#[derive(Clone, Default)]
+pub struct VirtioNet {}
+
+/// The internal data for the network device, used in interrupt and non-interrupt contexts
+struct VirtioNetDeviceInternal {
+    /// The interface to the device
+    transport: IrqGuarded<Transport>,
+    /// The link is up
+    up: AtomicBool,
+    /// The device reports its link status
+    link_status: bool,
+    /// Signalled when the receive interrupts fire
+    rx_notify: Arc<ReceiveNotifier>,
+    /// Signalled when the queue interrupts fire, used while waiting for a transmit descriptor
+    tx_notify: Arc<ReceiveNotifier>,
+}
+
+impl VirtioNetDeviceInternal {
+    /// Update the link status from an interrupt context
+    fn update_link_status_interrupt(&self) {
+        if self.link_status {
+            let status = self.transport.interrupt_access().config_u16(CONFIG_STATUS);
+            self.up
+                .store((status & STATUS_LINK_UP) != 0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A queue with a fixed buffer for each of its descriptors
+struct BufferedQueue {
+    /// The queue
+    queue: Virtqueue,
+    /// The buffer for each descriptor
+    buffers: Vec<crate::DmaMemorySlice<u8>>,
+}
+
+impl BufferedQueue {
+    /// Setup a queue on the device with up to the specified number of buffers
+    fn new(transport: &mut Transport, index: u16, buffers: u16) -> Result<Self, VirtioNetError> {
+        let size = transport.queue_size(index);
+        if size == 0 {
+            return Err(VirtioNetError::MissingQueue);
+        }
+        // The legacy interface can only use the size the device has chosen
+        let size = if transport.is_legacy() {
+            size
+        } else {
+            size.min(MAX_QUEUE_SIZE)
+        };
+        let mut queue = Virtqueue::new(index, size)?;
+        transport.setup_queue(&mut queue);
+        let mut b = Vec::with_capacity(buffers.min(size) as usize);
+        for _ in 0..buffers.min(size) {
+            b.push(crate::DmaMemorySlice::new(BUFFER_SIZE)?);
+        }
+        Ok(Self { queue, buffers: b })
+    }
+
+    /// Hand a buffer to the device with the specified length and flags
+    fn submit(&mut self, descriptor: u16, length: usize, flags: u16) {
+        let address = self.buffers[descriptor as usize].phys() as u64;
+        self.queue
+            .set_descriptor(descriptor, address, length as u32, flags);
+        self.queue.submit(descriptor);
+    }
+}
+
+#[doors_macros::enum_variant(NetworkAdapter)]
+/// The actual virtio network device
+pub struct VirtioNetDevice {
+    /// The internal structure used in interrupt handler and regular code
+    internal: Arc<VirtioNetDeviceInternal>,
+    /// The base address registers
+    _bars: [Option<BarSpace>; 6],
+    /// The receive queue and its buffers
+    rx: BufferedQueue,
+    /// The transmit queue and its buffers
+    tx: BufferedQueue,
+    /// The transmit descriptors not in use by the device
+    tx_free: Vec<u16>,
+    /// The length of the header that precedes each packet
+    header_length: usize,
+    /// The receive counters
+    stats: NetworkStatistics,
+    /// The mac address
+    mac_address: MacAddress,
+}
+
+impl NetworkAdapterTrait for VirtioNetDevice {
+    async fn get_mac_address(&mut self) -> MacAddress {
+        self.mac_address
+    }
+
+    async fn send_packet(&mut self, packet: &[u8]) -> Result<(), ()> {
+        if !self.internal.up.load(Ordering::Relaxed) {
+            return Err(());
+        }
+        let length = self.header_length + packet.len();
+        if length > BUFFER_SIZE {
+            return Err(());
+        }
+        let descriptor = match self.reclaim_transmit_descriptor() {
+            Some(d) => d,
+            None => self.wait_for_transmit_descriptor().await?,
+        };
+        {
+            let buffer = &mut self.tx.buffers[descriptor as usize];
+            // No offloads are requested, the stack fills in complete checksums
+            buffer[..self.header_length].fill(0);
+            buffer[self.header_length..length].copy_from_slice(packet);
+        }
+        self.tx.submit(descriptor, length, 0);
+        if self.tx.queue.needs_notification() {
+            self.internal
+                .transport
+                .access()
+                .await
+                .notify(&self.tx.queue);
+        }
+        Ok(())
+    }
+
+    async fn receive_packet(&mut self) -> Result<Vec<u8>, ()> {
+        let notifier = self.internal.rx_notify.clone();
+        loop {
+            if let Some(p) = self.check_for_received_packets().await {
+                return Ok(p);
+            }
+            notifier.wait().await;
+        }
+    }
+
+    async fn try_receive_packet(&mut self) -> Option<Vec<u8>> {
+        self.check_for_received_packets().await
+    }
+
+    fn receive_notifier(&self) -> Option<Arc<ReceiveNotifier>> {
+        Some(self.internal.rx_notify.clone())
+    }
+
+    async fn statistics(&mut self) -> NetworkStatistics {
+        self.stats
+    }
+
+    async fn set_multicast(&mut self, _addresses: &[MacAddress]) -> Result<(), ()> {
+        // Without the control queue the device passes every frame to the driver, so there is no filter to program
+        Ok(())
+    }
+}
+
+impl VirtioNetDevice {
+    /// Process the used ring of the receive queue, returning the next good packet if there is one.
+    /// Each processed buffer is handed back to the device.
+    async fn check_for_received_packets(&mut self) -> Option<Vec<u8>> {
+        let mut packet = None;
+        let mut returned = false;
+        while packet.is_none() {
+            let Some((descriptor, length)) = self.rx.queue.take_used() else {
+                break;
+            };
+            let length = (length as usize).min(BUFFER_SIZE);
+            if length < self.header_length {
+                self.stats.rx_errors += 1;
+            } else {
+                let buffer = &self.rx.buffers[descriptor as usize];
+                let flags = buffer[0];
+                let mut p = buffer[self.header_length..length].to_vec();
+                if (flags & HEADER_NEEDS_CSUM) != 0 {
+                    let start = u16::from_le_bytes([buffer[6], buffer[7]]) as usize;
+                    let offset = u16::from_le_bytes([buffer[8], buffer[9]]) as usize;
+                    Self::complete_checksum(&mut p, start, offset);
+                }
+                self.stats.rx_packets += 1;
+                self.stats.rx_bytes += p.len() as u64;
+                packet = Some(p);
+            }
+            self.rx.submit(descriptor, BUFFER_SIZE, DESC_WRITE);
+            returned = true;
+        }
+        if returned && self.rx.queue.needs_notification() {
+            self.internal
+                .transport
+                .access()
+                .await
+                .notify(&self.rx.queue);
+        }
+        packet
+    }
+
+    /// Take a transmit descriptor that is not in use, reclaiming the ones the device is finished with
+    fn reclaim_transmit_descriptor(&mut self) -> Option<u16> {
+        while let Some((d, _)) = self.tx.queue.take_used() {
+            self.tx_free.push(d);
+        }
+        self.tx_free.pop()
+    }
+
+    /// Wait for the device to finish with a transmit descriptor when all of them are in use.
+    /// Interrupts for the transmit queue are only enabled while waiting.
+    async fn wait_for_transmit_descriptor(&mut self) -> Result<u16, ()> {
+        use futures::future::{select, Either};
+        let notifier = self.internal.tx_notify.clone();
+        let mut timeout = core::pin::pin!(sleep(TRANSMIT_TIMEOUT));
+        self.tx.queue.enable_interrupts();
+        let descriptor = loop {
+            // Checked after enabling interrupts so that a descriptor used in between is not missed
+            if let Some(d) = self.reclaim_transmit_descriptor() {
+                break Ok(d);
+            }
+            match select(core::pin::pin!(notifier.wait()), timeout.as_mut()).await {
+                Either::Left(_) => {}
+                Either::Right(_) => break Err(()),
+            }
+        };
+        self.tx.queue.suppress_interrupts();
+        descriptor
+    }
+
+    /// Complete a partial checksum. The checksum field already holds the sum of the pseudo header,
+    /// so summing from the start of the transport header to the end of the packet gives the full checksum.
+    fn complete_checksum(packet: &mut [u8], start: usize, offset: usize) {
+        let field = start + offset;
+        if field + 2 <= packet.len() {
+            let c = checksum_finish(checksum_add(0, &packet[start..]));
+            packet[field..field + 2].copy_from_slice(&c.to_be_bytes());
+        }
+    }
+
+    /// Read the mac address from the device configuration, reading again if the configuration changes during the read
+    fn read_mac_address(transport: &Transport) -> MacAddress {
+        loop {
+            let generation = transport.config_generation();
+            let mut address = [0u8; 6];
+            for (i, a) in address.iter_mut().enumerate() {
+                *a = transport.config_u8(CONFIG_MAC + i as u16);
+            }
+            if transport.config_generation() == generation {
+                return MacAddress { address };
+            }
+        }
+    }
+
+    /// Bring up the device, negotiating features and setting up the queues.
+    /// The transport must not be shared with an interrupt handler yet.
+    fn setup(
+        mut transport: Transport,
+        bars: [Option<BarSpace>; 6],
+        irqnum: u8,
+        fallback_mac: MacAddress,
+    ) -> Result<Self, VirtioNetError> {
+        transport.reset();
+        transport.add_status(STATUS_ACKNOWLEDGE);
+        transport.add_status(STATUS_DRIVER);
+        let mut wanted = F_GUEST_CSUM | F_MAC | F_STATUS;
+        if !transport.is_legacy() {
+            wanted |= F_VERSION_1;
+        }
+        let features = transport.device_features() & wanted;
+        transport.set_driver_features(features);
+        if !transport.is_legacy() {
+            transport.add_status(STATUS_FEATURES_OK);
+            if (features & F_VERSION_1) == 0 || (transport.status() & STATUS_FEATURES_OK) == 0 {
+                transport.add_status(STATUS_FAILED);
+                return Err(VirtioNetError::FeaturesRejected);
+            }
+        }
+        let queues =
+            BufferedQueue::new(&mut transport, RECEIVE_QUEUE, RECEIVE_BUFFERS).and_then(|rx| {
+                BufferedQueue::new(&mut transport, TRANSMIT_QUEUE, TRANSMIT_BUFFERS)
+                    .map(|tx| (rx, tx))
+            });
+        let (mut rx, mut tx) = match queues {
+            Ok(q) => q,
+            Err(e) => {
+                transport.add_status(STATUS_FAILED);
+                return Err(e);
+            }
+        };
+        // Transmitted buffers are reclaimed when sending, so the device only needs to interrupt for them while every buffer is in use
+        tx.queue.suppress_interrupts();
+        let tx_free = (0..tx.buffers.len() as u16).rev().collect();
+        for d in 0..rx.buffers.len() as u16 {
+            rx.submit(d, BUFFER_SIZE, DESC_WRITE);
+        }
+        let mac_address = if (features & F_MAC) != 0 {
+            Self::read_mac_address(&transport)
+        } else {
+            fallback_mac
+        };
+        let link_status = (features & F_STATUS) != 0;
+        let up = !link_status || (transport.config_u16(CONFIG_STATUS) & STATUS_LINK_UP) != 0;
+        transport.add_status(STATUS_DRIVER_OK);
+        transport.notify(&rx.queue);
+        let com = IrqGuardedInner::new(irqnum, false, |_| {}, |_| {});
+        let header_length = if (features & F_VERSION_1) != 0 {
+            HEADER_LENGTH
+        } else {
+            LEGACY_HEADER_LENGTH
+        };
+        Ok(Self {
+            internal: Arc::new(VirtioNetDeviceInternal {
+                transport: IrqGuarded::new(transport, &com),
+                up: AtomicBool::new(up),
+                link_status,
+                rx_notify: Arc::new(ReceiveNotifier::new()),
+                tx_notify: Arc::new(ReceiveNotifier::new()),
+            }),
+            _bars: bars,
+            rx,
+            tx,
+            tx_free,
+            header_length,
+            stats: NetworkStatistics::default(),
+            mac_address,
+        })
+    }
+
+    /// The interrupt handler for the network device
+    fn handle_interrupt(this: &Arc<VirtioNetDeviceInternal>) {
+        let isr = this.transport.interrupt_access().isr();
+        if (isr & ISR_CONFIG) != 0 {
+            this.update_link_status_interrupt();
+        }
+        if (isr & ISR_QUEUE) != 0 {
+            this.rx_notify.notify();
+            this.tx_notify.notify();
+        }
+    }
+
+    /// Enable interrupts for the network device
+    async fn enable_interrupts(&self, sys: &System, irqnum: u8) {
+        crate::VGA
+            .print_str_async(&format!("Enabling interrupts on IRQ {}\r\n", irqnum))
+            .await;
+        use crate::kernel::SystemTrait;
+        let c = self.internal.clone();
+        sys.register_irq_handler(irqnum, move || VirtioNetDevice::handle_interrupt(&c));
+        sys.enable_irq(irqnum);
+    }
+}
+
+impl VirtioNet {
+    /// Create a new self, in const form
+    pub const fn new() -> Self {
+        Self {}
+    }
+}
+
+impl PciFunctionDriverTrait for VirtioNet {
+    async fn register(&self, m: &mut Vec<PciFunctionDriver>) {
+        crate::VGA
+            .print_str_async("Register virtio network pci driver\r\n")
+            .await;
+        if !m
+            .iter()
+            .any(|d| matches!(d, PciFunctionDriver::VirtioNet(_)))
+        {
+            m.push(self.clone().into());
+        }
+    }
+
+    fn match_table(&self) -> &'static [PciMatch] {
+        /// The virtio vendor id
+        const VIRTIO: u16 = 0x1af4;
+        &[
+            // The transitional device, with both interfaces
+            PciMatch::device(VIRTIO, 0x1000),
+            // The modern only device
+            PciMatch::device(VIRTIO, 0x1041),
+        ]
+    }
+
+    async fn parse_bars(
+        &mut self,
+        cs: &mut PciConfigurationSpace,
+        bus: &PciBus,
+        dev: &PciDevice,
+        f: &PciFunction,
+        config: &ConfigurationSpaceEnum,
+        mut bars: [Option<BarSpace>; 6],
+    ) -> PciProbeResult {
+        let transport = Transport::modern(cs, bus, dev, f, config, &mut bars)
+            .or_else(|| Transport::legacy(cs, bus, dev, f, config, &mut bars));
+        let irqnum = match config {
+            ConfigurationSpaceEnum::Standard(configuration_space_standard) => {
+                f.get_irq(bus, dev, configuration_space_standard)
+            }
+            _ => None,
+        };
+        if let (Some(transport), Some(irqnum)) = (transport, irqnum) {
+            crate::VGA
+                .print_str_async(&format!(
+                    "virtio-net: Using the {} interface\r\n",
+                    if transport.is_legacy() {
+                        "legacy"
+                    } else {
+                        "modern"
+                    }
+                ))
+                .await;
+            // A locally administered address derived from the location, for devices without one of their own
+            let location = f.location(bus, dev);
+            let fallback_mac = MacAddress {
+                address: [
+                    0x02,
+                    0,
+                    (cs.segment() & 0xff) as u8,
+                    location.bus,
+                    location.device,
+                    location.function,
+                ],
+            };
+            f.set_bus_mastering(cs, bus, dev, true);
+            match VirtioNetDevice::setup(transport, bars, irqnum, fallback_mac) {
+                Ok(mut d) => {
+                    {
+                        let sys = crate::SYSTEM.read();
+                        d.enable_interrupts(&sys, irqnum).await;
+                    }
+                    crate::VGA
+                        .print_str_async(&format!(
+                            "virtio-net: Mac address {}, link {}\r\n",
+                            d.mac_address,
+                            if d.internal.up.load(Ordering::Relaxed) {
+                                "up"
+                            } else {
+                                "down"
+                            }
+                        ))
+                        .await;
+                    let location = super::super::NetworkAdapterLocation::Pci {
+                        segment: cs.segment(),
+                        location,
+                    };
+                    super::super::register_network_adapter(d.into(), Some(location)).await;
+                    return PciProbeResult::Bound;
+                }
+                Err(e) => {
+                    crate::VGA
+                        .print_str_async(&format!("virtio-net: Setup failed {:?}\r\n", e))
+                        .await;
+                    f.set_bus_mastering(cs, bus, dev, false);
+                }
+            }
+        }
+        f.set_power_state(cs, bus, dev, PowerState::D3Hot).await;
+        PciProbeResult::Declined
+    }
+}
@@ -0,0 +1,201 @@
+//! Split virtqueues, the rings of descriptors that a driver and a virtio device use to exchange buffers
+
+use core::sync::atomic::{fence, Ordering};
+
+/// The device writes to the buffer of the descriptor instead of reading it
+pub const DESC_WRITE: u16 = 2;
+
+/// The alignment of the queue and its used ring required by the legacy interface
+const LEGACY_ALIGN: usize = 4096;
+/// The size of a descriptor in bytes
+const DESCRIPTOR_SIZE: usize = 16;
+/// The available ring flag that asks the device not to interrupt when it uses buffers
+const AVAILABLE_NO_INTERRUPT: u16 = 1;
+/// The used ring flag that tells the driver not to notify the device when it adds buffers
+const USED_NO_NOTIFY: u16 = 1;
+
+/// Compute the layout of a split virtqueue with the specified number of descriptors, as the legacy interface requires.
+/// Returns the offset of the available ring, the offset of the used ring and the total length of the queue.
+fn layout(size: u16) -> (usize, usize, usize) {
+    let q = size as usize;
+    let available = q * DESCRIPTOR_SIZE;
+    let used = (available + 6 + 2 * q).next_multiple_of(LEGACY_ALIGN);
+    let length = used + (6 + 8 * q).next_multiple_of(LEGACY_ALIGN);
+    (available, used, length)
+}
+
+/// A split virtqueue. The descriptor table, the available ring and the used ring are laid out in a single
+/// region the way the legacy interface requires, which also satisfies the alignment the modern interface requires.
+pub struct Virtqueue {
+    /// The index of the queue on the device
+    index: u16,
+    /// The number of descriptors, always a power of 2
+    size: u16,
+    /// The memory holding the queue
+    memory: crate::DmaMemorySlice<u8>,
+    /// The offset of the page aligned queue in the memory
+    start: usize,
+    /// The offset of the available ring from the start of the queue
+    available: usize,
+    /// The offset of the used ring from the start of the queue
+    used: usize,
+    /// The index of the next entry of the available ring
+    next_available: u16,
+    /// The index of the next entry of the used ring to process
+    last_used: u16,
+    /// The offset used to find the notification address of the queue, only used by the modern interface
+    notify_offset: u16,
+}
+
+impl Virtqueue {
+    /// Allocate a queue with the specified number of descriptors
+    pub fn new(index: u16, size: u16) -> Result<Self, core::alloc::AllocError> {
+        let (available, used, length) = layout(size);
+        let memory = crate::DmaMemorySlice::new(length + LEGACY_ALIGN)?;
+        let start = memory.phys().next_multiple_of(LEGACY_ALIGN) - memory.phys();
+        Ok(Self {
+            index,
+            size,
+            memory,
+            start,
+            available,
+            used,
+            next_available: 0,
+            last_used: 0,
+            notify_offset: 0,
+        })
+    }
+
+    /// The index of the queue on the device
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// The number of descriptors in the queue
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// The physical address of the descriptor table, which is also the start of the queue
+    pub fn descriptor_address(&self) -> u64 {
+        (self.memory.phys() + self.start) as u64
+    }
+
+    /// The physical address of the available ring
+    pub fn available_address(&self) -> u64 {
+        self.descriptor_address() + self.available as u64
+    }
+
+    /// The physical address of the used ring
+    pub fn used_address(&self) -> u64 {
+        self.descriptor_address() + self.used as u64
+    }
+
+    /// The offset used to find the notification address of the queue
+    pub fn notify_offset(&self) -> u16 {
+        self.notify_offset
+    }
+
+    /// Set the offset used to find the notification address of the queue, as read from the device
+    pub fn set_notify_offset(&mut self, offset: u16) {
+        self.notify_offset = offset;
+    }
+
+    /// Read a u16 at an offset from the start of the queue
+    fn read_u16(&self, offset: usize) -> u16 {
+        unsafe {
+            core::ptr::read_volatile(self.memory.as_ptr().add(self.start + offset) as *const u16)
+        }
+    }
+
+    /// Read a u32 at an offset from the start of the queue
+    fn read_u32(&self, offset: usize) -> u32 {
+        unsafe {
+            core::ptr::read_volatile(self.memory.as_ptr().add(self.start + offset) as *const u32)
+        }
+    }
+
+    /// Write a u16 at an offset from the start of the queue
+    fn write_u16(&mut self, offset: usize, val: u16) {
+        let start = self.start;
+        unsafe {
+            core::ptr::write_volatile(
+                self.memory.as_mut_ptr().add(start + offset) as *mut u16,
+                val,
+            )
+        }
+    }
+
+    /// Write a u32 at an offset from the start of the queue
+    fn write_u32(&mut self, offset: usize, val: u32) {
+        let start = self.start;
+        unsafe {
+            core::ptr::write_volatile(
+                self.memory.as_mut_ptr().add(start + offset) as *mut u32,
+                val,
+            )
+        }
+    }
+
+    /// Fill in a descriptor. The address must be the physical address of the buffer.
+    pub fn set_descriptor(&mut self, descriptor: u16, address: u64, length: u32, flags: u16) {
+        let o = descriptor as usize * DESCRIPTOR_SIZE;
+        self.write_u32(o, address as u32);
+        self.write_u32(o + 4, (address >> 32) as u32);
+        self.write_u32(o + 8, length);
+        self.write_u16(o + 12, flags);
+        self.write_u16(o + 14, 0);
+    }
+
+    /// Ask the device not to interrupt when it is finished with buffers of this queue
+    pub fn suppress_interrupts(&mut self) {
+        self.write_u16(self.available, AVAILABLE_NO_INTERRUPT);
+    }
+
+    /// Ask the device to interrupt again when it is finished with buffers of this queue
+    pub fn enable_interrupts(&mut self) {
+        self.write_u16(self.available, 0);
+        // The flag must be visible before the used ring is checked again
+        fence(Ordering::SeqCst);
+    }
+
+    /// Hand the descriptor chain starting at the specified descriptor to the device.
+    /// The device is not notified, see [Self::needs_notification].
+    pub fn submit(&mut self, head: u16) {
+        let slot = (self.next_available % self.size) as usize;
+        self.write_u16(self.available + 4 + 2 * slot, head);
+        // The entry must be visible before the index that publishes it
+        fence(Ordering::SeqCst);
+        self.next_available = self.next_available.wrapping_add(1);
+        self.write_u16(self.available + 2, self.next_available);
+        fence(Ordering::SeqCst);
+    }
+
+    /// Returns true if the device wants to be notified of newly submitted buffers
+    pub fn needs_notification(&self) -> bool {
+        (self.read_u16(self.used) & USED_NO_NOTIFY) == 0
+    }
+
+    /// Take the next descriptor chain the device is finished with, returning the head of the chain and the number of bytes the device wrote to it
+    pub fn take_used(&mut self) -> Option<(u16, u32)> {
+        if self.read_u16(self.used + 2) == self.last_used {
+            return None;
+        }
+        // The index must be read before the entry it publishes
+        fence(Ordering::SeqCst);
+        let slot = (self.last_used % self.size) as usize;
+        let id = self.read_u32(self.used + 4 + 8 * slot);
+        let length = self.read_u32(self.used + 8 + 8 * slot);
+        self.last_used = self.last_used.wrapping_add(1);
+        Some((id as u16, length))
+    }
+}
+
+/// Test the layout of queues against the sizes the legacy interface expects
+#[doors_macros::doors_test]
+fn virtqueue_layout_test() -> Result<(), ()> {
+    assert_eq!(layout(8), (128, 4096, 8192));
+    assert_eq!(layout(256), (4096, 8192, 12288));
+    assert_eq!(layout(1024), (16384, 20480, 32768));
+    Ok(())
+}
@@ -0,0 +1,394 @@
+//! The ways a virtio device is accessed over pci, the legacy interface in io space and the modern interface described by vendor specific capabilities
+
+use alloc::vec::Vec;
+
+use super::queue::Virtqueue;
+use crate::modules::pci::{
+    capability::TypedCapability, BarSpace, ConfigurationSpaceEnum, PciBus, PciConfigurationSpace,
+    PciDevice, PciFunction,
+};
+use crate::IoReadWrite;
+
+/// The driver has found the device
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+/// The driver knows how to drive the device
+pub const STATUS_DRIVER: u8 = 2;
+/// The driver is ready to use the device
+pub const STATUS_DRIVER_OK: u8 = 4;
+/// The driver has finished negotiating features
+pub const STATUS_FEATURES_OK: u8 = 8;
+/// The driver has given up on the device
+pub const STATUS_FAILED: u8 = 128;
+
+/// The interrupt status bit set when the device has used buffers of a queue
+pub const ISR_QUEUE: u8 = 1;
+/// The interrupt status bit set when the device specific configuration has changed
+pub const ISR_CONFIG: u8 = 2;
+
+/// The legacy register holding the features offered by the device
+const LEGACY_DEVICE_FEATURES: u16 = 0;
+/// The legacy register holding the features accepted by the driver
+const LEGACY_DRIVER_FEATURES: u16 = 4;
+/// The legacy register holding the page number of the selected queue
+const LEGACY_QUEUE_ADDRESS: u16 = 8;
+/// The legacy register holding the size of the selected queue
+const LEGACY_QUEUE_SIZE: u16 = 0xc;
+/// The legacy register that selects a queue
+const LEGACY_QUEUE_SELECT: u16 = 0xe;
+/// The legacy register written with the index of a queue to notify the device
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+/// The legacy device status register
+const LEGACY_STATUS: u16 = 0x12;
+/// The legacy interrupt status register, cleared on read
+const LEGACY_ISR: u16 = 0x13;
+/// The start of the device specific configuration for the legacy interface, when msi-x is not enabled
+const LEGACY_CONFIG: u16 = 0x14;
+
+/// The common configuration register that selects which half of the device features is visible
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0;
+/// The common configuration register holding the selected half of the device features
+const COMMON_DEVICE_FEATURE: usize = 4;
+/// The common configuration register that selects which half of the driver features is written
+const COMMON_DRIVER_FEATURE_SELECT: usize = 8;
+/// The common configuration register holding the selected half of the driver features
+const COMMON_DRIVER_FEATURE: usize = 0xc;
+/// The common configuration device status register
+const COMMON_STATUS: usize = 0x14;
+/// The common configuration register that changes whenever the device specific configuration changes
+const COMMON_CONFIG_GENERATION: usize = 0x15;
+/// The common configuration register that selects a queue
+const COMMON_QUEUE_SELECT: usize = 0x16;
+/// The common configuration register holding the size of the selected queue
+const COMMON_QUEUE_SIZE: usize = 0x18;
+/// The common configuration register that enables the selected queue
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+/// The common configuration register holding the notification offset of the selected queue
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+/// The common configuration register holding the address of the descriptor table of the selected queue
+const COMMON_QUEUE_DESC: usize = 0x20;
+/// The common configuration register holding the address of the available ring of the selected queue
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+/// The common configuration register holding the address of the used ring of the selected queue
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+/// The capability type of the common configuration
+const CAP_COMMON: u8 = 1;
+/// The capability type of the notification area
+const CAP_NOTIFY: u8 = 2;
+/// The capability type of the interrupt status register
+const CAP_ISR: u8 = 3;
+/// The capability type of the device specific configuration
+const CAP_DEVICE: u8 = 4;
+
+/// A structure of the modern interface, inside one of the mapped bars
+#[derive(Clone, Copy)]
+struct Region {
+    /// The index of the mapped bar in [ModernTransport::memory]
+    memory: usize,
+    /// The offset of the structure in the bar
+    offset: usize,
+}
+
+/// The modern interface, where each structure is found in a bar by a capability
+pub struct ModernTransport {
+    /// The bars that contain the structures
+    memory: Vec<crate::PciMemory>,
+    /// The common configuration
+    common: Region,
+    /// The notification area
+    notify: Region,
+    /// The distance between the notification addresses of the queues
+    notify_multiplier: u32,
+    /// The interrupt status register
+    isr: Region,
+    /// The device specific configuration
+    device: Option<Region>,
+}
+
+impl ModernTransport {
+    /// Read a u8 from a structure
+    fn read_u8(&self, r: Region, offset: usize) -> u8 {
+        self.memory[r.memory].read_u8(r.offset + offset)
+    }
+
+    /// Read a u16 from a structure
+    fn read_u16(&self, r: Region, offset: usize) -> u16 {
+        self.memory[r.memory].read_u16(r.offset + offset)
+    }
+
+    /// Read a u32 from a structure
+    fn read_u32(&self, r: Region, offset: usize) -> u32 {
+        self.memory[r.memory].read_u32(r.offset + offset)
+    }
+
+    /// Write a u8 to a structure
+    fn write_u8(&mut self, r: Region, offset: usize, val: u8) {
+        self.memory[r.memory].write_u8(r.offset + offset, val);
+    }
+
+    /// Write a u16 to a structure
+    fn write_u16(&mut self, r: Region, offset: usize, val: u16) {
+        self.memory[r.memory].write_u16(r.offset + offset, val);
+    }
+
+    /// Write a u32 to a structure
+    fn write_u32(&mut self, r: Region, offset: usize, val: u32) {
+        self.memory[r.memory].write_u32(r.offset + offset, val);
+    }
+
+    /// Write a u64 to a structure, as two u32 writes
+    fn write_u64(&mut self, r: Region, offset: usize, val: u64) {
+        self.write_u32(r, offset, val as u32);
+        self.write_u32(r, offset + 4, (val >> 32) as u32);
+    }
+}
+
+/// The interface used to access a virtio device
+pub enum Transport {
+    /// The legacy interface, in the io space of bar 0
+    Legacy(crate::IoPortArray<'static>),
+    /// The modern interface
+    Modern(ModernTransport),
+}
+
+impl Transport {
+    /// Use the legacy interface of a function, in the io space of bar 0
+    pub fn legacy(
+        cs: &mut PciConfigurationSpace,
+        bus: &PciBus,
+        dev: &PciDevice,
+        f: &PciFunction,
+        config: &ConfigurationSpaceEnum,
+        bars: &mut [Option<BarSpace>; 6],
+    ) -> Option<Self> {
+        bars[0]
+            .as_mut()?
+            .get_io(cs, bus, dev, f, config)
+            .map(Transport::Legacy)
+    }
+
+    /// Use the modern interface of a function, mapping the bars that its capabilities point to.
+    /// The first capability of each type is used, as the specification recommends.
+    pub fn modern(
+        cs: &mut PciConfigurationSpace,
+        bus: &PciBus,
+        dev: &PciDevice,
+        f: &PciFunction,
+        config: &ConfigurationSpaceEnum,
+        bars: &mut [Option<BarSpace>; 6],
+    ) -> Option<Self> {
+        let location = f.location(bus, dev);
+        let caps: Vec<_> = f
+            .capabilities(cs, bus, dev)
+            .filter_map(|c| match c.typed(location) {
+                TypedCapability::VendorSpecific(v) => Some(v),
+                _ => None,
+            })
+            .collect();
+        let mut memory = Vec::new();
+        let mut mapped: [Option<usize>; 6] = [None; 6];
+        let mut common = None;
+        let mut notify = None;
+        let mut notify_multiplier = 0;
+        let mut isr = None;
+        let mut device = None;
+        for c in caps {
+            let kind = c.read_u8(cs, 3);
+            let bar = c.read_u8(cs, 4) as usize;
+            let offset = c.read_u32(cs, 8) as usize;
+            let length = c.read_u32(cs, 12) as usize;
+            let slot = match kind {
+                CAP_COMMON => &mut common,
+                CAP_NOTIFY => &mut notify,
+                CAP_ISR => &mut isr,
+                CAP_DEVICE => &mut device,
+                _ => continue,
+            };
+            if slot.is_some() || bar >= bars.len() {
+                continue;
+            }
+            let index = match mapped[bar] {
+                Some(i) => i,
+                None => {
+                    let Some(m) = bars[bar]
+                        .as_mut()
+                        .and_then(|b| b.get_memory(cs, bus, dev, f, config))
+                    else {
+                        continue;
+                    };
+                    memory.push(m);
+                    mapped[bar] = Some(memory.len() - 1);
+                    memory.len() - 1
+                }
+            };
+            if offset + length > memory[index].size() {
+                continue;
+            }
+            if kind == CAP_NOTIFY {
+                notify_multiplier = c.read_u32(cs, 16);
+            }
+            *slot = Some(Region {
+                memory: index,
+                offset,
+            });
+        }
+        Some(Transport::Modern(ModernTransport {
+            memory,
+            common: common?,
+            notify: notify?,
+            notify_multiplier,
+            isr: isr?,
+            device,
+        }))
+    }
+
+    /// Returns true for the legacy interface
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, Transport::Legacy(_))
+    }
+
+    /// Read the device status
+    pub fn status(&self) -> u8 {
+        match self {
+            Transport::Legacy(io) => io.port::<u8>(LEGACY_STATUS).port_read(),
+            Transport::Modern(m) => m.read_u8(m.common, COMMON_STATUS),
+        }
+    }
+
+    /// Write the device status
+    pub fn set_status(&mut self, status: u8) {
+        match self {
+            Transport::Legacy(io) => io.port::<u8>(LEGACY_STATUS).port_write(status),
+            Transport::Modern(m) => m.write_u8(m.common, COMMON_STATUS, status),
+        }
+    }
+
+    /// Add bits to the device status
+    pub fn add_status(&mut self, status: u8) {
+        let s = self.status();
+        self.set_status(s | status);
+    }
+
+    /// Reset the device, waiting for the modern interface to report that the reset is done
+    pub fn reset(&mut self) {
+        self.set_status(0);
+        if !self.is_legacy() {
+            for _ in 0..100000 {
+                if self.status() == 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// The features offered by the device. The legacy interface only has the first 32.
+    pub fn device_features(&mut self) -> u64 {
+        match self {
+            Transport::Legacy(io) => io.port::<u32>(LEGACY_DEVICE_FEATURES).port_read() as u64,
+            Transport::Modern(m) => {
+                m.write_u32(m.common, COMMON_DEVICE_FEATURE_SELECT, 0);
+                let low = m.read_u32(m.common, COMMON_DEVICE_FEATURE);
+                m.write_u32(m.common, COMMON_DEVICE_FEATURE_SELECT, 1);
+                let high = m.read_u32(m.common, COMMON_DEVICE_FEATURE);
+                (low as u64) | ((high as u64) << 32)
+            }
+        }
+    }
+
+    /// Tell the device which of its features the driver uses
+    pub fn set_driver_features(&mut self, features: u64) {
+        match self {
+            Transport::Legacy(io) => io
+                .port::<u32>(LEGACY_DRIVER_FEATURES)
+                .port_write(features as u32),
+            Transport::Modern(m) => {
+                m.write_u32(m.common, COMMON_DRIVER_FEATURE_SELECT, 0);
+                m.write_u32(m.common, COMMON_DRIVER_FEATURE, features as u32);
+                m.write_u32(m.common, COMMON_DRIVER_FEATURE_SELECT, 1);
+                m.write_u32(m.common, COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+            }
+        }
+    }
+
+    /// The largest size of a queue, 0 when the queue does not exist
+    pub fn queue_size(&mut self, index: u16) -> u16 {
+        match self {
+            Transport::Legacy(io) => {
+                io.port::<u16>(LEGACY_QUEUE_SELECT).port_write(index);
+                io.port::<u16>(LEGACY_QUEUE_SIZE).port_read()
+            }
+            Transport::Modern(m) => {
+                m.write_u16(m.common, COMMON_QUEUE_SELECT, index);
+                m.read_u16(m.common, COMMON_QUEUE_SIZE)
+            }
+        }
+    }
+
+    /// Give the device the addresses of a queue and enable it.
+    /// The legacy interface requires the queue to be the size returned by [Self::queue_size].
+    pub fn setup_queue(&mut self, q: &mut Virtqueue) {
+        match self {
+            Transport::Legacy(io) => {
+                io.port::<u16>(LEGACY_QUEUE_SELECT).port_write(q.index());
+                io.port::<u32>(LEGACY_QUEUE_ADDRESS)
+                    .port_write((q.descriptor_address() >> 12) as u32);
+            }
+            Transport::Modern(m) => {
+                m.write_u16(m.common, COMMON_QUEUE_SELECT, q.index());
+                m.write_u16(m.common, COMMON_QUEUE_SIZE, q.size());
+                m.write_u64(m.common, COMMON_QUEUE_DESC, q.descriptor_address());
+                m.write_u64(m.common, COMMON_QUEUE_DRIVER, q.available_address());
+                m.write_u64(m.common, COMMON_QUEUE_DEVICE, q.used_address());
+                q.set_notify_offset(m.read_u16(m.common, COMMON_QUEUE_NOTIFY_OFF));
+                m.write_u16(m.common, COMMON_QUEUE_ENABLE, 1);
+            }
+        }
+    }
+
+    /// Tell the device that buffers were added to a queue
+    pub fn notify(&mut self, q: &Virtqueue) {
+        match self {
+            Transport::Legacy(io) => io.port::<u16>(LEGACY_QUEUE_NOTIFY).port_write(q.index()),
+            Transport::Modern(m) => {
+                let offset = q.notify_offset() as usize * m.notify_multiplier as usize;
+                m.write_u16(m.notify, offset, q.index());
+            }
+        }
+    }
+
+    /// Read the interrupt status, which acknowledges the interrupt
+    pub fn isr(&self) -> u8 {
+        match self {
+            Transport::Legacy(io) => io.port::<u8>(LEGACY_ISR).port_read(),
+            Transport::Modern(m) => m.read_u8(m.isr, 0),
+        }
+    }
+
+    /// A value that changes whenever the device specific configuration changes, always 0 for the legacy interface
+    pub fn config_generation(&self) -> u8 {
+        match self {
+            Transport::Legacy(_) => 0,
+            Transport::Modern(m) => m.read_u8(m.common, COMMON_CONFIG_GENERATION),
+        }
+    }
+
+    /// Read a u8 of the device specific configuration
+    pub fn config_u8(&self, offset: u16) -> u8 {
+        match self {
+            Transport::Legacy(io) => io.port::<u8>(LEGACY_CONFIG + offset).port_read(),
+            Transport::Modern(m) => m.device.map(|d| m.read_u8(d, offset as usize)).unwrap_or(0),
+        }
+    }
+
+    /// Read a u16 of the device specific configuration
+    pub fn config_u16(&self, offset: u16) -> u16 {
+        match self {
+            Transport::Legacy(io) => io.port::<u16>(LEGACY_CONFIG + offset).port_read(),
+            Transport::Modern(m) => m
+                .device
+                .map(|d| m.read_u16(d, offset as usize))
+                .unwrap_or(0),
+        }
+    }
+}
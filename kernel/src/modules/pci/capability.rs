@@ -0,0 +1,511 @@
+//! Pci capability lists, covering the standard list in the first 256 bytes of configuration space and the extended list of pci express
+
+use super::{PciConfigurationSpace, PciConfigurationSpaceTrait};
+
+/// The capability id of power management
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+/// The capability id of message signaled interrupts
+pub const CAP_MSI: u8 = 0x05;
+/// The capability id of vendor specific information
+pub const CAP_VENDOR_SPECIFIC: u8 = 0x09;
+/// The capability id of pci express
+pub const CAP_PCI_EXPRESS: u8 = 0x10;
+/// The capability id of extended message signaled interrupts
+pub const CAP_MSIX: u8 = 0x11;
+/// The extended capability id of advanced error reporting
+pub const EXT_CAP_AER: u16 = 0x0001;
+/// The extended capability id of vendor specific information
+pub const EXT_CAP_VENDOR_SPECIFIC: u16 = 0x000b;
+
+/// The location of a function in configuration space
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciLocation {
+    /// The bus number
+    pub bus: u8,
+    /// The device number
+    pub device: u8,
+    /// The function number
+    pub function: u8,
+}
+
+impl PciLocation {
+    /// Read a dword of configuration space, the offset does not have to be aligned
+    fn read_u32(&self, pci: &mut PciConfigurationSpace, offset: u16) -> Option<u32> {
+        pci.read_extended_u32(self.bus, self.device, self.function, offset & !3)
+    }
+
+    /// Read a word of configuration space
+    fn read_u16(&self, pci: &mut PciConfigurationSpace, offset: u16) -> Option<u16> {
+        self.read_u32(pci, offset)
+            .map(|v| (v >> ((offset & 2) * 8)) as u16)
+    }
+
+    /// Read a byte of configuration space
+    fn read_u8(&self, pci: &mut PciConfigurationSpace, offset: u16) -> Option<u8> {
+        self.read_u32(pci, offset)
+            .map(|v| (v >> ((offset & 3) * 8)) as u8)
+    }
+
+    /// Write a dword of configuration space, the offset must be aligned
+    fn write_u32(&self, pci: &mut PciConfigurationSpace, offset: u16, val: u32) -> Option<()> {
+        pci.write_extended_u32(self.bus, self.device, self.function, offset, val)
+    }
+
+    /// Write a word of configuration space, using a read-modify-write of the containing dword
+    fn write_u16(&self, pci: &mut PciConfigurationSpace, offset: u16, val: u16) -> Option<()> {
+        let shift = (offset & 2) * 8;
+        let v = self.read_u32(pci, offset)?;
+        let v = (v & !(0xffff << shift)) | ((val as u32) << shift);
+        pci.write_extended_u32(self.bus, self.device, self.function, offset & !3, v)
+    }
+}
+
+/// A single capability of a function
+#[derive(Clone, Copy, Debug)]
+pub enum Capability {
+    /// A capability in the standard list
+    Standard {
+        /// The capability id
+        id: u8,
+        /// The offset of the capability in configuration space
+        offset: u16,
+    },
+    /// A capability in the extended list of pci express functions
+    Extended {
+        /// The capability id
+        id: u16,
+        /// The version of the capability
+        version: u8,
+        /// The offset of the capability in configuration space
+        offset: u16,
+    },
+}
+
+/// A capability with typed access to its registers
+#[derive(Clone, Copy, Debug)]
+pub enum TypedCapability {
+    /// Power management
+    PowerManagement(PowerManagement),
+    /// Message signaled interrupts
+    Msi(Msi),
+    /// Extended message signaled interrupts
+    MsiX(MsiX),
+    /// Pci express
+    PciExpress(PciExpress),
+    /// Vendor specific information, in either list
+    VendorSpecific(VendorSpecific),
+    /// Advanced error reporting
+    Aer(Aer),
+    /// A capability without typed access
+    Other(Capability),
+}
+
+impl Capability {
+    /// Get typed access to the capability of a function
+    pub fn typed(&self, location: PciLocation) -> TypedCapability {
+        match *self {
+            Capability::Standard { id, offset } => match id {
+                CAP_POWER_MANAGEMENT => {
+                    TypedCapability::PowerManagement(PowerManagement { location, offset })
+                }
+                CAP_MSI => TypedCapability::Msi(Msi { location, offset }),
+                CAP_MSIX => TypedCapability::MsiX(MsiX { location, offset }),
+                CAP_PCI_EXPRESS => TypedCapability::PciExpress(PciExpress { location, offset }),
+                CAP_VENDOR_SPECIFIC => TypedCapability::VendorSpecific(VendorSpecific {
+                    location,
+                    offset,
+                    extended: false,
+                }),
+                _ => TypedCapability::Other(*self),
+            },
+            Capability::Extended { id, offset, .. } => match id {
+                EXT_CAP_AER => TypedCapability::Aer(Aer { location, offset }),
+                EXT_CAP_VENDOR_SPECIFIC => TypedCapability::VendorSpecific(VendorSpecific {
+                    location,
+                    offset,
+                    extended: true,
+                }),
+                _ => TypedCapability::Other(*self),
+            },
+        }
+    }
+
+    /// The offset of the capability in configuration space
+    pub fn offset(&self) -> u16 {
+        match self {
+            Capability::Standard { offset, .. } => *offset,
+            Capability::Extended { offset, .. } => *offset,
+        }
+    }
+}
+
+/// Iterates over the standard capabilities of a function, followed by the extended capabilities
+pub struct CapabilityIter<'a> {
+    /// The configuration space access
+    pci: &'a mut PciConfigurationSpace,
+    /// The function
+    location: PciLocation,
+    /// The offset of the next standard capability, 0 when the standard list is done
+    next: u16,
+    /// The offset of the next extended capability, 0 when the extended list is done
+    next_extended: u16,
+    /// The number of capabilities left before the lists are considered to loop
+    remaining: u16,
+}
+
+impl<'a> CapabilityIter<'a> {
+    /// Start iterating over the capabilities of a function
+    pub fn new(pci: &'a mut PciConfigurationSpace, location: PciLocation) -> Self {
+        let status = location.read_u16(pci, 6).unwrap_or(0);
+        let next = if (status & 0x10) != 0 && status != 0xffff {
+            let header = location.read_u8(pci, 0xe).unwrap_or(0) & 0x7f;
+            let pointer = if header == 2 { 0x14 } else { 0x34 };
+            location.read_u8(pci, pointer).unwrap_or(0) as u16 & 0xfc
+        } else {
+            0
+        };
+        Self {
+            pci,
+            location,
+            next,
+            next_extended: 0,
+            remaining: 48 + 960,
+        }
+    }
+}
+
+impl Iterator for CapabilityIter<'_> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        if self.next >= 0x40 {
+            let offset = self.next;
+            let v = self.location.read_u16(self.pci, offset)?;
+            self.next = (v >> 8) & 0xfc;
+            let id = v as u8;
+            if id == CAP_PCI_EXPRESS {
+                // Only pci express functions have the extended list
+                self.next_extended = 0x100;
+            }
+            return Some(Capability::Standard { id, offset });
+        }
+        self.next = 0;
+        if self.next_extended >= 0x100 {
+            let offset = self.next_extended;
+            let v = self.location.read_u32(self.pci, offset)?;
+            self.next_extended = ((v >> 20) as u16) & 0xffc;
+            if v == 0 || v == 0xffff_ffff {
+                return None;
+            }
+            return Some(Capability::Extended {
+                id: v as u16,
+                version: ((v >> 16) & 0xf) as u8,
+                offset,
+            });
+        }
+        None
+    }
+}
+
+/// The power states of a function
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerState {
+    /// Fully on
+    D0,
+    /// Light sleep, optional
+    D1,
+    /// Deeper sleep, optional
+    D2,
+    /// Off, with configuration space still accessible
+    D3Hot,
+}
+
+/// The power management control bit that is set when a function keeps its configuration when it leaves D3hot
+const NO_SOFT_RESET: u16 = 1 << 3;
+/// The offset of the command register
+const COMMAND: u16 = 4;
+/// The dwords of the configuration header after the class code, which hold the bars, the rom, and the interrupt line
+const HEADER_REGISTERS: core::ops::RangeInclusive<u16> = 0x3..=0xf;
+
+/// The power management capability
+#[derive(Clone, Copy, Debug)]
+pub struct PowerManagement {
+    /// The function
+    location: PciLocation,
+    /// The offset of the capability
+    offset: u16,
+}
+
+impl PowerManagement {
+    /// Read the power management capabilities register
+    pub fn capabilities(&self, pci: &mut PciConfigurationSpace) -> u16 {
+        self.location.read_u16(pci, self.offset + 2).unwrap_or(0)
+    }
+
+    /// Returns true if the function supports the power state
+    pub fn supports(&self, pci: &mut PciConfigurationSpace, state: PowerState) -> bool {
+        let pmc = self.capabilities(pci);
+        match state {
+            PowerState::D0 | PowerState::D3Hot => true,
+            PowerState::D1 => (pmc & (1 << 9)) != 0,
+            PowerState::D2 => (pmc & (1 << 10)) != 0,
+        }
+    }
+
+    /// Get the current power state
+    pub fn state(&self, pci: &mut PciConfigurationSpace) -> PowerState {
+        match self.location.read_u16(pci, self.offset + 4).unwrap_or(0) & 3 {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+
+    /// Change the power state, waiting for the transition to complete. Returns false if the state is not supported.
+    pub async fn set_state(&self, pci: &mut PciConfigurationSpace, state: PowerState) -> bool {
+        if !self.supports(pci, state) {
+            return false;
+        }
+        let old = self.state(pci);
+        if old == state {
+            return true;
+        }
+        let csr = self.location.read_u16(pci, self.offset + 4).unwrap_or(0);
+        // Leaving D3hot resets the function unless it says otherwise, which clears the bars and the command register
+        let saved =
+            (old == PowerState::D3Hot && (csr & NO_SOFT_RESET) == 0).then(|| self.save_header(pci));
+        // The pme status bit is cleared by writing 1, so it is written as 0 to leave it alone
+        let csr = (csr & !0x8003)
+            | match state {
+                PowerState::D0 => 0,
+                PowerState::D1 => 1,
+                PowerState::D2 => 2,
+                PowerState::D3Hot => 3,
+            };
+        self.location.write_u16(pci, self.offset + 4, csr);
+        // Transitions to and from D3hot take 10ms, transitions involving D2 take 200us
+        let us = if old == PowerState::D3Hot || state == PowerState::D3Hot {
+            10_000
+        } else if old == PowerState::D2 || state == PowerState::D2 {
+            200
+        } else {
+            0
+        };
+        transition_delay(us).await;
+        if let Some(saved) = saved {
+            self.restore_header(pci, &saved);
+        }
+        true
+    }
+
+    /// Read the command register and the header registers that a soft reset clears
+    fn save_header(&self, pci: &mut PciConfigurationSpace) -> (u16, [u32; 13]) {
+        let command = self.location.read_u16(pci, COMMAND).unwrap_or(0);
+        let mut header = [0; 13];
+        for (h, i) in header.iter_mut().zip(HEADER_REGISTERS) {
+            *h = self.location.read_u32(pci, i * 4).unwrap_or(0);
+        }
+        (command, header)
+    }
+
+    /// Write back the registers saved by [Self::save_header], enabling decoding with the command register last
+    fn restore_header(&self, pci: &mut PciConfigurationSpace, saved: &(u16, [u32; 13])) {
+        let (command, header) = saved;
+        for (h, i) in header.iter().zip(HEADER_REGISTERS) {
+            self.location.write_u32(pci, i * 4, *h);
+        }
+        // The status register is written as 0 so that none of its bits are cleared
+        self.location.write_u32(pci, COMMAND, *command as u32);
+    }
+}
+
+/// Wait for a power state transition to complete
+async fn transition_delay(us: u64) {
+    if us != 0 {
+        crate::modules::time::sleep(crate::modules::time::Duration::from_micros(us)).await;
+    }
+}
+
+/// The message signaled interrupts capability
+#[derive(Clone, Copy, Debug)]
+pub struct Msi {
+    /// The function
+    location: PciLocation,
+    /// The offset of the capability
+    offset: u16,
+}
+
+impl Msi {
+    /// Read the message control register
+    pub fn control(&self, pci: &mut PciConfigurationSpace) -> u16 {
+        self.location.read_u16(pci, self.offset + 2).unwrap_or(0)
+    }
+
+    /// Returns true if the function uses a 64-bit message address
+    pub fn is64(&self, pci: &mut PciConfigurationSpace) -> bool {
+        (self.control(pci) & (1 << 7)) != 0
+    }
+
+    /// Returns true if the function can mask each vector
+    pub fn per_vector_masking(&self, pci: &mut PciConfigurationSpace) -> bool {
+        (self.control(pci) & (1 << 8)) != 0
+    }
+
+    /// The number of vectors the function can use
+    pub fn vectors(&self, pci: &mut PciConfigurationSpace) -> u8 {
+        1 << ((self.control(pci) >> 1) & 7)
+    }
+
+    /// Returns true if msi is enabled
+    pub fn enabled(&self, pci: &mut PciConfigurationSpace) -> bool {
+        (self.control(pci) & 1) != 0
+    }
+}
+
+/// The extended message signaled interrupts capability
+#[derive(Clone, Copy, Debug)]
+pub struct MsiX {
+    /// The function
+    location: PciLocation,
+    /// The offset of the capability
+    offset: u16,
+}
+
+impl MsiX {
+    /// Read the message control register
+    pub fn control(&self, pci: &mut PciConfigurationSpace) -> u16 {
+        self.location.read_u16(pci, self.offset + 2).unwrap_or(0)
+    }
+
+    /// The number of entries in the vector table
+    pub fn table_size(&self, pci: &mut PciConfigurationSpace) -> u16 {
+        (self.control(pci) & 0x7ff) + 1
+    }
+
+    /// The bar index and offset in that bar of the vector table
+    pub fn table(&self, pci: &mut PciConfigurationSpace) -> (u8, u32) {
+        let v = self.location.read_u32(pci, self.offset + 4).unwrap_or(0);
+        ((v & 7) as u8, v & !7)
+    }
+
+    /// The bar index and offset in that bar of the pending bit array
+    pub fn pending(&self, pci: &mut PciConfigurationSpace) -> (u8, u32) {
+        let v = self.location.read_u32(pci, self.offset + 8).unwrap_or(0);
+        ((v & 7) as u8, v & !7)
+    }
+
+    /// Returns true if msi-x is enabled
+    pub fn enabled(&self, pci: &mut PciConfigurationSpace) -> bool {
+        (self.control(pci) & (1 << 15)) != 0
+    }
+}
+
+/// The pci express capability
+#[derive(Clone, Copy, Debug)]
+pub struct PciExpress {
+    /// The function
+    location: PciLocation,
+    /// The offset of the capability
+    offset: u16,
+}
+
+impl PciExpress {
+    /// Read the pci express capabilities register
+    pub fn capabilities(&self, pci: &mut PciConfigurationSpace) -> u16 {
+        self.location.read_u16(pci, self.offset + 2).unwrap_or(0)
+    }
+
+    /// The version of the capability structure
+    pub fn version(&self, pci: &mut PciConfigurationSpace) -> u8 {
+        (self.capabilities(pci) & 0xf) as u8
+    }
+
+    /// The device or port type, such as 0 for an endpoint or 4 for a root port
+    pub fn port_type(&self, pci: &mut PciConfigurationSpace) -> u8 {
+        ((self.capabilities(pci) >> 4) & 0xf) as u8
+    }
+
+    /// The link status register, containing the current speed and width of the link
+    pub fn link_status(&self, pci: &mut PciConfigurationSpace) -> u16 {
+        self.location.read_u16(pci, self.offset + 0x12).unwrap_or(0)
+    }
+}
+
+/// A vendor specific capability
+#[derive(Clone, Copy, Debug)]
+pub struct VendorSpecific {
+    /// The function
+    location: PciLocation,
+    /// The offset of the capability
+    offset: u16,
+    /// True for the extended capability
+    extended: bool,
+}
+
+impl VendorSpecific {
+    /// The offset of the capability, where the vendor defined contents can be found
+    pub fn offset(&self) -> u16 {
+        self.offset
+    }
+
+    /// The length of the capability in bytes
+    pub fn length(&self, pci: &mut PciConfigurationSpace) -> u16 {
+        if self.extended {
+            self.location
+                .read_u32(pci, self.offset + 4)
+                .map(|v| (v >> 20) as u16)
+                .unwrap_or(0)
+        } else {
+            self.location.read_u8(pci, self.offset + 2).unwrap_or(0) as u16
+        }
+    }
+
+    /// Read a byte of the capability, at an offset from the start of the capability
+    pub fn read_u8(&self, pci: &mut PciConfigurationSpace, offset: u16) -> u8 {
+        self.location
+            .read_u8(pci, self.offset + offset)
+            .unwrap_or(0)
+    }
+
+    /// Read a dword of the capability, at an offset from the start of the capability
+    pub fn read_u32(&self, pci: &mut PciConfigurationSpace, offset: u16) -> u32 {
+        self.location
+            .read_u32(pci, self.offset + offset)
+            .unwrap_or(0)
+    }
+}
+
+/// The advanced error reporting capability
+#[derive(Clone, Copy, Debug)]
+pub struct Aer {
+    /// The function
+    location: PciLocation,
+    /// The offset of the capability
+    offset: u16,
+}
+
+impl Aer {
+    /// The uncorrectable error status register
+    pub fn uncorrectable_status(&self, pci: &mut PciConfigurationSpace) -> u32 {
+        self.location.read_u32(pci, self.offset + 4).unwrap_or(0)
+    }
+
+    /// The correctable error status register
+    pub fn correctable_status(&self, pci: &mut PciConfigurationSpace) -> u32 {
+        self.location.read_u32(pci, self.offset + 0x10).unwrap_or(0)
+    }
+
+    /// Clear the errors recorded in both status registers, which are cleared by writing 1
+    pub fn clear(&self, pci: &mut PciConfigurationSpace) {
+        let l = self.location;
+        let u = self.uncorrectable_status(pci);
+        pci.write_extended_u32(l.bus, l.device, l.function, self.offset + 4, u);
+        let c = self.correctable_status(pci);
+        pci.write_extended_u32(l.bus, l.device, l.function, self.offset + 0x10, c);
+    }
+}
@@ -0,0 +1,122 @@
+//! Pci express enhanced configuration access mechanism (ecam), a memory mapped window onto the full 4 KiB configuration space of each function
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use crate::{Arc, Locked};
+
+/// The ecam access for each region of the system, filled in from the acpi mcfg table.
+/// The pci enumeration and the acpi handler share these, so that each bus is mapped once.
+pub static ECAM: Locked<Vec<Ecam>> = Locked::new(Vec::new());
+
+/// The size of the configuration space for a single bus
+const BUS_SIZE: usize = 1 << 20;
+
+/// A region of ecam address space for a range of busses in a single pci segment
+#[derive(Clone, Copy, Debug)]
+pub struct EcamRegion {
+    /// The pci segment group
+    pub segment: u16,
+    /// The physical address of the configuration space for bus 0 of the segment, even when the region starts at a later bus
+    pub base: u64,
+    /// The first bus of the region
+    pub bus_start: u8,
+    /// The last bus of the region
+    pub bus_end: u8,
+}
+
+/// Access to pci configuration space with ecam for a single region. Clones share the mapped busses.
+#[derive(Clone)]
+pub struct Ecam {
+    /// The region of configuration space
+    region: EcamRegion,
+    /// The configuration space of each bus, mapped uncached when the bus is first accessed
+    busses: Arc<Locked<BTreeMap<u8, Arc<crate::MappedMemory>>>>,
+}
+
+impl Ecam {
+    /// Construct a new self for the given region
+    pub fn new(region: EcamRegion) -> Self {
+        Self {
+            region,
+            busses: Arc::new(Locked::new(BTreeMap::new())),
+        }
+    }
+
+    /// Get the region the configuration access is for
+    pub fn region(&self) -> &EcamRegion {
+        &self.region
+    }
+
+    /// Returns true if the bus of the segment is covered by this region
+    pub fn contains(&self, segment: u16, bus: u8) -> bool {
+        segment == self.region.segment
+            && (self.region.bus_start..=self.region.bus_end).contains(&bus)
+    }
+
+    /// Get the mapped configuration space of a bus and the offset of the register in it
+    fn register(
+        &self,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+    ) -> Option<(Arc<crate::MappedMemory>, usize)> {
+        if !(self.region.bus_start..=self.region.bus_end).contains(&bus)
+            || device >= 32
+            || function >= 8
+            || offset >= 0x1000
+        {
+            return None;
+        }
+        let m = {
+            let mut busses = self.busses.sync_lock();
+            match busses.get(&bus) {
+                Some(m) => m.clone(),
+                None => {
+                    let address = self.region.base as usize + bus as usize * BUS_SIZE;
+                    let m = Arc::new(crate::MappedMemory::new(address, BUS_SIZE).ok()?);
+                    busses.insert(bus, m.clone());
+                    m
+                }
+            }
+        };
+        let o = ((device as usize) << 15) | ((function as usize) << 12) | (offset as usize & !3);
+        Some((m, o))
+    }
+}
+
+impl super::PciConfigurationSpaceTrait for Ecam {
+    fn read_u16(&mut self, bus: u8, device: u8, function: u8, offset: u8) -> u16 {
+        match self.register(bus, device, function, offset as u16) {
+            Some((m, o)) => m.read_u16(o + (offset as usize & 2)),
+            None => 0xffff,
+        }
+    }
+
+    fn read_u32(&mut self, bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+        self.read_extended_u32(bus, device, function, offset as u16)
+            .unwrap_or(0xffff_ffff)
+    }
+
+    fn write_u32(&mut self, bus: u8, device: u8, function: u8, offset: u8, val: u32) {
+        self.write_extended_u32(bus, device, function, offset as u16, val);
+    }
+
+    fn read_extended_u32(&mut self, bus: u8, device: u8, function: u8, offset: u16) -> Option<u32> {
+        self.register(bus, device, function, offset)
+            .map(|(m, o)| m.read_u32(o))
+    }
+
+    fn write_extended_u32(
+        &mut self,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        val: u32,
+    ) -> Option<()> {
+        self.register(bus, device, function, offset)
+            .map(|(m, o)| m.write_u32(o, val))
+    }
+}
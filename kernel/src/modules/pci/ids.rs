@@ -0,0 +1,74 @@
+//! Names of pci vendors, devices, and classes, generated by the build script from the pci.ids database.
+//! The tables are empty unless the pci_ids option is enabled in the kernel configuration.
+
+use alloc::string::String;
+
+include!(concat!(env!("OUT_DIR"), "/pci_ids.rs"));
+
+/// Get the name of a vendor
+pub fn vendor_name(vendor: u16) -> Option<&'static str> {
+    VENDORS
+        .binary_search_by_key(&vendor, |(v, _)| *v)
+        .ok()
+        .map(|i| VENDORS[i].1)
+}
+
+/// Get the name of a device
+pub fn device_name(vendor: u16, device: u16) -> Option<&'static str> {
+    DEVICES
+        .binary_search_by_key(&(vendor, device), |(v, d, _)| (*v, *d))
+        .ok()
+        .map(|i| DEVICES[i].2)
+}
+
+/// Get the name of a subclass, or the name of the class when the subclass is not known
+pub fn class_name(class: u8, subclass: u8) -> Option<&'static str> {
+    let subclass_name = SUBCLASSES
+        .binary_search_by_key(&(class, subclass), |(c, s, _)| (*c, *s))
+        .ok()
+        .map(|i| SUBCLASSES[i].2);
+    let class_name = CLASSES
+        .binary_search_by_key(&class, |(c, _)| *c)
+        .ok()
+        .map(|i| CLASSES[i].1);
+    subclass_name.or(class_name)
+}
+
+/// Get the name of a programming interface
+pub fn prog_if_name(class: u8, subclass: u8, prog_if: u8) -> Option<&'static str> {
+    PROG_IFS
+        .binary_search_by_key(&(class, subclass, prog_if), |(c, s, p, _)| (*c, *s, *p))
+        .ok()
+        .map(|i| PROG_IFS[i].3)
+}
+
+/// Describe a function with the names that are known, such as "Intel Corporation 82540EM Gigabit Ethernet Controller [Ethernet controller]".
+/// Returns None when none of the names are known.
+pub fn describe(ids: &super::PciIds) -> Option<String> {
+    let class = class_name(ids.class, ids.subclass);
+    let vendor = vendor_name(ids.vendor);
+    let device = device_name(ids.vendor, ids.device);
+    if class.is_none() && vendor.is_none() {
+        return None;
+    }
+    let mut s = String::new();
+    if let Some(v) = vendor {
+        s.push_str(v);
+        s.push(' ');
+    }
+    if let Some(d) = device {
+        s.push_str(d);
+        s.push(' ');
+    }
+    if let Some(c) = class {
+        s.push('[');
+        s.push_str(c);
+        if let Some(p) = prog_if_name(ids.class, ids.subclass, ids.prog_if) {
+            s.push_str(" (");
+            s.push_str(p);
+            s.push(')');
+        }
+        s.push(']');
+    }
+    Some(String::from(s.trim_end()))
+}
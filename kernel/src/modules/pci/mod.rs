@@ -1,16 +1,21 @@
 //! Code for the pci bus
 
 use crate::{AsyncLockedArc, LockedArc};
-use alloc::{collections::BTreeMap, format};
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
 use lazy_static::lazy_static;
 
+pub mod capability;
+#[cfg(kernel_machine = "pc64")]
+pub mod ecam;
+pub mod ids;
+pub mod resource;
 #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
 pub mod x86;
 
 lazy_static! {
-    /// The entire list of gpios for the kernel
-    pub static ref PCI_DRIVERS: AsyncLockedArc<BTreeMap<u32, PciFunctionDriver>> =
-        AsyncLockedArc::new(BTreeMap::new());
+    /// The registered pci function drivers, in the order they were registered
+    pub static ref PCI_DRIVERS: AsyncLockedArc<Vec<PciFunctionDriver>> =
+        AsyncLockedArc::new(Vec::new());
 }
 
 /// Represents an invalid value for a pci vendor
@@ -106,6 +111,11 @@ impl ConfigurationSpaceStandard {
         self.interrupt_line
     }
 
+    /// Get the interrupt pin, 1 for INTA through 4 for INTD, or 0 when the function does not use an interrupt pin
+    pub fn get_interrupt_pin(&self) -> u8 {
+        self.interrupt_pin
+    }
+
     /// Dump the configuration data contents
     pub async fn dump(&self, linestart: &str) {
         for i in 0..6 {
@@ -615,16 +625,42 @@ impl ConfigurationSpace {
         self.device
     }
 
+    /// Retrieve the identification of the function, used to match drivers to the function
+    pub fn get_ids(&self) -> PciIds {
+        let subsystem = match self.get_space() {
+            Some(ConfigurationSpaceEnum::Standard(cs)) => Some((cs.subsystem_vendor, cs.subsystem)),
+            _ => None,
+        };
+        PciIds {
+            vendor: self.vendor,
+            device: self.device,
+            subsystem,
+            class: self.class,
+            subclass: self.subclass,
+            prog_if: self.prog_if,
+        }
+    }
+
     /// Dump the configuration space
     pub async fn dump(&self, linestart: &str) {
         crate::VGA
             .print_str_async(&format!("{}Configuration space:\r\n", linestart))
             .await;
         crate::VGA
-            .print_str_async(&format!("{}Vendor: {:x}\r\n", linestart, self.vendor))
+            .print_str_async(&format!(
+                "{}Vendor: {:x} {}\r\n",
+                linestart,
+                self.vendor,
+                ids::vendor_name(self.vendor).unwrap_or("")
+            ))
             .await;
         crate::VGA
-            .print_str_async(&format!("{}Device: {:x}\r\n", linestart, self.device))
+            .print_str_async(&format!(
+                "{}Device: {:x} {}\r\n",
+                linestart,
+                self.device,
+                ids::device_name(self.vendor, self.device).unwrap_or("")
+            ))
             .await;
         crate::VGA
             .print_str_async(&format!("{}Command: {:x}\r\n", linestart, self.command.0))
@@ -642,7 +678,12 @@ impl ConfigurationSpace {
             .print_str_async(&format!("{}Subclass: {:x}\r\n", linestart, self.subclass))
             .await;
         crate::VGA
-            .print_str_async(&format!("{}Class: {:x}\r\n", linestart, self.class))
+            .print_str_async(&format!(
+                "{}Class: {:x} {}\r\n",
+                linestart,
+                self.class,
+                ids::class_name(self.class, self.subclass).unwrap_or("")
+            ))
             .await;
         crate::VGA
             .print_str_async(&format!("{}Cache: {:x}\r\n", linestart, self.cache_size))
@@ -729,8 +770,10 @@ pub trait PciTrait {
     async fn setup(&mut self);
     /// Print all devices on the system
     async fn print_devices(&mut self);
+    /// Print the tree of busses, devices, and functions on the system
+    async fn print_tree(&mut self);
     /// Run all drivers that can be associated with pci functions
-    async fn driver_run(&mut self, d: &mut BTreeMap<u32, PciFunctionDriver>);
+    async fn driver_run(&mut self, d: &mut [PciFunctionDriver]);
 }
 
 /// A BAR space
@@ -865,7 +908,8 @@ impl BarSpace {
         }
     }
 
-    /// Obtain the memory space specified by the bar, only if it is memory space
+    /// Obtain the memory space specified by the bar, only if it is memory space.
+    /// A bar that has already been assigned an address is mapped, otherwise memory is allocated for it and the bar is written.
     pub fn get_memory(
         &mut self,
         pci: &mut PciConfigurationSpace,
@@ -876,11 +920,14 @@ impl BarSpace {
     ) -> Option<crate::PciMemory> {
         match self {
             BarSpace::Memory32 {
-                base: _,
+                base,
                 size,
                 flags,
                 index,
             } => {
+                if *base != 0 {
+                    return crate::PciMemory::map(*base as usize, *size as usize).ok();
+                }
                 let pcim = crate::PciMemory::new(*size as usize);
                 if let Ok(pcim) = &pcim {
                     let newbar = BarSpace::Memory32 {
@@ -895,12 +942,26 @@ impl BarSpace {
                 pcim.ok()
             }
             BarSpace::Memory64 {
-                base: _,
-                size: _,
-                flags: _,
-                index: _,
+                base,
+                size,
+                flags,
+                index,
             } => {
-                todo!()
+                if *base != 0 {
+                    return crate::PciMemory::map(*base as usize, *size as usize).ok();
+                }
+                let pcim = crate::PciMemory::new(*size as usize);
+                if let Ok(pcim) = &pcim {
+                    let newbar = BarSpace::Memory64 {
+                        base: pcim.phys() as u64,
+                        size: pcim.size() as u64,
+                        flags: *flags,
+                        index: *index,
+                    };
+                    newbar.write_to_pci(pci, bus, dev, function, config);
+                    *self = newbar;
+                }
+                pcim.ok()
             }
             BarSpace::IO {
                 base: _,
@@ -1057,9 +1118,73 @@ impl PciFunction {
         pci.write_u32(bus.num, dev.dev, self.function, 4, rval);
     }
 
-    /// Returns a combination of vendor and device id, to identify a potential driver for the function
-    fn get_driver_id(&self, pci: &mut PciConfigurationSpace, bus: &PciBus, dev: &PciDevice) -> u32 {
-        pci.read_u32(bus.num, dev.dev, self.function, 0)
+    /// Get the irq used by the function. The interrupt routing described by the firmware is used when it exists,
+    /// otherwise the interrupt line register is used, which is only valid with the 8259 interrupt controllers.
+    /// Returns None when the firmware routes the function to an interrupt above the irq numbers the kernel supports.
+    pub fn get_irq(
+        &self,
+        bus: &PciBus,
+        dev: &PciDevice,
+        config: &ConfigurationSpaceStandard,
+    ) -> Option<u8> {
+        #[cfg(kernel_machine = "pc64")]
+        if let Some(irq) =
+            crate::modules::acpi::prt::pci_irq(bus.num, dev.dev, config.get_interrupt_pin())
+        {
+            return u8::try_from(irq.gsi).ok();
+        }
+        #[cfg(not(kernel_machine = "pc64"))]
+        let _ = (bus, dev);
+        Some(config.get_interrupt_line())
+    }
+
+    /// Get the location of the function in configuration space
+    pub fn location(&self, bus: &PciBus, dev: &PciDevice) -> capability::PciLocation {
+        capability::PciLocation {
+            bus: bus.num,
+            device: dev.dev,
+            function: self.function,
+        }
+    }
+
+    /// Iterate over the standard and extended capabilities of the function
+    pub fn capabilities<'a>(
+        &self,
+        pci: &'a mut PciConfigurationSpace,
+        bus: &PciBus,
+        dev: &PciDevice,
+    ) -> capability::CapabilityIter<'a> {
+        capability::CapabilityIter::new(pci, self.location(bus, dev))
+    }
+
+    /// Get the power management capability of the function, if it has one
+    pub fn power_management(
+        &self,
+        pci: &mut PciConfigurationSpace,
+        bus: &PciBus,
+        dev: &PciDevice,
+    ) -> Option<capability::PowerManagement> {
+        let location = self.location(bus, dev);
+        self.capabilities(pci, bus, dev)
+            .find_map(|c| match c.typed(location) {
+                capability::TypedCapability::PowerManagement(pm) => Some(pm),
+                _ => None,
+            })
+    }
+
+    /// Move the function to a power state. A function without power management is always in D0.
+    /// Returns false if the function does not support the power state.
+    pub async fn set_power_state(
+        &self,
+        pci: &mut PciConfigurationSpace,
+        bus: &PciBus,
+        dev: &PciDevice,
+        state: capability::PowerState,
+    ) -> bool {
+        match self.power_management(pci, bus, dev) {
+            Some(pm) => pm.set_state(pci, state).await,
+            None => state == capability::PowerState::D0,
+        }
     }
 
     /// Returns the vendor id by reading the value from pci configuration space
@@ -1102,6 +1227,23 @@ impl PciFunction {
         (header & 0x80) != 0
     }
 
+    /// Returns the bus number register (primary, secondary, subordinate, and secondary latency) if the function is a pci to pci bridge
+    fn bridge_busses(
+        &self,
+        pci: &mut PciConfigurationSpace,
+        bus: &PciBus,
+        dev: &PciDevice,
+    ) -> Option<u32> {
+        let header = pci.read_u16(bus.num, dev.dev, self.function, 14) as u8;
+        ((header & 0x7f) == 1).then(|| pci.read_u32(bus.num, dev.dev, self.function, 0x18))
+    }
+
+    /// Build the description of the function used for the pci tree
+    fn describe(&self, pci: &mut PciConfigurationSpace, bus: &PciBus, dev: &PciDevice) -> String {
+        let ids = self.get_all_configuration(pci, bus, dev).get_ids();
+        format!("{:02x}:{:02x}.{} {}", bus.num, dev.dev, self.function, ids)
+    }
+
     /// Parse the bar registers for the function
     fn parse_bars(
         &self,
@@ -1121,8 +1263,9 @@ impl PciFunction {
         config.process_bars(bars, |barnum, bar, bar64| {
             let orig_bar: u32 = pci.read_u32(bus.num, dev.dev, self.function, bar);
             let mut upper_orig_bar: u32 = 0;
+            let is64 = (orig_bar & 7) == 4;
 
-            if (orig_bar & 4) != 0 {
+            if is64 {
                 if let Some(b) = bar64 {
                     upper_orig_bar = pci.read_u32(bus.num, dev.dev, self.function, b);
                 } else {
@@ -1131,18 +1274,18 @@ impl PciFunction {
             }
 
             pci.write_u32(bus.num, dev.dev, self.function, bar, 0xFFFFFFFF);
-            if (orig_bar & 4) != 0 {
+            if is64 {
                 pci.write_u32(bus.num, dev.dev, self.function, bar64.unwrap(), 0xFFFFFFFF);
             }
             let size = pci.read_u32(bus.num, dev.dev, self.function, bar);
-            let barspace = if size & 1 == 0 {
+            let barspace = if orig_bar & 1 == 0 {
                 //memory space
-                let bar = if (orig_bar & 4) != 0 {
-                    let usize: u32 = pci.read_u32(bus.num, dev.dev, self.function, bar);
-                    let bar64 = orig_bar as u64;
+                let bar = if is64 {
+                    let usize: u32 = pci.read_u32(bus.num, dev.dev, self.function, bar64.unwrap());
+                    let bar64 = (orig_bar as u64) | ((upper_orig_bar as u64) << 32);
                     let size64 = (size as u64) | ((usize as u64) << 32);
-                    let size: u64 = !(size64 & 0xFFFFFFFFFFFFFFF0) + 1;
-                    if size64 != 0 {
+                    let size: u64 = (!(size64 & 0xFFFFFFFFFFFFFFF0)).wrapping_add(1);
+                    if size != 0 {
                         BarSpace::Memory64 {
                             base: bar64 & 0xFFFFFFFFFFFFFFF0,
                             size,
@@ -1153,7 +1296,7 @@ impl PciFunction {
                         BarSpace::Invalid { index: barnum }
                     }
                 } else {
-                    let size: u32 = !(size & 0xFFFFFFF0) + 1;
+                    let size: u32 = (!(size & 0xFFFFFFF0)).wrapping_add(1);
                     if size != 0 {
                         BarSpace::Memory32 {
                             base: orig_bar & 0xFFFFFFF0,
@@ -1165,7 +1308,7 @@ impl PciFunction {
                         BarSpace::Invalid { index: barnum }
                     }
                 };
-                if (orig_bar & 4) != 0 {
+                if is64 {
                     pci.write_u32(
                         bus.num,
                         dev.dev,
@@ -1176,8 +1319,8 @@ impl PciFunction {
                 }
                 bar
             } else {
-                //io space
-                let size: u32 = !(size & 0xFFFFFFFC) + 1;
+                //io space, the upper 16 bits are allowed to be hardwired to zero
+                let size: u32 = (!(size & 0xFFFFFFFC)).wrapping_add(1) & 0xFFFF;
                 BarSpace::IO {
                     base: orig_bar & 0xFFFFFFFC,
                     size,
@@ -1200,6 +1343,17 @@ impl PciFunction {
     async fn print(&self, pci: &mut PciConfigurationSpace, bus: &PciBus, dev: &PciDevice) {
         let config = self.get_all_configuration(pci, bus, dev);
         config.dump("\t\t\t").await;
+        let location = self.location(bus, dev);
+        let caps: Vec<capability::Capability> = self.capabilities(pci, bus, dev).collect();
+        for c in caps {
+            crate::VGA
+                .print_str_async(&format!(
+                    "\t\t\tCapability at {:x}: {:?}\r\n",
+                    c.offset(),
+                    c.typed(location)
+                ))
+                .await;
+        }
     }
 }
 
@@ -1246,6 +1400,8 @@ impl PciDevice {
 pub struct PciBus {
     /// The pci bus number
     num: u8,
+    /// The bus, device, and function numbers of the bridge that leads to this bus, None for a host bus
+    parent: Option<(u8, u8, u8)>,
     /// The devices detected on the bus
     devices: alloc::vec::Vec<PciDevice>,
 }
@@ -1256,6 +1412,7 @@ impl PciBus {
         let mut found = false;
         let mut bus = PciBus {
             num,
+            parent: None,
             devices: alloc::vec::Vec::new(),
         };
         for dev in 0..32 {
@@ -1271,6 +1428,132 @@ impl PciBus {
         }
     }
 
+    /// Get the bus number
+    pub fn num(&self) -> u8 {
+        self.num
+    }
+
+    /// Get the bus, device, and function numbers of the bridge that leads to this bus, None for a host bus
+    pub fn parent(&self) -> Option<(u8, u8, u8)> {
+        self.parent
+    }
+
+    /// Get the device number, function number, and bus number register of every pci to pci bridge on the bus
+    fn bridges(&self, pci: &mut PciConfigurationSpace) -> Vec<(u8, u8, u32)> {
+        let mut bridges = Vec::new();
+        for d in &self.devices {
+            for f in &d.functions {
+                if let Some(v) = f.bridge_busses(pci, self, d) {
+                    bridges.push((d.dev, f.function, v));
+                }
+            }
+        }
+        bridges.sort_by_key(|(d, f, _)| (*d, *f));
+        bridges
+    }
+
+    /// Add this bus and every bus behind the pci to pci bridges on it to busses, returning the highest bus number found.
+    /// Busses in probed are reused instead of being probed again.
+    /// Bridges that were not given bus numbers by the firmware are assigned numbers starting at next, up to limit.
+    fn enumerate(
+        self,
+        pci: &mut PciConfigurationSpace,
+        limit: u8,
+        next: &mut u16,
+        probed: &mut BTreeMap<u8, PciBus>,
+        busses: &mut Vec<PciBus>,
+    ) -> u8 {
+        let num = self.num;
+        let bridges = self.bridges(pci);
+        busses.push(self);
+        let mut highest = num;
+        let mut unconfigured = Vec::new();
+        for (dev, function, v) in bridges {
+            let (secondary, subordinate) = ((v >> 8) as u8, (v >> 16) as u8);
+            if secondary > num && subordinate >= secondary && subordinate <= limit {
+                if busses.iter().any(|b| b.num == secondary) {
+                    continue;
+                }
+                let mut child = probed
+                    .remove(&secondary)
+                    .or_else(|| PciBus::new(pci, secondary))
+                    .unwrap_or(PciBus {
+                        num: secondary,
+                        parent: None,
+                        devices: Vec::new(),
+                    });
+                child.parent = Some((num, dev, function));
+                let h = child.enumerate(pci, subordinate, next, probed, busses);
+                highest = highest.max(h).max(subordinate);
+            } else {
+                unconfigured.push((dev, function, v));
+            }
+        }
+        for (dev, function, v) in unconfigured {
+            if *next > limit as u16 {
+                crate::VGA.print_str(&format!(
+                    "pci: No bus number available for bridge {:02x}:{:02x}.{}\r\n",
+                    num, dev, function
+                ));
+                continue;
+            }
+            let secondary = *next as u8;
+            *next += 1;
+            let busreg = |subordinate: u8| {
+                (v & 0xff00_0000)
+                    | ((subordinate as u32) << 16)
+                    | ((secondary as u32) << 8)
+                    | num as u32
+            };
+            pci.write_u32(num, dev, function, 0x18, busreg(limit));
+            let mut child = PciBus::new(pci, secondary).unwrap_or(PciBus {
+                num: secondary,
+                parent: None,
+                devices: Vec::new(),
+            });
+            child.parent = Some((num, dev, function));
+            let h = child.enumerate(pci, limit, next, probed, busses);
+            pci.write_u32(num, dev, function, 0x18, busreg(h));
+            crate::VGA.print_str(&format!(
+                "pci: Assigned busses {}-{} to bridge {:02x}:{:02x}.{}\r\n",
+                secondary, h, num, dev, function
+            ));
+            highest = highest.max(h);
+        }
+        highest
+    }
+
+    /// Add the lines of the pci tree for this bus, and the busses behind it, to lines
+    fn tree(
+        &self,
+        pci: &mut PciConfigurationSpace,
+        busses: &[PciBus],
+        segment: u16,
+        depth: usize,
+        lines: &mut Vec<String>,
+    ) {
+        let mut functions: Vec<(&PciDevice, &PciFunction)> = self
+            .devices
+            .iter()
+            .flat_map(|d| d.functions.iter().map(move |f| (d, f)))
+            .collect();
+        functions.sort_by_key(|(d, f)| (d.dev, f.function));
+        for (d, f) in functions {
+            lines.push(format!(
+                "{}{:04x}:{}",
+                "  ".repeat(depth),
+                segment,
+                f.describe(pci, self, d)
+            ));
+            for child in busses
+                .iter()
+                .filter(|b| b.parent == Some((self.num, d.dev, f.function)))
+            {
+                child.tree(pci, busses, segment, depth + 1, lines);
+            }
+        }
+    }
+
     /// Check to see if a specific device exists
     fn find_device(&self, pci: &mut PciConfigurationSpace, devnum: u8) -> Option<PciDevice> {
         let d = PciDevice {
@@ -1290,30 +1573,60 @@ impl PciBus {
         }
     }
 
-    /// Run drivers that can be associated with pci functions
-    async fn driver_run(
-        &self,
-        map: &mut alloc::collections::btree_map::BTreeMap<u32, PciFunctionDriver>,
-        pci: &mut PciConfigurationSpace,
-    ) {
+    /// Run drivers that can be associated with pci functions.
+    /// The drivers with a matching entry are tried from the most specific match to the least specific, until one of them binds to the function.
+    async fn driver_run(&self, drivers: &mut [PciFunctionDriver], pci: &mut PciConfigurationSpace) {
         for d in &self.devices {
             for f in &d.functions {
-                let id = f.get_driver_id(pci, self, d);
+                let config = f.get_all_configuration(pci, self, d);
+                let ids = config.get_ids();
                 crate::VGA
-                    .print_str_async(&format!("Checking pci device {:x}\r\n", id))
+                    .print_str_async(&format!("Checking pci device {}\r\n", ids))
                     .await;
-                if map.contains_key(&id) {
+                let mut candidates: Vec<(u8, usize)> = drivers
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, drv)| {
+                        drv.match_table()
+                            .iter()
+                            .filter(|m| m.matches(&ids))
+                            .map(|m| m.specificity())
+                            .max()
+                            .map(|s| (s, i))
+                    })
+                    .collect();
+                candidates.sort_by_key(|(s, i)| (core::cmp::Reverse(*s), *i));
+                let mut bound = false;
+                // The function is powered up before its bars are sized, because leaving D3hot can reset them
+                if !candidates.is_empty()
+                    && f.set_power_state(pci, self, d, capability::PowerState::D0)
+                        .await
+                {
                     let config = f.get_all_configuration(pci, self, d);
-                    let code = map.get_mut(&id).unwrap();
-                    let mut bars: [Option<BarSpace>; 6] = [None; 6];
-                    f.parse_bars(&mut bars, pci, self, d, &config);
-                    code.parse_bars(pci, self, d, f, &config.get_space().unwrap(), bars)
-                        .await;
-                } else {
+                    if let Some(space) = config.get_space() {
+                        let mut bars: [Option<BarSpace>; 6] = [None; 6];
+                        f.parse_bars(&mut bars, pci, self, d, &config);
+                        for (_, i) in candidates {
+                            // A driver that declined the function may have put it back to sleep
+                            if !f
+                                .set_power_state(pci, self, d, capability::PowerState::D0)
+                                .await
+                            {
+                                break;
+                            }
+                            if drivers[i].parse_bars(pci, self, d, f, &space, bars).await
+                                == PciProbeResult::Bound
+                            {
+                                bound = true;
+                                break;
+                            }
+                        }
+                    }
+                }
+                if !bound {
                     crate::VGA
-                        .print_str_async(&format!("Unknown PCI FUNCTION: {:X}\r\n", id))
+                        .print_str_async(&format!("Unknown PCI FUNCTION: {}\r\n", ids))
                         .await;
-                    let config = f.get_all_configuration(pci, self, d);
                     config.dump("\t").await;
                 }
             }
@@ -1321,6 +1634,59 @@ impl PciBus {
     }
 }
 
+/// Find every bus reachable through the configuration space, following pci to pci bridges from each host bus.
+/// Host busses are the busses with devices that are not behind a bridge, which includes each host bus of a multi-function host bridge.
+/// Bridges without valid bus numbers are assigned numbers above all of the busses already in use.
+pub fn enumerate_busses(pci: &mut PciConfigurationSpace) -> Vec<PciBus> {
+    let range = pci.busses();
+    let limit = *range.end();
+    let mut probed = BTreeMap::new();
+    for i in range {
+        if let Some(bus) = PciBus::new(pci, i) {
+            probed.insert(i, bus);
+        }
+    }
+
+    let mut next = probed.keys().last().map(|n| *n as u16 + 1).unwrap_or(0);
+    let mut secondaries = Vec::new();
+    for bus in probed.values() {
+        for (_, _, v) in bus.bridges(pci) {
+            let (secondary, subordinate) = ((v >> 8) as u8, (v >> 16) as u8);
+            if secondary > bus.num && subordinate >= secondary {
+                secondaries.push(secondary);
+                next = next.max(subordinate as u16 + 1);
+            }
+        }
+    }
+    let roots: Vec<u8> = probed
+        .keys()
+        .filter(|n| !secondaries.contains(n))
+        .copied()
+        .collect();
+
+    let mut busses = Vec::new();
+    for root in roots {
+        if let Some(bus) = probed.remove(&root) {
+            bus.enumerate(pci, limit, &mut next, &mut probed, &mut busses);
+        }
+    }
+    // Busses that claimed to be behind a bridge that was never reached are treated as host busses
+    while let Some((_, bus)) = probed.pop_first() {
+        bus.enumerate(pci, limit, &mut next, &mut probed, &mut busses);
+    }
+    busses
+}
+
+/// Build an lspci style tree of the busses, with the functions behind each bridge indented below it
+pub fn pci_tree(pci: &mut PciConfigurationSpace, busses: &[PciBus]) -> Vec<String> {
+    let segment = pci.segment();
+    let mut lines = Vec::new();
+    for bus in busses.iter().filter(|b| b.parent.is_none()) {
+        bus.tree(pci, busses, segment, 0, &mut lines);
+    }
+    lines
+}
+
 /// a pci bus instance
 #[enum_dispatch::enum_dispatch(PciTrait)]
 pub enum Pci {
@@ -1339,13 +1705,30 @@ impl Pci {
 
 /// The trait for accessing pci configuration space
 #[enum_dispatch::enum_dispatch]
-trait PciConfigurationSpaceTrait {
+pub trait PciConfigurationSpaceTrait {
     /// Read a configuration word
     fn read_u16(&mut self, bus: u8, device: u8, function: u8, offset: u8) -> u16;
     /// Read a configuration dword
     fn read_u32(&mut self, bus: u8, device: u8, function: u8, offset: u8) -> u32;
     /// Write a configuration dword
     fn write_u32(&mut self, bus: u8, device: u8, function: u8, offset: u8, val: u32);
+    /// Read a configuration dword anywhere in the 4 KiB configuration space of a function.
+    /// Returns None when the access mechanism cannot reach the offset.
+    fn read_extended_u32(&mut self, bus: u8, device: u8, function: u8, offset: u16) -> Option<u32> {
+        (offset < 0x100).then(|| self.read_u32(bus, device, function, offset as u8))
+    }
+    /// Write a configuration dword anywhere in the 4 KiB configuration space of a function.
+    /// Returns None when the access mechanism cannot reach the offset.
+    fn write_extended_u32(
+        &mut self,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        val: u32,
+    ) -> Option<()> {
+        (offset < 0x100).then(|| self.write_u32(bus, device, function, offset as u8, val))
+    }
 }
 
 /// The enum for accessing pci configuration space
@@ -1354,15 +1737,181 @@ pub enum PciConfigurationSpace {
     /// Access pci configuration space with io on x86
     #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
     X86(x86::PciRegisters),
+    /// Access pci configuration space with memory mapped ecam
+    #[cfg(kernel_machine = "pc64")]
+    Ecam(ecam::Ecam),
+}
+
+impl PciConfigurationSpace {
+    /// The range of busses that can be accessed
+    pub fn busses(&self) -> core::ops::RangeInclusive<u8> {
+        match self {
+            #[cfg(kernel_machine = "pc64")]
+            Self::Ecam(e) => e.region().bus_start..=e.region().bus_end,
+            #[allow(unreachable_patterns)]
+            _ => 0..=255,
+        }
+    }
+
+    /// The pci segment group that can be accessed
+    pub fn segment(&self) -> u16 {
+        match self {
+            #[cfg(kernel_machine = "pc64")]
+            Self::Ecam(e) => e.region().segment,
+            #[allow(unreachable_patterns)]
+            _ => 0,
+        }
+    }
+}
+
+/// The identification of a pci function, used to match drivers to the function
+#[derive(Clone, Copy, Debug)]
+pub struct PciIds {
+    /// The manufacturer of the device
+    pub vendor: u16,
+    /// The device id, assigned by the vendor
+    pub device: u16,
+    /// The subsystem vendor and subsystem id, only present for standard functions
+    pub subsystem: Option<(u16, u16)>,
+    /// The class code
+    pub class: u8,
+    /// The subclass code
+    pub subclass: u8,
+    /// The programming interface
+    pub prog_if: u8,
+}
+
+impl core::fmt::Display for PciIds {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:04x} class {:02x}{:02x}{:02x}",
+            self.vendor, self.device, self.class, self.subclass, self.prog_if
+        )?;
+        if let Some((v, d)) = self.subsystem {
+            write!(f, " subsystem {:04x}:{:04x}", v, d)?;
+        }
+        if let Some(name) = ids::describe(self) {
+            write!(f, " {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// An entry in the match table of a pci driver. Each field that is None matches any value.
+#[derive(Clone, Copy, Debug)]
+pub struct PciMatch {
+    /// The vendor to match
+    pub vendor: Option<u16>,
+    /// The device id to match
+    pub device: Option<u16>,
+    /// The subsystem vendor to match
+    pub subsystem_vendor: Option<u16>,
+    /// The subsystem id to match
+    pub subsystem: Option<u16>,
+    /// The class code to match
+    pub class: Option<u8>,
+    /// The subclass code to match
+    pub subclass: Option<u8>,
+    /// The programming interface to match
+    pub prog_if: Option<u8>,
+}
+
+impl PciMatch {
+    /// An entry that matches every function
+    pub const ANY: Self = Self {
+        vendor: None,
+        device: None,
+        subsystem_vendor: None,
+        subsystem: None,
+        class: None,
+        subclass: None,
+        prog_if: None,
+    };
+
+    /// An entry that matches a specific vendor and device id
+    pub const fn device(vendor: u16, device: u16) -> Self {
+        Self {
+            vendor: Some(vendor),
+            device: Some(device),
+            ..Self::ANY
+        }
+    }
+
+    /// An entry that matches a class and subclass, and optionally a programming interface
+    pub const fn class(class: u8, subclass: u8, prog_if: Option<u8>) -> Self {
+        Self {
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if,
+            ..Self::ANY
+        }
+    }
+
+    /// Restrict the entry to a subsystem vendor and subsystem id
+    pub const fn with_subsystem(self, vendor: u16, subsystem: u16) -> Self {
+        Self {
+            subsystem_vendor: Some(vendor),
+            subsystem: Some(subsystem),
+            ..self
+        }
+    }
+
+    /// Returns true if the entry matches the function
+    pub fn matches(&self, ids: &PciIds) -> bool {
+        /// Check a single field of the entry
+        fn check<T: PartialEq>(m: Option<T>, v: T) -> bool {
+            m.is_none_or(|m| m == v)
+        }
+        let (svendor, sdevice) = match ids.subsystem {
+            Some((v, d)) => (Some(v), Some(d)),
+            None => (None, None),
+        };
+        check(self.vendor, ids.vendor)
+            && check(self.device, ids.device)
+            && (self.subsystem_vendor.is_none() || self.subsystem_vendor == svendor)
+            && (self.subsystem.is_none() || self.subsystem == sdevice)
+            && check(self.class, ids.class)
+            && check(self.subclass, ids.subclass)
+            && check(self.prog_if, ids.prog_if)
+    }
+
+    /// The number of fields the entry matches on, drivers with more specific matches are probed first
+    pub fn specificity(&self) -> u8 {
+        [
+            self.vendor.is_some(),
+            self.device.is_some(),
+            self.subsystem_vendor.is_some(),
+            self.subsystem.is_some(),
+            self.class.is_some(),
+            self.subclass.is_some(),
+            self.prog_if.is_some(),
+        ]
+        .iter()
+        .filter(|b| **b)
+        .count() as u8
+    }
+}
+
+/// The result of a pci driver probing a function
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PciProbeResult {
+    /// The driver has taken control of the function
+    Bound,
+    /// The driver does not support the function, the next matching driver will be tried
+    Declined,
 }
 
 /// The trait that pci function drivers must implement
 #[enum_dispatch::enum_dispatch]
 pub trait PciFunctionDriverTrait: Clone + Default {
-    /// Register the driver in the given map, must check to see if the driver is already registered
-    async fn register(&self, m: &mut BTreeMap<u32, PciFunctionDriver>);
+    /// Register the driver in the given list, must check to see if the driver is already registered
+    async fn register(&self, m: &mut Vec<PciFunctionDriver>);
+
+    /// The table of functions the driver can probe
+    fn match_table(&self) -> &'static [PciMatch];
 
-    /// Parse a bar register for the device
+    /// Probe a matching function, parsing the bar registers for the device. The function is in D0 when this is called.
     async fn parse_bars(
         &mut self,
         cs: &mut PciConfigurationSpace,
@@ -1371,7 +1920,7 @@ pub trait PciFunctionDriverTrait: Clone + Default {
         f: &PciFunction,
         config: &ConfigurationSpaceEnum,
         bars: [Option<BarSpace>; 6],
-    );
+    ) -> PciProbeResult;
 }
 
 /// Register all pci drivers with the driver map
@@ -1397,6 +1946,10 @@ pub enum PciFunctionDriver {
     Dummy(DummyPciFunctionDriver),
     /// Intel pro1000 ethernet driver
     IntelPro1000(crate::modules::network::intel::IntelPro1000),
+    /// Virtio network driver
+    VirtioNet(crate::modules::network::virtio::VirtioNet),
+    /// Realtek rtl8139 ethernet driver
+    Rtl8139(crate::modules::network::realtek::Rtl8139),
 }
 
 impl Default for PciFunctionDriver {
@@ -1409,6 +1962,8 @@ impl Default for PciFunctionDriver {
 static PCI_CODE: &[PciFunctionDriver] = &[
     PciFunctionDriver::Dummy(DummyPciFunctionDriver {}),
     PciFunctionDriver::IntelPro1000(crate::modules::network::intel::IntelPro1000::new()),
+    PciFunctionDriver::VirtioNet(crate::modules::network::virtio::VirtioNet::new()),
+    PciFunctionDriver::Rtl8139(crate::modules::network::realtek::Rtl8139::new()),
 ];
 
 /// A dummy pci driver that does nothing
@@ -1416,12 +1971,16 @@ static PCI_CODE: &[PciFunctionDriver] = &[
 pub struct DummyPciFunctionDriver {}
 
 impl PciFunctionDriverTrait for DummyPciFunctionDriver {
-    async fn register(&self, _m: &mut BTreeMap<u32, PciFunctionDriver>) {
+    async fn register(&self, _m: &mut Vec<PciFunctionDriver>) {
         crate::VGA
             .print_str_async("Register dummy pci driver\r\n")
             .await;
     }
 
+    fn match_table(&self) -> &'static [PciMatch] {
+        &[]
+    }
+
     async fn parse_bars(
         &mut self,
         _cs: &mut PciConfigurationSpace,
@@ -1430,18 +1989,35 @@ impl PciFunctionDriverTrait for DummyPciFunctionDriver {
         _f: &PciFunction,
         _config: &ConfigurationSpaceEnum,
         _bars: [Option<BarSpace>; 6],
-    ) {
-        panic!();
+    ) -> PciProbeResult {
+        PciProbeResult::Declined
     }
 }
 
-/// Setup the x86 pci space and register all pci drivers
+/// Setup the x86 pci space and register all pci drivers. Ecam is used for each region described by acpi, with io port access as the fallback.
 pub async fn setup_pci() {
-    let pci = crate::modules::pci::x86::Pci::new();
-    if let Some(pci) = pci {
+    let mut systems = alloc::vec::Vec::new();
+    #[cfg(kernel_machine = "pc64")]
+    let regions = ecam::ECAM.sync_lock().clone();
+    #[cfg(kernel_machine = "pc64")]
+    for e in regions {
+        let r = *e.region();
+        crate::VGA
+            .print_str_async(&format!(
+                "pci: Ecam for segment {} busses {}-{} at {:x}\r\n",
+                r.segment, r.bus_start, r.bus_end, r.base
+            ))
+            .await;
+        systems.push(crate::modules::pci::x86::Pci::new_ecam(e));
+    }
+    if systems.is_empty() {
+        systems.extend(crate::modules::pci::x86::Pci::new());
+    }
+    crate::modules::pci::pci_register_drivers().await;
+    for pci in systems {
         let mut pci = crate::modules::pci::Pci::X86Pci(pci);
         pci.setup().await;
-        crate::modules::pci::pci_register_drivers().await;
+        pci.print_tree().await;
         pci.driver_setup().await;
     }
 }
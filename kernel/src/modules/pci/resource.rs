@@ -0,0 +1,687 @@
+//! Assignment of pci bar and bridge window address space from the apertures of the host bridges.
+//! Firmware assignments are kept when they are usable, otherwise everything behind the host bus is assigned again.
+
+use alloc::vec::Vec;
+
+use super::{BarSpace, PciBus, PciConfigurationSpace, PciConfigurationSpaceTrait};
+
+/// The granularity of the io window of a pci to pci bridge
+const IO_WINDOW: u64 = 0x1000;
+/// The granularity of the memory windows of a pci to pci bridge
+const MEMORY_WINDOW: u64 = 0x10_0000;
+/// The first address above the 32-bit address space
+const FOUR_GB: u64 = 1 << 32;
+
+/// The address space that a resource is placed in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// Io space
+    Io,
+    /// Memory space that must be below 4 GiB, placed in the non-prefetchable window of bridges
+    Memory,
+    /// 64-bit prefetchable memory space, placed in the prefetchable window of bridges
+    Prefetchable,
+}
+
+/// All kinds of address space
+const KINDS: [Kind; 3] = [Kind::Io, Kind::Memory, Kind::Prefetchable];
+
+/// The address ranges that a host bridge forwards to pci, as (base, length)
+#[derive(Clone, Debug, Default)]
+pub struct Apertures {
+    /// The io port ranges
+    pub io: Vec<(u64, u64)>,
+    /// The memory ranges, above and below 4 GiB
+    pub memory: Vec<(u64, u64)>,
+}
+
+impl Apertures {
+    /// The apertures used when the firmware does not describe the host bridge
+    pub fn legacy() -> Self {
+        Self {
+            io: alloc::vec![(0x1000, 0xf000)],
+            memory: alloc::vec![(0xe000_0000, 0x1ec0_0000)],
+        }
+    }
+
+    /// Find the apertures of the host bridge for a bus from the platform devices, falling back to [Apertures::legacy].
+    /// The legacy io ports and the memory below 1 MiB are never used for assignment.
+    pub fn for_bus(bus: u8) -> Self {
+        let mut devices = crate::kernel::DEVICES.sync_lock();
+        let mut i = 0;
+        while devices.exists(i) {
+            let d = devices.module(i);
+            i += 1;
+            let d = d.sync_lock();
+            let crate::modules::Device::Platform(p) = &*d else {
+                continue;
+            };
+            if !p.is_compatible("PNP0A03") && !p.is_compatible("PNP0A08") {
+                continue;
+            }
+            let mut busses = p.resources.iter().filter_map(|r| match r {
+                crate::modules::platform::PlatformResource::BusNumbers { base, length } => {
+                    Some((*base, *length))
+                }
+                _ => None,
+            });
+            let covered = match busses.next() {
+                Some((base, length)) => (base..base + length).contains(&(bus as u16)),
+                None => bus == 0,
+            };
+            if !covered {
+                continue;
+            }
+            let clip = |(base, length): (u64, u64), min: u64| {
+                let end = base + length;
+                let base = base.max(min);
+                (end > base).then_some((base, end - base))
+            };
+            let io = p
+                .io_ports()
+                .filter_map(|(b, l)| clip((b as u64, l as u64), 0x1000))
+                .collect();
+            let memory = p.memory().filter_map(|r| clip(r, 0x10_0000)).collect();
+            return Self { io, memory };
+        }
+        Self::legacy()
+    }
+
+    /// The ranges that a kind of resource can be placed in
+    fn ranges(&self, kind: Kind, above_4g: bool) -> Vec<(u64, u64)> {
+        match kind {
+            Kind::Io => self.io.clone(),
+            Kind::Memory => self
+                .memory
+                .iter()
+                .filter(|(b, l)| b + l <= FOUR_GB)
+                .copied()
+                .collect(),
+            Kind::Prefetchable => {
+                let mut r: Vec<(u64, u64)> = self
+                    .memory
+                    .iter()
+                    .filter(|(b, l)| above_4g || b + l <= FOUR_GB)
+                    .copied()
+                    .collect();
+                // Prefer space above 4 GiB, leaving the scarce space below for the resources that need it
+                r.sort_by_key(|(b, _)| core::cmp::Reverse(*b));
+                r
+            }
+        }
+    }
+
+    /// Returns true if the range of addresses is contained in an aperture for the kind of resource
+    fn contains(&self, kind: Kind, base: u64, size: u64) -> bool {
+        self.ranges(kind, true)
+            .iter()
+            .any(|(b, l)| base >= *b && base + size <= b + l)
+    }
+}
+
+/// A bar of a function
+struct Bar {
+    /// The bus number
+    bus: u8,
+    /// The device number
+    device: u8,
+    /// The function number
+    function: u8,
+    /// The offset of the bar in configuration space
+    offset: u8,
+    /// The address space of the bar
+    kind: Kind,
+    /// True when the bar is 64 bits wide
+    is64: bool,
+    /// The address currently assigned
+    base: u64,
+    /// The size in bytes
+    size: u64,
+}
+
+/// A pci to pci bridge
+struct Bridge {
+    /// The bus number
+    bus: u8,
+    /// The device number
+    device: u8,
+    /// The function number
+    function: u8,
+    /// The bus behind the bridge
+    secondary: u8,
+    /// True when the prefetchable window supports 64-bit addresses
+    prefetchable64: bool,
+    /// The windows assigned to the bridge for each kind, as (base, size)
+    windows: [Option<(u64, u64)>; 3],
+}
+
+/// A resource to be placed behind a bus
+struct Request {
+    /// The index of the bar or bridge in [Resources]
+    target: Target,
+    /// The size in bytes
+    size: u64,
+    /// The required alignment
+    align: u64,
+}
+
+/// The item a [Request] is for
+enum Target {
+    /// A bar, indexed into [Resources::bars]
+    Bar(usize),
+    /// The window of a bridge, indexed into [Resources::bridges]
+    Window(usize),
+}
+
+/// Round a value up to an alignment
+fn align_up(v: u64, align: u64) -> u64 {
+    v.div_ceil(align) * align
+}
+
+/// The resources of all functions of a pci system
+struct Resources {
+    /// All the bars
+    bars: Vec<Bar>,
+    /// All the pci to pci bridges
+    bridges: Vec<Bridge>,
+}
+
+impl Resources {
+    /// Size all of the bars and find all the bridges of the busses
+    fn collect(pci: &mut PciConfigurationSpace, busses: &[PciBus]) -> Self {
+        let mut bars = Vec::new();
+        let mut bridges = Vec::new();
+        for bus in busses {
+            for d in &bus.devices {
+                for f in &d.functions {
+                    let config = f.get_all_configuration(pci, bus, d);
+                    let Some(space) = config.get_space() else {
+                        continue;
+                    };
+                    if let Some(child) = busses
+                        .iter()
+                        .find(|b| b.parent == Some((bus.num, d.dev, f.function)))
+                    {
+                        let pref = pci.read_u32(bus.num, d.dev, f.function, 0x24);
+                        bridges.push(Bridge {
+                            bus: bus.num,
+                            device: d.dev,
+                            function: f.function,
+                            secondary: child.num,
+                            prefetchable64: (pref & 0xf) == 1,
+                            windows: [None; 3],
+                        });
+                    }
+                    let mut barspace: [Option<BarSpace>; 6] = [None; 6];
+                    f.parse_bars(&mut barspace, pci, bus, d, &config);
+                    for b in barspace.iter().flatten() {
+                        let offset = space.get_bars()[b.get_index() as usize];
+                        let (kind, is64, base, size) = match *b {
+                            BarSpace::Memory32 { base, size, .. } => {
+                                (Kind::Memory, false, base as u64, size as u64)
+                            }
+                            BarSpace::Memory64 {
+                                base, size, flags, ..
+                            } => {
+                                let kind = if (flags & 8) != 0 {
+                                    Kind::Prefetchable
+                                } else {
+                                    Kind::Memory
+                                };
+                                (kind, true, base, size)
+                            }
+                            BarSpace::IO { base, size, .. } => {
+                                (Kind::Io, false, base as u64, size as u64)
+                            }
+                            BarSpace::Invalid { .. } => continue,
+                        };
+                        bars.push(Bar {
+                            bus: bus.num,
+                            device: d.dev,
+                            function: f.function,
+                            offset,
+                            kind,
+                            is64,
+                            base,
+                            size,
+                        });
+                    }
+                }
+            }
+        }
+        Self { bars, bridges }
+    }
+
+    /// The bus numbers of a host bus and every bus behind it
+    fn tree(&self, root: u8) -> Vec<u8> {
+        let mut busses = alloc::vec![root];
+        let mut i = 0;
+        while i < busses.len() {
+            let b = busses[i];
+            busses.extend(
+                self.bridges
+                    .iter()
+                    .filter(|br| br.bus == b)
+                    .map(|br| br.secondary),
+            );
+            i += 1;
+        }
+        busses
+    }
+
+    /// Read the current window of a bridge for a kind of resource, as (base, size)
+    fn read_window(pci: &mut PciConfigurationSpace, b: &Bridge, kind: Kind) -> Option<(u64, u64)> {
+        let (base, limit) = match kind {
+            Kind::Io => {
+                let v = pci.read_u32(b.bus, b.device, b.function, 0x1c);
+                let upper = pci.read_u32(b.bus, b.device, b.function, 0x30);
+                (
+                    ((v as u64 & 0xf0) << 8) | ((upper as u64 & 0xffff) << 16),
+                    ((v as u64 & 0xf000) | 0xfff) | ((upper as u64 >> 16) << 16),
+                )
+            }
+            Kind::Memory => {
+                let v = pci.read_u32(b.bus, b.device, b.function, 0x20);
+                (
+                    (v as u64 & 0xfff0) << 16,
+                    ((v as u64 & 0xfff0_0000) | 0xf_ffff),
+                )
+            }
+            Kind::Prefetchable => {
+                let v = pci.read_u32(b.bus, b.device, b.function, 0x24);
+                let (ubase, ulimit) = if b.prefetchable64 {
+                    (
+                        pci.read_u32(b.bus, b.device, b.function, 0x28) as u64,
+                        pci.read_u32(b.bus, b.device, b.function, 0x2c) as u64,
+                    )
+                } else {
+                    (0, 0)
+                };
+                (
+                    ((v as u64 & 0xfff0) << 16) | (ubase << 32),
+                    ((v as u64 & 0xfff0_0000) | 0xf_ffff) | (ulimit << 32),
+                )
+            }
+        };
+        (limit > base).then_some((base, limit - base + 1))
+    }
+
+    /// Check if the firmware assignment of the busses is usable.
+    /// Every bar must be assigned inside the apertures and inside the windows of every bridge above it, without overlapping another bar.
+    fn firmware_valid(
+        &self,
+        pci: &mut PciConfigurationSpace,
+        tree: &[u8],
+        apertures: &Apertures,
+    ) -> bool {
+        let bars: Vec<&Bar> = self.bars.iter().filter(|b| tree.contains(&b.bus)).collect();
+        for (i, b) in bars.iter().enumerate() {
+            if b.base == 0 || !apertures.contains(b.kind, b.base, b.size) {
+                return false;
+            }
+            if bars[i + 1..].iter().any(|o| {
+                (o.kind == Kind::Io) == (b.kind == Kind::Io)
+                    && o.base < b.base + b.size
+                    && b.base < o.base + o.size
+            }) {
+                return false;
+            }
+            let mut bus = b.bus;
+            while let Some(br) = self.bridges.iter().find(|br| br.secondary == bus) {
+                let windows: Vec<(u64, u64)> = match b.kind {
+                    Kind::Io => Self::read_window(pci, br, Kind::Io).into_iter().collect(),
+                    _ => [Kind::Memory, Kind::Prefetchable]
+                        .iter()
+                        .filter_map(|k| Self::read_window(pci, br, *k))
+                        .collect(),
+                };
+                if !windows
+                    .iter()
+                    .any(|(wb, ws)| b.base >= *wb && b.base + b.size <= wb + ws)
+                {
+                    return false;
+                }
+                bus = br.bus;
+            }
+        }
+        true
+    }
+
+    /// The resources of a kind that need to be placed directly behind a bus
+    fn requests(&self, bus: u8, kind: Kind) -> Vec<Request> {
+        let mut r: Vec<Request> = self
+            .bars
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.bus == bus && b.kind == kind)
+            .map(|(i, b)| Request {
+                target: Target::Bar(i),
+                size: b.size,
+                align: b.size,
+            })
+            .collect();
+        for (i, br) in self.bridges.iter().enumerate() {
+            if br.bus != bus {
+                continue;
+            }
+            let (size, align) = Self::layout(&self.requests(br.secondary, kind));
+            if size == 0 {
+                continue;
+            }
+            let granularity = if kind == Kind::Io {
+                IO_WINDOW
+            } else {
+                MEMORY_WINDOW
+            };
+            r.push(Request {
+                target: Target::Window(i),
+                size: align_up(size, granularity),
+                align: align.max(granularity),
+            });
+        }
+        r.sort_by_key(|r| core::cmp::Reverse(r.align));
+        r
+    }
+
+    /// The total size and alignment needed to place a set of requests, which must be sorted by decreasing alignment
+    fn layout(requests: &[Request]) -> (u64, u64) {
+        let mut size = 0;
+        for r in requests {
+            size = align_up(size, r.align) + r.size;
+        }
+        (size, requests.first().map(|r| r.align).unwrap_or(1))
+    }
+
+    /// Place the resources of a kind behind a bus, starting at base
+    fn place(&mut self, pci: &mut PciConfigurationSpace, bus: u8, kind: Kind, base: u64) {
+        let mut cursor = base;
+        for r in self.requests(bus, kind) {
+            cursor = align_up(cursor, r.align);
+            match r.target {
+                Target::Bar(i) => {
+                    let b = &mut self.bars[i];
+                    b.base = cursor;
+                    let flags = pci.read_u32(b.bus, b.device, b.function, b.offset)
+                        & if kind == Kind::Io { 3 } else { 0xf };
+                    pci.write_u32(
+                        b.bus,
+                        b.device,
+                        b.function,
+                        b.offset,
+                        (cursor as u32) | flags,
+                    );
+                    if b.is64 {
+                        pci.write_u32(
+                            b.bus,
+                            b.device,
+                            b.function,
+                            b.offset + 4,
+                            (cursor >> 32) as u32,
+                        );
+                    }
+                }
+                Target::Window(i) => {
+                    self.bridges[i].windows[kind as usize] = Some((cursor, r.size));
+                    let secondary = self.bridges[i].secondary;
+                    self.place(pci, secondary, kind, cursor);
+                }
+            }
+            cursor += r.size;
+        }
+    }
+
+    /// Write the assigned windows of a bridge, closing the windows that are not used
+    fn program_bridge(pci: &mut PciConfigurationSpace, b: &Bridge) {
+        let (bus, dev, f) = (b.bus, b.device, b.function);
+        let range = |kind: Kind| {
+            b.windows[kind as usize]
+                .map(|(base, size)| (base, base + size - 1))
+                .unwrap_or((0xffff_ffff_ffff_f000, 0))
+        };
+
+        let (base, limit) = range(Kind::Io);
+        let v = pci.read_u32(bus, dev, f, 0x1c) & 0x0f0f;
+        let v = v | ((base as u32 >> 8) & 0xf0) | ((limit as u32) & 0xf000);
+        pci.write_u32(bus, dev, f, 0x1c, v);
+        pci.write_u32(
+            bus,
+            dev,
+            f,
+            0x30,
+            ((base >> 16) as u32 & 0xffff) | (((limit >> 16) as u32 & 0xffff) << 16),
+        );
+
+        let (base, limit) = range(Kind::Memory);
+        pci.write_u32(
+            bus,
+            dev,
+            f,
+            0x20,
+            ((base >> 16) as u32 & 0xfff0) | (limit as u32 & 0xfff0_0000),
+        );
+
+        let (base, limit) = range(Kind::Prefetchable);
+        let v = pci.read_u32(bus, dev, f, 0x24) & 0x000f_000f;
+        pci.write_u32(
+            bus,
+            dev,
+            f,
+            0x24,
+            v | ((base >> 16) as u32 & 0xfff0) | (limit as u32 & 0xfff0_0000),
+        );
+        if b.prefetchable64 {
+            pci.write_u32(bus, dev, f, 0x28, (base >> 32) as u32);
+            pci.write_u32(bus, dev, f, 0x2c, (limit >> 32) as u32);
+        }
+    }
+
+    /// Set or clear the decoding bits of the command register of a function, leaving the status register unchanged
+    fn set_command(pci: &mut PciConfigurationSpace, bus: u8, dev: u8, f: u8, set: u16, clear: u16) {
+        let v = pci.read_u32(bus, dev, f, 4) as u16;
+        pci.write_u32(bus, dev, f, 4, ((v & !clear) | set) as u32);
+    }
+}
+
+/// Find free space for a resource in the apertures, avoiding the ranges already used
+fn allocate(ranges: &[(u64, u64)], used: &[(u64, u64)], size: u64, align: u64) -> Option<u64> {
+    for (base, length) in ranges {
+        let end = base + length;
+        let mut cursor = align_up(*base, align);
+        while cursor + size <= end {
+            match used
+                .iter()
+                .find(|(ub, us)| cursor < ub + us && *ub < cursor + size)
+            {
+                Some((ub, us)) => cursor = align_up(ub + us, align),
+                None => return Some(cursor),
+            }
+        }
+    }
+    None
+}
+
+/// Assign address space to the bars and bridge windows of every host bus that the firmware did not assign properly,
+/// then enable decoding for every function that has resources.
+pub fn assign_resources(pci: &mut PciConfigurationSpace, busses: &[PciBus]) {
+    let mut resources = Resources::collect(pci, busses);
+    let roots: Vec<u8> = busses
+        .iter()
+        .filter(|b| b.parent.is_none())
+        .map(|b| b.num)
+        .collect();
+
+    let mut used: [Vec<(u64, u64)>; 2] = [Vec::new(), Vec::new()];
+    let mut reassign = Vec::new();
+    for root in roots {
+        let tree = resources.tree(root);
+        let apertures = Apertures::for_bus(root);
+        if resources.firmware_valid(pci, &tree, &apertures) {
+            for b in resources.bars.iter().filter(|b| tree.contains(&b.bus)) {
+                used[(b.kind != Kind::Io) as usize].push((b.base, b.size));
+            }
+        } else {
+            reassign.push((root, tree, apertures));
+        }
+    }
+
+    for (root, tree, apertures) in reassign {
+        crate::VGA.print_str(&alloc::format!(
+            "pci: Assigning resources for host bus {}\r\n",
+            root
+        ));
+        for b in resources.bars.iter().filter(|b| tree.contains(&b.bus)) {
+            Resources::set_command(pci, b.bus, b.device, b.function, 0, 3);
+        }
+        let above_4g = resources
+            .bridges
+            .iter()
+            .filter(|b| tree.contains(&b.bus))
+            .all(|b| b.prefetchable64);
+        for kind in KINDS {
+            let requests = resources.requests(root, kind);
+            let (size, align) = Resources::layout(&requests);
+            if size == 0 {
+                continue;
+            }
+            let u = &mut used[(kind != Kind::Io) as usize];
+            let base = allocate(&apertures.ranges(kind, above_4g), u, size, align).or_else(|| {
+                // Prefetchable resources can share the space of the non-prefetchable ones
+                (kind == Kind::Prefetchable)
+                    .then(|| allocate(&apertures.ranges(Kind::Memory, false), u, size, align))
+                    .flatten()
+            });
+            match base {
+                Some(base) => {
+                    u.push((base, size));
+                    resources.place(pci, root, kind, base);
+                    crate::VGA.print_str(&alloc::format!(
+                        "pci: {:?} space {:x} size {:x}\r\n",
+                        kind,
+                        base,
+                        size
+                    ));
+                }
+                None => crate::VGA.print_str(&alloc::format!(
+                    "pci: No {:?} space for {:x} bytes behind host bus {}\r\n",
+                    kind,
+                    size,
+                    root
+                )),
+            }
+        }
+        for b in resources.bridges.iter().filter(|b| tree.contains(&b.bus)) {
+            Resources::program_bridge(pci, b);
+        }
+    }
+
+    for b in &resources.bars {
+        if b.base != 0 {
+            let bit = if b.kind == Kind::Io { 1 } else { 2 };
+            Resources::set_command(pci, b.bus, b.device, b.function, bit, 0);
+        }
+    }
+    for b in &resources.bridges {
+        // Bridges forward both kinds of cycles, and bus mastering lets them forward dma from the devices behind them
+        Resources::set_command(pci, b.bus, b.device, b.function, 7, 0);
+    }
+}
+
+/// Test the alignment and packing of requests and the search for free space
+#[doors_macros::doors_test]
+fn resource_allocate_test() -> Result<(), ()> {
+    assert_eq!(align_up(0, 0x1000), 0);
+    assert_eq!(align_up(0x1001, 0x1000), 0x2000);
+    assert_eq!(align_up(0x2000, 0x1000), 0x2000);
+
+    let request = |size, align| Request {
+        target: Target::Bar(0),
+        size,
+        align,
+    };
+    assert_eq!(Resources::layout(&[]), (0, 1));
+    assert_eq!(
+        Resources::layout(&[
+            request(0x1000, 0x1000),
+            request(0x100, 0x100),
+            request(0x10, 0x10)
+        ]),
+        (0x1110, 0x1000)
+    );
+    assert_eq!(
+        Resources::layout(&[request(0x10, 0x100), request(0x10, 0x100)]),
+        (0x110, 0x100)
+    );
+
+    assert_eq!(
+        allocate(&[(0x1800, 0x10000)], &[], 0x1000, 0x4000),
+        Some(0x4000)
+    );
+    // Space already used is skipped, keeping the alignment
+    let used = [(0x1000, 0x800)];
+    assert_eq!(
+        allocate(&[(0x1000, 0x3000)], &used, 0x1000, 0x1000),
+        Some(0x2000)
+    );
+    assert_eq!(
+        allocate(&[(0x1000, 0x3000)], &used, 0x2000, 0x1000),
+        Some(0x2000)
+    );
+    // Exhaustion of the only range, then falling through to the next range
+    assert_eq!(allocate(&[(0x1000, 0x3000)], &used, 0x3000, 0x1000), None);
+    assert_eq!(
+        allocate(
+            &[(0x1000, 0x3000), (0x10000, 0x10000)],
+            &used,
+            0x3000,
+            0x1000
+        ),
+        Some(0x10000)
+    );
+    Ok(())
+}
+
+/// Test the placement of memory and 64-bit prefetchable resources in the apertures
+#[doors_macros::doors_test]
+fn resource_aperture_test() -> Result<(), ()> {
+    let low = (0xc000_0000, 0x1000_0000);
+    let high = (0x8_0000_0000, 0x1_0000_0000);
+    let apertures = Apertures {
+        io: alloc::vec![(0x1000, 0xf000)],
+        memory: alloc::vec![low, high],
+    };
+    assert_eq!(apertures.ranges(Kind::Io, true), [(0x1000, 0xf000)]);
+    assert_eq!(apertures.ranges(Kind::Memory, true), [low]);
+    assert_eq!(apertures.ranges(Kind::Prefetchable, true), [high, low]);
+    assert_eq!(apertures.ranges(Kind::Prefetchable, false), [low]);
+
+    assert!(apertures.contains(Kind::Memory, 0xc000_0000, 0x1000));
+    assert!(!apertures.contains(Kind::Memory, 0xcfff_f000, 0x2000));
+    assert!(!apertures.contains(Kind::Memory, 0x8_0000_0000, 0x1000));
+    assert!(apertures.contains(Kind::Prefetchable, 0x8_0000_0000, 0x1000));
+
+    // Prefetchable resources go above 4 GiB when every bridge supports it, otherwise below
+    let size = 0x1000_0000;
+    assert_eq!(
+        allocate(&apertures.ranges(Kind::Prefetchable, true), &[], size, size),
+        Some(0x8_0000_0000)
+    );
+    assert_eq!(
+        allocate(
+            &apertures.ranges(Kind::Prefetchable, false),
+            &[],
+            size,
+            size
+        ),
+        Some(0xc000_0000)
+    );
+    assert_eq!(
+        allocate(
+            &apertures.ranges(Kind::Memory, false),
+            &[low],
+            0x1000,
+            0x1000
+        ),
+        None
+    );
+    Ok(())
+}
@@ -26,6 +26,16 @@ impl PciRegisters {
 }
 
 impl PciRegisters {
+    /// Construct a new self without claiming the io ports, for acpi methods that access pci configuration space.
+    /// # Safety
+    /// The caller must ensure that accesses do not interleave with other users of pci configuration space.
+    pub unsafe fn new_unchecked() -> Self {
+        Self {
+            address: IOPORTS.get_port_unchecked(0xcf8),
+            data: IOPORTS.get_port_unchecked(0xcfc),
+        }
+    }
+
     /// Set the pci configuration space address that will be either read or written next
     fn set_address(&mut self, bus: u8, device: u8, function: u8, offset: u8) {
         let a: u32 = ((bus as u32) << 16)
@@ -71,6 +81,15 @@ impl Pci {
             busses: alloc::vec::Vec::new(),
         })
     }
+
+    /// Construct a pci system that uses the given ecam access
+    #[cfg(kernel_machine = "pc64")]
+    pub fn new_ecam(ecam: super::ecam::Ecam) -> Self {
+        Self {
+            configuration: super::PciConfigurationSpace::Ecam(ecam),
+            busses: alloc::vec::Vec::new(),
+        }
+    }
 }
 
 impl super::PciTrait for Pci {
@@ -78,14 +97,21 @@ impl super::PciTrait for Pci {
         crate::VGA
             .print_str_async("pci: Probing for pci busses\r\n")
             .await;
-        for i in 0..=255 {
-            if let Some(bus) = super::PciBus::new(&mut self.configuration, i) {
-                crate::VGA
-                    .print_str_async(&format!("pci: Bus {} exists\r\n", i))
-                    .await;
-                self.busses.push(bus);
-            }
+        self.busses = super::enumerate_busses(&mut self.configuration);
+        for bus in &self.busses {
+            let s = match bus.parent() {
+                Some((b, d, f)) => format!(
+                    "pci: Bus {} exists behind bridge {:02x}:{:02x}.{}\r\n",
+                    bus.num(),
+                    b,
+                    d,
+                    f
+                ),
+                None => format!("pci: Host bus {} exists\r\n", bus.num()),
+            };
+            crate::VGA.print_str_async(&s).await;
         }
+        super::resource::assign_resources(&mut self.configuration, &self.busses);
         crate::VGA
             .print_str_async("pci: Done probing for pci busses\r\n")
             .await;
@@ -100,10 +126,13 @@ impl super::PciTrait for Pci {
         }
     }
 
-    async fn driver_run(
-        &mut self,
-        d: &mut alloc::collections::btree_map::BTreeMap<u32, super::PciFunctionDriver>,
-    ) {
+    async fn print_tree(&mut self) {
+        for l in super::pci_tree(&mut self.configuration, &self.busses) {
+            crate::VGA.print_str_async(&format!("{}\r\n", l)).await;
+        }
+    }
+
+    async fn driver_run(&mut self, d: &mut [super::PciFunctionDriver]) {
         for bus in &self.busses {
             bus.driver_run(d, &mut self.configuration).await;
         }
@@ -0,0 +1,87 @@
+//! Platform devices are devices that are described by firmware (such as acpi) instead of being discovered on a bus.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// A resource used by a platform device
+#[derive(Clone, Debug, PartialEq)]
+pub enum PlatformResource {
+    /// A range of io ports
+    Io {
+        /// The first io port
+        base: u16,
+        /// The number of io ports
+        length: u16,
+    },
+    /// A range of memory mapped registers
+    Memory {
+        /// The physical address of the start of the range
+        base: u64,
+        /// The length in bytes
+        length: u64,
+    },
+    /// An interrupt, specified by its global system interrupt number
+    Irq {
+        /// The interrupt number
+        irq: u32,
+        /// True when the interrupt is level triggered, false for edge triggered
+        level: bool,
+        /// True when the interrupt is active low
+        active_low: bool,
+    },
+    /// A dma channel
+    Dma(u8),
+    /// A range of bus numbers, used by bus bridges
+    BusNumbers {
+        /// The first bus number
+        base: u16,
+        /// The number of busses
+        length: u16,
+    },
+}
+
+/// A device described by the platform firmware
+#[derive(Clone, Debug)]
+pub struct PlatformDevice {
+    /// The full name of the device in the firmware
+    pub name: String,
+    /// The hardware id of the device
+    pub hid: Option<String>,
+    /// The compatible ids of the device
+    pub cids: Vec<String>,
+    /// The unique id of the device, distinguishing devices with the same hardware id
+    pub uid: Option<u64>,
+    /// The resources currently used by the device
+    pub resources: Vec<PlatformResource>,
+}
+
+impl PlatformDevice {
+    /// Returns true if the hardware id or any of the compatible ids match the given id
+    pub fn is_compatible(&self, id: &str) -> bool {
+        self.hid.as_deref() == Some(id) || self.cids.iter().any(|c| c == id)
+    }
+
+    /// Iterate over the io port ranges of the device, as (base, length)
+    pub fn io_ports(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.resources.iter().filter_map(|r| match r {
+            PlatformResource::Io { base, length } => Some((*base, *length)),
+            _ => None,
+        })
+    }
+
+    /// Iterate over the memory ranges of the device, as (base, length)
+    pub fn memory(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.resources.iter().filter_map(|r| match r {
+            PlatformResource::Memory { base, length } => Some((*base, *length)),
+            _ => None,
+        })
+    }
+
+    /// Iterate over the interrupt numbers of the device
+    pub fn irqs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.resources.iter().filter_map(|r| match r {
+            PlatformResource::Irq { irq, .. } => Some(*irq),
+            _ => None,
+        })
+    }
+}
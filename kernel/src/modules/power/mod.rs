@@ -2,3 +2,5 @@
 
 #[cfg(kernel_machine = "stm32f769i-disco")]
 pub mod stm32f769;
+#[cfg(kernel_machine = "pc64")]
+pub mod x86;
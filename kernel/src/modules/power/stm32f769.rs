@@ -17,4 +17,13 @@ impl Power {
             regs: &mut *(addr as *mut PowerRegisters),
         }
     }
+
+    /// Allow or prevent writes to the backup domain, which holds the rtc and its clock configuration
+    pub fn set_backup_domain_access(&mut self, v: bool) {
+        let mut newval = unsafe { core::ptr::read_volatile(&self.regs.regs[0]) } & !(1 << 8);
+        if v {
+            newval |= 1 << 8;
+        }
+        unsafe { core::ptr::write_volatile(&mut self.regs.regs[0], newval) };
+    }
 }
@@ -0,0 +1,199 @@
+//! Acpi power management for x86 pc hardware, covering sleep states, soft-off, and reset
+
+use acpi::fadt::Fadt;
+use aml::{value::Args, AmlContext, AmlName, AmlValue};
+
+use crate::kernel::SystemTrait;
+use crate::modules::acpi::AcpiRegister;
+use crate::{IoReadWrite, Locked};
+
+/// The sleep type field of the pm1 control registers
+const SLP_TYP_MASK: u16 = 7 << 10;
+/// The sleep enable bit of the pm1 control registers
+const SLP_EN: u16 = 1 << 13;
+
+/// The acpi power information for the system, filled in when the fadt is parsed
+pub static ACPI_POWER: Locked<Option<AcpiPower>> = Locked::new(None);
+
+/// The errors that can occur for power management
+#[derive(Debug)]
+pub enum PowerError {
+    /// The acpi power information or the aml context is not present
+    NoAcpi,
+    /// The requested sleep state is not supported by the firmware
+    SleepStateNotSupported,
+    /// An aml method failed to execute
+    MethodFailed(aml::AmlError),
+    /// The reset register is not supported
+    ResetNotSupported,
+    /// The sleep state was written to the hardware but the system kept running
+    NoEffect,
+}
+
+/// The fixed hardware used for power management, from the fadt
+pub struct AcpiPower {
+    /// The pm1a control register
+    pm1a_control: Option<AcpiRegister>,
+    /// The pm1b control register, not present on most systems
+    pm1b_control: Option<AcpiRegister>,
+    /// The reset register and the value to write to it
+    reset: Option<(AcpiRegister, u8)>,
+}
+
+impl AcpiPower {
+    /// Gather the power management registers from the fadt
+    pub fn new(fadt: &Fadt) -> Self {
+        let pm1a_control = fadt
+            .pm1a_control_block()
+            .ok()
+            .and_then(|ga| AcpiRegister::new(&ga, 2));
+        let pm1b_control = fadt
+            .pm1b_control_block()
+            .ok()
+            .flatten()
+            .and_then(|ga| AcpiRegister::new(&ga, 2));
+        let reset = if fadt.flags.supports_system_reset_via_fadt() {
+            fadt.reset_register()
+                .ok()
+                .and_then(|ga| AcpiRegister::new(&ga, 1))
+                .map(|r| (r, fadt.reset_value))
+        } else {
+            None
+        };
+        Self {
+            pm1a_control,
+            pm1b_control,
+            reset,
+        }
+    }
+
+    /// Get the sleep type values for pm1a and pm1b from the \_Sx_ object of the given sleep state
+    fn sleep_type(aml: &mut AmlContext, state: u8) -> Option<(u16, u16)> {
+        let name = AmlName::from_str(&alloc::format!("\\_S{}_", state)).ok()?;
+        let v = aml.namespace.get_by_path(&name).ok()?.clone();
+        if let AmlValue::Package(p) = v {
+            let a = p.first()?.as_integer(aml).ok()? as u16 & 7;
+            let b = p
+                .get(1)
+                .and_then(|b| b.as_integer(aml).ok())
+                .map(|b| b as u16 & 7)
+                .unwrap_or(a);
+            Some((a, b))
+        } else {
+            None
+        }
+    }
+
+    /// Invoke an optional method that takes the sleep state as its only argument. A missing method is not an error.
+    fn invoke_state_method(
+        aml: &mut AmlContext,
+        method: &str,
+        state: u8,
+    ) -> Result<(), PowerError> {
+        let name = AmlName::from_str(method).map_err(PowerError::MethodFailed)?;
+        if aml.namespace.get_by_path(&name).is_err() {
+            return Ok(());
+        }
+        let args = Args::from_list(alloc::vec![AmlValue::Integer(state as u64)])
+            .map_err(PowerError::MethodFailed)?;
+        aml.invoke_method(&name, args)
+            .map(|_| ())
+            .map_err(PowerError::MethodFailed)
+    }
+
+    /// Enter the specified sleep state. State 5 is soft-off and does not return when successful.
+    /// The lower sleep states return after the system wakes up, with the caller responsible for any state that does not survive the sleep.
+    pub fn enter_sleep_state(&self, aml: &mut AmlContext, state: u8) -> Result<(), PowerError> {
+        let pm1a = self
+            .pm1a_control
+            .as_ref()
+            .ok_or(PowerError::SleepStateNotSupported)?;
+        let (typ_a, typ_b) =
+            Self::sleep_type(aml, state).ok_or(PowerError::SleepStateNotSupported)?;
+        Self::invoke_state_method(aml, "\\_PTS", state)?;
+        crate::SYSTEM.read().disable_interrupts_for(|| {
+            let va = (pm1a.read_u16(0) & !SLP_TYP_MASK) | (typ_a << 10);
+            pm1a.write_u16(0, va);
+            if let Some(pm1b) = &self.pm1b_control {
+                let vb = (pm1b.read_u16(0) & !SLP_TYP_MASK) | (typ_b << 10);
+                pm1b.write_u16(0, vb);
+                pm1a.write_u16(0, va | SLP_EN);
+                pm1b.write_u16(0, vb | SLP_EN);
+            } else {
+                pm1a.write_u16(0, va | SLP_EN);
+            }
+        });
+        Self::invoke_state_method(aml, "\\_WAK", state)
+    }
+
+    /// Reset the system with the reset register, returning if the reset register is not supported or did not work
+    pub fn reset(&self) -> Result<(), PowerError> {
+        let (r, v) = self.reset.as_ref().ok_or(PowerError::ResetNotSupported)?;
+        r.write_u8(0, *v);
+        Ok(())
+    }
+}
+
+/// Turn the system off, using acpi soft-off if possible. If that fails, interrupts are disabled and the processor halts forever.
+pub fn shutdown() -> ! {
+    crate::VGA.print_str("Shutting down\r\n");
+    let power = ACPI_POWER.sync_lock();
+    let mut aml = crate::boot::x86::boot64::AML_CONTEXT.sync_lock();
+    let e = match (power.as_ref(), aml.as_mut()) {
+        // Soft-off only returns when the hardware ignored the request
+        (Some(p), Some(aml)) => p
+            .enter_sleep_state(aml, 5)
+            .err()
+            .unwrap_or(PowerError::NoEffect),
+        _ => PowerError::NoAcpi,
+    };
+    crate::VGA.print_fixed_str(doors_macros2::fixed_string_format!(
+        "Failed to shutdown {:?}, halting\r\n",
+        e
+    ));
+    halt()
+}
+
+/// Reset the system. The acpi reset register is tried first, then the keyboard controller, then a triple fault.
+pub fn reboot() -> ! {
+    crate::VGA.print_str("Rebooting\r\n");
+    x86_64::instructions::interrupts::disable();
+    if let Some(p) = ACPI_POWER.sync_lock().as_ref() {
+        if p.reset().is_ok() {
+            delay();
+        }
+    }
+
+    let mut kbc_status = unsafe { crate::boot::x86::IOPORTS.get_port_unchecked::<u8>(0x64) };
+    for _ in 0..0x10000 {
+        if (kbc_status.port_read() & 2) == 0 {
+            break;
+        }
+    }
+    kbc_status.port_write(0xfe);
+    delay();
+
+    let idt = x86_64::structures::DescriptorTablePointer {
+        limit: 0,
+        base: x86_64::VirtAddr::new(0),
+    };
+    unsafe { x86_64::instructions::tables::lidt(&idt) };
+    x86_64::instructions::interrupts::int3();
+    halt()
+}
+
+/// Wait a short time for a reset to take effect, by reading an unused io port
+fn delay() {
+    let mut p = unsafe { crate::boot::x86::IOPORTS.get_port_unchecked::<u8>(0x80) };
+    for _ in 0..100_000 {
+        let _: u8 = p.port_read();
+    }
+}
+
+/// Halt the processor forever
+fn halt() -> ! {
+    x86_64::instructions::interrupts::disable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
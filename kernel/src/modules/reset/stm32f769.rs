@@ -201,6 +201,55 @@ impl<'a> Module<'a> {
         v != 0
     }
 
+    /// Set the low speed external oscillator enable bit. The backup domain must be writable.
+    pub fn set_lse(&mut self, s: bool) {
+        let mut newval = unsafe { core::ptr::read_volatile(&self.registers.regs[28]) } & !(1 << 0);
+        if s {
+            newval |= 1 << 0;
+        }
+        unsafe { core::ptr::write_volatile(&mut self.registers.regs[28], newval) };
+    }
+
+    /// Is the low speed external oscillator ready?
+    pub fn lse_ready(&self) -> bool {
+        let val = unsafe { core::ptr::read_volatile(&self.registers.regs[28]) };
+        (val & (1 << 1)) != 0
+    }
+
+    /// Set the low speed internal oscillator enable bit
+    pub fn set_lsi(&mut self, s: bool) {
+        let mut newval = unsafe { core::ptr::read_volatile(&self.registers.regs[29]) } & !(1 << 0);
+        if s {
+            newval |= 1 << 0;
+        }
+        unsafe { core::ptr::write_volatile(&mut self.registers.regs[29], newval) };
+    }
+
+    /// Is the low speed internal oscillator ready?
+    pub fn lsi_ready(&self) -> bool {
+        let val = unsafe { core::ptr::read_volatile(&self.registers.regs[29]) };
+        (val & (1 << 1)) != 0
+    }
+
+    /// The clock selected for the rtc. 0 is none, 1 is the lse, 2 is the lsi, 3 is the divided hse
+    pub fn get_rtc_mux(&self) -> u8 {
+        let v = unsafe { core::ptr::read_volatile(&self.registers.regs[28]) };
+        ((v >> 8) & 3) as u8
+    }
+
+    /// Is the rtc clock enabled?
+    pub fn rtc_enabled(&self) -> bool {
+        let v = unsafe { core::ptr::read_volatile(&self.registers.regs[28]) };
+        (v & (1 << 15)) != 0
+    }
+
+    /// Select the clock for the rtc and enable it. The selection can only be changed after a reset of the backup domain.
+    pub fn enable_rtc(&mut self, mux: u8) {
+        let v = unsafe { core::ptr::read_volatile(&self.registers.regs[28]) } & !(3 << 8);
+        let newval = v | ((mux as u32 & 3) << 8) | (1 << 15);
+        unsafe { core::ptr::write_volatile(&mut self.registers.regs[28], newval) };
+    }
+
     /// Is the third pll ready and locked?
     pub fn third_pll_locked(&self) -> bool {
         let v = unsafe { core::ptr::read_volatile(&self.registers.regs[0]) } & (1 << 29);
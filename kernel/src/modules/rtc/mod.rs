@@ -0,0 +1,105 @@
+//! Real time clock drivers, which keep track of the date and time while the system is off
+
+#[cfg(any(kernel_machine = "stm32f769i-disco", kernel_machine = "pc64"))]
+use crate::LockedArc;
+
+use crate::modules::time::DateTime;
+use crate::modules::timer::TimerCallback;
+
+#[cfg(kernel_machine = "stm32f769i-disco")]
+pub mod stm32f769;
+
+#[cfg(kernel_machine = "pc64")]
+pub mod x86;
+
+/// The errors that can occur using a real time clock
+#[derive(Debug)]
+pub enum RtcError {
+    /// The clock has not been set or is not running
+    NotRunning,
+    /// The date and time read from or given to the clock is not valid
+    InvalidTime,
+    /// The clock cannot represent the requested value
+    NotSupported,
+    /// The interrupt for the clock is not available
+    InterruptUnavailable,
+}
+
+/// The trait implemented by real time clocks
+#[enum_dispatch::enum_dispatch]
+pub trait RtcTrait {
+    /// Read the current date and time
+    fn read_time(&self) -> Result<DateTime, RtcError>;
+    /// Set the current date and time
+    fn set_time(&self, t: &DateTime) -> Result<(), RtcError>;
+    /// Call the callback every day when the time of day matches the hour, minute, and second of the given time. It is called from an interrupt context.
+    fn set_alarm(&self, t: &DateTime, f: TimerCallback) -> Result<(), RtcError>;
+    /// Stop the alarm
+    fn clear_alarm(&self);
+    /// Call the callback at the specified rate in hertz. Each clock supports a limited set of rates. It is called from an interrupt context.
+    fn set_periodic(&self, hz: u32, f: TimerCallback) -> Result<(), RtcError>;
+    /// Stop the periodic callback
+    fn stop_periodic(&self);
+}
+
+/// An enumeration of all the real time clocks
+#[enum_dispatch::enum_dispatch(RtcTrait)]
+pub enum Rtc {
+    /// The cmos real time clock of x86 pc hardware
+    #[cfg(kernel_machine = "pc64")]
+    X86Cmos(LockedArc<x86::CmosRtc>),
+    /// The real time clock of the stm32f769
+    #[cfg(kernel_machine = "stm32f769i-disco")]
+    Stm32f769(LockedArc<stm32f769::Rtc>),
+    /// The dummy implementation
+    Dummy(DummyRtc),
+}
+
+/// A dummy implementation of a real time clock
+pub struct DummyRtc {}
+
+impl RtcTrait for DummyRtc {
+    fn read_time(&self) -> Result<DateTime, RtcError> {
+        Err(RtcError::NotRunning)
+    }
+
+    fn set_time(&self, _t: &DateTime) -> Result<(), RtcError> {
+        Err(RtcError::NotSupported)
+    }
+
+    fn set_alarm(&self, _t: &DateTime, _f: TimerCallback) -> Result<(), RtcError> {
+        Err(RtcError::InterruptUnavailable)
+    }
+
+    fn clear_alarm(&self) {}
+
+    fn set_periodic(&self, _hz: u32, _f: TimerCallback) -> Result<(), RtcError> {
+        Err(RtcError::InterruptUnavailable)
+    }
+
+    fn stop_periodic(&self) {}
+}
+
+/// Set the wall clock of the kernel from the first registered real time clock, returning the time that was read.
+pub fn sync_wall_clock() -> Result<DateTime, RtcError> {
+    let rtc = {
+        let mut rtcs = crate::kernel::RTCS.sync_lock();
+        if !rtcs.exists(0) {
+            return Err(RtcError::NotRunning);
+        }
+        rtcs.module(0)
+    };
+    let t = rtc.sync_lock().read_time()?;
+    crate::modules::time::set_wall_clock(t);
+    Ok(t)
+}
+
+/// Convert a binary coded decimal value to binary
+fn bcd_to_binary(v: u8) -> u8 {
+    (v >> 4) * 10 + (v & 0xf)
+}
+
+/// Convert a binary value less than 100 to binary coded decimal
+fn binary_to_bcd(v: u8) -> u8 {
+    ((v / 10) << 4) | (v % 10)
+}
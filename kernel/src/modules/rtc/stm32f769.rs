@@ -0,0 +1,331 @@
+//! The real time clock of the stm32f769
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::modules::time::DateTime;
+use crate::modules::timer::TimerCallback;
+use crate::{Locked, LockedArc};
+
+use super::{bcd_to_binary, binary_to_bcd, RtcError, RtcTrait};
+
+/// The address of the external interrupt controller
+const EXTI_ADDRESS: u32 = 0x4001_3c00;
+/// The address of the interrupt set enable registers of the nvic
+const NVIC_ISER_ADDRESS: u32 = 0xe000_e100;
+/// The exti line connected to the rtc alarm
+const EXTI_ALARM: u32 = 1 << 17;
+/// The exti line connected to the rtc wakeup timer
+const EXTI_WAKEUP: u32 = 1 << 22;
+/// The interrupt number of the rtc alarm
+const ALARM_IRQ: u32 = 41;
+/// The interrupt number of the rtc wakeup timer
+const WAKEUP_IRQ: u32 = 3;
+
+/// The time register
+const TR: usize = 0;
+/// The date register
+const DR: usize = 1;
+/// The control register
+const CR: usize = 2;
+/// The initialization and status register
+const ISR: usize = 3;
+/// The prescaler register
+const PRER: usize = 4;
+/// The wakeup timer register
+const WUTR: usize = 5;
+/// The alarm a register
+const ALRMAR: usize = 7;
+/// The write protection register
+const WPR: usize = 9;
+
+/// The wakeup timer counts the rtc clock divided by 16
+const CR_WUCKSEL_DIV16: u32 = 0;
+/// The mask for the wakeup timer clock selection
+const CR_WUCKSEL: u32 = 7;
+/// The hour format is am/pm instead of 24 hour
+const CR_FMT: u32 = 1 << 6;
+/// Alarm a is enabled
+const CR_ALRAE: u32 = 1 << 8;
+/// The wakeup timer is enabled
+const CR_WUTE: u32 = 1 << 10;
+/// The alarm a interrupt is enabled
+const CR_ALRAIE: u32 = 1 << 12;
+/// The wakeup timer interrupt is enabled
+const CR_WUTIE: u32 = 1 << 14;
+
+/// Alarm a can be written
+const ISR_ALRAWF: u32 = 1 << 0;
+/// The wakeup timer can be written
+const ISR_WUTWF: u32 = 1 << 2;
+/// The calendar has been initialized
+const ISR_INITS: u32 = 1 << 4;
+/// The calendar shadow registers are synchronized
+const ISR_RSF: u32 = 1 << 5;
+/// The calendar is in initialization mode
+const ISR_INITF: u32 = 1 << 6;
+/// Request initialization mode
+const ISR_INIT: u32 = 1 << 7;
+/// Alarm a has matched
+const ISR_ALRAF: u32 = 1 << 8;
+/// The wakeup timer has expired
+const ISR_WUTF: u32 = 1 << 10;
+
+/// Alarm a ignores the date
+const ALRMAR_MSK4: u32 = 1 << 31;
+
+/// The address of the rtc registers, for the interrupt handlers
+static RTC_ADDRESS: AtomicU32 = AtomicU32::new(0);
+/// The function called for the alarm interrupt
+static ALARM_CALLBACK: Locked<Option<TimerCallback>> = Locked::new(None);
+/// The function called for the wakeup timer interrupt
+static WAKEUP_CALLBACK: Locked<Option<TimerCallback>> = Locked::new(None);
+
+/// The registers of the rtc
+struct Registers {
+    /// The registers
+    regs: [u32; 20],
+}
+
+/// Clear a flag of the isr register, without disturbing the init bit
+unsafe fn clear_isr_flag(regs: *mut Registers, flag: u32) {
+    let isr = core::ptr::read_volatile(&(*regs).regs[ISR]);
+    core::ptr::write_volatile(
+        &mut (*regs).regs[ISR],
+        !(flag | ISR_INIT) | (isr & ISR_INIT),
+    );
+}
+
+/// Acknowledge an exti line
+unsafe fn clear_exti(line: u32) {
+    // The pending register
+    core::ptr::write_volatile((EXTI_ADDRESS + 0x14) as *mut u32, line);
+}
+
+/// Handle an rtc interrupt, calling the callback
+fn handle_interrupt(flag: u32, line: u32, callback: &Locked<Option<TimerCallback>>) {
+    let addr = RTC_ADDRESS.load(Ordering::Relaxed);
+    if addr != 0 {
+        unsafe { clear_isr_flag(addr as *mut Registers, flag) };
+    }
+    unsafe { clear_exti(line) };
+    if let Some(f) = callback.sync_lock().as_mut() {
+        f();
+    }
+}
+
+/// The interrupt handler for the rtc alarm interrupt
+pub extern "C" fn rtc_alarm_handler() {
+    handle_interrupt(ISR_ALRAF, EXTI_ALARM, &ALARM_CALLBACK);
+}
+
+/// The interrupt handler for the rtc wakeup interrupt
+pub extern "C" fn rtc_wakeup_handler() {
+    handle_interrupt(ISR_WUTF, EXTI_WAKEUP, &WAKEUP_CALLBACK);
+}
+
+/// The real time clock of the stm32f769
+pub struct Rtc {
+    /// The registers
+    regs: &'static mut Registers,
+    /// The frequency of the clock for the rtc in hertz
+    clock: u32,
+}
+
+impl Rtc {
+    /// Construct the rtc, the clock for the rtc must be running and the backup domain must be writable.
+    /// [rtc_alarm_handler] and [rtc_wakeup_handler] must be installed as the interrupt handlers for the rtc.
+    pub unsafe fn new(addr: u32, clock: u32) -> Self {
+        let mut s = Self {
+            regs: &mut *(addr as *mut Registers),
+            clock,
+        };
+        RTC_ADDRESS.store(addr, Ordering::Relaxed);
+        s.disable_write_protection();
+        let cr = s.read(CR);
+        s.write(CR, cr & !(CR_ALRAIE | CR_WUTIE));
+        if (s.read(ISR) & ISR_INITS) == 0 {
+            s.enter_init();
+            // A 128 asynchronous prescaler keeps power down, the synchronous prescaler makes the 1 hz calendar clock
+            s.write(PRER, clock / 128 - 1);
+            s.write(PRER, (127 << 16) | (clock / 128 - 1));
+            let cr = s.read(CR);
+            s.write(CR, cr & !CR_FMT);
+            s.exit_init();
+        }
+        s.enable_write_protection();
+        s
+    }
+
+    /// Read a register
+    fn read(&self, i: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(&self.regs.regs[i]) }
+    }
+
+    /// Write a register
+    fn write(&mut self, i: usize, v: u32) {
+        unsafe { core::ptr::write_volatile(&mut self.regs.regs[i], v) };
+    }
+
+    /// Remove the write protection of the rtc registers
+    fn disable_write_protection(&mut self) {
+        self.write(WPR, 0xca);
+        self.write(WPR, 0x53);
+    }
+
+    /// Restore the write protection of the rtc registers
+    fn enable_write_protection(&mut self) {
+        self.write(WPR, 0xff);
+    }
+
+    /// Stop the calendar so that it can be written
+    fn enter_init(&mut self) {
+        let isr = self.read(ISR);
+        self.write(ISR, isr | ISR_INIT);
+        while (self.read(ISR) & ISR_INITF) == 0 {}
+    }
+
+    /// Restart the calendar
+    fn exit_init(&mut self) {
+        let isr = self.read(ISR);
+        self.write(ISR, isr & !ISR_INIT);
+    }
+
+    /// Enable the exti line and the nvic interrupt for an rtc interrupt
+    fn enable_interrupt(line: u32, irq: u32) {
+        unsafe {
+            let imr = EXTI_ADDRESS as *mut u32;
+            let rtsr = (EXTI_ADDRESS + 8) as *mut u32;
+            core::ptr::write_volatile(imr, core::ptr::read_volatile(imr) | line);
+            core::ptr::write_volatile(rtsr, core::ptr::read_volatile(rtsr) | line);
+            core::ptr::write_volatile(
+                (NVIC_ISER_ADDRESS + 4 * (irq / 32)) as *mut u32,
+                1 << (irq % 32),
+            );
+        }
+    }
+
+    /// Disable the alarm and its interrupt
+    fn disable_alarm(&mut self) {
+        self.disable_write_protection();
+        let cr = self.read(CR);
+        self.write(CR, cr & !(CR_ALRAE | CR_ALRAIE));
+        while (self.read(ISR) & ISR_ALRAWF) == 0 {}
+        self.enable_write_protection();
+    }
+
+    /// Disable the wakeup timer and its interrupt
+    fn disable_wakeup(&mut self) {
+        self.disable_write_protection();
+        let cr = self.read(CR);
+        self.write(CR, cr & !(CR_WUTE | CR_WUTIE));
+        while (self.read(ISR) & ISR_WUTWF) == 0 {}
+        self.enable_write_protection();
+    }
+}
+
+impl RtcTrait for LockedArc<Rtc> {
+    fn read_time(&self) -> Result<DateTime, RtcError> {
+        let s = self.sync_lock();
+        if (s.read(ISR) & ISR_INITS) == 0 {
+            return Err(RtcError::NotRunning);
+        }
+        while (s.read(ISR) & ISR_RSF) == 0 {}
+        // Reading the time register locks the date register until it is read
+        let tr = s.read(TR);
+        let dr = s.read(DR);
+        let t = DateTime {
+            year: 2000 + bcd_to_binary((dr >> 16) as u8) as u16,
+            month: bcd_to_binary(((dr >> 8) & 0x1f) as u8),
+            day: bcd_to_binary((dr & 0x3f) as u8),
+            hour: bcd_to_binary(((tr >> 16) & 0x3f) as u8),
+            minute: bcd_to_binary(((tr >> 8) & 0x7f) as u8),
+            second: bcd_to_binary((tr & 0x7f) as u8),
+        };
+        if t.is_valid() {
+            Ok(t)
+        } else {
+            Err(RtcError::InvalidTime)
+        }
+    }
+
+    fn set_time(&self, t: &DateTime) -> Result<(), RtcError> {
+        if !t.is_valid() {
+            return Err(RtcError::InvalidTime);
+        }
+        if !(2000..2100).contains(&t.year) {
+            return Err(RtcError::NotSupported);
+        }
+        let weekday = match t.weekday() {
+            0 => 7,
+            d => d as u32,
+        };
+        let tr = (binary_to_bcd(t.hour) as u32) << 16
+            | (binary_to_bcd(t.minute) as u32) << 8
+            | binary_to_bcd(t.second) as u32;
+        let dr = (binary_to_bcd((t.year - 2000) as u8) as u32) << 16
+            | weekday << 13
+            | (binary_to_bcd(t.month) as u32) << 8
+            | binary_to_bcd(t.day) as u32;
+        let mut s = self.sync_lock();
+        s.disable_write_protection();
+        s.enter_init();
+        s.write(TR, tr);
+        s.write(DR, dr);
+        s.exit_init();
+        let isr = s.read(ISR);
+        s.write(ISR, isr & !ISR_RSF);
+        s.enable_write_protection();
+        Ok(())
+    }
+
+    fn set_alarm(&self, t: &DateTime, f: TimerCallback) -> Result<(), RtcError> {
+        if !t.is_valid() {
+            return Err(RtcError::InvalidTime);
+        }
+        let mut s = self.sync_lock();
+        s.disable_alarm();
+        ALARM_CALLBACK.replace(Some(f));
+        s.disable_write_protection();
+        s.write(
+            ALRMAR,
+            ALRMAR_MSK4
+                | (binary_to_bcd(t.hour) as u32) << 16
+                | (binary_to_bcd(t.minute) as u32) << 8
+                | binary_to_bcd(t.second) as u32,
+        );
+        unsafe { clear_isr_flag(&mut *s.regs, ISR_ALRAF) };
+        let cr = s.read(CR);
+        s.write(CR, cr | CR_ALRAE | CR_ALRAIE);
+        s.enable_write_protection();
+        Rtc::enable_interrupt(EXTI_ALARM, ALARM_IRQ);
+        Ok(())
+    }
+
+    fn clear_alarm(&self) {
+        self.sync_lock().disable_alarm();
+        ALARM_CALLBACK.replace(None);
+    }
+
+    fn set_periodic(&self, hz: u32, f: TimerCallback) -> Result<(), RtcError> {
+        let mut s = self.sync_lock();
+        let wakeup_clock = s.clock / 16;
+        if hz == 0 || hz > wakeup_clock || (wakeup_clock % hz) != 0 {
+            return Err(RtcError::NotSupported);
+        }
+        s.disable_wakeup();
+        WAKEUP_CALLBACK.replace(Some(f));
+        s.disable_write_protection();
+        s.write(WUTR, wakeup_clock / hz - 1);
+        unsafe { clear_isr_flag(&mut *s.regs, ISR_WUTF) };
+        let cr = s.read(CR) & !CR_WUCKSEL;
+        s.write(CR, cr | CR_WUCKSEL_DIV16 | CR_WUTE | CR_WUTIE);
+        s.enable_write_protection();
+        Rtc::enable_interrupt(EXTI_WAKEUP, WAKEUP_IRQ);
+        Ok(())
+    }
+
+    fn stop_periodic(&self) {
+        self.sync_lock().disable_wakeup();
+        WAKEUP_CALLBACK.replace(None);
+    }
+}
@@ -0,0 +1,382 @@
+//! The cmos real time clock of x86 pc hardware
+
+use crate::kernel::SystemTrait;
+use crate::modules::time::DateTime;
+use crate::modules::timer::TimerCallback;
+use crate::{Arc, IoPortArray, IoReadWrite, Locked, LockedArc};
+
+use super::{bcd_to_binary, binary_to_bcd, RtcError, RtcTrait};
+
+/// The irq used by the real time clock
+const RTC_IRQ: u8 = 8;
+
+/// The seconds register
+const REG_SECONDS: u8 = 0;
+/// The seconds alarm register
+const REG_SECONDS_ALARM: u8 = 1;
+/// The minutes register
+const REG_MINUTES: u8 = 2;
+/// The minutes alarm register
+const REG_MINUTES_ALARM: u8 = 3;
+/// The hours register
+const REG_HOURS: u8 = 4;
+/// The hours alarm register
+const REG_HOURS_ALARM: u8 = 5;
+/// The day of the week register, 1 is sunday
+const REG_WEEKDAY: u8 = 6;
+/// The day of the month register
+const REG_DAY: u8 = 7;
+/// The month register
+const REG_MONTH: u8 = 8;
+/// The year register, the last two digits of the year
+const REG_YEAR: u8 = 9;
+/// Status register a
+const REG_STATUS_A: u8 = 0x0a;
+/// Status register b
+const REG_STATUS_B: u8 = 0x0b;
+/// Status register c, reading it acknowledges the interrupt
+const REG_STATUS_C: u8 = 0x0c;
+
+/// An update of the time registers is in progress
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+/// The mask for the rate selection of the periodic interrupt
+const STATUS_A_RATE: u8 = 0x0f;
+/// Updates of the time registers are inhibited so they can be written
+const STATUS_B_SET: u8 = 0x80;
+/// The periodic interrupt is enabled
+const STATUS_B_PERIODIC: u8 = 0x40;
+/// The alarm interrupt is enabled
+const STATUS_B_ALARM: u8 = 0x20;
+/// The time registers are binary instead of binary coded decimal
+const STATUS_B_BINARY: u8 = 0x04;
+/// The hours register is in 24 hour mode
+const STATUS_B_24_HOUR: u8 = 0x02;
+/// The periodic interrupt occurred
+const STATUS_C_PERIODIC: u8 = 0x40;
+/// The alarm interrupt occurred
+const STATUS_C_ALARM: u8 = 0x20;
+
+/// The pm flag of the hours register in 12 hour mode
+const HOUR_PM: u8 = 0x80;
+
+/// The registers of the cmos, shared between the clock and its interrupt handler
+struct CmosRegisters {
+    /// The index and data ports, 0x70-0x71
+    ports: IoPortArray<'static>,
+}
+
+impl CmosRegisters {
+    /// Read a register of the cmos
+    fn read(&self, reg: u8) -> u8 {
+        self.ports.port(0).port_write(reg);
+        self.ports.port(1).port_read()
+    }
+
+    /// Write a register of the cmos
+    fn write(&self, reg: u8, val: u8) {
+        self.ports.port(0).port_write(reg);
+        self.ports.port(1).port_write(val);
+    }
+}
+
+/// The time registers of the cmos, as read from the hardware
+#[derive(PartialEq, Eq)]
+struct RawTime {
+    /// The seconds register
+    second: u8,
+    /// The minutes register
+    minute: u8,
+    /// The hours register
+    hour: u8,
+    /// The day of the month register
+    day: u8,
+    /// The month register
+    month: u8,
+    /// The year register
+    year: u8,
+    /// The century register, if it exists
+    century: Option<u8>,
+}
+
+/// The callbacks for the interrupts of the real time clock
+struct RtcCallbacks {
+    /// Called for the periodic interrupt
+    periodic: Option<TimerCallback>,
+    /// Called for the alarm interrupt
+    alarm: Option<TimerCallback>,
+}
+
+/// The cmos real time clock
+pub struct CmosRtc {
+    /// The registers of the cmos
+    regs: Arc<CmosRegisters>,
+    /// The cmos register that holds the century, from the acpi fadt
+    century: Option<u8>,
+    /// The callbacks for the interrupts, shared with the interrupt handler
+    callbacks: Arc<Locked<RtcCallbacks>>,
+    /// True when irq 8 is claimed from the hpet and the interrupt handler is registered
+    irq_claimed: bool,
+}
+
+impl CmosRtc {
+    /// Claim the io ports of the cmos. The century register is specified by the acpi fadt, when it exists.
+    pub fn new(century: Option<u8>) -> Option<Self> {
+        let iom = crate::IO_PORT_MANAGER.as_ref()?;
+        let ports = iom.get_ports(0x70, 2)?;
+        let regs = Arc::new(CmosRegisters { ports });
+        crate::SYSTEM.read().disable_interrupts_for(|| {
+            let b = regs.read(REG_STATUS_B);
+            regs.write(REG_STATUS_B, b & !(STATUS_B_PERIODIC | STATUS_B_ALARM));
+            regs.read(REG_STATUS_C);
+        });
+        Some(Self {
+            regs,
+            century,
+            callbacks: Arc::new(Locked::new(RtcCallbacks {
+                periodic: None,
+                alarm: None,
+            })),
+            irq_claimed: false,
+        })
+    }
+
+    /// Read all of the time registers once no update is in progress
+    fn read_raw(&self) -> RawTime {
+        crate::SYSTEM.read().disable_interrupts_for(|| {
+            while (self.regs.read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS) != 0 {}
+            RawTime {
+                second: self.regs.read(REG_SECONDS),
+                minute: self.regs.read(REG_MINUTES),
+                hour: self.regs.read(REG_HOURS),
+                day: self.regs.read(REG_DAY),
+                month: self.regs.read(REG_MONTH),
+                year: self.regs.read(REG_YEAR),
+                century: self.century.map(|c| self.regs.read(c)),
+            }
+        })
+    }
+
+    /// Convert a value to the format used by the cmos
+    fn encode(v: u8, binary: bool) -> u8 {
+        if binary {
+            v
+        } else {
+            binary_to_bcd(v)
+        }
+    }
+
+    /// Convert a value from the format used by the cmos
+    fn decode(v: u8, binary: bool) -> u8 {
+        if binary {
+            v
+        } else {
+            bcd_to_binary(v)
+        }
+    }
+
+    /// Convert an hour to the format used by the cmos
+    fn encode_hour(h: u8, status_b: u8) -> u8 {
+        let binary = (status_b & STATUS_B_BINARY) != 0;
+        if (status_b & STATUS_B_24_HOUR) != 0 {
+            Self::encode(h, binary)
+        } else {
+            let h12 = if h % 12 == 0 { 12 } else { h % 12 };
+            let pm = if h >= 12 { HOUR_PM } else { 0 };
+            Self::encode(h12, binary) | pm
+        }
+    }
+
+    /// Convert an hour from the format used by the cmos
+    fn decode_hour(h: u8, status_b: u8) -> u8 {
+        let binary = (status_b & STATUS_B_BINARY) != 0;
+        if (status_b & STATUS_B_24_HOUR) != 0 {
+            Self::decode(h, binary)
+        } else {
+            let h12 = Self::decode(h & !HOUR_PM, binary) % 12;
+            if (h & HOUR_PM) != 0 {
+                h12 + 12
+            } else {
+                h12
+            }
+        }
+    }
+
+    /// Claim the irq and register the interrupt handler, enable the specified interrupt and unmask the irq
+    fn enable_interrupt(&mut self, bit: u8) -> Result<(), RtcError> {
+        let sys = crate::SYSTEM.read();
+        if !self.irq_claimed {
+            if !crate::modules::timer::x86::claim_rtc_irq() {
+                return Err(RtcError::InterruptUnavailable);
+            }
+            // Registered after every claim, because the hpet replaces the handler while it has taken over irq 8
+            let regs = self.regs.clone();
+            let callbacks = self.callbacks.clone();
+            sys.register_irq_handler(RTC_IRQ, move || {
+                let c = regs.read(REG_STATUS_C);
+                let mut cb = callbacks.sync_lock();
+                if (c & STATUS_C_PERIODIC) != 0 {
+                    if let Some(f) = cb.periodic.as_mut() {
+                        f();
+                    }
+                }
+                if (c & STATUS_C_ALARM) != 0 {
+                    if let Some(f) = cb.alarm.as_mut() {
+                        f();
+                    }
+                }
+            });
+            self.irq_claimed = true;
+        }
+        sys.disable_interrupts_for(|| {
+            let b = self.regs.read(REG_STATUS_B);
+            self.regs.write(REG_STATUS_B, b | bit);
+            self.regs.read(REG_STATUS_C);
+        });
+        sys.enable_irq(RTC_IRQ);
+        Ok(())
+    }
+
+    /// Disable the specified interrupt, masking and releasing the irq when no interrupts remain enabled
+    fn disable_interrupt(&mut self, bit: u8) {
+        let sys = crate::SYSTEM.read();
+        let b = sys.disable_interrupts_for(|| {
+            let b = self.regs.read(REG_STATUS_B) & !bit;
+            self.regs.write(REG_STATUS_B, b);
+            b
+        });
+        if (b & (STATUS_B_PERIODIC | STATUS_B_ALARM)) == 0 && self.irq_claimed {
+            sys.disable_irq(RTC_IRQ);
+            crate::modules::timer::x86::release_rtc_irq();
+            self.irq_claimed = false;
+        }
+    }
+
+    /// Replace one of the interrupt callbacks, without the interrupt handler being able to run at the same time
+    fn replace_callback(&self, alarm: bool, f: Option<TimerCallback>) {
+        let mut f = f;
+        let old = crate::SYSTEM.read().disable_interrupts_for(|| {
+            let mut cb = self.callbacks.sync_lock();
+            let c = if alarm {
+                &mut cb.alarm
+            } else {
+                &mut cb.periodic
+            };
+            core::mem::replace(c, f.take())
+        });
+        drop(old);
+    }
+}
+
+impl RtcTrait for LockedArc<CmosRtc> {
+    fn read_time(&self) -> Result<DateTime, RtcError> {
+        let s = self.sync_lock();
+        // Read until two reads in a row agree, so an update between reads is not a problem
+        let raw = loop {
+            let a = s.read_raw();
+            let b = s.read_raw();
+            if a == b {
+                break a;
+            }
+        };
+        let status_b = crate::SYSTEM
+            .read()
+            .disable_interrupts_for(|| s.regs.read(REG_STATUS_B));
+        let binary = (status_b & STATUS_B_BINARY) != 0;
+        let year = CmosRtc::decode(raw.year, binary) as u16;
+        let year = match raw.century {
+            Some(c) => CmosRtc::decode(c, binary) as u16 * 100 + year,
+            None if year < 70 => 2000 + year,
+            None => 1900 + year,
+        };
+        let t = DateTime {
+            year,
+            month: CmosRtc::decode(raw.month, binary),
+            day: CmosRtc::decode(raw.day, binary),
+            hour: CmosRtc::decode_hour(raw.hour, status_b),
+            minute: CmosRtc::decode(raw.minute, binary),
+            second: CmosRtc::decode(raw.second, binary),
+        };
+        if t.is_valid() {
+            Ok(t)
+        } else {
+            Err(RtcError::InvalidTime)
+        }
+    }
+
+    fn set_time(&self, t: &DateTime) -> Result<(), RtcError> {
+        if !t.is_valid() {
+            return Err(RtcError::InvalidTime);
+        }
+        let s = self.sync_lock();
+        if s.century.is_none() && !(1970..2070).contains(&t.year) {
+            return Err(RtcError::NotSupported);
+        }
+        crate::SYSTEM.read().disable_interrupts_for(|| {
+            let b = s.regs.read(REG_STATUS_B);
+            let binary = (b & STATUS_B_BINARY) != 0;
+            s.regs.write(REG_STATUS_B, b | STATUS_B_SET);
+            s.regs.write(REG_SECONDS, CmosRtc::encode(t.second, binary));
+            s.regs.write(REG_MINUTES, CmosRtc::encode(t.minute, binary));
+            s.regs.write(REG_HOURS, CmosRtc::encode_hour(t.hour, b));
+            s.regs.write(REG_WEEKDAY, t.weekday() + 1);
+            s.regs.write(REG_DAY, CmosRtc::encode(t.day, binary));
+            s.regs.write(REG_MONTH, CmosRtc::encode(t.month, binary));
+            s.regs
+                .write(REG_YEAR, CmosRtc::encode((t.year % 100) as u8, binary));
+            if let Some(c) = s.century {
+                s.regs
+                    .write(c, CmosRtc::encode((t.year / 100) as u8, binary));
+            }
+            s.regs.write(REG_STATUS_B, b & !STATUS_B_SET);
+        });
+        Ok(())
+    }
+
+    fn set_alarm(&self, t: &DateTime, f: TimerCallback) -> Result<(), RtcError> {
+        if !t.is_valid() {
+            return Err(RtcError::InvalidTime);
+        }
+        let mut s = self.sync_lock();
+        s.disable_interrupt(STATUS_B_ALARM);
+        crate::SYSTEM.read().disable_interrupts_for(|| {
+            let b = s.regs.read(REG_STATUS_B);
+            let binary = (b & STATUS_B_BINARY) != 0;
+            s.regs
+                .write(REG_SECONDS_ALARM, CmosRtc::encode(t.second, binary));
+            s.regs
+                .write(REG_MINUTES_ALARM, CmosRtc::encode(t.minute, binary));
+            s.regs
+                .write(REG_HOURS_ALARM, CmosRtc::encode_hour(t.hour, b));
+        });
+        s.replace_callback(true, Some(f));
+        s.enable_interrupt(STATUS_B_ALARM)
+    }
+
+    fn clear_alarm(&self) {
+        let mut s = self.sync_lock();
+        s.disable_interrupt(STATUS_B_ALARM);
+        s.replace_callback(true, None);
+    }
+
+    fn set_periodic(&self, hz: u32, f: TimerCallback) -> Result<(), RtcError> {
+        // The periodic interrupt runs at 32768 >> (rate - 1) hz, for a rate of 3 to 15
+        if !hz.is_power_of_two() || !(2..=8192).contains(&hz) {
+            return Err(RtcError::NotSupported);
+        }
+        let rate = (16 - hz.trailing_zeros()) as u8;
+        let mut s = self.sync_lock();
+        s.disable_interrupt(STATUS_B_PERIODIC);
+        crate::SYSTEM.read().disable_interrupts_for(|| {
+            let a = s.regs.read(REG_STATUS_A);
+            s.regs.write(REG_STATUS_A, (a & !STATUS_A_RATE) | rate);
+        });
+        s.replace_callback(false, Some(f));
+        s.enable_interrupt(STATUS_B_PERIODIC)
+    }
+
+    fn stop_periodic(&self) {
+        let mut s = self.sync_lock();
+        s.disable_interrupt(STATUS_B_PERIODIC);
+        s.replace_callback(false, None);
+    }
+}
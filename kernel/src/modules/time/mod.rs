@@ -0,0 +1,418 @@
+//! Kernel time keeping code
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Poll, Waker};
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use spin::RwLock;
+
+use crate::kernel::SystemTrait;
+use crate::modules::timer::{TimerError, TimerInstance, TimerInstanceTrait};
+use crate::{Arc, Locked};
+
+pub use core::time::Duration;
+
+#[cfg(kernel_machine = "stm32f769i-disco")]
+pub mod stm32f769;
+
+#[cfg(kernel_machine = "pc64")]
+pub mod x86;
+
+/// The monotonic clock for the kernel
+static MONOTONIC_CLOCK: RwLock<Option<MonotonicClock>> = RwLock::new(None);
+
+/// The wall clock for the kernel, the unix time at a specific instant of the monotonic clock
+static WALL_CLOCK: RwLock<Option<(Duration, Instant)>> = RwLock::new(None);
+
+/// The timer that wakes the tasks waiting in [sleep] and [sleep_until]
+static SLEEP_TIMER: RwLock<Option<TimerInstance>> = RwLock::new(None);
+
+/// The tasks waiting for an instant, as (identifier of the [Sleep], deadline, waker).
+/// The interrupt of [SLEEP_TIMER] locks this too, so it must only be locked with interrupts disabled.
+static SLEEPERS: Locked<Vec<(u64, Instant, Waker)>> = Locked::new(Vec::new());
+
+/// The identifier for the next [Sleep]
+static NEXT_SLEEP: AtomicU64 = AtomicU64::new(0);
+
+/// Set the source for the monotonic clock of the kernel. Instants from a previous clock source are not comparable to instants from the new clock source.
+/// The wall clock is carried over to the new clock source.
+pub fn set_monotonic_clock(c: MonotonicClock) {
+    let wall = unix_time();
+    MONOTONIC_CLOCK.write().replace(c);
+    if let Some(wall) = wall {
+        WALL_CLOCK.write().replace((wall, Instant::now()));
+    }
+}
+
+/// Set the wall clock of the kernel to the given date and time, usually read from a real time clock.
+pub fn set_wall_clock(t: DateTime) {
+    WALL_CLOCK
+        .write()
+        .replace((Duration::from_secs(t.to_unix_seconds()), Instant::now()));
+}
+
+/// The amount of time since the unix epoch, if the wall clock has been set
+pub fn unix_time() -> Option<Duration> {
+    let (base, at) = *WALL_CLOCK.read().as_ref()?;
+    Some(base + at.elapsed())
+}
+
+/// The current date and time, if the wall clock has been set
+pub fn wall_clock() -> Option<DateTime> {
+    unix_time().map(|d| DateTime::from_unix_seconds(d.as_secs()))
+}
+
+/// The amount of time the kernel has been running, or more precisely, the amount of time since the monotonic clock was started.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(Instant::now().nanos)
+}
+
+/// The trait implemented by all sources of monotonic time
+#[enum_dispatch::enum_dispatch]
+pub trait MonotonicClockTrait {
+    /// The number of nanoseconds since the clock was started. This value must never decrease.
+    fn nanoseconds(&self) -> u64;
+}
+
+/// An enumeration of all the sources of monotonic time
+#[enum_dispatch::enum_dispatch(MonotonicClockTrait)]
+pub enum MonotonicClock {
+    /// The invariant time stamp counter of x86 processors
+    #[cfg(kernel_machine = "pc64")]
+    X86Tsc(x86::TscClock),
+    /// The systick timer of the stm32f769
+    #[cfg(kernel_machine = "stm32f769i-disco")]
+    Stm32SysTick(stm32f769::SysTickClock),
+    /// A counter of periodic timer interrupts
+    TickCounter(TickCounter),
+}
+
+/// A measurement of the monotonic clock of the kernel. It has nanosecond resolution, but the accuracy depends on the source of the clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    /// The number of nanoseconds since the clock was started
+    nanos: u64,
+}
+
+impl Instant {
+    /// Get the current instant. This will be the beginning of time when no clock source exists.
+    pub fn now() -> Self {
+        let nanos = MONOTONIC_CLOCK
+            .read()
+            .as_ref()
+            .map(|c| c.nanoseconds())
+            .unwrap_or(0);
+        Self { nanos }
+    }
+
+    /// The amount of time elapsed from another instant to this one, or zero if that instant is later than this one.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    /// The amount of time elapsed since this instant
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+
+    /// Returns the instant that is the specified duration after self, if it can be represented
+    pub fn checked_add(&self, d: Duration) -> Option<Instant> {
+        let d: u64 = d.as_nanos().try_into().ok()?;
+        Some(Self {
+            nanos: self.nanos.checked_add(d)?,
+        })
+    }
+
+    /// Returns the instant that is the specified duration before self, if it can be represented
+    pub fn checked_sub(&self, d: Duration) -> Option<Instant> {
+        let d: u64 = d.as_nanos().try_into().ok()?;
+        Some(Self {
+            nanos: self.nanos.checked_sub(d)?,
+        })
+    }
+}
+
+impl core::ops::Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, rhs: Duration) -> Self::Output {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl core::ops::AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl core::ops::Sub<Duration> for Instant {
+    type Output = Instant;
+    fn sub(self, rhs: Duration) -> Self::Output {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl core::ops::Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Instant) -> Self::Output {
+        self.duration_since(rhs)
+    }
+}
+
+/// A calendar date and time of day, in utc
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    /// The full year, such as 2024
+    pub year: u16,
+    /// The month, 1-12
+    pub month: u8,
+    /// The day of the month, starting at 1
+    pub day: u8,
+    /// The hour, 0-23
+    pub hour: u8,
+    /// The minute, 0-59
+    pub minute: u8,
+    /// The second, 0-59
+    pub second: u8,
+}
+
+/// The number of days from 1970-01-01 to the given date of the proleptic gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// The (year, month, day) for a number of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    /// The number of days in the specified month of the specified year
+    pub fn days_in_month(year: u16, month: u8) -> u8 {
+        match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 => {
+                if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 {
+                    29
+                } else {
+                    28
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    /// Returns true when all of the fields are in range
+    pub fn is_valid(&self) -> bool {
+        self.month >= 1
+            && self.day >= 1
+            && self.day <= Self::days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Build a date and time from a number of seconds since the unix epoch
+    pub fn from_unix_seconds(secs: u64) -> Self {
+        let days = (secs / 86400) as i64;
+        let rem = secs % 86400;
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rem / 3600) as u8,
+            minute: ((rem / 60) % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+
+    /// The number of seconds since the unix epoch. Dates before the epoch give 0.
+    pub fn to_unix_seconds(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let secs =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        secs.max(0) as u64
+    }
+
+    /// The day of the week, where 0 is sunday and 6 is saturday
+    pub fn weekday(&self) -> u8 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        // 1970-01-01 was a thursday
+        (days + 4).rem_euclid(7) as u8
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Test the conversion of dates to and from unix time
+#[doors_macros::doors_test]
+fn date_time_conversion_test() -> Result<(), ()> {
+    let epoch = DateTime::from_unix_seconds(0);
+    assert_eq!(epoch.year, 1970);
+    assert_eq!(epoch.month, 1);
+    assert_eq!(epoch.day, 1);
+    assert_eq!(epoch.weekday(), 4);
+    let leap = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 13,
+        minute: 37,
+        second: 42,
+    };
+    assert!(leap.is_valid());
+    assert_eq!(leap.to_unix_seconds(), 1709213862);
+    assert_eq!(DateTime::from_unix_seconds(1709213862), leap);
+    Ok(())
+}
+
+/// A monotonic clock that counts the interrupts of a periodic timer. This is a fallback for when there is no better source of time.
+pub struct TickCounter {
+    /// The number of interrupts that have occurred, atomic so that reading it does not need interrupts disabled
+    ticks: Arc<AtomicU64>,
+    /// The number of nanoseconds per interrupt
+    period_ns: u64,
+    /// The timer generating the interrupts
+    _timer: TimerInstance,
+}
+
+impl TickCounter {
+    /// Start a tick counter with the given timer, interrupting every specified number of microseconds.
+    pub fn new(timer: TimerInstance, period_us: u32) -> Result<Self, TimerError> {
+        let ticks = Arc::new(AtomicU64::new(0));
+        let t2 = ticks.clone();
+        timer.periodic_us(
+            period_us,
+            Box::new(move || {
+                t2.fetch_add(1, Ordering::Relaxed);
+            }),
+        )?;
+        Ok(Self {
+            ticks,
+            period_ns: period_us as u64 * 1000,
+            _timer: timer,
+        })
+    }
+}
+
+impl MonotonicClockTrait for TickCounter {
+    fn nanoseconds(&self) -> u64 {
+        // This is called from interrupt handlers, such as the one waking sleeping tasks, so it must not enable interrupts
+        self.ticks.load(Ordering::Relaxed) * self.period_ns
+    }
+}
+
+/// Start the timer that wakes the tasks waiting in [sleep] and [sleep_until], interrupting every specified number of microseconds.
+/// Until this is done, a sleeping task is polled again every time the executor runs.
+pub fn start_sleep_timer(timer: TimerInstance, period_us: u32) -> Result<(), TimerError> {
+    timer.periodic_us(period_us, Box::new(wake_sleepers))?;
+    SLEEP_TIMER.write().replace(timer);
+    Ok(())
+}
+
+/// Wake the sleeping tasks whose deadline has passed. This is called from an interrupt context.
+fn wake_sleepers() {
+    let now = Instant::now();
+    let mut s = SLEEPERS.sync_lock();
+    let mut i = 0;
+    while i < s.len() {
+        if s[i].1 <= now {
+            let (_, _, w) = s.swap_remove(i);
+            w.wake();
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// Returns a future that completes once the monotonic clock reaches the deadline, or immediately when there is no monotonic clock
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        id: NEXT_SLEEP.fetch_add(1, Ordering::Relaxed),
+        deadline,
+    }
+}
+
+/// Returns a future that completes after the specified amount of time
+pub fn sleep(d: Duration) -> Sleep {
+    let now = Instant::now();
+    sleep_until(now.checked_add(d).unwrap_or(Instant { nanos: u64::MAX }))
+}
+
+/// A future that completes when the monotonic clock reaches an instant
+pub struct Sleep {
+    /// The identifier of the sleep in [SLEEPERS]
+    id: u64,
+    /// When the future completes
+    deadline: Instant,
+}
+
+impl Sleep {
+    /// The instant that the future completes at
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Remove the waker of the sleep from [SLEEPERS]
+    fn unregister(&self) {
+        crate::SYSTEM.read().disable_interrupts_for(|| {
+            SLEEPERS.sync_lock().retain(|e| e.0 != self.id);
+        });
+    }
+}
+
+impl core::future::Future for Sleep {
+    type Output = ();
+    fn poll(self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context<'_>) -> Poll<()> {
+        // Without a clock the deadline would never be reached
+        if Instant::now() >= self.deadline || MONOTONIC_CLOCK.read().is_none() {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        if SLEEP_TIMER.read().is_none() {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        crate::SYSTEM.read().disable_interrupts_for(|| {
+            let mut s = SLEEPERS.sync_lock();
+            match s.iter_mut().find(|e| e.0 == self.id) {
+                Some(e) => e.2.clone_from(cx.waker()),
+                None => s.push((self.id, self.deadline, cx.waker().clone())),
+            }
+        });
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
@@ -0,0 +1,75 @@
+//! Time keeping code for the stm32f769
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// The number of times the systick counter has wrapped around
+static SYSTICK_WRAPS: AtomicU32 = AtomicU32::new(0);
+
+/// The reload value used for the systick counter, the largest it supports
+const SYSTICK_RELOAD: u32 = 0x00ff_ffff;
+
+/// The interrupt control and state register of the cortex-m system control block
+const ICSR: usize = 0xe000_ed04;
+/// The bit of [ICSR] that is set while the systick exception is pending
+const ICSR_PENDSTSET: u32 = 1 << 26;
+
+/// Returns true when the systick counter has wrapped and the exception has not been handled yet
+fn systick_pending() -> bool {
+    (unsafe { core::ptr::read_volatile(ICSR as *const u32) } & ICSR_PENDSTSET) != 0
+}
+
+/// The registers of the systick timer
+struct Registers {
+    /// The registers
+    regs: [u32; 4],
+}
+
+/// The interrupt handler for the systick exception
+pub extern "C" fn systick_handler() {
+    SYSTICK_WRAPS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A monotonic clock based on the systick timer of the cortex-m processor
+pub struct SysTickClock {
+    /// The registers
+    regs: &'static mut Registers,
+    /// The frequency of the processor clock in hertz
+    frequency: u64,
+}
+
+impl SysTickClock {
+    /// Start the systick timer, counting the processor clock running at the specified frequency.
+    /// [systick_handler] must be installed as the handler for the systick exception.
+    pub unsafe fn new(addr: u32, frequency: u64) -> Self {
+        let mut s = Self {
+            regs: &mut *(addr as *mut Registers),
+            frequency,
+        };
+        core::ptr::write_volatile(&mut s.regs.regs[0], 0);
+        core::ptr::write_volatile(&mut s.regs.regs[1], SYSTICK_RELOAD);
+        core::ptr::write_volatile(&mut s.regs.regs[2], 0);
+        SYSTICK_WRAPS.store(0, Ordering::Relaxed);
+        // Enable the counter with the exception, using the processor clock
+        core::ptr::write_volatile(&mut s.regs.regs[0], 7);
+        s
+    }
+}
+
+impl super::MonotonicClockTrait for SysTickClock {
+    fn nanoseconds(&self) -> u64 {
+        // A wrap that is pending but not handled yet, because interrupts are disabled or the exception has not been taken,
+        // is not in the wrap count yet. Retry when the pending state changes around the read of the counter.
+        let (wraps, count) = loop {
+            let w1 = SYSTICK_WRAPS.load(Ordering::Relaxed);
+            let p1 = systick_pending();
+            let c = unsafe { core::ptr::read_volatile(&self.regs.regs[2]) };
+            let p2 = systick_pending();
+            let w2 = SYSTICK_WRAPS.load(Ordering::Relaxed);
+            if w1 == w2 && p1 == p2 {
+                break (w1 + p1 as u32, c);
+            }
+        };
+        let ticks = wraps as u64 * (SYSTICK_RELOAD as u64 + 1) + (SYSTICK_RELOAD - count) as u64;
+        (ticks as u128 * 1_000_000_000 / self.frequency as u128) as u64
+    }
+}
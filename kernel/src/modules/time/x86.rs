@@ -0,0 +1,65 @@
+//! Time keeping code for x86 hardware
+
+use raw_cpuid::{CpuId, CpuIdReaderNative};
+
+use crate::modules::timer::TimerError;
+
+/// The number of microseconds used to calibrate the time stamp counter against another timer
+const CALIBRATION_US: u32 = 10_000;
+
+/// A monotonic clock based on the invariant time stamp counter of the processor
+pub struct TscClock {
+    /// The frequency of the time stamp counter in hertz
+    frequency: u64,
+    /// The value of the time stamp counter when the clock was started
+    start: u64,
+}
+
+/// Read the time stamp counter
+fn read_tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+impl TscClock {
+    /// Build a clock from the time stamp counter, if it is invariant.
+    /// The frequency is obtained from cpuid when it is available, otherwise the delay function is used to calibrate the counter.
+    pub fn new(
+        cpuid: &CpuId<CpuIdReaderNative>,
+        delay_us: impl FnOnce(u32) -> Result<(), TimerError>,
+    ) -> Option<Self> {
+        let invariant = cpuid
+            .get_advanced_power_mgmt_info()
+            .map(|a| a.has_invariant_tsc())
+            .unwrap_or(false);
+        if !invariant {
+            return None;
+        }
+        let frequency = if let Some(f) = cpuid.get_tsc_info().and_then(|t| t.tsc_frequency()) {
+            f
+        } else {
+            let start = read_tsc();
+            delay_us(CALIBRATION_US).ok()?;
+            let end = read_tsc();
+            (end - start) * (1_000_000 / CALIBRATION_US as u64)
+        };
+        if frequency == 0 {
+            return None;
+        }
+        Some(Self {
+            frequency,
+            start: read_tsc(),
+        })
+    }
+
+    /// The frequency of the time stamp counter in hertz
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
+
+impl super::MonotonicClockTrait for TscClock {
+    fn nanoseconds(&self) -> u64 {
+        let ticks = read_tsc().wrapping_sub(self.start);
+        (ticks as u128 * 1_000_000_000 / self.frequency as u128) as u64
+    }
+}
@@ -1,16 +1,38 @@
 //! Timer related code
 
-#[cfg(kernel_machine = "stm32f769i-disco")]
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Poll, Waker};
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::kernel::SystemTrait;
+use crate::modules::time::Instant;
+use crate::{Arc, Locked};
+
+#[cfg(any(kernel_machine = "stm32f769i-disco", kernel_machine = "pc64"))]
 use crate::LockedArc;
 
 #[cfg(kernel_machine = "stm32f769i-disco")]
 pub mod stm32f769;
 
+#[cfg(kernel_machine = "pc64")]
+pub mod x86;
+
+/// The function called when a timer interrupt mode expires. It is called from an interrupt context.
+pub type TimerCallback = Box<dyn FnMut() + Send + Sync>;
+
 /// The errors that can occur obtaining a timer
 #[derive(Debug)]
 pub enum TimerError {
     /// The timer desired is in use
     TimerIsAlreadyUsed,
+    /// The timer desired does not exist
+    TimerDoesNotExist,
+    /// The timer does not support the requested mode of operation
+    ModeNotSupported,
+    /// The requested duration cannot be produced by the timer
+    InvalidDuration,
 }
 
 /// The trait implemented by timer provider implementations
@@ -27,6 +49,211 @@ pub trait TimerInstanceTrait {
     fn delay_ms(&self, ms: u32);
     /// Delay a specified number of microseconds
     fn delay_us(&self, us: u32);
+    /// Call the callback once, after the specified number of microseconds
+    fn oneshot_us(&self, us: u32, f: TimerCallback) -> Result<(), TimerError>;
+    /// Call the callback every time the specified number of microseconds elapses
+    fn periodic_us(&self, us: u32, f: TimerCallback) -> Result<(), TimerError>;
+    /// Stop a running oneshot or periodic timer
+    fn stop(&self);
+    /// The number of times the timer has expired since it was last started with oneshot_us or periodic_us
+    fn expirations(&self) -> u64;
+    /// The number of microseconds since the timer was last started with oneshot_us or periodic_us, 0 if it has never been started
+    fn elapsed_us(&self) -> u64;
+    /// Returns a future that completes once, after the specified number of microseconds
+    fn oneshot_future(&self, us: u32) -> Result<TimerFuture, TimerError> {
+        let event = TimerEvent::new();
+        let e2 = event.clone();
+        self.oneshot_us(us, Box::new(move || e2.fire()))?;
+        Ok(TimerFuture { event })
+    }
+    /// Returns a stream that produces the number of expirations since the previous item, every time the specified number of microseconds elapses.
+    /// The timer keeps running when the stream is dropped, until it is stopped.
+    fn periodic_stream(&self, us: u32) -> Result<TimerStream, TimerError> {
+        let event = TimerEvent::new();
+        let e2 = event.clone();
+        self.periodic_us(us, Box::new(move || e2.fire()))?;
+        Ok(TimerStream { event })
+    }
+    /// Asynchronously delay a specified number of microseconds
+    async fn delay_us_async(&self, us: u32) -> Result<(), TimerError> {
+        self.oneshot_future(us)?.await;
+        Ok(())
+    }
+    /// Asynchronously delay a specified number of milliseconds
+    async fn delay_ms_async(&self, ms: u32) -> Result<(), TimerError> {
+        let us = ms.checked_mul(1000).ok_or(TimerError::InvalidDuration)?;
+        self.delay_us_async(us).await
+    }
+}
+
+/// The state shared between a timer interrupt and the futures and streams waiting on it
+struct TimerEvent {
+    /// The number of expirations that have not been consumed yet
+    count: AtomicU32,
+    /// The wakers waiting for the timer to expire
+    wakers: Locked<Vec<Waker>>,
+}
+
+impl TimerEvent {
+    /// Create a new event with no expirations
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            count: AtomicU32::new(0),
+            wakers: Locked::new(Vec::new()),
+        })
+    }
+
+    /// Record an expiration and wake everything waiting on it. This is called from an interrupt context.
+    fn fire(&self) {
+        self.count.fetch_add(1, Ordering::AcqRel);
+        // Drained in place, so that the interrupt handler does not free memory
+        for w in self.wakers.sync_lock().drain(..) {
+            w.wake();
+        }
+    }
+
+    /// Take all of the pending expirations, registering the waker when there are none
+    fn poll_take(&self, cx: &mut core::task::Context<'_>) -> Poll<u32> {
+        let c = self.count.swap(0, Ordering::AcqRel);
+        if c != 0 {
+            return Poll::Ready(c);
+        }
+        // The interrupt handler locks the wakers too
+        crate::SYSTEM
+            .read()
+            .disable_interrupts_for(|| crate::add_waker(&mut self.wakers.sync_lock(), cx.waker()));
+        // The timer may have expired before the waker was registered
+        let c = self.count.swap(0, Ordering::AcqRel);
+        if c != 0 {
+            Poll::Ready(c)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// A future that completes when a timer expires
+pub struct TimerFuture {
+    /// The event for the timer
+    event: Arc<TimerEvent>,
+}
+
+impl core::future::Future for TimerFuture {
+    type Output = ();
+    fn poll(self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context<'_>) -> Poll<()> {
+        self.event.poll_take(cx).map(|_| ())
+    }
+}
+
+/// A stream of timer expirations, each item is the number of expirations since the previous item
+pub struct TimerStream {
+    /// The event for the timer
+    event: Arc<TimerEvent>,
+}
+
+impl futures::Stream for TimerStream {
+    type Item = u32;
+    fn poll_next(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.event.poll_take(cx).map(Some)
+    }
+}
+
+/// The state of a timer that is running in one of the interrupt driven modes
+struct InterruptState {
+    /// The function to call when the timer expires. It must not modify the timer that calls it.
+    callback: TimerCallback,
+    /// True when the timer restarts after calling the callback
+    periodic: bool,
+    /// The number of hardware interrupts that make up a single period of the timer
+    interrupts_per_period: u32,
+    /// The number of hardware interrupts remaining in the current period
+    remaining: u32,
+    /// False once a oneshot timer has expired
+    running: bool,
+    /// The number of times the timer has expired
+    expirations: u64,
+    /// When the timer was started
+    started: Instant,
+}
+
+impl InterruptState {
+    /// Construct a new self
+    fn new(callback: TimerCallback, periodic: bool, interrupts_per_period: u32) -> Self {
+        Self {
+            callback,
+            periodic,
+            interrupts_per_period,
+            remaining: interrupts_per_period,
+            running: true,
+            expirations: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+/// The interrupt state of a timer, shared between the timer and the interrupt handler for the timer
+type SharedInterruptState = crate::Arc<Locked<Option<InterruptState>>>;
+
+/// Process a single hardware interrupt for a timer.
+/// Returns true when the timer should continue to generate interrupts.
+fn process_interrupt(state: &SharedInterruptState) -> bool {
+    let mut s = state.sync_lock();
+    if let Some(st) = s.as_mut().filter(|st| st.running) {
+        st.remaining = st.remaining.saturating_sub(1);
+        if st.remaining == 0 {
+            st.expirations += 1;
+            (st.callback)();
+            if st.periodic {
+                st.remaining = st.interrupts_per_period;
+                true
+            } else {
+                st.running = false;
+                false
+            }
+        } else {
+            true
+        }
+    } else {
+        false
+    }
+}
+
+/// Replace the interrupt state for a timer, without the interrupt handler for the timer being able to run at the same time
+fn set_interrupt_state(state: &SharedInterruptState, news: Option<InterruptState>) {
+    let mut news = news;
+    let old = crate::SYSTEM.read().disable_interrupts_for(|| {
+        let mut s = state.sync_lock();
+        core::mem::replace(&mut *s, news.take())
+    });
+    drop(old);
+}
+
+/// Read the (expirations, microseconds since started) counters of a timer
+fn interrupt_counters(state: &SharedInterruptState) -> (u64, u64) {
+    let c = crate::SYSTEM.read().disable_interrupts_for(|| {
+        state
+            .sync_lock()
+            .as_ref()
+            .map(|st| (st.expirations, st.started))
+    });
+    match c {
+        Some((e, started)) => (e, started.elapsed().as_micros() as u64),
+        None => (0, 0),
+    }
+}
+
+/// Split a number of hardware ticks into a quantity of interrupts each of an equal number of ticks, with no more than max ticks each.
+/// Returns (ticks per interrupt, number of interrupts)
+fn split_ticks(ticks: u64, max: u64) -> Result<(u64, u32), TimerError> {
+    let ticks = ticks.max(1);
+    let interrupts = ticks.div_ceil(max);
+    let interrupts: u32 = interrupts
+        .try_into()
+        .map_err(|_| TimerError::InvalidDuration)?;
+    Ok((ticks / interrupts as u64, interrupts))
 }
 
 /// An enumeration of all the types of timers
@@ -35,6 +262,15 @@ pub enum Timer {
     /// The stm32f769 timer module
     #[cfg(kernel_machine = "stm32f769i-disco")]
     Stm32f769(LockedArc<stm32f769::TimerGroup>),
+    /// The x86 programmable interval timer
+    #[cfg(kernel_machine = "pc64")]
+    X86Pit(LockedArc<x86::pit::Pit>),
+    /// The x86 high precision event timer
+    #[cfg(kernel_machine = "pc64")]
+    X86Hpet(LockedArc<x86::hpet::Hpet>),
+    /// The x86 local apic timer
+    #[cfg(kernel_machine = "pc64")]
+    X86LocalApic(LockedArc<x86::lapic::LocalApicTimer>),
     /// The dummy implementation
     Dummy(DummyTimer),
 }
@@ -45,6 +281,15 @@ pub enum TimerInstance {
     /// A basic stm32f769 timer instance
     #[cfg(kernel_machine = "stm32f769i-disco")]
     BasicStm327f69Timer(LockedArc<stm32f769::Timer>),
+    /// A channel of the x86 programmable interval timer
+    #[cfg(kernel_machine = "pc64")]
+    X86PitChannel(LockedArc<x86::pit::PitChannel>),
+    /// A comparator of the x86 high precision event timer
+    #[cfg(kernel_machine = "pc64")]
+    X86HpetComparator(LockedArc<x86::hpet::HpetComparator>),
+    /// The x86 local apic timer
+    #[cfg(kernel_machine = "pc64")]
+    X86LocalApicTimer(LockedArc<x86::lapic::LocalApicTimerInstance>),
     /// The dummy implementation
     Dummy(DummyTimer),
 }
@@ -62,4 +307,22 @@ impl TimerInstanceTrait for DummyTimer {
     fn delay_ms(&self, _ms: u32) {}
 
     fn delay_us(&self, _us: u32) {}
+
+    fn oneshot_us(&self, _us: u32, _f: TimerCallback) -> Result<(), TimerError> {
+        Err(TimerError::ModeNotSupported)
+    }
+
+    fn periodic_us(&self, _us: u32, _f: TimerCallback) -> Result<(), TimerError> {
+        Err(TimerError::ModeNotSupported)
+    }
+
+    fn stop(&self) {}
+
+    fn expirations(&self) -> u64 {
+        0
+    }
+
+    fn elapsed_us(&self) -> u64 {
+        0
+    }
 }
@@ -1,11 +1,65 @@
 //! Timer modules for the stm32f769
 
-use crate::{modules::clock::ClockRefTrait, LockedArc};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{
+    interrupt_counters, process_interrupt, set_interrupt_state, split_ticks, InterruptState,
+    SharedInterruptState, TimerCallback, TimerError,
+};
+use crate::{modules::clock::ClockRefTrait, Arc, Locked, LockedArc, MutexGuard};
 
 struct Registers {
     regs: [u32; 16],
 }
 
+/// The address of the nvic interrupt set enable registers
+const NVIC_ISER_ADDRESS: u32 = 0xe000_e100;
+/// The update interrupt enable bit of the dier register
+const DIER_UIE: u32 = 1;
+/// Only counter overflow generates an update interrupt, not a software update event
+const CR1_URS: u32 = 1 << 2;
+
+/// The timer groups that can generate update interrupts, as (register address, interrupt number)
+const UPDATE_INTERRUPTS: [(u32, u32); 2] = [(0x4001_0000, 25), (0x4001_0400, 44)];
+
+/// The register addresses of the timer groups with an active update interrupt, for the interrupt handlers
+static UPDATE_ADDRESSES: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+/// The interrupt state for the update interrupt of each timer group
+static UPDATE_STATES: [Locked<Option<SharedInterruptState>>; 2] =
+    [Locked::new(None), Locked::new(None)];
+
+/// Process the update interrupt for the specified slot of [UPDATE_INTERRUPTS]
+fn handle_update(slot: usize) {
+    let addr = UPDATE_ADDRESSES[slot].load(Ordering::Relaxed);
+    if addr == 0 {
+        return;
+    }
+    let regs = addr as *mut Registers;
+    // Clear the update interrupt flag
+    unsafe { core::ptr::write_volatile(&mut (*regs).regs[4], !1) };
+    let state = UPDATE_STATES[slot].sync_lock().clone();
+    let running = state.map(|s| process_interrupt(&s)).unwrap_or(false);
+    if !running {
+        unsafe {
+            let dier = core::ptr::read_volatile(&(*regs).regs[3]);
+            core::ptr::write_volatile(&mut (*regs).regs[3], dier & !DIER_UIE);
+            let c = core::ptr::read_volatile(&(*regs).regs[0]);
+            core::ptr::write_volatile(&mut (*regs).regs[0], c & !1);
+        }
+    }
+}
+
+/// The interrupt handler for the update interrupt of timer 1, shared with timer 10
+pub extern "C" fn tim1_update_handler() {
+    handle_update(0);
+}
+
+/// The interrupt handler for the update interrupt of timer 8, shared with timer 13
+pub extern "C" fn tim8_update_handler() {
+    handle_update(1);
+}
+
 /// The basic timer module. This covers functionality of timer 1 and 8
 pub struct TimerGroup {
     /// The registers
@@ -16,6 +70,12 @@ pub struct TimerGroup {
     usage: u8,
     /// The input clock to the timer.
     clock: crate::modules::clock::ClockRef,
+    /// The slot of [UPDATE_INTERRUPTS] for the timer, if it can generate update interrupts
+    update_slot: Option<usize>,
+    /// The timer that is using the update interrupt
+    update_owner: Option<u8>,
+    /// The interrupt state for the update interrupt
+    irq_state: SharedInterruptState,
 }
 
 impl TimerGroup {
@@ -26,6 +86,9 @@ impl TimerGroup {
             clocks_used: 0,
             usage: 0,
             clock,
+            update_slot: UPDATE_INTERRUPTS.iter().position(|(a, _)| *a == addr),
+            update_owner: None,
+            irq_state: Arc::new(Locked::new(None)),
         }
     }
 
@@ -73,6 +136,36 @@ impl TimerGroup {
         unsafe { core::ptr::write_volatile(&mut self.regs.regs[0], c & !1) };
     }
 
+    /// Enable or disable the update interrupt
+    fn set_update_interrupt(&mut self, e: bool) {
+        let v = unsafe { core::ptr::read_volatile(&self.regs.regs[3]) } & !DIER_UIE;
+        let v = if e { v | DIER_UIE } else { v };
+        unsafe { core::ptr::write_volatile(&mut self.regs.regs[3], v) };
+    }
+
+    /// Setup the counter to overflow after the specified number of counts, each of the specified number of input clocks
+    fn set_period(&mut self, prescaler: u32, counts: u32) {
+        let c = unsafe { core::ptr::read_volatile(&self.regs.regs[0]) };
+        unsafe { core::ptr::write_volatile(&mut self.regs.regs[0], c | CR1_URS) };
+        unsafe { core::ptr::write_volatile(&mut self.regs.regs[10], prescaler - 1) };
+        unsafe { core::ptr::write_volatile(&mut self.regs.regs[11], counts - 1) };
+        unsafe { core::ptr::write_volatile(&mut self.regs.regs[9], 0) };
+        self.update();
+        unsafe { core::ptr::write_volatile(&mut self.regs.regs[4], !1) };
+    }
+
+    /// Stop the update interrupt, restoring the full counter range for the delay functions
+    fn stop_update(&mut self) {
+        if let Some(slot) = self.update_slot {
+            self.set_update_interrupt(false);
+            self.stop_timer();
+            UPDATE_ADDRESSES[slot].store(0, Ordering::Relaxed);
+            unsafe { core::ptr::write_volatile(&mut self.regs.regs[11], 0xffff) };
+        }
+        self.update_owner = None;
+        set_interrupt_state(&self.irq_state, None);
+    }
+
     /// Generate an update event
     fn update(&mut self) {
         unsafe { core::ptr::write_volatile(&mut self.regs.regs[0x14 / 4], 1) };
@@ -113,9 +206,9 @@ impl TimerGroup {
 
 impl super::TimerTrait for LockedArc<TimerGroup> {
     fn get_timer(&mut self, i: u8) -> Result<super::TimerInstance, super::TimerError> {
-        let mut s = self.lock();
+        let mut s = self.sync_lock();
         let check = 1u8 << i;
-        if (s.clocks_used & check) == 0 {
+        if (s.clocks_used & check) == 0 && s.update_owner.is_none() {
             s.clocks_used |= check;
             Ok(super::TimerInstance::BasicStm327f69Timer(LockedArc::new(
                 Timer {
@@ -139,7 +232,10 @@ pub struct Timer {
 
 impl Drop for Timer {
     fn drop(&mut self) {
-        let mut t = self.timer.lock();
+        let mut t = self.timer.sync_lock();
+        if t.update_owner == Some(self.index) {
+            t.stop_update();
+        }
         t.unadjust();
         let check = 1u8 << self.index;
         t.clocks_used &= !check;
@@ -147,7 +243,48 @@ impl Drop for Timer {
 }
 
 impl Timer {
-    fn delay_cycles(&self, counts_required: u64, mut t: spin::MutexGuard<'_, TimerGroup>) {
+    /// Start the update interrupt of the timer group, calling the callback after the specified number of microseconds.
+    /// This requires exclusive use of the timer group.
+    fn start(&self, us: u32, f: TimerCallback, periodic: bool) -> Result<(), TimerError> {
+        let mut t = self.timer.sync_lock();
+        let slot = t.update_slot.ok_or(TimerError::ModeNotSupported)?;
+        if t.clocks_used != (1 << self.index) || t.update_owner.is_some_and(|o| o != self.index) {
+            return Err(TimerError::TimerIsAlreadyUsed);
+        }
+        t.clock.enable_clock();
+        let freq = t
+            .clock
+            .clock_frequency()
+            .ok_or(TimerError::ModeNotSupported)?;
+        let total = (freq as u128 * us as u128).div_ceil(1_000_000).max(1);
+        let prescaler = total.div_ceil(0x10000).min(0x10000) as u64;
+        let counts = (total / prescaler as u128).min(u64::MAX as u128) as u64;
+        let (counts, interrupts) = split_ticks(counts, 0x10000)?;
+        // The interrupt must not run while the interrupt state is replaced
+        t.set_update_interrupt(false);
+        t.stop_timer();
+        set_interrupt_state(
+            &t.irq_state,
+            Some(InterruptState::new(f, periodic, interrupts)),
+        );
+        UPDATE_STATES[slot].replace(Some(t.irq_state.clone()));
+        let addr = &mut *t.regs as *mut Registers as u32;
+        UPDATE_ADDRESSES[slot].store(addr, Ordering::Relaxed);
+        t.update_owner = Some(self.index);
+        t.set_period(prescaler as u32, counts as u32);
+        t.set_update_interrupt(true);
+        let irq = UPDATE_INTERRUPTS[slot].1;
+        unsafe {
+            core::ptr::write_volatile(
+                (NVIC_ISER_ADDRESS + 4 * (irq / 32)) as *mut u32,
+                1 << (irq % 32),
+            )
+        };
+        t.start_timer();
+        Ok(())
+    }
+
+    fn delay_cycles(&self, counts_required: u64, mut t: MutexGuard<'_, TimerGroup>) {
         if counts_required > 0xFFFF {
             let mut counter = 0;
             loop {
@@ -184,8 +321,8 @@ impl Timer {
 
 impl super::TimerInstanceTrait for LockedArc<Timer> {
     fn delay_us(&self, us: u32) {
-        let s = self.lock();
-        let mut t = s.timer.lock();
+        let s = self.sync_lock();
+        let mut t = s.timer.sync_lock();
         t.clock.enable_clock();
 
         let freq = t.clock.clock_frequency().unwrap();
@@ -208,8 +345,8 @@ impl super::TimerInstanceTrait for LockedArc<Timer> {
     }
 
     fn delay_ms(&self, ms: u32) {
-        let s = self.lock();
-        let mut t = s.timer.lock();
+        let s = self.sync_lock();
+        let mut t = s.timer.sync_lock();
         t.clock.enable_clock();
 
         let freq = t.clock.clock_frequency().unwrap();
@@ -230,4 +367,40 @@ impl super::TimerInstanceTrait for LockedArc<Timer> {
         }
         s.delay_cycles(counts_required, t);
     }
+
+    fn oneshot_us(&self, us: u32, f: TimerCallback) -> Result<(), TimerError> {
+        self.sync_lock().start(us, f, false)
+    }
+
+    fn periodic_us(&self, us: u32, f: TimerCallback) -> Result<(), TimerError> {
+        self.sync_lock().start(us, f, true)
+    }
+
+    fn stop(&self) {
+        let s = self.sync_lock();
+        let mut t = s.timer.sync_lock();
+        if t.update_owner == Some(s.index) {
+            t.stop_update();
+        }
+    }
+
+    fn expirations(&self) -> u64 {
+        let s = self.sync_lock();
+        let t = s.timer.sync_lock();
+        if t.update_owner == Some(s.index) {
+            interrupt_counters(&t.irq_state).0
+        } else {
+            0
+        }
+    }
+
+    fn elapsed_us(&self) -> u64 {
+        let s = self.sync_lock();
+        let t = s.timer.sync_lock();
+        if t.update_owner == Some(s.index) {
+            interrupt_counters(&t.irq_state).1
+        } else {
+            0
+        }
+    }
 }
@@ -0,0 +1,386 @@
+//! The high precision event timer of x86 hardware, located with the acpi hpet table
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::kernel::SystemTrait;
+use crate::modules::timer::{
+    interrupt_counters, process_interrupt, set_interrupt_state, split_ticks, InterruptState,
+    SharedInterruptState, TimerCallback, TimerError, TimerInstance, TimerInstanceTrait, TimerTrait,
+};
+use crate::{Arc, Locked, LockedArc, MappedMemory};
+
+/// The general capabilities and id register
+const GENERAL_CAPABILITIES: usize = 0;
+/// The general configuration register
+const GENERAL_CONFIGURATION: usize = 0x10;
+/// The general interrupt status register
+const GENERAL_INTERRUPT_STATUS: usize = 0x20;
+/// The main counter value register
+const MAIN_COUNTER: usize = 0xf0;
+
+/// The counter is enabled
+const CONFIG_ENABLE: u64 = 1 << 0;
+/// Comparator 0 is routed to irq 0 and comparator 1 is routed to irq 8
+const CONFIG_LEGACY_REPLACEMENT: u64 = 1 << 1;
+/// The hpet is capable of legacy replacement routing
+const CAPABILITY_LEGACY_REPLACEMENT: u64 = 1 << 15;
+/// The main counter is 64 bits wide
+const CAPABILITY_COUNTER_64: u64 = 1 << 13;
+
+/// The comparator generates level triggered interrupts
+const COMPARATOR_LEVEL_TRIGGERED: u64 = 1 << 1;
+/// The comparator interrupt is enabled
+const COMPARATOR_INTERRUPT_ENABLE: u64 = 1 << 2;
+/// The comparator runs in periodic mode
+const COMPARATOR_PERIODIC: u64 = 1 << 3;
+/// The comparator is capable of periodic mode
+const COMPARATOR_PERIODIC_CAPABLE: u64 = 1 << 4;
+/// The comparator is 64 bits wide
+const COMPARATOR_64: u64 = 1 << 5;
+/// Allows writing the accumulator of a periodic comparator
+const COMPARATOR_VALUE_SET: u64 = 1 << 6;
+
+/// The configuration and capability register for a comparator
+const fn comparator_config(n: u8) -> usize {
+    0x100 + 0x20 * n as usize
+}
+
+/// The comparator value register for a comparator
+const fn comparator_value(n: u8) -> usize {
+    0x108 + 0x20 * n as usize
+}
+
+/// The irq used by the specified comparator, when legacy replacement routing is in use
+const fn legacy_irq(n: u8) -> u8 {
+    if n == 0 {
+        0
+    } else {
+        8
+    }
+}
+
+/// The registers of the hpet, shared between the hpet, its comparators and the interrupt handlers
+struct HpetRegisters {
+    /// The memory mapped registers
+    mem: MappedMemory,
+    /// The period of the main counter, in femtoseconds
+    period_fs: u64,
+    /// True when the main counter is 64 bits
+    counter_64: bool,
+    /// The number of ticks to add to comparators 0 and 1 on every interrupt, used when the comparator does not support periodic mode. 0 when not used.
+    rearm: [AtomicU64; 2],
+}
+
+impl HpetRegisters {
+    /// Read the main counter
+    fn counter(&self) -> u64 {
+        if self.counter_64 {
+            self.mem.read_u64(MAIN_COUNTER)
+        } else {
+            self.mem.read_u32(MAIN_COUNTER) as u64
+        }
+    }
+
+    /// The number of ticks that have elapsed since the main counter read start
+    fn ticks_since(&self, start: u64) -> u64 {
+        if self.counter_64 {
+            self.counter().wrapping_sub(start)
+        } else {
+            (self.counter() as u32).wrapping_sub(start as u32) as u64
+        }
+    }
+
+    /// Convert a number of microseconds into ticks of the main counter
+    fn us_to_ticks(&self, us: u64) -> u64 {
+        let t = (us as u128 * 1_000_000_000).div_ceil(self.period_fs as u128);
+        t.min(u64::MAX as u128) as u64
+    }
+
+    /// Busy wait for the specified number of ticks of the main counter
+    fn delay_ticks(&self, ticks: u64) {
+        let mut remaining = ticks;
+        while remaining > 0 {
+            let t = remaining.min(0x7fff_ffff);
+            let start = self.counter();
+            while self.ticks_since(start) < t {}
+            remaining -= t;
+        }
+    }
+
+    /// Stop a comparator from generating interrupts
+    fn disarm(&self, n: u8) {
+        let c = self.mem.read_u64(comparator_config(n));
+        self.mem.write_u64(
+            comparator_config(n),
+            c & !(COMPARATOR_INTERRUPT_ENABLE | COMPARATOR_PERIODIC),
+        );
+    }
+
+    /// Setup the next interrupt for a comparator that does not support periodic mode
+    fn rearm(&self, n: u8) {
+        let ticks = self.rearm[n as usize].load(Ordering::Relaxed);
+        if ticks != 0 {
+            let v = self.mem.read_u64(comparator_value(n));
+            self.mem
+                .write_u64(comparator_value(n), v.wrapping_add(ticks));
+        }
+    }
+}
+
+/// The high precision event timer
+pub struct Hpet {
+    /// The registers of the hpet
+    regs: Arc<HpetRegisters>,
+    /// The comparators that are currently handed out
+    used: u32,
+    /// The number of comparators
+    num_comparators: u8,
+    /// True when the hpet is capable of legacy replacement routing, required for interrupts
+    legacy_capable: bool,
+    /// The interrupt state for comparators 0 and 1
+    irq_states: [SharedInterruptState; 2],
+    /// The comparators that generate interrupts through legacy replacement routing
+    legacy_users: u8,
+}
+
+impl Hpet {
+    /// Map the hpet at the specified physical address and start the main counter
+    pub fn new(base: usize) -> Option<Self> {
+        let mem = MappedMemory::new(base, 0x400).ok()?;
+        let cap = mem.read_u64(GENERAL_CAPABILITIES);
+        let period_fs = cap >> 32;
+        if period_fs == 0 || period_fs > 100_000_000 {
+            return None;
+        }
+        let num_comparators = ((cap >> 8) & 0x1f) as u8 + 1;
+        for i in 0..num_comparators {
+            let c = mem.read_u64(comparator_config(i));
+            mem.write_u64(
+                comparator_config(i),
+                c & !(COMPARATOR_INTERRUPT_ENABLE | COMPARATOR_PERIODIC),
+            );
+        }
+        let conf = mem.read_u64(GENERAL_CONFIGURATION);
+        mem.write_u64(GENERAL_CONFIGURATION, conf | CONFIG_ENABLE);
+        Some(Self {
+            regs: Arc::new(HpetRegisters {
+                mem,
+                period_fs,
+                counter_64: (cap & CAPABILITY_COUNTER_64) != 0,
+                rearm: [AtomicU64::new(0), AtomicU64::new(0)],
+            }),
+            used: 0,
+            num_comparators,
+            legacy_capable: (cap & CAPABILITY_LEGACY_REPLACEMENT) != 0,
+            irq_states: [Arc::new(Locked::new(None)), Arc::new(Locked::new(None))],
+            legacy_users: 0,
+        })
+    }
+
+    /// The frequency of the main counter in hertz
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.regs.period_fs
+    }
+
+    /// Read the main counter
+    pub fn counter(&self) -> u64 {
+        self.regs.counter()
+    }
+
+    /// Busy wait for the specified number of microseconds using the main counter.
+    /// This does not require claiming a comparator, so it is used for calibrating other timers.
+    pub fn calibration_delay_us(&self, us: u32) -> Result<(), TimerError> {
+        self.regs.delay_ticks(self.regs.us_to_ticks(us as u64));
+        Ok(())
+    }
+
+    /// Switch to legacy replacement routing for the specified comparator, taking over irq 0 and irq 8.
+    /// Returns false when the pit or the cmos real time clock generate interrupts.
+    fn enable_legacy_replacement(&mut self, n: u8) -> bool {
+        if self.legacy_users != 0 {
+            self.legacy_users |= 1 << n;
+            return true;
+        }
+        if !super::claim_legacy_replacement() {
+            return false;
+        }
+        self.legacy_users = 1 << n;
+        let sys = crate::SYSTEM.read();
+        for n in 0..2u8 {
+            let regs = self.regs.clone();
+            let state = self.irq_states[n as usize].clone();
+            sys.register_irq_handler(legacy_irq(n), move || {
+                regs.mem.write_u64(GENERAL_INTERRUPT_STATUS, 1 << n);
+                if process_interrupt(&state) {
+                    regs.rearm(n);
+                } else {
+                    regs.disarm(n);
+                }
+            });
+        }
+        let conf = self.regs.mem.read_u64(GENERAL_CONFIGURATION);
+        self.regs
+            .mem
+            .write_u64(GENERAL_CONFIGURATION, conf | CONFIG_LEGACY_REPLACEMENT);
+        true
+    }
+
+    /// The specified comparator no longer generates interrupts.
+    /// Once no comparator does, irq 0 and irq 8 are handed back to the pit and the cmos real time clock.
+    fn disable_legacy_replacement(&mut self, n: u8) {
+        if self.legacy_users == 0 {
+            return;
+        }
+        self.legacy_users &= !(1 << n);
+        if self.legacy_users != 0 {
+            return;
+        }
+        let sys = crate::SYSTEM.read();
+        for n in 0..2u8 {
+            sys.disable_irq(legacy_irq(n));
+        }
+        let conf = self.regs.mem.read_u64(GENERAL_CONFIGURATION);
+        self.regs
+            .mem
+            .write_u64(GENERAL_CONFIGURATION, conf & !CONFIG_LEGACY_REPLACEMENT);
+        super::release_legacy_replacement();
+    }
+}
+
+impl TimerTrait for LockedArc<Hpet> {
+    fn get_timer(&mut self, i: u8) -> Result<TimerInstance, TimerError> {
+        let mut s = self.sync_lock();
+        if i >= s.num_comparators {
+            return Err(TimerError::TimerDoesNotExist);
+        }
+        let check = 1u32 << i;
+        if (s.used & check) == 0 {
+            s.used |= check;
+            Ok(TimerInstance::X86HpetComparator(LockedArc::new(
+                HpetComparator {
+                    hpet: self.clone(),
+                    regs: s.regs.clone(),
+                    index: i,
+                },
+            )))
+        } else {
+            Err(TimerError::TimerIsAlreadyUsed)
+        }
+    }
+}
+
+/// A single comparator of the hpet. Only comparators 0 and 1 can generate interrupts.
+pub struct HpetComparator {
+    /// The hpet that the comparator belongs to
+    hpet: LockedArc<Hpet>,
+    /// The registers of the hpet
+    regs: Arc<HpetRegisters>,
+    /// The comparator number
+    index: u8,
+}
+
+impl HpetComparator {
+    /// Start the comparator generating interrupts
+    fn start(&self, us: u32, f: TimerCallback, periodic: bool) -> Result<(), TimerError> {
+        let state = {
+            let mut h = self.hpet.sync_lock();
+            if self.index >= 2 || !h.legacy_capable || !h.enable_legacy_replacement(self.index) {
+                return Err(TimerError::ModeNotSupported);
+            }
+            h.irq_states[self.index as usize].clone()
+        };
+        let n = self.index;
+        let conf = self.regs.mem.read_u64(comparator_config(n));
+        let max = if self.regs.counter_64 && (conf & COMPARATOR_64) != 0 {
+            u64::MAX / 2
+        } else {
+            0x7fff_ffff
+        };
+        let (ticks, interrupts) = split_ticks(self.regs.us_to_ticks(us as u64), max)?;
+        self.regs.disarm(n);
+        set_interrupt_state(&state, Some(InterruptState::new(f, periodic, interrupts)));
+        let conf = conf & !(COMPARATOR_LEVEL_TRIGGERED | COMPARATOR_PERIODIC);
+        let now = self.regs.counter();
+        if (conf & COMPARATOR_PERIODIC_CAPABLE) != 0 {
+            self.regs.rearm[n as usize].store(0, Ordering::Relaxed);
+            self.regs.mem.write_u64(
+                comparator_config(n),
+                conf | COMPARATOR_INTERRUPT_ENABLE | COMPARATOR_PERIODIC | COMPARATOR_VALUE_SET,
+            );
+            self.regs
+                .mem
+                .write_u64(comparator_value(n), now.wrapping_add(ticks));
+            self.regs.mem.write_u64(comparator_value(n), ticks);
+        } else {
+            self.regs.rearm[n as usize].store(ticks, Ordering::Relaxed);
+            self.regs
+                .mem
+                .write_u64(comparator_config(n), conf | COMPARATOR_INTERRUPT_ENABLE);
+            self.regs
+                .mem
+                .write_u64(comparator_value(n), now.wrapping_add(ticks));
+        }
+        crate::SYSTEM.read().enable_irq(legacy_irq(n));
+        Ok(())
+    }
+
+    /// The (expirations, microseconds since started) counters of the comparator
+    fn counters(&self) -> (u64, u64) {
+        if self.index < 2 {
+            let state = self.hpet.sync_lock().irq_states[self.index as usize].clone();
+            interrupt_counters(&state)
+        } else {
+            (0, 0)
+        }
+    }
+
+    /// Stop the comparator from generating interrupts
+    fn halt(&self) {
+        self.regs.disarm(self.index);
+        if self.index < 2 {
+            let mut h = self.hpet.sync_lock();
+            set_interrupt_state(&h.irq_states[self.index as usize], None);
+            h.disable_legacy_replacement(self.index);
+        }
+    }
+}
+
+impl Drop for HpetComparator {
+    fn drop(&mut self) {
+        self.halt();
+        let mut h = self.hpet.sync_lock();
+        h.used &= !(1 << self.index);
+    }
+}
+
+impl TimerInstanceTrait for LockedArc<HpetComparator> {
+    fn delay_ms(&self, ms: u32) {
+        let s = self.sync_lock();
+        s.regs.delay_ticks(s.regs.us_to_ticks(ms as u64 * 1000));
+    }
+
+    fn delay_us(&self, us: u32) {
+        let s = self.sync_lock();
+        s.regs.delay_ticks(s.regs.us_to_ticks(us as u64));
+    }
+
+    fn oneshot_us(&self, us: u32, f: TimerCallback) -> Result<(), TimerError> {
+        self.sync_lock().start(us, f, false)
+    }
+
+    fn periodic_us(&self, us: u32, f: TimerCallback) -> Result<(), TimerError> {
+        self.sync_lock().start(us, f, true)
+    }
+
+    fn stop(&self) {
+        self.sync_lock().halt();
+    }
+
+    fn expirations(&self) -> u64 {
+        self.sync_lock().counters().0
+    }
+
+    fn elapsed_us(&self) -> u64 {
+        self.sync_lock().counters().1
+    }
+}
@@ -0,0 +1,198 @@
+//! The timer built into the local apic of x86 processors
+
+use crate::boot::x86::boot64::{LAPIC_TIMER_IRQ, LOCAL_APIC};
+use crate::kernel::SystemTrait;
+use crate::modules::timer::{
+    interrupt_counters, process_interrupt, set_interrupt_state, split_ticks, InterruptState,
+    SharedInterruptState, TimerCallback, TimerError, TimerInstance, TimerInstanceTrait, TimerTrait,
+};
+use crate::{Arc, Locked, LockedArc};
+
+/// The local vector table entry for the timer
+const LVT_TIMER: usize = 0x320;
+/// The initial count register
+const INITIAL_COUNT: usize = 0x380;
+/// The current count register
+const CURRENT_COUNT: usize = 0x390;
+/// The divide configuration register
+const DIVIDE_CONFIGURATION: usize = 0x3e0;
+
+/// The divide configuration value for dividing the timer input clock by 16
+const DIVIDE_BY_16: u32 = 3;
+/// The timer interrupt is masked
+const LVT_MASKED: u32 = 1 << 16;
+/// The timer runs in periodic mode
+const LVT_PERIODIC: u32 = 1 << 17;
+/// The interrupt vector for the timer
+const TIMER_VECTOR: u32 = 0x20 + LAPIC_TIMER_IRQ as u32;
+
+/// The number of microseconds used to calibrate the timer against another timer
+const CALIBRATION_US: u32 = 10_000;
+
+/// The local apic timer of the processor
+pub struct LocalApicTimer {
+    /// The frequency of the timer in hertz, after the divider
+    frequency: u64,
+    /// True when the timer is handed out
+    used: bool,
+    /// The interrupt state of the timer
+    irq_state: SharedInterruptState,
+}
+
+impl LocalApicTimer {
+    /// Calibrate the local apic timer, using the given function to delay a specified number of microseconds.
+    pub fn new(delay_us: impl FnOnce(u32) -> Result<(), TimerError>) -> Option<Self> {
+        let apic = LOCAL_APIC.try_get().ok()?;
+        apic.write(DIVIDE_CONFIGURATION, DIVIDE_BY_16);
+        apic.write(LVT_TIMER, LVT_MASKED | TIMER_VECTOR);
+        apic.write(INITIAL_COUNT, u32::MAX);
+        let e = delay_us(CALIBRATION_US);
+        let current = apic.read(CURRENT_COUNT);
+        apic.write(INITIAL_COUNT, 0);
+        e.ok()?;
+        let elapsed = u32::MAX - current;
+        if elapsed == 0 {
+            return None;
+        }
+        let frequency = elapsed as u64 * (1_000_000 / CALIBRATION_US as u64);
+        let irq_state: SharedInterruptState = Arc::new(Locked::new(None));
+        {
+            let state = irq_state.clone();
+            crate::SYSTEM
+                .read()
+                .register_irq_handler(LAPIC_TIMER_IRQ, move || {
+                    if !process_interrupt(&state) {
+                        if let Ok(apic) = LOCAL_APIC.try_get() {
+                            apic.write(INITIAL_COUNT, 0);
+                        }
+                    }
+                });
+        }
+        Some(Self {
+            frequency,
+            used: false,
+            irq_state,
+        })
+    }
+
+    /// The frequency of the timer in hertz
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
+
+impl TimerTrait for LockedArc<LocalApicTimer> {
+    fn get_timer(&mut self, i: u8) -> Result<TimerInstance, TimerError> {
+        if i != 0 {
+            return Err(TimerError::TimerDoesNotExist);
+        }
+        let mut s = self.sync_lock();
+        if s.used {
+            return Err(TimerError::TimerIsAlreadyUsed);
+        }
+        s.used = true;
+        Ok(TimerInstance::X86LocalApicTimer(LockedArc::new(
+            LocalApicTimerInstance {
+                timer: self.clone(),
+                frequency: s.frequency,
+                irq_state: s.irq_state.clone(),
+            },
+        )))
+    }
+}
+
+/// The handed out instance of the local apic timer
+pub struct LocalApicTimerInstance {
+    /// The timer this instance belongs to
+    timer: LockedArc<LocalApicTimer>,
+    /// The frequency of the timer in hertz
+    frequency: u64,
+    /// The interrupt state of the timer
+    irq_state: SharedInterruptState,
+}
+
+impl LocalApicTimerInstance {
+    /// Convert a number of microseconds into timer ticks
+    fn us_to_ticks(&self, us: u64) -> u64 {
+        (us * self.frequency).div_ceil(1_000_000)
+    }
+
+    /// Busy wait for the specified number of timer ticks
+    fn delay_ticks(&self, ticks: u64) {
+        if let Ok(apic) = LOCAL_APIC.try_get() {
+            let mut remaining = ticks;
+            apic.write(LVT_TIMER, LVT_MASKED | TIMER_VECTOR);
+            while remaining > 0 {
+                let t = remaining.min(u32::MAX as u64);
+                apic.write(INITIAL_COUNT, t as u32);
+                while apic.read(CURRENT_COUNT) != 0 {}
+                remaining -= t;
+            }
+        }
+    }
+
+    /// Start the timer generating interrupts
+    fn start(&self, us: u32, f: TimerCallback, periodic: bool) -> Result<(), TimerError> {
+        let apic = LOCAL_APIC
+            .try_get()
+            .map_err(|_| TimerError::ModeNotSupported)?;
+        let (ticks, interrupts) = split_ticks(self.us_to_ticks(us as u64), u32::MAX as u64)?;
+        self.halt();
+        set_interrupt_state(
+            &self.irq_state,
+            Some(InterruptState::new(f, periodic, interrupts)),
+        );
+        apic.write(LVT_TIMER, LVT_MASKED | LVT_PERIODIC | TIMER_VECTOR);
+        apic.write(INITIAL_COUNT, ticks as u32);
+        crate::SYSTEM.read().enable_irq(LAPIC_TIMER_IRQ);
+        Ok(())
+    }
+
+    /// Stop the timer from generating interrupts
+    fn halt(&self) {
+        crate::SYSTEM.read().disable_irq(LAPIC_TIMER_IRQ);
+        if let Ok(apic) = LOCAL_APIC.try_get() {
+            apic.write(INITIAL_COUNT, 0);
+        }
+        set_interrupt_state(&self.irq_state, None);
+    }
+}
+
+impl Drop for LocalApicTimerInstance {
+    fn drop(&mut self) {
+        self.halt();
+        self.timer.sync_lock().used = false;
+    }
+}
+
+impl TimerInstanceTrait for LockedArc<LocalApicTimerInstance> {
+    fn delay_ms(&self, ms: u32) {
+        let s = self.sync_lock();
+        s.delay_ticks(s.us_to_ticks(ms as u64 * 1000));
+    }
+
+    fn delay_us(&self, us: u32) {
+        let s = self.sync_lock();
+        s.delay_ticks(s.us_to_ticks(us as u64));
+    }
+
+    fn oneshot_us(&self, us: u32, f: TimerCallback) -> Result<(), TimerError> {
+        self.sync_lock().start(us, f, false)
+    }
+
+    fn periodic_us(&self, us: u32, f: TimerCallback) -> Result<(), TimerError> {
+        self.sync_lock().start(us, f, true)
+    }
+
+    fn stop(&self) {
+        self.sync_lock().halt();
+    }
+
+    fn expirations(&self) -> u64 {
+        interrupt_counters(&self.sync_lock().irq_state).0
+    }
+
+    fn elapsed_us(&self) -> u64 {
+        interrupt_counters(&self.sync_lock().irq_state).1
+    }
+}
@@ -0,0 +1,61 @@
+//! Timer providers for x86 hardware
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+pub mod hpet;
+pub mod lapic;
+pub mod pit;
+
+/// The owners of irq 0 and irq 8, a combination of [IRQ_PIT], [IRQ_RTC] and [IRQ_HPET].
+/// Each claim is a single atomic operation, so the hpet can never take the irqs over while the pit or the cmos real time clock uses them.
+static IRQ_OWNERS: AtomicU8 = AtomicU8::new(0);
+
+/// Channel 0 of the pit generates interrupts on irq 0, which may be the tick of the monotonic clock
+const IRQ_PIT: u8 = 1 << 0;
+/// The cmos real time clock generates interrupts on irq 8
+const IRQ_RTC: u8 = 1 << 1;
+/// The hpet has taken over irq 0 and irq 8 with legacy replacement routing.
+/// Neither the pit nor the cmos real time clock can generate interrupts while this is set.
+const IRQ_HPET: u8 = 1 << 2;
+
+/// Claim an irq for the pit or the cmos real time clock, which fails when the hpet has taken it over
+fn claim_legacy_irq(owner: u8) -> bool {
+    IRQ_OWNERS
+        .fetch_update(Ordering::Acquire, Ordering::Acquire, |o| {
+            ((o & IRQ_HPET) == 0).then_some(o | owner)
+        })
+        .is_ok()
+}
+
+/// Claim irq 8 for the cmos real time clock. Returns false when the hpet has already taken over irq 8.
+pub fn claim_rtc_irq() -> bool {
+    claim_legacy_irq(IRQ_RTC)
+}
+
+/// Release irq 8 when the cmos real time clock stops generating interrupts
+pub fn release_rtc_irq() {
+    IRQ_OWNERS.fetch_and(!IRQ_RTC, Ordering::Release);
+}
+
+/// Claim irq 0 for channel 0 of the pit. Returns false when the hpet has already taken over irq 0.
+fn claim_pit_irq() -> bool {
+    claim_legacy_irq(IRQ_PIT)
+}
+
+/// Release irq 0 when channel 0 of the pit stops generating interrupts
+fn release_pit_irq() {
+    IRQ_OWNERS.fetch_and(!IRQ_PIT, Ordering::Release);
+}
+
+/// Claim irq 0 and irq 8 for legacy replacement routing of the hpet.
+/// Returns false when the pit or the cmos real time clock generate interrupts.
+fn claim_legacy_replacement() -> bool {
+    IRQ_OWNERS
+        .compare_exchange(0, IRQ_HPET, Ordering::Acquire, Ordering::Acquire)
+        .is_ok()
+}
+
+/// Release irq 0 and irq 8 when the hpet stops using legacy replacement routing
+fn release_legacy_replacement() {
+    IRQ_OWNERS.fetch_and(!IRQ_HPET, Ordering::Release);
+}
@@ -0,0 +1,236 @@
+//! The programmable interval timer (8253/8254) of x86 pc hardware
+
+use crate::kernel::SystemTrait;
+use crate::modules::timer::{
+    interrupt_counters, process_interrupt, set_interrupt_state, split_ticks, InterruptState,
+    SharedInterruptState, TimerCallback, TimerError, TimerInstance, TimerInstanceTrait, TimerTrait,
+};
+use crate::{Arc, IoPortArray, IoReadWrite, Locked, LockedArc};
+
+/// The frequency of the clock input of the pit, in hertz
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// The irq used by channel 0 of the pit
+const PIT_IRQ: u8 = 0;
+
+/// The registers of the pit, shared between the pit and its channels
+struct PitRegisters {
+    /// The io ports for the pit, 0x40-0x43
+    ports: IoPortArray<'static>,
+    /// The io port that controls the gate of channel 2, 0x61
+    gate: IoPortArray<'static>,
+}
+
+impl PitRegisters {
+    /// Program a channel with the given mode and count. A count of 0 corresponds to 65536.
+    fn program(&self, channel: u8, mode: u8, count: u16) {
+        self.ports
+            .port(3)
+            .port_write((channel << 6) | 0x30 | (mode << 1));
+        self.ports
+            .port(channel as u16)
+            .port_write((count & 0xff) as u8);
+        self.ports
+            .port(channel as u16)
+            .port_write((count >> 8) as u8);
+    }
+
+    /// Stop a channel from counting. Writing the mode without a count halts the counter.
+    fn stop(&self, channel: u8) {
+        self.ports.port(3).port_write((channel << 6) | 0x30);
+    }
+
+    /// Read the state of the output pin of a channel, using the read-back command
+    fn output(&self, channel: u8) -> bool {
+        self.ports.port(3).port_write(0xe0u8 | (2 << channel));
+        let status: u8 = self.ports.port(channel as u16).port_read();
+        (status & 0x80) != 0
+    }
+
+    /// Enable the gate for channel 2, with the speaker output disabled
+    fn enable_gate(&self) {
+        let v: u8 = self.gate.port(0).port_read();
+        self.gate.port(0).port_write((v & !2) | 1);
+    }
+
+    /// Busy wait for the specified number of pit ticks, using the specified channel
+    fn delay_ticks(&self, channel: u8, ticks: u64) {
+        let mut remaining = ticks;
+        if channel == 2 {
+            self.enable_gate();
+        }
+        while remaining > 0 {
+            let t = remaining.min(0xffff);
+            self.program(channel, 0, t as u16);
+            while !self.output(channel) {}
+            remaining -= t;
+        }
+    }
+}
+
+/// Convert a number of microseconds into pit ticks
+fn us_to_ticks(us: u32) -> u64 {
+    (us as u64 * PIT_FREQUENCY).div_ceil(1_000_000)
+}
+
+/// The programmable interval timer of an x86 pc
+pub struct Pit {
+    /// The registers of the pit
+    regs: Arc<PitRegisters>,
+    /// The channels that are currently handed out
+    used: u8,
+    /// The interrupt state of channel 0
+    irq_state: SharedInterruptState,
+}
+
+impl Pit {
+    /// Claim the io ports of the pit
+    pub fn new() -> Option<Self> {
+        let iom = crate::IO_PORT_MANAGER.as_ref()?;
+        let ports = iom.get_ports(0x40, 4)?;
+        let gate = iom.get_ports(0x61, 1)?;
+        let regs = Arc::new(PitRegisters { ports, gate });
+        regs.stop(0);
+        let irq_state: SharedInterruptState = Arc::new(Locked::new(None));
+        Some(Self {
+            regs,
+            used: 0,
+            irq_state,
+        })
+    }
+
+    /// Busy wait for the specified number of microseconds using channel 2 of the pit, if it is not in use.
+    /// This does not require claiming a channel, so it is used for calibrating other timers.
+    pub fn calibration_delay_us(&self, us: u32) -> Result<(), TimerError> {
+        if (self.used & (1 << 2)) == 0 {
+            self.regs.delay_ticks(2, us_to_ticks(us));
+            Ok(())
+        } else {
+            Err(TimerError::TimerIsAlreadyUsed)
+        }
+    }
+}
+
+impl TimerTrait for LockedArc<Pit> {
+    fn get_timer(&mut self, i: u8) -> Result<TimerInstance, TimerError> {
+        if i != 0 && i != 2 {
+            return Err(TimerError::TimerDoesNotExist);
+        }
+        let mut s = self.sync_lock();
+        let check = 1u8 << i;
+        if (s.used & check) == 0 {
+            s.used |= check;
+            Ok(TimerInstance::X86PitChannel(LockedArc::new(PitChannel {
+                pit: self.clone(),
+                regs: s.regs.clone(),
+                channel: i,
+                irq_state: s.irq_state.clone(),
+            })))
+        } else {
+            Err(TimerError::TimerIsAlreadyUsed)
+        }
+    }
+}
+
+/// A single channel of the pit. Only channel 0 can generate interrupts.
+pub struct PitChannel {
+    /// The pit that the channel belongs to
+    pit: LockedArc<Pit>,
+    /// The registers of the pit
+    regs: Arc<PitRegisters>,
+    /// The channel number
+    channel: u8,
+    /// The interrupt state of channel 0
+    irq_state: SharedInterruptState,
+}
+
+impl PitChannel {
+    /// Start channel 0 generating interrupts
+    fn start(&self, us: u32, f: TimerCallback, periodic: bool) -> Result<(), TimerError> {
+        if self.channel != 0 || !super::claim_pit_irq() {
+            return Err(TimerError::ModeNotSupported);
+        }
+        let (ticks, interrupts) = split_ticks(us_to_ticks(us).max(2), 0xffff)?;
+        {
+            // Registered after every claim, because the hpet replaces the handler while it has taken over irq 0
+            let sys = crate::SYSTEM.read();
+            sys.disable_irq(PIT_IRQ);
+            let regs = self.regs.clone();
+            let state = self.irq_state.clone();
+            sys.register_irq_handler(PIT_IRQ, move || {
+                if !process_interrupt(&state) {
+                    regs.stop(0);
+                }
+            });
+        }
+        set_interrupt_state(
+            &self.irq_state,
+            Some(InterruptState::new(f, periodic, interrupts)),
+        );
+        self.regs.program(0, 2, ticks as u16);
+        crate::SYSTEM.read().enable_irq(PIT_IRQ);
+        Ok(())
+    }
+}
+
+impl Drop for PitChannel {
+    fn drop(&mut self) {
+        if self.channel == 0 {
+            crate::SYSTEM.read().disable_irq(PIT_IRQ);
+            set_interrupt_state(&self.irq_state, None);
+            super::release_pit_irq();
+        }
+        self.regs.stop(self.channel);
+        let mut p = self.pit.sync_lock();
+        p.used &= !(1 << self.channel);
+    }
+}
+
+impl TimerInstanceTrait for LockedArc<PitChannel> {
+    fn delay_ms(&self, ms: u32) {
+        let s = self.sync_lock();
+        s.regs
+            .delay_ticks(s.channel, (ms as u64 * PIT_FREQUENCY).div_ceil(1000));
+    }
+
+    fn delay_us(&self, us: u32) {
+        let s = self.sync_lock();
+        s.regs.delay_ticks(s.channel, us_to_ticks(us));
+    }
+
+    fn oneshot_us(&self, us: u32, f: TimerCallback) -> Result<(), TimerError> {
+        self.sync_lock().start(us, f, false)
+    }
+
+    fn periodic_us(&self, us: u32, f: TimerCallback) -> Result<(), TimerError> {
+        self.sync_lock().start(us, f, true)
+    }
+
+    fn stop(&self) {
+        let s = self.sync_lock();
+        if s.channel == 0 {
+            crate::SYSTEM.read().disable_irq(PIT_IRQ);
+            set_interrupt_state(&s.irq_state, None);
+            super::release_pit_irq();
+        }
+        s.regs.stop(s.channel);
+    }
+
+    fn expirations(&self) -> u64 {
+        let s = self.sync_lock();
+        if s.channel == 0 {
+            interrupt_counters(&s.irq_state).0
+        } else {
+            0
+        }
+    }
+
+    fn elapsed_us(&self) -> u64 {
+        let s = self.sync_lock();
+        if s.channel == 0 {
+            interrupt_counters(&s.irq_state).1
+        } else {
+            0
+        }
+    }
+}
@@ -6,7 +6,7 @@ use rand::RngCore;
 struct Args {
     /// Name of the network interface to use
     #[arg(short, long)]
-    name: String,
+    name: Option<String>,
 
     /// Number of packets to listen for
     #[arg(short, long, default_value_t = 10)]
@@ -15,15 +15,51 @@ struct Args {
     /// Number of random packets to send
     #[arg(short, long, default_value_t = 0)]
     random_count: usize,
+
+    /// Address and port of a udp echo service to exchange datagrams with, such as 10.0.2.15:7
+    #[arg(short, long)]
+    udp: Option<std::net::SocketAddr>,
+
+    /// Number of datagrams to send to the udp echo service
+    #[arg(long, default_value_t = 4)]
+    udp_count: usize,
+}
+
+/// Send random datagrams to a udp echo service and check that they come back unchanged
+fn udp_echo(target: std::net::SocketAddr, count: usize) -> std::io::Result<()> {
+    let s = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    s.set_read_timeout(Some(std::time::Duration::from_secs(2)))?;
+    s.connect(target)?;
+    let mut rng = rand::rng();
+    for i in 0..count {
+        let mut buf = vec![0; 64];
+        rng.fill_bytes(&mut buf);
+        let start = std::time::Instant::now();
+        s.send(&buf)?;
+        let mut reply = [0; 1500];
+        match s.recv(&mut reply) {
+            Ok(n) if reply[..n] == buf[..] => {
+                println!("Datagram {} echoed in {:?}", i, start.elapsed())
+            }
+            Ok(n) => println!("Datagram {} came back with different contents, {} bytes", i, n),
+            Err(e) => println!("No reply to datagram {}: {:?}", i, e),
+        }
+    }
+    Ok(())
 }
 
 fn main() {
     let args = Args::parse();
+    if let Some(target) = args.udp {
+        if let Err(e) = udp_echo(target, args.udp_count) {
+            println!("Failed to exchange datagrams {:?}", e);
+        }
+    }
     let d = pcap::Device::list();
     let mut interfaces = Vec::new();
     if let Ok(list) = d {
         for d in list {
-            if d.name == args.name {
+            if Some(&d.name) == args.name.as_ref() {
                 println!("Device:");
                 println!("\t {:?}", d);
                 println!();